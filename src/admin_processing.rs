@@ -0,0 +1,197 @@
+use std::io;
+use rusqlite::Connection;
+use crate::configuration::Config;
+use crate::role_object::{Permission, Role};
+use serde_json::json;
+use crate::{audit_management, totp_management, user_management};
+use crate::user_object::User;
+use crate::utilities::{clear_screen, get_yes_or_no, require_permission};
+
+pub(crate) fn list_users(database_name: &str, config: &Config) {
+    clear_screen();
+    print_list_users_header();
+    let connection = match Connection::open(database_name) {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("Failed to connect to the database: {}", e);
+            return;
+        }
+    };
+
+    let mut stmt = match connection.prepare(
+        "SELECT users.user_id, users.email, users.firstname, users.lastname,
+                COALESCE(user_roles.role, 'patron')
+         FROM users
+         LEFT JOIN user_roles ON user_roles.user_id = users.user_id
+         ORDER BY users.user_id",
+    ) {
+        Ok(stmt) => stmt,
+        Err(e) => {
+            eprintln!("Failed to prepare user query: {}", e);
+            return;
+        }
+    };
+
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i32>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, String>(4)?,
+        ))
+    });
+
+    match rows {
+        Ok(rows) => {
+            for (shown, (user_id, email, firstname, lastname, role_name)) in rows.flatten().enumerate() {
+                if shown == config.interface.max_list_rows {
+                    println!("Only the first {} users are shown.", shown);
+                    break;
+                }
+                let locked = user_management::is_account_locked(database_name, &email, config)
+                    .unwrap_or(false);
+                let role = Role::from_name(&role_name).unwrap_or_default();
+                let two_factor = totp_management::is_two_factor_enabled(database_name, user_id)
+                    .unwrap_or(false);
+                println!(
+                    "ID: {}, Email: {}, Name: {} {}, Role: {}, 2FA: {}, Locked: {}",
+                    user_id, email, firstname, lastname, role.get_display_name(), two_factor, locked
+                );
+            }
+        }
+        Err(e) => println!("Error retrieving users: {}", e),
+    }
+}
+
+pub(crate) fn unlock_user(database_name: &str, actor: &User) -> bool {
+    clear_screen();
+    print_unlock_user_header();
+    loop {
+        println!("Enter the email of the user to unlock: ");
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input).is_err() {
+            println!("Failed to read input. Please try again.");
+            continue;
+        }
+        let email = input.trim().to_lowercase();
+        if email.is_empty() {
+            println!("No email entered. Please try again.");
+            continue;
+        }
+
+        if user_management::get_user_id_by_email(database_name, &email).is_err() {
+            println!("No user with email {} exists. Please try again.", email);
+            continue;
+        }
+
+        println!("You would like to unlock {}? (y/n)", email);
+        if !get_yes_or_no() { return false; }
+
+        return match user_management::unlock_account(database_name, &email) {
+            Ok(_) => {
+                audit_management::log_event(
+                    database_name, Some(actor.get_user_id()), audit_management::ADMIN_UNLOCKED_USER, &email, json!({})
+                );
+                true
+            }
+            Err(e) => {
+                println!("Failed to unlock {}: {}", email, e);
+                false
+            }
+        };
+    }
+}
+
+pub(crate) fn change_user_role(database_name: &str, actor: &User) -> bool {
+    clear_screen();
+    print_change_role_header();
+    let (user_id, email) = loop {
+        println!("Enter the email of the user whose role you want to change: ");
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input).is_err() {
+            println!("Failed to read input. Please try again.");
+            continue;
+        }
+        let email = input.trim().to_lowercase();
+        match user_management::get_user_id_by_email(database_name, &email) {
+            Ok(user_id) => break (user_id, email),
+            Err(_) => println!("No user with email {} exists. Please try again.", email),
+        }
+    };
+
+    if user_id == actor.get_user_id() {
+        println!("You cannot change your own role.");
+        return false;
+    }
+
+    let current_role = match user_management::get_user_role_by_id(database_name, &user_id) {
+        Ok(role) => role,
+        Err(e) => {
+            println!("Failed to read the current role of {}: {}", email, e);
+            return false;
+        }
+    };
+    println!("{} currently has the role: {}", email, current_role.get_display_name());
+
+    let new_role = loop {
+        println!("Choose the new role:");
+        for (index, role) in Role::ALL.iter().enumerate() {
+            println!("\t{}. {}", index + 1, role.get_display_name());
+        }
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input).is_err() {
+            println!("Failed to read input. Please try again.");
+            continue;
+        }
+        match input.trim().parse::<usize>() {
+            Ok(choice) if (1..=Role::ALL.len()).contains(&choice) => break Role::ALL[choice - 1],
+            _ => println!("Invalid choice. Please try again."),
+        }
+    };
+
+    // Only system administrators may hand out or take away system administration.
+    if (new_role == Role::Sysadmin || current_role == Role::Sysadmin)
+        && !require_permission(actor, Permission::ManageSystem)
+    {
+        return false;
+    }
+
+    println!("Change the role of {} to {}? (y/n)", email, new_role.get_display_name());
+    if !get_yes_or_no() { return false; }
+
+    match user_management::set_user_role(database_name, user_id, new_role) {
+        Ok(_) => {
+            audit_management::log_event(
+                database_name,
+                Some(actor.get_user_id()),
+                audit_management::ADMIN_CHANGED_ROLE,
+                &email,
+                json!({ "role": { "old": current_role.get_name(), "new": new_role.get_name() } }),
+            );
+            true
+        }
+        Err(e) => {
+            println!("Failed to change the role of {}: {}", email, e);
+            false
+        }
+    }
+}
+
+fn print_list_users_header() {
+    println!("################");
+    println!("## List Users ##");
+    println!("################");
+}
+
+fn print_unlock_user_header() {
+    println!("#################");
+    println!("## Unlock User ##");
+    println!("#################");
+}
+
+fn print_change_role_header() {
+    println!("######################");
+    println!("## Change User Role ##");
+    println!("######################");
+}
@@ -0,0 +1,233 @@
+use std::error::Error;
+use std::fs;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use serde_json::Value;
+use crate::configuration::Config;
+use crate::utilities::{self, clear_screen, get_optional_input, get_yes_or_no};
+
+// Action names are stored in the database, so existing ones must not be renamed.
+pub const USER_REGISTERED: &str = "user.registered";
+pub const LOGIN_SUCCEEDED: &str = "login.succeeded";
+pub const LOGIN_FAILED: &str = "login.failed";
+pub const LOGIN_LOCKED: &str = "login.locked";
+pub const EMAIL_VERIFIED: &str = "user.email_verified";
+pub const PASSWORD_RESET: &str = "user.password_reset";
+pub const TWO_FACTOR_ENABLED: &str = "user.two_factor_enabled";
+pub const TWO_FACTOR_DISABLED: &str = "user.two_factor_disabled";
+pub const RECOVERY_CODES_REGENERATED: &str = "user.recovery_codes_regenerated";
+pub const BOOK_ADDED_TO_COLLECTION: &str = "book.added_to_collection";
+pub const BOOK_DELETED_FROM_COLLECTION: &str = "book.deleted_from_collection";
+pub const BOOK_RECORD_EDITED: &str = "book.record_edited";
+pub const BOOK_RECORD_REFRESHED: &str = "book.record_refreshed";
+pub const BOOKS_MERGED: &str = "book.merged";
+pub const AUTHORS_MERGED: &str = "author.merged";
+pub const HOLDING_LOCATED: &str = "holding.located";
+pub const HOLDINGS_MARKED_LOST: &str = "holding.marked_lost";
+pub const ADMIN_UNLOCKED_USER: &str = "admin.unlocked_user";
+pub const ADMIN_CHANGED_ROLE: &str = "admin.changed_role";
+
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    pub audit_id: i64,
+    pub occurred_at: String,
+    pub actor_user_id: Option<i32>,
+    pub actor_email: Option<String>,
+    pub action: String,
+    pub target: String,
+    pub diff: String,
+}
+
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    pub user_email: Option<String>,
+    pub action: Option<String>,
+    pub from: Option<i64>,
+    pub until: Option<i64>,
+}
+
+/*
+ *  The audit_log table is append-only: triggers created alongside it
+ *  refuse any UPDATE or DELETE, so entries can only ever be added here.
+ *  The actor's email is copied into the entry so it still makes sense
+ *  after the user has been removed.
+ */
+pub fn record_event(connection: &Connection, actor_user_id: Option<i32>, action: &str, target: &str, diff: Value) -> Result<(), rusqlite::Error> {
+    connection.execute(
+        "INSERT INTO audit_log (occurred_at, actor_user_id, actor_email, action, target, diff)
+         VALUES (?1, ?2, (SELECT email FROM users WHERE user_id = ?2), ?3, ?4, ?5)",
+        params![utilities::current_timestamp(), actor_user_id, action, target, diff.to_string()],
+    )?;
+    Ok(())
+}
+
+/// Convenience wrapper for callers without an open connection. Failures are
+/// reported but never stop the action that is being audited.
+pub fn log_event(database_name: &str, actor_user_id: Option<i32>, action: &str, target: &str, diff: Value) {
+    let result = Connection::open(database_name)
+        .and_then(|connection| record_event(&connection, actor_user_id, action, target, diff));
+    if let Err(e) = result {
+        eprintln!("Failed to write to the audit log: {}", e);
+    }
+}
+
+pub fn query_audit_log(database_name: &str, filter: &AuditFilter) -> Result<Vec<AuditEntry>, rusqlite::Error> {
+    let connection = Connection::open(database_name)?;
+    let mut stmt = connection.prepare(
+        "SELECT audit_id, occurred_at, actor_user_id, actor_email, action, target, diff
+         FROM audit_log
+         WHERE (?1 IS NULL OR actor_email = ?1 OR target = ?1)
+           AND (?2 IS NULL OR action = ?2 OR action LIKE ?2 || '.%')
+           AND (?3 IS NULL OR occurred_at >= ?3)
+           AND (?4 IS NULL OR occurred_at <= ?4)
+         ORDER BY audit_id",
+    )?;
+    let rows = stmt.query_map(
+        params![filter.user_email, filter.action, filter.from, filter.until],
+        |row| {
+            Ok(AuditEntry {
+                audit_id: row.get(0)?,
+                occurred_at: utilities::format_timestamp(row.get(1)?),
+                actor_user_id: row.get(2)?,
+                actor_email: row.get(3)?,
+                action: row.get(4)?,
+                target: row.get(5)?,
+                diff: row.get(6)?,
+            })
+        },
+    )?;
+    rows.collect()
+}
+
+pub fn export_audit_log(entries: &[AuditEntry], format: &str) -> Result<String, Box<dyn Error>> {
+    let file_name = format!("audit_log_{}.{}", utilities::current_timestamp(), format);
+    match format {
+        "csv" => {
+            let mut writer = csv::Writer::from_path(&file_name)?;
+            for entry in entries {
+                writer.serialize(entry)?;
+            }
+            writer.flush()?;
+        }
+        "json" => fs::write(&file_name, serde_json::to_string_pretty(entries)?)?,
+        other => return Err(format!("Unknown export format: {}", other).into()),
+    }
+    Ok(file_name)
+}
+
+pub(crate) fn view_audit_log(database_name: &str, config: &Config) -> bool {
+    clear_screen();
+    print_audit_log_header();
+    let filter = AuditFilter {
+        user_email: get_optional_input("Filter by user email (leave blank for all):")
+            .map(|email| email.to_lowercase()),
+        action: get_optional_input("Filter by action, e.g. login or login.failed (leave blank for all):"),
+        from: get_optional_date("From date YYYY-MM-DD (leave blank for no limit):", false),
+        until: get_optional_date("Until date YYYY-MM-DD (leave blank for no limit):", true),
+    };
+
+    let entries = match query_audit_log(database_name, &filter) {
+        Ok(entries) => entries,
+        Err(e) => {
+            println!("Failed to read the audit log: {}", e);
+            return false;
+        }
+    };
+
+    // Only the most recent entries are printed; an export always contains all of them.
+    let skipped = entries.len().saturating_sub(config.interface.max_list_rows);
+    if skipped > 0 {
+        println!("{} older entries are not shown. Export to see all of them.", skipped);
+    }
+    for entry in &entries[skipped..] {
+        println!(
+            "[{}] {} by {} on {} {}",
+            entry.occurred_at,
+            entry.action,
+            entry.actor_email.as_deref().unwrap_or("(nobody)"),
+            entry.target,
+            entry.diff
+        );
+    }
+    println!("{} matching entr{}.", entries.len(), if entries.len() == 1 { "y" } else { "ies" });
+    if entries.is_empty() { return true; }
+
+    println!("Would you like to export these entries? (y/n)");
+    if !get_yes_or_no() { return true; }
+    let format = loop {
+        match get_optional_input("Export format (csv/json):").as_deref() {
+            Some("csv") => break "csv",
+            Some("json") => break "json",
+            _ => println!("Invalid format. Please enter csv or json."),
+        }
+    };
+    match export_audit_log(&entries, format) {
+        Ok(file_name) => {
+            println!("Audit log exported to {}", file_name);
+            true
+        }
+        Err(e) => {
+            println!("Failed to export the audit log: {}", e);
+            false
+        }
+    }
+}
+
+fn get_optional_date(prompt: &str, end_of_day: bool) -> Option<i64> {
+    loop {
+        let input = get_optional_input(prompt)?;
+        match utilities::parse_date_to_timestamp(&input, end_of_day) {
+            Some(timestamp) => return Some(timestamp),
+            None => println!("Invalid date. Please use the format YYYY-MM-DD."),
+        }
+    }
+}
+
+fn print_audit_log_header() {
+    println!("###############");
+    println!("## Audit Log ##");
+    println!("###############");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::initialisation;
+
+    #[test]
+    fn entries_keep_the_actors_email_and_cannot_be_changed() {
+        let (database_name, connection) = initialisation::open_test_database("audit_append_only");
+        connection.execute(
+            "INSERT INTO users (user_id, email, firstname, lastname) VALUES (1, 'ada@example.org', 'Ada', 'Lovelace')", [],
+        ).unwrap();
+        record_event(&connection, Some(1), LOGIN_FAILED, "ada@example.org", json!({"attempts": 2})).unwrap();
+        connection.execute("DELETE FROM users WHERE user_id = 1", []).unwrap();
+
+        let entries = query_audit_log(&database_name, &AuditFilter::default()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].actor_email.as_deref(), Some("ada@example.org"));
+        assert_eq!(entries[0].diff, r#"{"attempts":2}"#);
+        assert!(connection.execute("UPDATE audit_log SET action = 'x'", []).is_err());
+        assert!(connection.execute("DELETE FROM audit_log", []).is_err());
+    }
+
+    #[test]
+    fn the_filter_matches_a_user_an_action_family_and_a_date_range() {
+        let (database_name, connection) = initialisation::open_test_database("audit_filter");
+        record_event(&connection, None, LOGIN_FAILED, "ada@example.org", json!({})).unwrap();
+        record_event(&connection, None, LOGIN_SUCCEEDED, "bob@example.org", json!({})).unwrap();
+        record_event(&connection, None, BOOK_RECORD_EDITED, "book 1", json!({})).unwrap();
+
+        let actions = |filter: AuditFilter| -> Vec<String> {
+            query_audit_log(&database_name, &filter).unwrap().into_iter().map(|entry| entry.action).collect()
+        };
+        assert_eq!(actions(AuditFilter { action: Some("login".to_string()), ..AuditFilter::default() }), vec![LOGIN_FAILED, LOGIN_SUCCEEDED]);
+        // "log" is not the start of a family, only of a word.
+        assert!(actions(AuditFilter { action: Some("log".to_string()), ..AuditFilter::default() }).is_empty());
+        assert_eq!(actions(AuditFilter { user_email: Some("bob@example.org".to_string()), ..AuditFilter::default() }), vec![LOGIN_SUCCEEDED]);
+        let now = utilities::current_timestamp();
+        assert_eq!(actions(AuditFilter { from: Some(now - 60), until: Some(now + 60), ..AuditFilter::default() }).len(), 3);
+        assert!(actions(AuditFilter { until: Some(now - 60), ..AuditFilter::default() }).is_empty());
+    }
+}
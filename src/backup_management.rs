@@ -0,0 +1,370 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use chrono::Local;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rusqlite::backup::Backup;
+use rusqlite::Connection;
+use crate::configuration::BackupConfig;
+use crate::initialisation;
+use crate::utilities::clear_screen;
+
+const BACKUP_EXTENSION: &str = ".sqlite.gz";
+// Pages copied per step of the online backup; other connections can use
+// the database in between steps.
+const PAGES_PER_STEP: i32 = 100;
+const PAUSE_BETWEEN_STEPS: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Default)]
+pub struct IntegrityReport {
+    pub integrity_errors: Vec<String>,
+    pub foreign_key_errors: Vec<String>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.integrity_errors.is_empty() && self.foreign_key_errors.is_empty()
+    }
+}
+
+/*
+ *  Backups are taken with the SQLite online backup API, so they are
+ *  consistent even while rLMS is in use. The copy is written next to
+ *  the backups, gzipped into <database>-<timestamp>.sqlite.gz and then
+ *  removed. Old backups are pruned according to the retention settings.
+ */
+pub fn create_backup(database_name: &str, backup_config: &BackupConfig) -> Result<PathBuf, Box<dyn Error>> {
+    let directory = Path::new(&backup_config.directory);
+    fs::create_dir_all(directory)?;
+    let file_name = format!(
+        "{}-{}{}",
+        get_database_stem(database_name),
+        Local::now().format("%Y%m%d-%H%M%S-%3f"),
+        BACKUP_EXTENSION
+    );
+    let backup_path = directory.join(&file_name);
+    let copy_path = directory.join(format!("{}.part", file_name));
+
+    let result = copy_database(database_name, &copy_path).and_then(|_| compress(&copy_path, &backup_path));
+    let _ = fs::remove_file(&copy_path);
+    result?;
+
+    prune_backups(database_name, backup_config)?;
+    Ok(backup_path)
+}
+
+fn copy_database(database_name: &str, destination: &Path) -> Result<(), Box<dyn Error>> {
+    let source = Connection::open(database_name)?;
+    let mut copy = Connection::open(destination)?;
+    Backup::new(&source, &mut copy)?.run_to_completion(PAGES_PER_STEP, PAUSE_BETWEEN_STEPS, None)?;
+    Ok(())
+}
+
+fn compress(source: &Path, destination: &Path) -> Result<(), Box<dyn Error>> {
+    let mut input = BufReader::new(File::open(source)?);
+    let mut encoder = GzEncoder::new(BufWriter::new(File::create(destination)?), Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+fn decompress(source: &Path, destination: &Path) -> Result<(), Box<dyn Error>> {
+    let mut decoder = GzDecoder::new(BufReader::new(File::open(source)?));
+    let mut output = BufWriter::new(File::create(destination)?);
+    io::copy(&mut decoder, &mut output)?;
+    Ok(())
+}
+
+fn get_database_stem(database_name: &str) -> String {
+    Path::new(database_name)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| "rlms".to_string())
+}
+
+/// Returns the backups of this database, oldest first.
+pub fn list_backups(database_name: &str, backup_config: &BackupConfig) -> Result<Vec<PathBuf>, io::Error> {
+    let prefix = format!("{}-", get_database_stem(database_name));
+    let directory = Path::new(&backup_config.directory);
+    if !directory.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut backups: Vec<PathBuf> = fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .map(|name| name.to_string_lossy())
+                .is_some_and(|name| name.starts_with(&prefix) && name.ends_with(BACKUP_EXTENSION))
+        })
+        .collect();
+    // The timestamp in the name sorts the same way as the time it was taken.
+    backups.sort();
+    Ok(backups)
+}
+
+fn prune_backups(database_name: &str, backup_config: &BackupConfig) -> Result<(), io::Error> {
+    let backups = list_backups(database_name, backup_config)?;
+    let excess = backups.len().saturating_sub(backup_config.keep_last);
+    let max_age = Duration::from_secs(backup_config.max_age_days * 24 * 60 * 60);
+
+    for (index, backup) in backups.iter().enumerate() {
+        let too_old = backup_config.max_age_days > 0
+            && fs::metadata(backup)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                .is_some_and(|age| age > max_age);
+        // The newest backup is always kept, however old it is.
+        if index < excess || (too_old && index + 1 < backups.len()) {
+            fs::remove_file(backup)?;
+        }
+    }
+    Ok(())
+}
+
+/*
+ *  Replaces the contents of the database with a backup. The backup is
+ *  checked before anything is touched: it must pass the integrity check
+ *  and must not come from a newer version of rLMS than this one. A backup
+ *  of the current database is taken first, so a restore can be undone.
+ */
+pub fn restore_backup(database_name: &str, backup_path: &Path, backup_config: &BackupConfig) -> Result<PathBuf, Box<dyn Error>> {
+    let restore_path = PathBuf::from(format!("{}.restore", database_name));
+    let result = restore_from_copy(database_name, backup_path, &restore_path, backup_config);
+    let _ = fs::remove_file(&restore_path);
+    result
+}
+
+fn restore_from_copy(database_name: &str, backup_path: &Path, restore_path: &Path, backup_config: &BackupConfig) -> Result<PathBuf, Box<dyn Error>> {
+    decompress(backup_path, restore_path)
+        .map_err(|e| format!("Failed to read backup {}: {}", backup_path.display(), e))?;
+    let restored = Connection::open(restore_path)?;
+
+    let version = initialisation::get_schema_version(&restored)
+        .map_err(|e| format!("{} is not a valid database backup: {}", backup_path.display(), e))?;
+    if version > initialisation::SCHEMA_VERSION {
+        return Err(format!(
+            "The backup has schema version {} but this version of rLMS only supports up to {}",
+            version,
+            initialisation::SCHEMA_VERSION
+        ).into());
+    }
+    let has_users: bool = restored.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'users')",
+        [],
+        |row| row.get(0),
+    )?;
+    if !has_users {
+        return Err(format!("{} is not an rLMS database backup", backup_path.display()).into());
+    }
+    let report = check_connection_integrity(&restored)?;
+    if !report.is_ok() {
+        return Err(format!("The backup {} failed its integrity check", backup_path.display()).into());
+    }
+
+    let safety_backup = create_backup(database_name, backup_config)?;
+    let mut live = Connection::open(database_name)?;
+    Backup::new(&restored, &mut live)?.run_to_completion(PAGES_PER_STEP, PAUSE_BETWEEN_STEPS, None)?;
+    drop(live);
+
+    // Backups from an older version are brought up to date straight away.
+    if !initialisation::update_existing_tables(database_name) {
+        return Err("Failed to update the restored database tables".into());
+    }
+    Ok(safety_backup)
+}
+
+pub fn check_integrity(database_name: &str) -> Result<IntegrityReport, rusqlite::Error> {
+    let connection = Connection::open(database_name)?;
+    check_connection_integrity(&connection)
+}
+
+fn check_connection_integrity(connection: &Connection) -> Result<IntegrityReport, rusqlite::Error> {
+    let mut report = IntegrityReport::default();
+
+    let mut stmt = connection.prepare("PRAGMA integrity_check")?;
+    for message in stmt.query_map([], |row| row.get::<_, String>(0))? {
+        let message = message?;
+        if message != "ok" {
+            report.integrity_errors.push(message);
+        }
+    }
+
+    let mut stmt = connection.prepare("PRAGMA foreign_key_check")?;
+    let rows = stmt.query_map([], |row| {
+        Ok(format!(
+            "{} row {} refers to a missing row in {}",
+            row.get::<_, String>(0)?,
+            row.get::<_, Option<i64>>(1)?.map_or("?".to_string(), |id| id.to_string()),
+            row.get::<_, String>(2)?
+        ))
+    })?;
+    for row in rows {
+        report.foreign_key_errors.push(row?);
+    }
+
+    Ok(report)
+}
+
+pub fn print_integrity_report(report: &IntegrityReport) {
+    if report.is_ok() {
+        println!("Integrity check passed. No problems found.");
+        return;
+    }
+    for message in &report.integrity_errors {
+        println!("Integrity problem: {}", message);
+    }
+    for message in &report.foreign_key_errors {
+        println!("Foreign key problem: {}", message);
+    }
+    println!(
+        "{} integrity problem(s) and {} foreign key problem(s) found.",
+        report.integrity_errors.len(),
+        report.foreign_key_errors.len()
+    );
+}
+
+pub(crate) fn backup_database(database_name: &str, backup_config: &BackupConfig) -> bool {
+    clear_screen();
+    print_backup_header();
+    println!("Backing up {}...", database_name);
+    match create_backup(database_name, backup_config) {
+        Ok(path) => {
+            println!("Backup written to {}", path.display());
+            true
+        }
+        Err(e) => {
+            println!("Backup failed: {}", e);
+            false
+        }
+    }
+}
+
+pub(crate) fn check_database(database_name: &str) -> bool {
+    clear_screen();
+    print_check_database_header();
+    match check_integrity(database_name) {
+        Ok(report) => {
+            print_integrity_report(&report);
+            report.is_ok()
+        }
+        Err(e) => {
+            println!("Failed to check the database: {}", e);
+            false
+        }
+    }
+}
+
+/*
+ *  Runs for as long as the server does, taking a backup every
+ *  backup.interval_minutes. The backup itself blocks, so it is moved off
+ *  the async runtime.
+ */
+pub async fn run_scheduled_backups(database_name: String, backup_config: BackupConfig) {
+    let mut interval = tokio::time::interval(Duration::from_secs(backup_config.interval_minutes * 60));
+    // The first tick completes immediately; the first backup is one interval after start-up.
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let database_name = database_name.clone();
+        let backup_config = backup_config.clone();
+        match tokio::task::spawn_blocking(move || {
+            create_backup(&database_name, &backup_config).map_err(|e| e.to_string())
+        }).await {
+            Ok(Ok(path)) => log::info!("Scheduled backup written to {}", path.display()),
+            Ok(Err(e)) => log::error!("Scheduled backup failed: {}", e),
+            Err(e) => log::error!("Scheduled backup task failed: {}", e),
+        }
+    }
+}
+
+fn print_backup_header() {
+    println!("#####################");
+    println!("## Backup Database ##");
+    println!("#####################");
+}
+
+fn print_check_database_header() {
+    println!("####################");
+    println!("## Check Database ##");
+    println!("####################");
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utilities;
+
+    // A database file with all the tables in a directory of its own.
+    fn create_database(name: &str) -> (PathBuf, String, BackupConfig) {
+        utilities::set_pause_scale(0.0);
+        let directory = std::env::temp_dir().join(format!("rlms-{}-{}", name, utilities::generate_token(8)));
+        fs::create_dir_all(&directory).unwrap();
+        let database_name = directory.join("library.sqlite").to_string_lossy().to_string();
+        assert!(initialisation::create_initial_tables(&database_name));
+        let backup_config = BackupConfig {
+            directory: directory.join("backups").to_string_lossy().to_string(),
+            keep_last: 2,
+            ..BackupConfig::default()
+        };
+        (directory, database_name, backup_config)
+    }
+
+    fn count_books(database_name: &str) -> i64 {
+        Connection::open(database_name).unwrap()
+            .query_row("SELECT COUNT(*) FROM books", [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn a_restore_brings_back_the_backup_and_keeps_the_current_database_as_a_backup() {
+        let (directory, database_name, backup_config) = create_database("restore");
+        let backup = create_backup(&database_name, &backup_config).unwrap();
+        Connection::open(&database_name).unwrap()
+            .execute("INSERT INTO books (title, author) VALUES ('The Hobbit', 'J. R. R. Tolkien')", []).unwrap();
+
+        let safety_backup = restore_backup(&database_name, &backup, &backup_config).unwrap();
+        assert_eq!(count_books(&database_name), 0);
+        assert!(!Path::new(&format!("{}.restore", database_name)).exists());
+        restore_backup(&database_name, &safety_backup, &backup_config).unwrap();
+        assert_eq!(count_books(&database_name), 1);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn only_the_most_recent_backups_are_kept() {
+        let (directory, database_name, backup_config) = create_database("prune");
+        let backups: Vec<PathBuf> = (0..3).map(|_| create_backup(&database_name, &backup_config).unwrap()).collect();
+        assert_eq!(list_backups(&database_name, &backup_config).unwrap(), backups[1..]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn backups_from_a_newer_version_are_refused() {
+        let (directory, database_name, backup_config) = create_database("newer");
+        Connection::open(&database_name).unwrap()
+            .pragma_update(None, "user_version", initialisation::SCHEMA_VERSION + 1).unwrap();
+        let backup = create_backup(&database_name, &backup_config).unwrap();
+        assert!(restore_backup(&database_name, &backup, &backup_config).is_err());
+        // Nothing was touched, not even by a safety backup.
+        assert_eq!(list_backups(&database_name, &backup_config).unwrap(), vec![backup]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn the_integrity_check_reports_rows_that_refer_to_missing_rows() {
+        let (directory, database_name, _) = create_database("integrity");
+        assert!(check_integrity(&database_name).unwrap().is_ok());
+        Connection::open(&database_name).unwrap()
+            .execute("INSERT INTO libraries (user_id, book_id) VALUES (1, 1)", []).unwrap();
+        let report = check_integrity(&database_name).unwrap();
+        assert!(report.integrity_errors.is_empty());
+        assert_eq!(report.foreign_key_errors.len(), 2);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
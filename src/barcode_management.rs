@@ -0,0 +1,318 @@
+use std::error::Error;
+use rusqlite::{params, Connection, OptionalExtension};
+use crate::configuration::BarcodeConfig;
+
+/*
+ *  Barcodes identify copies and patrons at the desk. Each one is made
+ *  from the configured prefix, a digit saying what it identifies and the
+ *  copy or user ID, so it is unique without keeping a counter. Once
+ *  issued a barcode is stored, so changing the prefix or symbology later
+ *  never changes a label that has already been printed.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarcodeKind {
+    Item,
+    Patron,
+}
+
+impl BarcodeKind {
+    // The digit after the prefix; barcode numbers must never change once issued.
+    fn get_digit(&self) -> char {
+        match self {
+            BarcodeKind::Item => '1',
+            BarcodeKind::Patron => '2',
+        }
+    }
+
+    // The table the barcode is stored in, and its key.
+    fn get_table(&self) -> (&'static str, &'static str) {
+        match self {
+            BarcodeKind::Item => ("holdings", "holding_id"),
+            BarcodeKind::Patron => ("users", "user_id"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Symbology {
+    Code128,
+    Ean13,
+}
+
+impl Symbology {
+    pub fn from_name(name: &str) -> Option<Symbology> {
+        match name.trim().to_lowercase().as_str() {
+            "code128" => Some(Symbology::Code128),
+            "ean13" => Some(Symbology::Ean13),
+            _ => None,
+        }
+    }
+
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Symbology::Code128 => "Code 128",
+            Symbology::Ean13 => "EAN-13",
+        }
+    }
+}
+
+// What a scanned barcode belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarcodeOwner {
+    Item(i64),
+    Patron(i32),
+}
+
+// Digits before the EAN-13 check digit; Code 128 barcodes are the same length so both kinds read alike.
+const BARCODE_BODY_LENGTH: usize = 12;
+
+/*
+ *  Bar and space widths of each Code 128 symbol, starting with a bar.
+ *  103 to 105 are the start codes for code sets A, B and C, and 106 is
+ *  the stop code, which ends with an extra bar.
+ */
+const CODE128_PATTERNS: [&str; 107] = [
+    "212222", "222122", "222221", "121223", "121322", "131222", "122213", "122312", "132212", "221213",
+    "221312", "231212", "112232", "122132", "122231", "113222", "123122", "123221", "223211", "221132",
+    "221231", "213212", "223112", "312131", "311222", "321122", "321221", "312212", "322112", "322211",
+    "212123", "212321", "232121", "111323", "131123", "131321", "112313", "132113", "132311", "211313",
+    "231113", "231311", "112133", "112331", "132131", "113123", "113321", "133121", "313121", "211331",
+    "231131", "213113", "213311", "213131", "311123", "311321", "331121", "312113", "312311", "332111",
+    "314111", "221411", "431111", "111224", "111422", "121124", "121421", "141122", "141221", "112214",
+    "112412", "122114", "122411", "142112", "142211", "241211", "221114", "413111", "241112", "134111",
+    "111242", "121142", "121241", "114212", "124112", "124211", "411212", "421112", "421211", "212141",
+    "214121", "412121", "111143", "111341", "131141", "114113", "114311", "411113", "411311", "113141",
+    "114131", "311141", "411131", "211412", "211214", "211232", "2331112",
+];
+const CODE128_START_B: usize = 104;
+const CODE128_START_C: usize = 105;
+const CODE128_STOP: usize = 106;
+
+// EAN-13 left-hand "L" codes; "R" codes are their inverse and "G" codes the R codes reversed.
+const EAN_L_CODES: [&str; 10] = [
+    "0001101", "0011001", "0010011", "0111101", "0100011", "0110001", "0101111", "0111011", "0110111", "0001011",
+];
+// The first digit of an EAN-13 is not drawn, but chosen by which left-hand digits use G codes.
+const EAN_PARITY: [&str; 10] = [
+    "LLLLLL", "LLGLGG", "LLGGLG", "LLGGGL", "LGLLGG", "LGGLLG", "LGGGLL", "LGLGLL", "LGLGGL", "LGGLGL",
+];
+
+pub fn make_barcode(config: &BarcodeConfig, kind: BarcodeKind, id: i64) -> Result<String, String> {
+    let width = BARCODE_BODY_LENGTH.saturating_sub(config.prefix.len() + 1);
+    let body = format!("{}{}{:0>width$}", config.prefix, kind.get_digit(), id, width = width);
+    match Symbology::from_name(&config.symbology).unwrap_or(Symbology::Code128) {
+        Symbology::Ean13 if body.len() > BARCODE_BODY_LENGTH => Err(format!(
+            "ID {} is too long for an EAN-13 barcode with the prefix {}. Use a shorter prefix or Code 128.",
+            id, config.prefix
+        )),
+        Symbology::Ean13 => Ok(format!("{}{}", body, get_ean_check_digit(&body))),
+        Symbology::Code128 => Ok(body),
+    }
+}
+
+// Weights alternate 1 and 3 from the first digit, as for ISBN-13.
+fn get_ean_check_digit(body: &str) -> char {
+    let sum: u32 = body.chars()
+        .filter_map(|c| c.to_digit(10))
+        .enumerate()
+        .map(|(index, digit)| if index % 2 == 0 { digit } else { digit * 3 })
+        .sum();
+    char::from_digit((10 - sum % 10) % 10, 10).unwrap_or('0')
+}
+
+pub fn is_valid_ean13(value: &str) -> bool {
+    value.len() == 13
+        && value.chars().all(|c| c.is_ascii_digit())
+        && value.ends_with(get_ean_check_digit(&value[..12]))
+}
+
+/*
+ *  Encodes a barcode as its modules from left to right, true for a bar,
+ *  without the quiet zones either side. A valid EAN-13 is drawn as one,
+ *  and anything else as Code 128, so a barcode issued under an earlier
+ *  configuration still prints the way it was first printed.
+ */
+pub fn encode(value: &str) -> Result<(Symbology, Vec<bool>), String> {
+    if is_valid_ean13(value) {
+        Ok((Symbology::Ean13, encode_ean13(value)))
+    } else {
+        encode_code128(value).map(|modules| (Symbology::Code128, modules))
+    }
+}
+
+fn encode_code128(value: &str) -> Result<Vec<bool>, String> {
+    if value.is_empty() || !value.chars().all(|c| (' '..='~').contains(&c)) {
+        return Err(format!("\"{}\" cannot be drawn as a Code 128 barcode.", value));
+    }
+    // Code set C packs two digits into each symbol, so numbers of even length use it.
+    let mut codes = Vec::new();
+    if value.len().is_multiple_of(2) && value.bytes().all(|b| b.is_ascii_digit()) {
+        codes.push(CODE128_START_C);
+        codes.extend(value.as_bytes().chunks(2).map(|pair| ((pair[0] - b'0') * 10 + (pair[1] - b'0')) as usize));
+    } else {
+        codes.push(CODE128_START_B);
+        codes.extend(value.bytes().map(|b| (b - b' ') as usize));
+    }
+    // The start code and first symbol both have weight 1, then each symbol its position.
+    let checksum = codes.iter().enumerate().map(|(index, code)| code * index.max(1)).sum::<usize>() % 103;
+    codes.push(checksum);
+    codes.push(CODE128_STOP);
+
+    let mut modules = Vec::new();
+    for code in codes {
+        for (index, width) in CODE128_PATTERNS[code].bytes().enumerate() {
+            modules.extend(std::iter::repeat_n(index % 2 == 0, (width - b'0') as usize));
+        }
+    }
+    Ok(modules)
+}
+
+fn encode_ean13(value: &str) -> Vec<bool> {
+    let digits: Vec<usize> = value.bytes().map(|b| (b - b'0') as usize).collect();
+    let mut pattern = String::from("101");
+    for (index, parity) in EAN_PARITY[digits[0]].chars().enumerate() {
+        let l_code = EAN_L_CODES[digits[index + 1]];
+        if parity == 'L' {
+            pattern.push_str(l_code);
+        } else {
+            pattern.extend(l_code.chars().rev().map(|c| if c == '0' { '1' } else { '0' }));
+        }
+    }
+    pattern.push_str("01010");
+    for &digit in &digits[7..] {
+        pattern.extend(EAN_L_CODES[digit].chars().map(|c| if c == '0' { '1' } else { '0' }));
+    }
+    pattern.push_str("101");
+    pattern.chars().map(|c| c == '1').collect()
+}
+
+/// The stored barcode of a copy or patron, issuing one first if they have none.
+pub fn get_or_assign_barcode(connection: &Connection, config: &BarcodeConfig, kind: BarcodeKind, id: i64) -> Result<String, Box<dyn Error>> {
+    let (table, key) = kind.get_table();
+    let existing: Option<String> = connection.query_row(
+        &format!("SELECT barcode FROM {} WHERE {} = ?1", table, key),
+        params![id],
+        |row| row.get(0),
+    )?;
+    if let Some(barcode) = existing {
+        return Ok(barcode);
+    }
+    let barcode = make_barcode(config, kind, id)?;
+    connection.execute(
+        &format!("UPDATE {} SET barcode = ?2 WHERE {} = ?1", table, key),
+        params![id, barcode],
+    )?;
+    Ok(barcode)
+}
+
+/// Issues barcodes to every copy and patron without one; returns how many of each were issued.
+pub fn assign_missing_barcodes(database_name: &str, config: &BarcodeConfig) -> Result<(usize, usize), Box<dyn Error>> {
+    let mut connection = Connection::open(database_name)?;
+    let transaction = connection.transaction()?;
+    let mut issued = Vec::new();
+    for kind in [BarcodeKind::Item, BarcodeKind::Patron] {
+        let (table, key) = kind.get_table();
+        let ids = transaction
+            .prepare(&format!("SELECT {} FROM {} WHERE barcode IS NULL ORDER BY {}", key, table, key))?
+            .query_map([], |row| row.get::<_, i64>(0))?
+            .collect::<Result<Vec<i64>, _>>()?;
+        for id in &ids {
+            get_or_assign_barcode(&transaction, config, kind, *id)?;
+        }
+        issued.push(ids.len());
+    }
+    transaction.commit()?;
+    Ok((issued[0], issued[1]))
+}
+
+pub fn find_barcode(connection: &Connection, value: &str) -> Result<Option<BarcodeOwner>, rusqlite::Error> {
+    let value = value.trim();
+    let holding_id = connection.query_row(
+        "SELECT holding_id FROM holdings WHERE barcode = ?1",
+        params![value],
+        |row| row.get(0),
+    ).optional()?;
+    if let Some(holding_id) = holding_id {
+        return Ok(Some(BarcodeOwner::Item(holding_id)));
+    }
+    let user_id = connection.query_row(
+        "SELECT user_id FROM users WHERE barcode = ?1",
+        params![value],
+        |row| row.get(0),
+    ).optional()?;
+    Ok(user_id.map(BarcodeOwner::Patron))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialisation;
+
+    fn config(symbology: &str, prefix: &str) -> BarcodeConfig {
+        BarcodeConfig { symbology: symbology.to_string(), prefix: prefix.to_string() }
+    }
+
+    #[test]
+    fn ean_check_digits_match_published_barcodes() {
+        assert_eq!(get_ean_check_digit("400638133393"), '1');
+        assert_eq!(get_ean_check_digit("978030640615"), '7');
+        assert_eq!(get_ean_check_digit("291000000042"), '0');
+    }
+
+    #[test]
+    fn is_valid_ean13_checks_length_digits_and_check_digit() {
+        assert!(is_valid_ean13("4006381333931"));
+        assert!(is_valid_ean13("9780306406157"));
+        assert!(!is_valid_ean13("4006381333932"));
+        assert!(!is_valid_ean13("400638133393"));
+        assert!(!is_valid_ean13("400638133393X"));
+        assert!(!is_valid_ean13("29100000004é"));
+    }
+
+    #[test]
+    fn make_barcode_pads_the_id_after_the_prefix_and_kind() {
+        assert_eq!(make_barcode(&config("code128", "29"), BarcodeKind::Item, 7), Ok("291000000007".to_string()));
+        assert_eq!(make_barcode(&config("code128", "29"), BarcodeKind::Patron, 7), Ok("292000000007".to_string()));
+        assert_eq!(make_barcode(&config("ean13", "29"), BarcodeKind::Item, 7), Ok("2910000000079".to_string()));
+        assert!(is_valid_ean13(&make_barcode(&config("ean13", "21"), BarcodeKind::Patron, 123456).unwrap()));
+    }
+
+    #[test]
+    fn make_barcode_refuses_ids_too_long_for_ean13() {
+        assert!(make_barcode(&config("ean13", "2900000000"), BarcodeKind::Item, 123).is_err());
+        assert_eq!(make_barcode(&config("code128", "2900000000"), BarcodeKind::Item, 123), Ok("29000000001123".to_string()));
+    }
+
+    #[test]
+    fn encode_draws_ean13_and_code128_at_their_widths() {
+        let (symbology, modules) = encode("4006381333931").unwrap();
+        assert_eq!(symbology, Symbology::Ean13);
+        assert_eq!(modules.len(), 95);
+        // Code set C: start, two symbols, checksum and the 13-module stop code.
+        let (symbology, modules) = encode("1234").unwrap();
+        assert_eq!(symbology, Symbology::Code128);
+        assert_eq!(modules.len(), 4 * 11 + 13);
+        assert!(encode("").is_err());
+    }
+
+    #[test]
+    fn issued_barcodes_are_unique_and_never_change() {
+        let (database_name, connection) = initialisation::open_test_database("barcodes_unique");
+        // The unique indexes come with the upgrade that added barcodes.
+        assert!(initialisation::update_existing_tables(&database_name));
+        connection.execute_batch(
+            "INSERT INTO users (user_id, email, firstname, lastname) VALUES (1, 'ada@example.org', 'Ada', 'Lovelace');
+             INSERT INTO books (book_id, title, author) VALUES (1, 'The Hobbit', 'J. R. R. Tolkien');
+             INSERT INTO holdings (holding_id, book_id, created_at) VALUES (1, 1, 0), (2, 1, 0);",
+        ).unwrap();
+
+        assert_eq!(assign_missing_barcodes(&database_name, &config("code128", "29")).unwrap(), (2, 1));
+        assert_eq!(assign_missing_barcodes(&database_name, &config("code128", "29")).unwrap(), (0, 0));
+        assert_eq!(find_barcode(&connection, " 291000000002 ").unwrap(), Some(BarcodeOwner::Item(2)));
+        assert_eq!(find_barcode(&connection, "292000000001").unwrap(), Some(BarcodeOwner::Patron(1)));
+        assert_eq!(find_barcode(&connection, "291000000003").unwrap(), None);
+        // A new prefix applies to new barcodes only.
+        assert_eq!(get_or_assign_barcode(&connection, &config("ean13", "21"), BarcodeKind::Item, 1).unwrap(), "291000000001");
+        assert!(connection.execute("UPDATE holdings SET barcode = '291000000001' WHERE holding_id = 2", []).is_err());
+    }
+}
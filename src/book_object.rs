@@ -1,4 +1,5 @@
 use serde::Deserialize;
+use crate::series_processing;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Subject {
@@ -28,6 +29,29 @@ pub struct WorkLink {
     pub key: String,
 }
 
+/*
+ *  Identifiers other than the ISBN, e.g. ("lccn", "62019420"). The scheme
+ *  is one of IDENTIFIER_SCHEMES; "accession" is the library's own number.
+ */
+pub const IDENTIFIER_SCHEMES: [&str; 3] = ["lccn", "oclc", "accession"];
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Identifier {
+    pub scheme: String,
+    pub value: String,
+}
+
+/*
+ *  A series the book belongs to and its place in it. Positions can be
+ *  fractional, e.g. 2.5 for a novella between the second and third
+ *  books; a book without a number is listed after the numbered ones.
+ */
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct SeriesEntry {
+    pub name: String,
+    pub position: Option<f64>,
+}
+
 
 /* NOTE TO ADD: [HIGH PRIORITY]
  * We need to adjust the constructor method of the book
@@ -40,7 +64,7 @@ pub struct WorkLink {
  * as the book_id itself so that I can use these
  * within the program.
  */
-#[derive(Debug, Deserialize, Default)]
+#[derive(Debug, Deserialize, Default, Clone)]
 pub struct Book {
     pub book_id: Option<u32>,
     pub isbn: String,
@@ -52,6 +76,10 @@ pub struct Book {
     pub works: Option<Vec<WorkLink>>,
     pub subjects: Option<Vec<Subject>>,
     pub publishers: Option<Vec<Publisher>>,
+    #[serde(default)]
+    pub identifiers: Vec<Identifier>,
+    #[serde(default)]
+    pub series: Vec<SeriesEntry>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -64,6 +92,8 @@ pub struct OpenLibraryBook {
     pub works: Option<Vec<WorkLink>>,
     pub subjects: Option<Vec<Subject>>,
     pub publishers: Option<Vec<Publisher>>,
+    // Keyed by scheme, e.g. "lccn" or "isbn_13"; every scheme can have several values.
+    pub identifiers: Option<std::collections::HashMap<String, Vec<String>>>,
 }
 
 impl Book {
@@ -73,7 +103,11 @@ impl Book {
             None => println!("Book ID: Not available"),
         }
 
-        println!("ISBN: {}", self.isbn);
+        if self.isbn.is_empty() {
+            println!("ISBN: Not available");
+        } else {
+            println!("ISBN: {}", self.isbn);
+        }
         println!("Title: {}", self.title);
 
         // Handle authors
@@ -99,20 +133,11 @@ impl Book {
             println!("Number of Pages: Not available");
         }
 
-        // Handle cover images
-        if let Some(cover) = &self.cover {
-            println!("Cover URLs:");
-            if let Some(small) = &cover.small {
-                println!("  Small: {}", small);
-            }
-            if let Some(medium) = &cover.medium {
-                println!("  Medium: {}", medium);
-            }
-            if let Some(large) = &cover.large {
-                println!("  Large: {}", large);
-            }
+        // Covers are downloaded and stored when the book is saved, see cover_management.
+        if self.has_cover() {
+            println!("Cover: Available");
         } else {
-            println!("Cover URLs: Not available");
+            println!("Cover: Not available");
         }
 
         // Handle subjects
@@ -130,6 +155,17 @@ impl Book {
         } else {
             println!("Publishers: Not available");
         }
+
+        for identifier in &self.identifiers {
+            println!("{}: {}", identifier.scheme.to_uppercase(), identifier.value);
+        }
+
+        for series in &self.series {
+            match series.position {
+                Some(position) => println!("Series: {} #{}", series.name, series_processing::format_position(position)),
+                None => println!("Series: {}", series.name),
+            }
+        }
     }
     pub fn new_without_id(
         isbn: String,
@@ -153,6 +189,8 @@ impl Book {
             works,
             subjects,
             publishers,
+            identifiers: Vec::new(),
+            series: Vec::new(),
         }
     }
     pub fn get_id(&self) -> Option<u32> { self.book_id.clone() }
@@ -2,14 +2,24 @@ use std::error::Error;
 use std::io;
 use std::io::Write;
 use rusqlite::{params, Connection, Result};
-use crate::book_object::{Book};
+use std::collections::HashMap;
+use crate::book_object::{Book, Identifier, SeriesEntry, IDENTIFIER_SCHEMES};
 use crate::{book_object};
 use crate::user_object::User;
-use crate::utilities::{clear_screen, get_yes_or_no};
+use crate::role_object::Permission;
+use crate::audit_management;
+use crate::holding_object::Holding;
+use crate::holding_processing;
+use crate::{catalogue_processing, cover_management, label_processing, provenance_management, reading_processing, series_processing, work_processing};
+use crate::label_object::LabelKind;
+use crate::catalogue_processing::FieldChange;
+use crate::configuration::{Config, MetadataConfig};
+use serde_json::json;
+use crate::utilities::{self, clear_screen, get_yes_or_no};
 use anyhow::{Context};
 
 pub fn is_valid_isbn(isbn: &str) -> bool {
-    let cleaned: String = isbn.chars().filter(|c| c.is_digit(10)).collect();
+    let cleaned: String = isbn.chars().filter(|c| c.is_ascii_digit()).collect();
     match cleaned.len() {
         10 => is_valid_isbn10(&cleaned),
         13 => is_valid_isbn13(&cleaned),
@@ -58,9 +68,10 @@ fn is_valid_isbn13(isbn: &str) -> bool {
  *
  *        This will use the Open Library API so bef sure to check there if
  *        you would like your program to take into account other fields
- *        that they offer.
+ *        that they offer. The server and timeout come from the [metadata]
+ *        section of the configuration.
  */
-pub async fn get_book_info(isbn: &str) -> Result<Book, Box<dyn Error>> {
+pub async fn get_book_info(isbn: &str, metadata: &MetadataConfig) -> Result<Book, Box<dyn Error>> {
     let trimmed_isbn = isbn.trim(); // Ensure the ISBN is trimmed
 
     if !is_valid_isbn(trimmed_isbn) {
@@ -68,11 +79,15 @@ pub async fn get_book_info(isbn: &str) -> Result<Book, Box<dyn Error>> {
     }
 
     let url = format!(
-        "https://openlibrary.org/api/books?bibkeys=ISBN:{}&format=json&jscmd=data",
+        "{}/api/books?bibkeys=ISBN:{}&format=json&jscmd=data",
+        metadata.open_library_url.trim_end_matches('/'),
         trimmed_isbn
     );
 
-    let response = reqwest::get(&url).await?;
+    let client = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(metadata.request_timeout_seconds))
+        .build()?;
+    let response = client.get(&url).send().await?;
 
     if !response.status().is_success() {
         return Err(format!("Failed to fetch data: HTTP {}", response.status()).into());
@@ -84,6 +99,14 @@ pub async fn get_book_info(isbn: &str) -> Result<Book, Box<dyn Error>> {
     let key = format!("ISBN:{}", trimmed_isbn);
     if let Some(book_data) = json.get(&key) {
         let ol_book: book_object::OpenLibraryBook = serde_json::from_value(book_data.clone())?;
+        // The books API leaves out series, so they come from the edition record.
+        let series = match get_series(&client, metadata, trimmed_isbn).await {
+            Ok(series) => series,
+            Err(e) => {
+                log::warn!("Could not look up the series of ISBN {}: {}", trimmed_isbn, e);
+                Vec::new()
+            }
+        };
         let book = Book {
             book_id: None,
             isbn: trimmed_isbn.to_string(),
@@ -95,6 +118,8 @@ pub async fn get_book_info(isbn: &str) -> Result<Book, Box<dyn Error>> {
             works: ol_book.works,
             subjects: ol_book.subjects,
             publishers: ol_book.publishers,
+            identifiers: get_identifiers(ol_book.identifiers.as_ref()),
+            series,
         };
         Ok(book)
     } else {
@@ -102,6 +127,39 @@ pub async fn get_book_info(isbn: &str) -> Result<Book, Box<dyn Error>> {
     }
 }
 
+/*
+ *  Open Library keeps series on the edition as free text such as
+ *  "The Lord of the Rings ; 2" or "Discworld (3)". An edition that is
+ *  missing or has no series gives an empty list rather than an error.
+ */
+async fn get_series(client: &reqwest::Client, metadata: &MetadataConfig, isbn: &str) -> Result<Vec<SeriesEntry>, Box<dyn Error>> {
+    let url = format!("{}/isbn/{}.json", metadata.open_library_url.trim_end_matches('/'), isbn);
+    let response = client.get(&url).send().await?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(Vec::new());
+    }
+    if !response.status().is_success() {
+        return Err(format!("Failed to fetch data: HTTP {}", response.status()).into());
+    }
+    let edition: serde_json::Value = serde_json::from_str(&response.text().await?)?;
+    let series = edition.get("series")
+        .and_then(|series| series.as_array())
+        .map(|series| series.iter().filter_map(|entry| entry.as_str()).filter_map(series_processing::parse_series).collect())
+        .unwrap_or_default();
+    Ok(series)
+}
+
+// Keeps the identifiers rLMS knows about; the ISBNs are already in the record.
+fn get_identifiers(identifiers: Option<&HashMap<String, Vec<String>>>) -> Vec<Identifier> {
+    let Some(identifiers) = identifiers else { return Vec::new(); };
+    IDENTIFIER_SCHEMES.iter()
+        .filter_map(|scheme| {
+            let value = identifiers.get(*scheme)?.first()?;
+            Some(Identifier { scheme: scheme.to_string(), value: value.trim().to_string() })
+        })
+        .collect()
+}
+
 fn get_books_by_user(conn: &Connection, user_id: i32) -> Result<Vec<Book>> {
     // Prepare the SQL query
     let mut stmt = conn.prepare(
@@ -115,7 +173,7 @@ fn get_books_by_user(conn: &Connection, user_id: i32) -> Result<Vec<Book>> {
     let book_iter = stmt.query_map(params![user_id], |row| {
         Ok(Book {
             book_id: row.get(0)?,
-            isbn: row.get(3)?,
+            isbn: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
             title: row.get(1)?,
             authors: vec![book_object::Author { name: row.get(2)?}],
             publish_date: String::new(),
@@ -124,6 +182,8 @@ fn get_books_by_user(conn: &Connection, user_id: i32) -> Result<Vec<Book>> {
             works: None,
             subjects: None,
             publishers: None,
+            identifiers: Vec::new(),
+            series: Vec::new(),
         })
     })?;
 
@@ -136,40 +196,54 @@ fn get_books_by_user(conn: &Connection, user_id: i32) -> Result<Vec<Book>> {
     Ok(books)
 }
 
+// The user's own copies, reading log, shelves and tags go with the book, all or none of them; library copies are not affected.
+fn remove_book_from_collection(connection: &mut Connection, user_id: i32, book_id: i32) -> Result<(usize, usize), rusqlite::Error> {
+    let transaction = connection.transaction()?;
+    let deleted = transaction.execute(
+        "DELETE FROM libraries WHERE user_id = ?1 AND book_id = ?2",
+        params![user_id, book_id],
+    )?;
+    // Dropping the transaction leaves everything as it was.
+    if deleted == 0 {
+        return Ok((0, 0));
+    }
+    transaction.execute(
+        "DELETE FROM reading_sessions WHERE user_id = ?1 AND book_id = ?2",
+        params![user_id, book_id],
+    )?;
+    label_processing::remove_book_from_all_labels(&transaction, user_id, book_id)?;
+    let copies = holding_processing::delete_holdings_for_book(&transaction, book_id, user_id)?;
+    transaction.commit()?;
+    Ok((deleted, copies))
+}
+
 pub(crate) fn delete_book_from_collection(database_name: &str, user: &User) -> bool {
     clear_screen();
     print_delete_book_header();
-    let mut see_list: bool = false;
-    loop {
-        println!("Would you like to see a list of books (if you do not know the book ID)? (y/n):");
-        see_list = get_yes_or_no();
-        if see_list {
-            // Connect to the database
-            let connection = match Connection::open(database_name) {
-                Ok(conn) => conn,
-                Err(e) => {
-                    eprintln!("Failed to connect to the database: {}", e);
-                    return false;
-                }
-            };
-
-            match get_books_by_user(&connection, user.get_user_id()) {
-                Ok(books) => {
-                    for book in books {
-                        println!(
-                            "ID: {}, Title: {}, Author: {}, ISBN: {}",
-                            book.book_id.map(|id| id.to_string()).unwrap_or_else(|| "Not available".to_string()),
-                            book.title,
-                            book.authors.get(0).map_or("Unknown Author", |a| a.name.as_str()),
-                            book.isbn
-                        );
-                    }
+    println!("Would you like to see a list of books (if you do not know the book ID)? (y/n):");
+    if get_yes_or_no() {
+        // Connect to the database
+        let connection = match Connection::open(database_name) {
+            Ok(conn) => conn,
+            Err(e) => {
+                eprintln!("Failed to connect to the database: {}", e);
+                return false;
+            }
+        };
+
+        match get_books_by_user(&connection, user.get_user_id()) {
+            Ok(books) => {
+                for book in books {
+                    println!(
+                        "ID: {}, Title: {}, Author: {}, ISBN: {}",
+                        book.book_id.map(|id| id.to_string()).unwrap_or_else(|| "Not available".to_string()),
+                        book.title,
+                        book.authors.first().map_or("Unknown Author", |a| a.name.as_str()),
+                        if book.isbn.is_empty() { "Not available" } else { &book.isbn }
+                    );
                 }
-                Err(e) => println!("Error retrieving books: {}", e),
             }
-            break;
-        } else {
-            break;
+            Err(e) => println!("Error retrieving books: {}", e),
         }
     }
 
@@ -186,7 +260,7 @@ pub(crate) fn delete_book_from_collection(database_name: &str, user: &User) -> b
 
         match choice.parse::<u32>() {
             Ok(converted_choice) => {
-                let connection = match Connection::open(database_name) {
+                let mut connection = match Connection::open(database_name) {
                     Ok(connection) => connection,
                     Err(e) => {
                         eprintln!("Failed to connect to the database: {}", e);
@@ -198,10 +272,49 @@ pub(crate) fn delete_book_from_collection(database_name: &str, user: &User) -> b
                     // confirm deletion of book
                     println!("You would like to delete book with the ID {}? (y/n)", converted_choice);
                     if !get_yes_or_no() { continue; }
-                    connection.execute(
-                        "DELETE FROM libraries WHERE user_id = ?1 AND book_id = ?2",
-                        params![user.get_user_id(), converted_choice],
-                    ).with_context(|| format!("Failed to delete book with ID {} from user {}", converted_choice, user.get_user_id()));
+                    let (title, isbn): (String, Option<String>) = match connection.query_row(
+                        "SELECT title, isbn FROM books WHERE book_id = ?1",
+                        params![converted_choice],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    ) {
+                        Ok(book) => book,
+                        Err(e) => {
+                            println!("Failed to read book with ID {}: {}", converted_choice, e);
+                            return false;
+                        }
+                    };
+                    match holding_processing::count_copies_on_loan(&connection, converted_choice as i32, user.get_user_id()) {
+                        Ok(0) => {}
+                        Ok(on_loan) => {
+                            println!("{} of your copies of this book are on loan. Check them in before deleting the book.", on_loan);
+                            return false;
+                        }
+                        Err(e) => {
+                            println!("Failed to check the loans of book with ID {}: {}", converted_choice, e);
+                            return false;
+                        }
+                    }
+                    let (deleted, copies_deleted) = match remove_book_from_collection(&mut connection, user.get_user_id(), converted_choice as i32)
+                        .with_context(|| format!("Failed to delete book with ID {} from user {}", converted_choice, user.get_user_id())) {
+                        Ok(result) => result,
+                        Err(e) => {
+                            println!("{:#}", e);
+                            return false;
+                        }
+                    };
+                    if deleted == 0 {
+                        println!("Book with ID {} is not in your collection.", converted_choice);
+                        return false;
+                    }
+                    if let Err(e) = audit_management::record_event(
+                        &connection,
+                        Some(user.get_user_id()),
+                        audit_management::BOOK_DELETED_FROM_COLLECTION,
+                        &get_audit_target(isbn.as_deref(), converted_choice as i32),
+                        json!({ "book_id": converted_choice, "title": title, "copies_deleted": copies_deleted }),
+                    ) {
+                        eprintln!("Failed to write to the audit log: {}", e);
+                    }
                     return true;
                 } else {
                     println!("There is no book with ID: {}. Please try again.", converted_choice);
@@ -216,12 +329,6 @@ pub(crate) fn delete_book_from_collection(database_name: &str, user: &User) -> b
     }
 }
 
-fn book_in_library_already(conn: &Connection, isbn: &str) -> Result<bool> {
-    let mut stmt = conn.prepare("SELECT EXISTS(SELECT 1 FROM books WHERE isbn = ?)")?;
-    let exists: i32 = stmt.query_row(params![isbn], |row| row.get(0))?;
-    Ok(exists != 0)
-}
-
 fn book_exists(conn: &Connection, book_id: u32) -> Result<bool> {
     let mut stmt = conn.prepare("SELECT EXISTS(SELECT 1 FROM books WHERE book_id = ?)")?;
     let exists: i32 = stmt.query_row(params![book_id], |row| row.get(0))?;
@@ -229,6 +336,139 @@ fn book_exists(conn: &Connection, book_id: u32) -> Result<bool> {
     Ok(exists != 0)
 }
 
+// Asks until the user enters the ID of a book in the catalogue.
+pub(crate) fn get_book_id_from_user(connection: &Connection) -> i32 {
+    loop {
+        println!("Enter the ID of the book: ");
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input).is_err() {
+            println!("Failed to read input. Please try again.");
+            continue;
+        }
+        match input.trim().parse::<u32>() {
+            Ok(book_id) if book_exists(connection, book_id).unwrap_or(false) => return book_id as i32,
+            Ok(book_id) => println!("There is no book with ID: {}. Please try again.", book_id),
+            Err(_) => println!("Invalid ID. Please enter a valid book ID."),
+        }
+    }
+}
+
+pub(crate) fn view_book_details(database_name: &str, user: &User) -> bool {
+    clear_screen();
+    print_book_details_header();
+    let connection = match Connection::open(database_name) {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("Failed to connect to the database: {}", e);
+            return false;
+        }
+    };
+
+    let book_id = get_book_id_from_user(&connection);
+
+    let (title, isbn, publisher, publish_date, number_of_pages, source): (String, Option<String>, Option<String>, Option<String>, Option<u32>, String) = match connection.query_row(
+        "SELECT title, isbn, publisher, publish_date, number_of_pages, source FROM books WHERE book_id = ?1",
+        params![book_id],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?)),
+    ) {
+        Ok(book) => book,
+        Err(e) => {
+            println!("Failed to read book with ID {}: {}", book_id, e);
+            return false;
+        }
+    };
+    let authors = catalogue_processing::get_book_authors(&connection, book_id).unwrap_or_default();
+    let subjects = catalogue_processing::get_book_subjects(&connection, book_id).unwrap_or_default();
+    let identifiers = catalogue_processing::get_book_identifiers(&connection, book_id).unwrap_or_default();
+
+    println!("Book ID: {}", book_id);
+    println!("ISBN: {}", isbn.as_deref().unwrap_or("Not available"));
+    println!("Title: {}", title);
+    println!("Author(s): {}", authors.join(", "));
+    println!("Publisher(s): {}", publisher.as_deref().unwrap_or("Not available"));
+    println!("Publish Date: {}", publish_date.as_deref().unwrap_or("Not available"));
+    match number_of_pages {
+        Some(pages) => println!("Number of Pages: {}", pages),
+        None => println!("Number of Pages: Not available"),
+    }
+    if !subjects.is_empty() {
+        println!("Subjects: {}", subjects.join(", "));
+    }
+    for identifier in identifiers {
+        println!("{}: {}", identifier.scheme.to_uppercase(), identifier.value);
+    }
+    match cover_management::get_cover_summary(&connection, book_id) {
+        Ok(Some((width, height, size))) => println!("Cover: Stored ({}x{}, {} KB)", width, height, size.div_ceil(1024)),
+        Ok(None) => println!("Cover: Not available"),
+        Err(e) => println!("Error retrieving the cover: {}", e),
+    }
+    println!("Catalogued from: {}", if source == catalogue_processing::SOURCE_MANUAL { "Manual entry" } else { "Open Library" });
+    if let Ok(fields) = provenance_management::get_manual_fields(&connection, book_id) {
+        if !fields.is_empty() {
+            println!("Corrected by hand: {}", fields.join(", "));
+        }
+    }
+    match work_processing::get_work_for_book(&connection, book_id) {
+        Ok(Some((work_key, work_title))) => println!("Work: {} ({})", work_title, work_key),
+        Ok(None) => println!("Work: Not available"),
+        Err(e) => println!("Error retrieving the work: {}", e),
+    }
+    match series_processing::get_series_for_book(&connection, book_id) {
+        Ok(series) => {
+            if let Some(series) = series_processing::describe_series(&series) {
+                println!("Series: {}", series);
+            }
+        }
+        Err(e) => println!("Error retrieving the series: {}", e),
+    }
+
+    let library_copies = holding_processing::get_holdings_for_book(&connection, book_id, None)
+        .map(|holdings| holdings.len())
+        .unwrap_or(0);
+    println!("Library copies: {}", library_copies);
+    match holding_processing::get_holdings_for_book(&connection, book_id, Some(user.get_user_id())) {
+        Ok(holdings) if holdings.is_empty() => println!("Your copies: None"),
+        Ok(holdings) => {
+            println!("Your copies:");
+            holdings.iter().for_each(Holding::print_holding_info);
+        }
+        Err(e) => println!("Error retrieving your copies: {}", e),
+    }
+    match reading_processing::get_reading_state(&connection, user.get_user_id(), book_id) {
+        Ok(Some(state)) => state.print_reading_info(),
+        Ok(None) => {}
+        Err(e) => println!("Error retrieving your reading log: {}", e),
+    }
+    for kind in [LabelKind::Shelf, LabelKind::Tag] {
+        match label_processing::get_labels_for_book(&connection, user.get_user_id(), kind, book_id) {
+            Ok(names) if names.is_empty() => {}
+            Ok(names) => println!("Your {}s: {}", kind.get_name(), names.join(", ")),
+            Err(e) => println!("Error retrieving your {}s: {}", kind.get_name(), e),
+        }
+    }
+
+    match work_processing::get_other_editions(&connection, book_id) {
+        Ok(editions) if editions.is_empty() => println!("Other editions of this book: None in the catalogue"),
+        Ok(editions) => {
+            println!("Other editions of this book:");
+            for (edition_id, edition_title, edition_isbn) in editions {
+                println!(
+                    "  ID: {}, Title: {}, ISBN: {}",
+                    edition_id, edition_title, edition_isbn.as_deref().unwrap_or("Not available")
+                );
+            }
+        }
+        Err(e) => println!("Error retrieving other editions: {}", e),
+    }
+    true
+}
+
+fn print_book_details_header() {
+    println!("##################");
+    println!("## Book Details ##");
+    println!("##################");
+}
+
 fn print_delete_book_header() {
     println!("#################################");
     println!("## Delete Book From Collection ##");
@@ -240,10 +480,81 @@ fn print_add_book_header() {
     println!("############################");
 }
 
-pub(crate) async fn add_new_book_to_collection(database_name: &str, user: &User) -> bool {
+pub(crate) async fn add_new_book_to_collection(database_name: &str, config: &Config, user: &User) -> bool {
     clear_screen();
     print_add_book_header();
-    // get the ISBN from the user
+    println!(
+        "How would you like to add the book?\n\
+        \t1. Look up its ISBN on Open Library\n\
+        \t2. Enter its details manually (e.g. no ISBN, or not on Open Library)"
+    );
+    let manual = loop {
+        let mut choice = String::new();
+        if io::stdin().read_line(&mut choice).is_err() {
+            println!("Failed to read input. Please try again.");
+            continue;
+        }
+        match choice.trim() {
+            "1" => break false,
+            "2" => break true,
+            _ => println!("Invalid choice. Please enter 1 or 2."),
+        }
+    };
+
+    let (book, source, corrections) = if manual {
+        if !user.has_permission(Permission::AddToCatalogue) {
+            println!("You do not have permission to add new books to the shared catalogue");
+            return false;
+        }
+        let mut book = Book::default();
+        catalogue_processing::get_book_details_from_user(&mut book, true);
+        (book, catalogue_processing::SOURCE_MANUAL, Vec::new())
+    } else {
+        match get_book_by_isbn(database_name, config).await {
+            Some(found) => found,
+            None => return false,
+        }
+    };
+
+    // Cataloguers and librarians also add the library's own copies.
+    let library_owned = user.has_permission(Permission::EditCatalogue) && {
+        println!("Is this copy owned by the library rather than by you? (y/n)");
+        get_yes_or_no()
+    };
+    let mut holding = Holding::new(0, if library_owned { None } else { Some(user.get_user_id()) });
+    println!("Would you like to record details of this copy (condition, price, location...)? (y/n)");
+    if get_yes_or_no() {
+        holding_processing::get_holding_details_from_user(&mut holding);
+    }
+
+    // Only a fetched record has cover URLs; the cover is downloaded once the book is saved.
+    let fetched = (source == catalogue_processing::SOURCE_OPEN_LIBRARY).then(|| book.clone());
+    let book_id = match upload_book_to_database(book, source, &corrections, user, &mut holding, database_name) {
+        Ok(book_id) => book_id,
+        Err(e) => {
+            println!("Error saving book: {}", e);
+            return false;
+        }
+    };
+    if let Some(fetched) = fetched {
+        match cover_management::store_cover_for_book(database_name, config, book_id, &fetched).await {
+            Ok(true) => println!("The cover was downloaded and stored."),
+            Ok(false) => {}
+            Err(e) => println!("Could not download the cover: {}", e),
+        }
+    }
+
+    true
+}
+
+/*
+ *  Asks for an ISBN and returns the record to save along with where it
+ *  came from. An ISBN that is already catalogued is not looked up again.
+ *  A fetched record can be corrected before it is saved, in which case
+ *  the corrections are returned too, and if Open Library does not have
+ *  the ISBN the details can be typed in instead.
+ */
+async fn get_book_by_isbn(database_name: &str, config: &Config) -> Option<(Book, &'static str, Vec<FieldChange>)> {
     let mut isbn: String = String::new();
     loop {
         println!("Enter ISBN(10 or 13):");
@@ -262,46 +573,187 @@ pub(crate) async fn add_new_book_to_collection(database_name: &str, user: &User)
             break;
         }
     }
+    let isbn = isbn.trim().to_string();
+
+    let already_catalogued = Connection::open(database_name)
+        .and_then(|connection| catalogue_processing::find_existing_record(
+            &connection,
+            &Book { isbn: isbn.clone(), ..Book::default() },
+        ));
+    match already_catalogued {
+        Ok(Some(_)) => return Some((Book { isbn, ..Book::default() }, catalogue_processing::SOURCE_OPEN_LIBRARY, Vec::new())),
+        Ok(None) => {}
+        Err(e) => {
+            println!("Failed to search the catalogue: {}", e);
+            return None;
+        }
+    }
 
-    match get_book_info(isbn.trim()).await {
-        Ok(book) => {
-            upload_book_to_database(book, isbn.trim(), &user, database_name);
-        },
+    match get_book_info(&isbn, &config.metadata).await {
+        Ok(mut book) => {
+            book.print_book_info();
+            println!("Would you like to edit this record before saving it? (y/n)");
+            let mut corrections = Vec::new();
+            if get_yes_or_no() {
+                let fetched = book.clone();
+                catalogue_processing::get_book_details_from_user(&mut book, false);
+                corrections = catalogue_processing::get_changed_fields(&fetched, &book);
+            }
+            Some((book, catalogue_processing::SOURCE_OPEN_LIBRARY, corrections))
+        }
         Err(e) => {
             println!("Error fetching book information: {}", e);
+            println!("Would you like to enter the details of this book manually instead? (y/n)");
+            if !get_yes_or_no() {
+                return None;
+            }
+            let mut book = Book { isbn, ..Book::default() };
+            catalogue_processing::get_book_details_from_user(&mut book, false);
+            Some((book, catalogue_processing::SOURCE_MANUAL, Vec::new()))
         }
     }
+}
 
-    true
+/*
+ *  The catalogue has one record per book, shared by every user. If the
+ *  book is already catalogued (same ISBN or identifier) the existing
+ *  record is used as it is, and only a new holding (copy) is added for it.
+ *  Corrections made to a fetched record before saving it are recorded as
+ *  set by hand, so a later refresh keeps them.
+ */
+fn upload_book_to_database(book: Book, source: &str, corrections: &[FieldChange], user: &User, holding: &mut Holding, database_name: &str) -> anyhow::Result<i32, Box<dyn Error>> {
+    let mut connection = Connection::open(database_name)?;
+    let transaction = connection.transaction()?;
+
+    let existing = catalogue_processing::find_existing_record(&transaction, &book)
+        .context("Failed to check if the book is already in the catalogue")?;
+    let (book_id, title) = match &existing {
+        Some((book_id, title)) => {
+            println!("This book is already in the catalogue as \"{}\". Your copy will be added to that record.", title);
+            (*book_id, title.clone())
+        }
+        None => {
+            if !user.has_permission(Permission::AddToCatalogue) {
+                return Err("You do not have permission to add new books to the shared catalogue".into());
+            }
+            let book_id = catalogue_processing::save_catalogue_record(&transaction, &book, source)
+                .context("Failed to save the book to the catalogue")?;
+            for correction in corrections {
+                provenance_management::record_edit(
+                    &transaction,
+                    book_id,
+                    correction.field,
+                    correction.old_value.as_deref(),
+                    correction.new_value.as_deref(),
+                    catalogue_processing::SOURCE_MANUAL,
+                    Some(user.get_user_id()),
+                ).context("Failed to record the corrections to the book")?;
+            }
+            (book_id, book.title.trim().to_string())
+        }
+    };
+
+    let user_id = user.get_user_id();
+    if !holding.is_library_owned() {
+        // A user may own several copies, but the book is only in their collection once.
+        transaction.execute(
+            "INSERT OR IGNORE INTO libraries (user_id, book_id, added_at) VALUES (?1, ?2, ?3)",
+            params![user_id, book_id, utilities::current_timestamp()],
+        ).context("Failed to execute insert into libraries table")?;
+    }
+    holding.book_id = book_id;
+    let holding_id = holding_processing::create_holding(&transaction, holding)
+        .context("Failed to execute insert into holdings table")?;
+    holding.holding_id = Some(holding_id);
+
+    audit_management::record_event(
+        &transaction,
+        Some(user_id),
+        audit_management::BOOK_ADDED_TO_COLLECTION,
+        &get_audit_target(Some(&book.isbn), book_id),
+        json!({
+            "book_id": book_id,
+            "title": title,
+            "new_catalogue_record": existing.is_none(),
+            "source": source,
+            "holding_id": holding_id,
+            "library_owned": holding.is_library_owned(),
+        }),
+    )?;
+    transaction.commit()?;
+    Ok(book_id)
+}
+
+// Books are audited by ISBN where they have one, so entries stay readable.
+pub(crate) fn get_audit_target(isbn: Option<&str>, book_id: i32) -> String {
+    match isbn.map(str::trim).filter(|isbn| !isbn.is_empty()) {
+        Some(isbn) => isbn.to_string(),
+        None => format!("book:{}", book_id),
+    }
 }
 
-fn upload_book_to_database(book: Book, isbn: &str, user: &User, database_name: &str) -> anyhow::Result<(), Box<dyn Error>> {
-    let connection = Connection::open(database_name)?;
-    let primary_author = &book.get_authors()[0].name;
-    let title = book.get_title();
-    let exists = book_in_library_already(&connection, isbn)
-        .with_context(|| format!("Failed to check if the book with ISBN {} exists", isbn))?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialisation;
 
-    if !exists {
-        crate::utilities::pause(15);
-        connection.execute(
-            "INSERT INTO books (title, author, isbn) VALUES (?1, ?2, ?3)",
-            params![title, primary_author, isbn.trim()],
-        ).with_context(|| "Failed to execute INSERT into books table")?;
+    fn count(connection: &Connection, query: &str) -> i64 {
+        connection.query_row(query, [], |row| row.get(0)).unwrap()
     }
 
-    let query = "SELECT book_id FROM books WHERE isbn = ?1 AND title = ?2";
-    let book_id: i32 = connection.query_row(
-        query,
-        params![isbn.trim(), title],
-        |row| row.get(0)
-    ).context("Failed to retrieve book_id from books table")?;
+    fn open_collection(name: &str) -> Connection {
+        let (_, connection) = initialisation::open_test_database(name);
+        connection.execute_batch(
+            "INSERT INTO books (book_id, title, author, isbn) VALUES (1, 'The Hobbit', 'J. R. R. Tolkien', '978-0-261-10221-7');
+             INSERT INTO libraries (user_id, book_id) VALUES (1, 1), (2, 1);
+             INSERT INTO reading_sessions (user_id, book_id, started_on) VALUES (1, 1, '2024-01-01');
+             INSERT INTO holdings (holding_id, book_id, owner_user_id, created_at) VALUES (1, 1, 1, 0), (2, 1, 1, 0), (3, 1, 2, 0);
+             INSERT INTO loans (holding_id, user_id, checked_out_at, due_at, returned_at) VALUES (1, 3, 0, 10, 5), (3, 3, 0, 10, 5);
+             INSERT INTO stocktake_scans (stocktake_id, barcode, holding_id, scanned_at) VALUES (1, 'A', 2, 0);
+             INSERT INTO holds (book_id, user_id, placed_at, holding_id) VALUES (1, 3, 0, 2);",
+        ).unwrap();
+        connection
+    }
 
-    let user_id = user.get_user_id();
+    #[test]
+    fn deleting_a_book_takes_only_the_users_copies_and_what_refers_to_them() {
+        let mut connection = open_collection("delete_book");
+        assert_eq!(remove_book_from_collection(&mut connection, 1, 1).unwrap(), (1, 2));
+        assert_eq!(count(&connection, "SELECT COUNT(*) FROM libraries"), 1);
+        assert_eq!(count(&connection, "SELECT COUNT(*) FROM reading_sessions"), 0);
+        assert_eq!(count(&connection, "SELECT COUNT(*) FROM holdings WHERE owner_user_id = 2"), 1);
+        assert_eq!(count(&connection, "SELECT COUNT(*) FROM holdings"), 1);
+        // The loan of the other user's copy stays; the scan and hold forget the deleted copy.
+        assert_eq!(count(&connection, "SELECT COUNT(*) FROM loans"), 1);
+        assert_eq!(count(&connection, "SELECT COUNT(*) FROM stocktake_scans WHERE holding_id IS NULL"), 1);
+        assert_eq!(count(&connection, "SELECT COUNT(*) FROM holds WHERE holding_id IS NULL"), 1);
+    }
 
-    connection.execute(
-        "INSERT INTO libraries (user_id, book_id) VALUES (?1, ?2)",
-        params![user_id, book_id],
-    ).context("Failed to execute insert into libraries table")?;
-    Ok(())
-}
\ No newline at end of file
+    #[test]
+    fn a_book_not_in_the_collection_leaves_everything_alone() {
+        let mut connection = open_collection("delete_missing_book");
+        connection.execute("DELETE FROM libraries WHERE user_id = 1", []).unwrap();
+        assert_eq!(remove_book_from_collection(&mut connection, 1, 1).unwrap(), (0, 0));
+        assert_eq!(count(&connection, "SELECT COUNT(*) FROM reading_sessions"), 1);
+        assert_eq!(count(&connection, "SELECT COUNT(*) FROM holdings"), 3);
+    }
+
+    #[test]
+    fn copies_out_on_loan_are_counted() {
+        let connection = open_collection("delete_on_loan");
+        assert_eq!(holding_processing::count_copies_on_loan(&connection, 1, 1).unwrap(), 0);
+        connection.execute("INSERT INTO loans (holding_id, user_id, checked_out_at, due_at) VALUES (2, 3, 0, 10)", []).unwrap();
+        assert_eq!(holding_processing::count_copies_on_loan(&connection, 1, 1).unwrap(), 1);
+        assert_eq!(holding_processing::count_copies_on_loan(&connection, 1, 2).unwrap(), 0);
+    }
+
+    #[test]
+    fn records_are_found_by_isbn_however_it_was_written() {
+        let connection = open_collection("find_by_isbn");
+        for isbn in ["9780261102217", "978 0 261 10221 7", "0-261-10221-4", "0261102214"] {
+            let book = Book { isbn: isbn.to_string(), ..Book::default() };
+            assert_eq!(catalogue_processing::find_existing_record(&connection, &book).unwrap(), Some((1, "The Hobbit".to_string())), "{}", isbn);
+            assert_eq!(catalogue_processing::find_other_record(&connection, &book, 1).unwrap(), None);
+        }
+    }
+}
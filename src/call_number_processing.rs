@@ -0,0 +1,125 @@
+/*
+ *  Call numbers are kept as they are written on the spine, along with a
+ *  sort key that puts them in shelf order. Neither Dewey nor Library of
+ *  Congress numbers sort as plain text: 95 comes before 823 in Dewey,
+ *  and PR605 before PR6039 in LCC, because class numbers are read as
+ *  whole numbers while the fractions and cutters after them are decimals.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallNumberScheme {
+    Dewey,
+    Lcc,
+    // Anything else, e.g. "FIC TOL", which is sorted as text.
+    Local,
+}
+
+impl CallNumberScheme {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            CallNumberScheme::Dewey => "Dewey",
+            CallNumberScheme::Lcc => "Library of Congress",
+            CallNumberScheme::Local => "local",
+        }
+    }
+}
+
+// The class number of a Dewey call number, e.g. "823.912" in "823.912 TOL".
+fn parse_dewey(call_number: &str) -> Option<(&str, &str, &str)> {
+    let class = call_number.split_whitespace().next()?;
+    let (whole, fraction) = class.split_once('.').unwrap_or((class, ""));
+    let is_digits = |text: &str| text.chars().all(|c| c.is_ascii_digit());
+    if whole.is_empty() || whole.len() > 3 || !is_digits(whole) || !is_digits(fraction) {
+        return None;
+    }
+    Some((whole, fraction, call_number.trim()[class.len()..].trim()))
+}
+
+// The class letters and number of an LCC call number, e.g. "PR" and "6039" in "PR6039.O32 L6 1991".
+fn parse_lcc(call_number: &str) -> Option<(String, &str, &str, &str)> {
+    let call_number = call_number.trim();
+    let letters_end = call_number.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(call_number.len());
+    let letters = &call_number[..letters_end];
+    if !(1..=3).contains(&letters.len()) {
+        return None;
+    }
+    let rest = call_number[letters_end..].trim_start();
+    let whole_end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    let whole = &rest[..whole_end];
+    if whole.is_empty() || whole.len() > 5 {
+        return None;
+    }
+    // A fraction only follows the class number if a digit comes straight after the point.
+    let rest = &rest[whole_end..];
+    let (fraction, rest) = match rest.strip_prefix('.') {
+        Some(after) if after.starts_with(|c: char| c.is_ascii_digit()) => {
+            let fraction_end = after.find(|c: char| !c.is_ascii_digit()).unwrap_or(after.len());
+            (&after[..fraction_end], &after[fraction_end..])
+        }
+        _ => ("", rest),
+    };
+    Some((letters.to_uppercase(), whole, fraction, rest))
+}
+
+pub fn detect_scheme(call_number: &str) -> CallNumberScheme {
+    if parse_dewey(call_number).is_some() {
+        CallNumberScheme::Dewey
+    } else if parse_lcc(call_number).is_some() {
+        CallNumberScheme::Lcc
+    } else {
+        CallNumberScheme::Local
+    }
+}
+
+/*
+ *  Builds a key that sorts as text in shelf order. Class numbers are
+ *  padded so whole numbers compare by value, and fractions and cutters
+ *  are left as they are because they are decimals. A space separates
+ *  the parts, so a shorter number comes before a longer one that starts
+ *  the same way. Dewey numbers are shelved before LCC and local ones.
+ */
+pub fn get_sort_key(call_number: &str) -> String {
+    let normalise = |rest: &str| -> String {
+        rest.split(|c: char| c.is_whitespace() || c == '.')
+            .filter(|part| !part.is_empty())
+            .map(str::to_uppercase)
+            .collect::<Vec<String>>()
+            .join(" ")
+    };
+    if let Some((whole, fraction, rest)) = parse_dewey(call_number) {
+        format!("1 {:0>3}{} {}", whole, fraction, normalise(rest)).trim_end().to_string()
+    } else if let Some((letters, whole, fraction, rest)) = parse_lcc(call_number) {
+        format!("2 {:<3}{:0>5}{} {}", letters, whole, fraction, normalise(rest)).trim_end().to_string()
+    } else {
+        format!("3 {}", normalise(call_number))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sort_keys_read_class_numbers_as_whole_numbers() {
+        assert_eq!(get_sort_key("823.912 TOL"), "1 823912 TOL");
+        assert_eq!(get_sort_key("95 abc"), "1 095 ABC");
+        assert_eq!(get_sort_key("PR6039.O32 L6 1991"), "2 PR 06039 O32 L6 1991");
+        assert_eq!(get_sort_key("QA76.73.R87"), "2 QA 0007673 R87");
+        assert_eq!(get_sort_key("fic  tol"), "3 FIC TOL");
+    }
+
+    #[test]
+    fn sort_keys_put_call_numbers_in_shelf_order() {
+        let shelf = ["95 ABC", "823 TOL", "823.9 TOL", "823.912 TOL", "PR605 .B5", "PR6039.O32 L6", "QA76.73.R87", "FIC TOL"];
+        let mut sorted = shelf.to_vec();
+        sorted.sort_by_key(|call_number| get_sort_key(call_number));
+        assert_eq!(sorted, shelf);
+    }
+
+    #[test]
+    fn detect_scheme_tells_dewey_lcc_and_local_apart() {
+        assert_eq!(detect_scheme("823.912 TOL"), CallNumberScheme::Dewey);
+        assert_eq!(detect_scheme("PR6039.O32 L6 1991"), CallNumberScheme::Lcc);
+        assert_eq!(detect_scheme("FIC TOL"), CallNumberScheme::Local);
+        assert_eq!(detect_scheme("1234.5"), CallNumberScheme::Local);
+    }
+}
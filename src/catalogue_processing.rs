@@ -0,0 +1,703 @@
+use std::error::Error;
+use std::io;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+use rusqlite::types::Value as SqlValue;
+use serde_json::{json, Map, Value};
+use crate::audit_management;
+use crate::book_object::{Author, Book, Identifier, Publisher, Subject, IDENTIFIER_SCHEMES};
+use crate::book_processing::{self, get_audit_target, get_book_info, is_valid_isbn};
+use crate::configuration::{Config, MetadataConfig};
+use crate::{duplicate_processing, provenance_management};
+use crate::series_processing;
+use crate::user_object::User;
+use crate::utilities::{clear_screen, get_yes_or_no};
+use crate::work_processing;
+
+/*
+ *  Catalogue records come from Open Library or are typed in by hand.
+ *  Either way they are saved by save_catalogue_record, which also keeps
+ *  the full list of authors, the subjects and any extra identifiers in
+ *  their own tables. books.author stays as the first author, which is
+ *  what lists and searches show.
+ */
+pub const SOURCE_OPEN_LIBRARY: &str = "openlibrary";
+pub const SOURCE_MANUAL: &str = "manual";
+
+/// Finds a catalogue record for the same book by ISBN or by any of its identifiers.
+pub fn find_existing_record(connection: &Connection, book: &Book) -> Result<Option<(i32, String)>, rusqlite::Error> {
+    find_record(connection, book, None)
+}
+
+/// Finds a record other than book_id that already has one of the book's ISBN or identifiers.
+pub fn find_other_record(connection: &Connection, book: &Book, book_id: i32) -> Result<Option<(i32, String)>, rusqlite::Error> {
+    find_record(connection, book, Some(book_id))
+}
+
+fn find_record(connection: &Connection, book: &Book, excluded_book_id: Option<i32>) -> Result<Option<(i32, String)>, rusqlite::Error> {
+    // The same ISBN may have been stored with hyphens, or as ISBN-10.
+    if !book.isbn.trim().is_empty() {
+        let mut parameters = vec![excluded_book_id.map_or(SqlValue::Null, |book_id| SqlValue::Integer(book_id.into()))];
+        let condition = duplicate_processing::get_isbn_condition(&book.isbn, &mut parameters);
+        let existing = connection.query_row(
+            &format!("SELECT book_id, title FROM books WHERE book_id IS NOT ?1 AND {} ORDER BY book_id", condition),
+            params_from_iter(parameters),
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).optional()?;
+        if existing.is_some() {
+            return Ok(existing);
+        }
+    }
+    for identifier in &book.identifiers {
+        let existing = connection.query_row(
+            "SELECT books.book_id, books.title FROM books
+             JOIN book_identifiers ON book_identifiers.book_id = books.book_id
+             WHERE book_identifiers.scheme = ?1 AND book_identifiers.value = ?2 AND books.book_id IS NOT ?3",
+            params![identifier.scheme, identifier.value, excluded_book_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).optional()?;
+        if existing.is_some() {
+            return Ok(existing);
+        }
+    }
+    Ok(None)
+}
+
+pub fn save_catalogue_record(connection: &Connection, book: &Book, source: &str) -> Result<i32, rusqlite::Error> {
+    let publishers = book.publishers.as_ref()
+        .map(|publishers| publishers.iter().map(|p| p.name.as_str()).collect::<Vec<&str>>().join("; "))
+        .filter(|publishers| !publishers.is_empty());
+    let isbn = Some(book.isbn.trim()).filter(|isbn| !isbn.is_empty());
+    let publish_date = Some(book.publish_date.trim()).filter(|date| !date.is_empty());
+    let primary_author = book.authors.first().map_or("Unknown Author", |author| author.name.as_str());
+
+    connection.execute(
+        "INSERT INTO books (title, author, isbn, publisher, publish_date, number_of_pages, source)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![book.title.trim(), primary_author, isbn, publishers, publish_date, book.number_of_pages, source],
+    )?;
+    let book_id = connection.last_insert_rowid() as i32;
+
+    for (position, author) in book.authors.iter().enumerate() {
+        connection.execute(
+            "INSERT INTO book_authors (book_id, position, name) VALUES (?1, ?2, ?3)",
+            params![book_id, position as i32, author.name.trim()],
+        )?;
+    }
+    for subject in book.subjects.iter().flatten() {
+        connection.execute(
+            "INSERT OR IGNORE INTO book_subjects (book_id, subject) VALUES (?1, ?2)",
+            params![book_id, subject.name.trim()],
+        )?;
+    }
+    for identifier in &book.identifiers {
+        connection.execute(
+            "INSERT OR IGNORE INTO book_identifiers (book_id, scheme, value) VALUES (?1, ?2, ?3)",
+            params![book_id, identifier.scheme, identifier.value.trim()],
+        )?;
+    }
+    if let Some(work_key) = work_processing::get_work_key(book) {
+        let work_id = work_processing::get_or_create_work(connection, &work_key, book.title.trim(), primary_author)?;
+        work_processing::link_book_to_work(connection, book_id, work_id)?;
+    }
+    series_processing::save_book_series(connection, book_id, &book.series)?;
+    Ok(book_id)
+}
+
+pub fn get_book_authors(connection: &Connection, book_id: i32) -> Result<Vec<String>, rusqlite::Error> {
+    let mut stmt = connection.prepare("SELECT name FROM book_authors WHERE book_id = ?1 ORDER BY position")?;
+    let authors = stmt.query_map(params![book_id], |row| row.get(0))?;
+    authors.collect()
+}
+
+pub fn get_book_subjects(connection: &Connection, book_id: i32) -> Result<Vec<String>, rusqlite::Error> {
+    let mut stmt = connection.prepare("SELECT subject FROM book_subjects WHERE book_id = ?1 ORDER BY subject")?;
+    let subjects = stmt.query_map(params![book_id], |row| row.get(0))?;
+    subjects.collect()
+}
+
+pub fn get_book_identifiers(connection: &Connection, book_id: i32) -> Result<Vec<Identifier>, rusqlite::Error> {
+    let mut stmt = connection.prepare("SELECT scheme, value FROM book_identifiers WHERE book_id = ?1 ORDER BY scheme")?;
+    let identifiers = stmt.query_map(params![book_id], |row| Ok(Identifier { scheme: row.get(0)?, value: row.get(1)? }))?;
+    identifiers.collect()
+}
+
+/*
+ *  The fields of a record that can be edited and whose source is kept.
+ *  Lists (authors, publishers, subjects) are compared, and kept in the
+ *  edit history, as one value separated by semicolons.
+ */
+pub const RECORD_FIELDS: [&str; 10] = [
+    "title", "authors", "publishers", "publish_date", "number_of_pages", "subjects", "isbn", "lccn", "oclc", "accession",
+];
+
+#[derive(Debug)]
+pub struct FieldChange {
+    pub field: &'static str,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct RefreshResult {
+    pub updated: Vec<FieldChange>,
+    pub kept_manual: Vec<FieldChange>,
+}
+
+pub fn get_field_value(book: &Book, field: &str) -> Option<String> {
+    let value = match field {
+        "title" => book.title.clone(),
+        "authors" => join_names(book.authors.iter().map(|author| author.name.trim())),
+        "publishers" => join_names(book.publishers.iter().flatten().map(|publisher| publisher.name.trim())),
+        "publish_date" => book.publish_date.clone(),
+        "number_of_pages" => book.number_of_pages.map(|pages| pages.to_string()).unwrap_or_default(),
+        "subjects" => {
+            // Stored subjects come back sorted, so both sides are sorted before comparing.
+            let mut subjects: Vec<&str> = book.subjects.iter().flatten().map(|subject| subject.name.trim()).collect();
+            subjects.sort_unstable();
+            subjects.dedup();
+            subjects.join("; ")
+        }
+        "isbn" => book.isbn.clone(),
+        scheme => book.identifiers.iter()
+            .find(|identifier| identifier.scheme == scheme)
+            .map(|identifier| identifier.value.clone())
+            .unwrap_or_default(),
+    };
+    Some(value.trim().to_string()).filter(|value| !value.is_empty())
+}
+
+pub fn get_changed_fields(old: &Book, new: &Book) -> Vec<FieldChange> {
+    RECORD_FIELDS.iter()
+        .map(|field| FieldChange { field, old_value: get_field_value(old, field), new_value: get_field_value(new, field) })
+        .filter(|change| change.old_value != change.new_value)
+        .collect()
+}
+
+/// Reads a saved catalogue record back into a Book, e.g. to edit it.
+pub fn load_catalogue_record(connection: &Connection, book_id: i32) -> Result<Book, rusqlite::Error> {
+    let mut book = connection.query_row(
+        "SELECT title, isbn, publisher, publish_date, number_of_pages FROM books WHERE book_id = ?1",
+        params![book_id],
+        |row| {
+            Ok(Book {
+                book_id: Some(book_id as u32),
+                title: row.get(0)?,
+                isbn: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                publishers: row.get::<_, Option<String>>(2)?
+                    .map(|publishers| split_names(&publishers).into_iter().map(|name| Publisher { name }).collect()),
+                publish_date: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+                number_of_pages: row.get(4)?,
+                ..Book::default()
+            })
+        },
+    )?;
+    book.authors = get_book_authors(connection, book_id)?.into_iter().map(|name| Author { name }).collect();
+    let subjects = get_book_subjects(connection, book_id)?;
+    if !subjects.is_empty() {
+        book.subjects = Some(subjects.into_iter().map(|name| Subject { name }).collect());
+    }
+    book.identifiers = get_book_identifiers(connection, book_id)?;
+    book.series = series_processing::get_series_for_book(connection, book_id)?;
+    Ok(book)
+}
+
+fn write_field(connection: &Connection, book_id: i32, field: &str, value: Option<&str>) -> Result<(), rusqlite::Error> {
+    match field {
+        "title" | "publishers" | "publish_date" | "number_of_pages" | "isbn" => {
+            let column = if field == "publishers" { "publisher" } else { field };
+            connection.execute(&format!("UPDATE books SET {} = ?2 WHERE book_id = ?1", column), params![book_id, value])?;
+        }
+        "authors" => {
+            let authors = value.map(split_names).unwrap_or_default();
+            connection.execute("DELETE FROM book_authors WHERE book_id = ?1", params![book_id])?;
+            for (position, name) in authors.iter().enumerate() {
+                connection.execute(
+                    "INSERT INTO book_authors (book_id, position, name) VALUES (?1, ?2, ?3)",
+                    params![book_id, position as i32, name],
+                )?;
+            }
+            let primary_author = authors.first().map_or("Unknown Author", String::as_str);
+            connection.execute("UPDATE books SET author = ?2 WHERE book_id = ?1", params![book_id, primary_author])?;
+        }
+        "subjects" => {
+            connection.execute("DELETE FROM book_subjects WHERE book_id = ?1", params![book_id])?;
+            for subject in value.map(split_names).unwrap_or_default() {
+                connection.execute(
+                    "INSERT OR IGNORE INTO book_subjects (book_id, subject) VALUES (?1, ?2)",
+                    params![book_id, subject],
+                )?;
+            }
+        }
+        scheme => {
+            connection.execute(
+                "DELETE FROM book_identifiers WHERE book_id = ?1 AND scheme = ?2",
+                params![book_id, scheme],
+            )?;
+            if let Some(value) = value {
+                connection.execute(
+                    "INSERT INTO book_identifiers (book_id, scheme, value) VALUES (?1, ?2, ?3)",
+                    params![book_id, scheme, value],
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes the changes to the record, recording each in its edit history under the given source.
+pub fn apply_record_changes(connection: &Connection, book_id: i32, changes: &[FieldChange], source: &str, editor_user_id: Option<i32>) -> Result<(), rusqlite::Error> {
+    for change in changes {
+        write_field(connection, book_id, change.field, change.new_value.as_deref())?;
+        provenance_management::record_edit(
+            connection,
+            book_id,
+            change.field,
+            change.old_value.as_deref(),
+            change.new_value.as_deref(),
+            source,
+            editor_user_id,
+        )?;
+    }
+    Ok(())
+}
+
+/*
+ *  Brings a record up to date with a freshly fetched copy from Open
+ *  Library. Fields set by hand are never overwritten, and fields Open
+ *  Library has no value for are kept rather than cleared, which also
+ *  keeps local identifiers such as the accession number.
+ */
+pub fn refresh_catalogue_record(connection: &mut Connection, book_id: i32, fetched: &Book, editor_user_id: Option<i32>) -> Result<RefreshResult, rusqlite::Error> {
+    let transaction = connection.transaction()?;
+    let stored = load_catalogue_record(&transaction, book_id)?;
+    let mut result = RefreshResult::default();
+    for change in get_changed_fields(&stored, fetched) {
+        if change.new_value.is_none() {
+            continue;
+        }
+        if provenance_management::get_field_source(&transaction, book_id, change.field)? == SOURCE_MANUAL {
+            result.kept_manual.push(change);
+        } else {
+            result.updated.push(change);
+        }
+    }
+    apply_record_changes(&transaction, book_id, &result.updated, SOURCE_OPEN_LIBRARY, editor_user_id)?;
+
+    if let Some(work_key) = work_processing::get_work_key(fetched) {
+        let primary_author = fetched.authors.first().map_or("Unknown Author", |author| author.name.as_str());
+        let work_id = work_processing::get_or_create_work(&transaction, &work_key, fetched.title.trim(), primary_author)?;
+        work_processing::link_book_to_work(&transaction, book_id, work_id)?;
+    }
+    // Series set by hand are left alone; otherwise Open Library's are added to any already there.
+    if provenance_management::get_field_source(&transaction, book_id, "series")? != SOURCE_MANUAL {
+        let old_value = series_processing::describe_series(&series_processing::get_series_for_book(&transaction, book_id)?);
+        series_processing::save_book_series(&transaction, book_id, &fetched.series)?;
+        let new_value = series_processing::describe_series(&series_processing::get_series_for_book(&transaction, book_id)?);
+        if new_value != old_value {
+            provenance_management::record_edit(&transaction, book_id, "series", old_value.as_deref(), new_value.as_deref(), SOURCE_OPEN_LIBRARY, editor_user_id)?;
+            result.updated.push(FieldChange { field: "series", old_value, new_value });
+        }
+    }
+    if !result.updated.is_empty() {
+        audit_management::record_event(
+            &transaction,
+            editor_user_id,
+            audit_management::BOOK_RECORD_REFRESHED,
+            &get_audit_target(Some(&stored.isbn), book_id),
+            json!({
+                "book_id": book_id,
+                "changes": get_changes_json(&result.updated),
+                "kept_manual": result.kept_manual.iter().map(|change| change.field).collect::<Vec<&str>>(),
+            }),
+        )?;
+    }
+    transaction.commit()?;
+    Ok(result)
+}
+
+/*
+ *  Refreshes every catalogue record that has an ISBN, or just the one
+ *  given, returning how many records were changed, how many fields set
+ *  by hand were kept and how many records could not be looked up.
+ */
+pub async fn refresh_catalogue(database_name: &str, metadata: &MetadataConfig, book_id: Option<i32>) -> Result<(usize, usize, usize), Box<dyn Error>> {
+    let mut connection = Connection::open(database_name)?;
+    let books: Vec<(i32, String)> = {
+        let mut stmt = connection.prepare(
+            "SELECT book_id, isbn FROM books WHERE isbn IS NOT NULL AND (?1 IS NULL OR book_id = ?1) ORDER BY book_id",
+        )?;
+        let rows = stmt.query_map(params![book_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect::<Result<_, _>>()?
+    };
+
+    let (mut updated, mut kept, mut failed) = (0, 0, 0);
+    for (book_id, isbn) in books {
+        let fetched = match get_book_info(&isbn, metadata).await {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                log::warn!("Could not look up ISBN {}: {}", isbn, e);
+                failed += 1;
+                continue;
+            }
+        };
+        let result = refresh_catalogue_record(&mut connection, book_id, &fetched, None)?;
+        if !result.updated.is_empty() {
+            log::info!("Book {} refreshed: {}", book_id, get_field_names(&result.updated));
+            updated += 1;
+        }
+        kept += result.kept_manual.len();
+    }
+    Ok((updated, kept, failed))
+}
+
+fn get_changes_json(changes: &[FieldChange]) -> Value {
+    let changes: Map<String, Value> = changes.iter()
+        .map(|change| (change.field.to_string(), json!({ "old": change.old_value, "new": change.new_value })))
+        .collect();
+    Value::Object(changes)
+}
+
+fn get_field_names(changes: &[FieldChange]) -> String {
+    changes.iter().map(|change| change.field).collect::<Vec<&str>>().join(", ")
+}
+
+fn print_changes(changes: &[FieldChange]) {
+    for change in changes {
+        println!(
+            "  {}: \"{}\" -> \"{}\"",
+            change.field,
+            change.old_value.as_deref().unwrap_or(""),
+            change.new_value.as_deref().unwrap_or("")
+        );
+    }
+}
+
+/*
+ *  Lets a cataloguer correct a saved record by hand, or refresh it from
+ *  Open Library. Every change is kept in the record's edit history.
+ */
+pub(crate) async fn edit_book_record(database_name: &str, config: &Config, user: &User) -> bool {
+    clear_screen();
+    print_edit_record_header();
+    let mut connection = match Connection::open(database_name) {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("Failed to connect to the database: {}", e);
+            return false;
+        }
+    };
+
+    let book_id = book_processing::get_book_id_from_user(&connection);
+    let stored = match load_catalogue_record(&connection, book_id) {
+        Ok(book) => book,
+        Err(e) => {
+            println!("Failed to read book with ID {}: {}", book_id, e);
+            return false;
+        }
+    };
+    stored.print_book_info();
+    match provenance_management::get_manual_fields(&connection, book_id) {
+        Ok(fields) if !fields.is_empty() => println!("Corrected by hand: {}", fields.join(", ")),
+        Ok(_) => {}
+        Err(e) => println!("Error retrieving the record's history: {}", e),
+    }
+
+    println!("1. Edit the details by hand");
+    println!("2. Refresh from Open Library");
+    println!("3. Set series");
+    println!("0. Cancel");
+    let choice = loop {
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input).is_err() {
+            println!("Failed to read input. Please try again.");
+            continue;
+        }
+        match input.trim() {
+            "1" => break 1,
+            "2" => break 2,
+            "3" => break 3,
+            "0" => break 0,
+            _ => println!("Invalid choice. Please enter 1, 2, 3 or 0."),
+        }
+    };
+
+    match choice {
+        1 => edit_record_by_hand(&mut connection, user, book_id, &stored),
+        2 => refresh_record_from_open_library(&mut connection, config, user, book_id, &stored).await,
+        3 => series_processing::set_series_from_user(&mut connection, user, book_id),
+        _ => false,
+    }
+}
+
+fn edit_record_by_hand(connection: &mut Connection, user: &User, book_id: i32, stored: &Book) -> bool {
+    let mut book = stored.clone();
+    get_book_details_from_user(&mut book, true);
+    let changes = get_changed_fields(stored, &book);
+    if changes.is_empty() {
+        println!("No changes were made.");
+        return false;
+    }
+
+    match find_other_record(connection, &book, book_id) {
+        Ok(Some((other_id, other_title))) => {
+            println!("That ISBN or identifier already belongs to \"{}\" (ID {}).", other_title, other_id);
+            return false;
+        }
+        Ok(None) => {}
+        Err(e) => {
+            println!("Failed to search the catalogue: {}", e);
+            return false;
+        }
+    }
+
+    println!("The following changes will be saved:");
+    print_changes(&changes);
+    println!("Save these changes? (y/n)");
+    if !get_yes_or_no() {
+        return false;
+    }
+
+    let result = connection.transaction().and_then(|transaction| {
+        apply_record_changes(&transaction, book_id, &changes, SOURCE_MANUAL, Some(user.get_user_id()))?;
+        audit_management::record_event(
+            &transaction,
+            Some(user.get_user_id()),
+            audit_management::BOOK_RECORD_EDITED,
+            &get_audit_target(Some(&book.isbn), book_id),
+            json!({ "book_id": book_id, "changes": get_changes_json(&changes) }),
+        )?;
+        transaction.commit()
+    });
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            println!("Failed to save the changes: {}", e);
+            false
+        }
+    }
+}
+
+async fn refresh_record_from_open_library(connection: &mut Connection, config: &Config, user: &User, book_id: i32, stored: &Book) -> bool {
+    if stored.isbn.is_empty() {
+        println!("This record has no ISBN, so it cannot be looked up on Open Library.");
+        return false;
+    }
+    let fetched = match get_book_info(&stored.isbn, &config.metadata).await {
+        Ok(fetched) => fetched,
+        Err(e) => {
+            println!("Error fetching book information: {}", e);
+            return false;
+        }
+    };
+
+    match refresh_catalogue_record(connection, book_id, &fetched, Some(user.get_user_id())) {
+        Ok(result) => {
+            if result.updated.is_empty() {
+                println!("The record already matches Open Library.");
+            } else {
+                println!("Updated from Open Library:");
+                print_changes(&result.updated);
+            }
+            if !result.kept_manual.is_empty() {
+                println!("Kept because they were corrected by hand: {}", get_field_names(&result.kept_manual));
+            }
+            !result.updated.is_empty()
+        }
+        Err(e) => {
+            println!("Failed to refresh the record: {}", e);
+            false
+        }
+    }
+}
+
+fn print_edit_record_header() {
+    println!("######################");
+    println!("## Edit Book Record ##");
+    println!("######################");
+}
+
+/*
+ *  The form used both for typing in a new record and for correcting one
+ *  fetched from Open Library. Each question shows the current value in
+ *  brackets: enter keeps it, "-" clears it. The ISBN of a fetched record
+ *  is what it was looked up by, so it is only asked for when editable.
+ */
+pub(crate) fn get_book_details_from_user(book: &mut Book, isbn_editable: bool) {
+    println!("Press enter to keep the value in [brackets], or enter - to clear it.");
+    loop {
+        match get_field_from_user("Title", Some(&book.title)) {
+            Some(title) => {
+                book.title = title;
+                break;
+            }
+            None => println!("A title is required."),
+        }
+    }
+
+    let authors = join_names(book.authors.iter().map(|author| author.name.as_str()));
+    loop {
+        let authors = get_field_from_user("Authors, separated by semicolons", Some(&authors))
+            .map(|authors| split_names(&authors))
+            .unwrap_or_default();
+        if authors.is_empty() {
+            println!("At least one author is required. Use \"Unknown\" if the author is not known.");
+            continue;
+        }
+        book.authors = authors.into_iter().map(|name| Author { name }).collect();
+        break;
+    }
+
+    let publishers = book.publishers.as_ref().map(|publishers| join_names(publishers.iter().map(|p| p.name.as_str())));
+    book.publishers = get_field_from_user("Publishers, separated by semicolons", publishers.as_deref())
+        .map(|publishers| split_names(&publishers).into_iter().map(|name| Publisher { name }).collect());
+    book.publish_date = get_field_from_user("Publication date (e.g. 1965 or March 1965)", Some(&book.publish_date))
+        .unwrap_or_default();
+    book.number_of_pages = loop {
+        let current = book.number_of_pages.map(|pages| pages.to_string());
+        match get_field_from_user("Number of pages", current.as_deref()) {
+            None => break None,
+            Some(pages) => match pages.parse::<u32>() {
+                Ok(pages) if pages > 0 => break Some(pages),
+                _ => println!("Invalid number of pages. Please enter a whole number."),
+            },
+        }
+    };
+    let subjects = book.subjects.as_ref().map(|subjects| join_names(subjects.iter().map(|s| s.name.as_str())));
+    book.subjects = get_field_from_user("Subjects, separated by semicolons", subjects.as_deref())
+        .map(|subjects| split_names(&subjects).into_iter().map(|name| Subject { name }).collect());
+
+    if isbn_editable {
+        book.isbn = loop {
+            match get_field_from_user("ISBN (10 or 13)", Some(&book.isbn)) {
+                None => break String::new(),
+                Some(isbn) if is_valid_isbn(&isbn) => break isbn,
+                Some(isbn) => println!("Invalid ISBN {}. Please try again.", isbn),
+            }
+        };
+    }
+
+    for scheme in IDENTIFIER_SCHEMES {
+        let label = match scheme {
+            "lccn" => "LCCN",
+            "oclc" => "OCLC number",
+            _ => "Local accession number",
+        };
+        let current = book.identifiers.iter()
+            .find(|identifier| identifier.scheme == scheme)
+            .map(|identifier| identifier.value.clone());
+        book.identifiers.retain(|identifier| identifier.scheme != scheme);
+        if let Some(value) = get_field_from_user(label, current.as_deref()) {
+            book.identifiers.push(Identifier { scheme: scheme.to_string(), value });
+        }
+    }
+}
+
+pub(crate) fn get_field_from_user(label: &str, current: Option<&str>) -> Option<String> {
+    let current = current.map(str::trim).filter(|current| !current.is_empty());
+    loop {
+        match current {
+            Some(current) => println!("{} [{}]:", label, current),
+            None => println!("{}:", label),
+        }
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input).is_err() {
+            println!("Failed to read input. Please try again.");
+            continue;
+        }
+        return match input.trim() {
+            "" => current.map(str::to_string),
+            "-" => None,
+            value => Some(value.to_string()),
+        };
+    }
+}
+
+fn split_names(names: &str) -> Vec<String> {
+    names.split(';')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn join_names<'a>(names: impl Iterator<Item = &'a str>) -> String {
+    names.collect::<Vec<&str>>().join("; ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialisation;
+
+    // A pamphlet with no ISBN, catalogued by hand under the library's own number.
+    fn get_manual_book() -> Book {
+        Book {
+            title: "Parish Walks".to_string(),
+            authors: vec![Author { name: "A. Walker".to_string() }, Author { name: "B. Rambler".to_string() }],
+            publishers: Some(vec![Publisher { name: "Parish Council".to_string() }]),
+            publish_date: "1983".to_string(),
+            number_of_pages: Some(24),
+            subjects: Some(vec![Subject { name: "Walking".to_string() }, Subject { name: "Local history".to_string() }]),
+            identifiers: vec![Identifier { scheme: "accession".to_string(), value: "PAM-0042".to_string() }],
+            ..Book::default()
+        }
+    }
+
+    #[test]
+    fn a_record_without_an_isbn_is_saved_and_read_back_in_full() {
+        let (_, connection) = initialisation::open_test_database("manual_round_trip");
+        let book = get_manual_book();
+        let book_id = save_catalogue_record(&connection, &book, SOURCE_MANUAL).unwrap();
+
+        let (isbn, author, source): (Option<String>, String, String) = connection.query_row(
+            "SELECT isbn, author, source FROM books WHERE book_id = ?1", params![book_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        ).unwrap();
+        assert_eq!((isbn, author.as_str(), source.as_str()), (None, "A. Walker", SOURCE_MANUAL));
+        assert!(get_changed_fields(&book, &load_catalogue_record(&connection, book_id).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn records_without_an_isbn_are_matched_by_their_identifiers_only() {
+        let (_, connection) = initialisation::open_test_database("manual_identifiers");
+        let book_id = save_catalogue_record(&connection, &get_manual_book(), SOURCE_MANUAL).unwrap();
+
+        let same_number = Book {
+            identifiers: vec![Identifier { scheme: "accession".to_string(), value: "PAM-0042".to_string() }],
+            ..Book::default()
+        };
+        assert_eq!(find_existing_record(&connection, &same_number).unwrap(), Some((book_id, "Parish Walks".to_string())));
+        assert_eq!(find_other_record(&connection, &same_number, book_id).unwrap(), None);
+        // Another record without an ISBN is not the same book just because neither has one.
+        let other_pamphlet = Book { title: "Parish Walks".to_string(), ..Book::default() };
+        assert_eq!(find_existing_record(&connection, &other_pamphlet).unwrap(), None);
+    }
+
+    #[test]
+    fn a_refresh_keeps_hand_edits_and_values_open_library_does_not_have() {
+        let (_, mut connection) = initialisation::open_test_database("refresh_keeps_manual");
+        let fetched = Book {
+            title: "The Hobit".to_string(),
+            isbn: "9780261102217".to_string(),
+            authors: vec![Author { name: "J. R. R. Tolkien".to_string() }],
+            publish_date: "1937".to_string(),
+            ..Book::default()
+        };
+        let book_id = save_catalogue_record(&connection, &fetched, SOURCE_OPEN_LIBRARY).unwrap();
+        let stored = load_catalogue_record(&connection, book_id).unwrap();
+        let edited = Book {
+            title: "The Hobbit".to_string(),
+            identifiers: vec![Identifier { scheme: "accession".to_string(), value: "1987-001".to_string() }],
+            ..stored.clone()
+        };
+        apply_record_changes(&connection, book_id, &get_changed_fields(&stored, &edited), SOURCE_MANUAL, None).unwrap();
+
+        let refetched = Book { publish_date: "1951".to_string(), ..fetched };
+        let result = refresh_catalogue_record(&mut connection, book_id, &refetched, None).unwrap();
+        assert_eq!(result.updated.iter().map(|change| change.field).collect::<Vec<&str>>(), vec!["publish_date"]);
+        assert_eq!(result.kept_manual.iter().map(|change| change.field).collect::<Vec<&str>>(), vec!["title"]);
+        let refreshed = load_catalogue_record(&connection, book_id).unwrap();
+        assert_eq!((refreshed.title.as_str(), refreshed.publish_date.as_str()), ("The Hobbit", "1951"));
+        assert_eq!(get_field_value(&refreshed, "accession").as_deref(), Some("1987-001"));
+    }
+}
@@ -0,0 +1,376 @@
+use std::env;
+use std::path::PathBuf;
+use rusqlite::Connection;
+use crate::configuration::Config;
+use crate::{backup_management, barcode_management, catalogue_processing, cover_management, initialisation, mailer, notification_processing, seed_management, user_management, web_server, work_processing};
+use crate::utilities;
+
+/*
+ *  Subcommands that run without the interactive menus, e.g.
+ *      rlms config check [path]
+ *  Each returns the exit code for the process.
+ */
+pub async fn run(args: &[String]) -> i32 {
+    match args.iter().map(String::as_str).collect::<Vec<&str>>().as_slice() {
+        ["config", "check"] => check_config(utilities::get_config_path()),
+        ["config", "check", path] => check_config(PathBuf::from(path)),
+        ["hash-password"] => hash_password(),
+        ["setup"] => setup(env::var("RLMS_SEED_FILE").ok().as_deref()),
+        ["setup", seed_path] => setup(Some(seed_path)),
+        ["db", "backup"] => backup(),
+        ["db", "restore", backup_path] => restore(PathBuf::from(backup_path)),
+        ["db", "check"] => check_database(),
+        ["catalogue", "link-works"] => link_works().await,
+        ["catalogue", "refresh"] => refresh_catalogue(None).await,
+        ["catalogue", "refresh", book_id] => match book_id.parse::<i32>() {
+            Ok(book_id) => refresh_catalogue(Some(book_id)).await,
+            Err(_) => {
+                println!("Invalid book ID: {}", book_id);
+                2
+            }
+        },
+        ["catalogue", "covers"] => fetch_missing_covers().await,
+        ["barcodes", "issue"] => issue_barcodes(),
+        ["notifications", "send"] => send_notifications(),
+        ["notifications", "pending"] => list_pending_notifications(),
+        ["serve"] => serve().await,
+        _ => {
+            print_usage();
+            2
+        }
+    }
+}
+
+fn check_config(path: PathBuf) -> i32 {
+    let path = path.to_string_lossy();
+    let mut config = match Config::load(&path) {
+        Ok(Some(config)) => config,
+        Ok(None) => {
+            println!("Configuration file {} does not exist.", path);
+            return 1;
+        }
+        Err(e) => {
+            println!("{}", e);
+            return 1;
+        }
+    };
+    if let Err(e) = config.apply_env_overrides() {
+        println!("{}", e);
+        return 1;
+    }
+
+    let errors = config.validate();
+    if errors.is_empty() {
+        println!("Configuration file {} is valid.", path);
+        0
+    } else {
+        println!("Configuration file {} has {} problem(s):", path, errors.len());
+        for error in errors {
+            println!("\t{}", error);
+        }
+        1
+    }
+}
+
+fn setup(seed_path: Option<&str>) -> i32 {
+    let config_path = utilities::get_config_path();
+    match seed_management::run_headless_setup(&config_path.to_string_lossy(), seed_path) {
+        Ok(report) => {
+            seed_management::print_report(&report);
+            0
+        }
+        Err(e) => {
+            println!("Setup failed: {}", e);
+            1
+        }
+    }
+}
+
+// Prints the salt and hash to put in a seed file for a new user.
+fn hash_password() -> i32 {
+    let mut config = match Config::load(&utilities::get_config_path().to_string_lossy()) {
+        Ok(config) => config.unwrap_or_default(),
+        Err(e) => {
+            println!("{}", e);
+            return 1;
+        }
+    };
+    if let Err(e) = config.apply_env_overrides() {
+        println!("{}", e);
+        return 1;
+    }
+
+    let password = utilities::get_password_from_user();
+    let salt = utilities::generate_salt(config.auth.salt_length).trim().to_string();
+    match utilities::hash_password(&password, &salt, config.auth.bcrypt_cost) {
+        Ok(hashed) => {
+            // Printed as TOML with escaping, as the salt may contain quotes or backslashes.
+            println!("salt = {}", toml::Value::String(salt));
+            println!("password_hash = {}", toml::Value::String(hashed));
+            0
+        }
+        Err(e) => {
+            println!("Failed to hash the password: {}", e);
+            1
+        }
+    }
+}
+
+/*
+ *  Loads the configuration of an installation that has already been set
+ *  up, the same way the interactive program does, for the commands below.
+ */
+fn load_installation() -> Result<Config, String> {
+    let config_path = utilities::get_config_path();
+    let mut config = Config::load(&config_path.to_string_lossy())
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("Configuration file {} does not exist. Run setup first.", config_path.display()))?;
+    config.apply_env_overrides().map_err(|e| e.to_string())?;
+    let errors = config.validate();
+    if !errors.is_empty() {
+        return Err(format!("The configuration has {} problem(s):\n\t{}", errors.len(), errors.join("\n\t")));
+    }
+    utilities::set_pause_scale(config.interface.pause_scale);
+    if let Err(e) = utilities::init_logging(&config.logging) {
+        println!("Failed to set up logging: {}", e);
+    }
+    if !initialisation::update_existing_tables(config.database.file.as_deref().unwrap_or_default()) {
+        return Err("Failed to update the database tables.".to_string());
+    }
+    Ok(config)
+}
+
+fn backup() -> i32 {
+    let config = match load_installation() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            return 1;
+        }
+    };
+    match backup_management::create_backup(config.database.file.as_deref().unwrap_or_default(), &config.backup) {
+        Ok(path) => {
+            println!("Backup written to {}", path.display());
+            0
+        }
+        Err(e) => {
+            println!("Backup failed: {}", e);
+            1
+        }
+    }
+}
+
+fn restore(backup_path: PathBuf) -> i32 {
+    let config = match load_installation() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            return 1;
+        }
+    };
+    let database_name = config.database.file.as_deref().unwrap_or_default();
+    match backup_management::restore_backup(database_name, &backup_path, &config.backup) {
+        Ok(safety_backup) => {
+            println!("Restored {} from {}", database_name, backup_path.display());
+            println!("The previous contents were backed up to {}", safety_backup.display());
+            0
+        }
+        Err(e) => {
+            println!("Restore failed: {}", e);
+            1
+        }
+    }
+}
+
+fn check_database() -> i32 {
+    let config = match load_installation() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            return 1;
+        }
+    };
+    match backup_management::check_integrity(config.database.file.as_deref().unwrap_or_default()) {
+        Ok(report) => {
+            backup_management::print_integrity_report(&report);
+            if report.is_ok() { 0 } else { 1 }
+        }
+        Err(e) => {
+            println!("Failed to check the database: {}", e);
+            1
+        }
+    }
+}
+
+async fn link_works() -> i32 {
+    let config = match load_installation() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            return 1;
+        }
+    };
+    match work_processing::link_unlinked_books(config.database.file.as_deref().unwrap_or_default(), &config.metadata).await {
+        Ok((linked, skipped)) => {
+            println!("{} book(s) linked to their work, {} could not be linked.", linked, skipped);
+            0
+        }
+        Err(e) => {
+            println!("Failed to link books to works: {}", e);
+            1
+        }
+    }
+}
+
+async fn refresh_catalogue(book_id: Option<i32>) -> i32 {
+    let config = match load_installation() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            return 1;
+        }
+    };
+    match catalogue_processing::refresh_catalogue(config.database.file.as_deref().unwrap_or_default(), &config.metadata, book_id).await {
+        Ok((updated, kept, failed)) => {
+            println!(
+                "{} record(s) updated, {} field(s) corrected by hand kept, {} record(s) could not be looked up.",
+                updated, kept, failed
+            );
+            0
+        }
+        Err(e) => {
+            println!("Failed to refresh the catalogue: {}", e);
+            1
+        }
+    }
+}
+
+async fn fetch_missing_covers() -> i32 {
+    let config = match load_installation() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            return 1;
+        }
+    };
+    match cover_management::fetch_missing_covers(config.database.file.as_deref().unwrap_or_default(), &config).await {
+        Ok((stored, missing, failed)) => {
+            println!(
+                "{} cover(s) stored, {} book(s) have no cover on Open Library, {} could not be downloaded.",
+                stored, missing, failed
+            );
+            0
+        }
+        Err(e) => {
+            println!("Failed to download covers: {}", e);
+            1
+        }
+    }
+}
+
+fn issue_barcodes() -> i32 {
+    let config = match load_installation() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            return 1;
+        }
+    };
+    match barcode_management::assign_missing_barcodes(config.database.file.as_deref().unwrap_or_default(), &config.barcodes) {
+        Ok((items, patrons)) => {
+            println!("Issued {} item barcode(s) and {} patron barcode(s).", items, patrons);
+            0
+        }
+        Err(e) => {
+            println!("Failed to issue barcodes: {}", e);
+            1
+        }
+    }
+}
+
+fn send_notifications() -> i32 {
+    let config = match load_installation() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            return 1;
+        }
+    };
+    let result = mailer::mailer_from_config(&config)
+        .and_then(|mailer| notification_processing::send_notifications(config.database.file.as_deref().unwrap_or_default(), &config, mailer.as_ref()));
+    match result {
+        Ok(report) => {
+            report.print_report();
+            if report.failed > 0 { 1 } else { 0 }
+        }
+        Err(e) => {
+            println!("Failed to send notifications: {}", e);
+            1
+        }
+    }
+}
+
+// Lists what `notifications send` would send now, without sending it.
+fn list_pending_notifications() -> i32 {
+    let config = match load_installation() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            return 1;
+        }
+    };
+    let pending = Connection::open(config.database.file.as_deref().unwrap_or_default())
+        .and_then(|connection| notification_processing::find_pending_notifications(&connection, &config.notifications, utilities::current_timestamp()));
+    match pending {
+        Ok(pending) => {
+            println!("{} notification(s) due to be sent.", pending.len());
+            for notification in pending {
+                notification.print_notification_info();
+            }
+            0
+        }
+        Err(e) => {
+            println!("Failed to find notifications: {}", e);
+            1
+        }
+    }
+}
+
+async fn serve() -> i32 {
+    let config = match load_installation() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            return 1;
+        }
+    };
+    if let Err(e) = user_management::init_dummy_password_hash(&config) {
+        println!("Failed to prepare for logins: {}", e);
+        return 1;
+    }
+    match web_server::run_server(config).await {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("Server failed: {}", e);
+            1
+        }
+    }
+}
+
+fn print_usage() {
+    println!("Usage:");
+    println!("\trlms                        Start the interactive program");
+    println!("\trlms config check [path]    Check a configuration file for problems");
+    println!("\trlms setup [seed-file]      Set up or top up the database without prompts");
+    println!("\trlms hash-password          Print a salt and password hash for a seed file");
+    println!("\trlms db backup              Back up the database");
+    println!("\trlms db restore <backup>    Replace the database with a backup");
+    println!("\trlms db check               Check the database for corruption");
+    println!("\trlms catalogue link-works   Group existing catalogue records by work");
+    println!("\trlms catalogue refresh [id] Update records from Open Library, keeping hand corrections");
+    println!("\trlms catalogue covers       Download covers for records that have none stored");
+    println!("\trlms barcodes issue         Issue barcodes to copies and patrons that have none");
+    println!("\trlms notifications send     Send due-soon, overdue and hold-ready notifications");
+    println!("\trlms notifications pending  List the notifications that are due, without sending them");
+    println!("\trlms serve                  Run the server, including scheduled backups and notifications");
+}
@@ -1,32 +1,646 @@
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::env;
+use std::error::Error;
 use std::fs;
-use std::io::{self, Write};
+use std::io;
+use std::path::Path;
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+/*
+ *  The configuration is split into sections, one per part of the program.
+ *  Every setting has a default, so a configuration file only needs to
+ *  contain the settings that differ from it. Files ending in .toml are
+ *  read and written as TOML, anything else as JSON.
+ *
+ *  Any setting can be overridden from the environment with a variable
+ *  named RLMS_<SECTION>_<SETTING>, e.g. RLMS_DATABASE_FILE or
+ *  RLMS_AUTH_BCRYPT_COST.
+ */
+const ENV_PREFIX: &str = "RLMS_";
+
+// The cover sizes Open Library offers, smallest first.
+pub const COVER_SIZES: [&str; 3] = ["small", "medium", "large"];
+// The barcode symbologies new barcodes can be issued in.
+pub const BARCODE_SYMBOLOGIES: [&str; 2] = ["code128", "ean13"];
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
-    pub database_file: Option<String>,
+    pub database: DatabaseConfig,
+    pub metadata: MetadataConfig,
+    pub auth: AuthConfig,
+    pub web: WebConfig,
+    pub logging: LoggingConfig,
+    pub mail: MailConfig,
+    pub interface: InterfaceConfig,
+    pub backup: BackupConfig,
+    pub covers: CoverConfig,
+    pub barcodes: BarcodeConfig,
+    pub oai: OaiConfig,
+    pub sru: SruConfig,
+    pub notifications: NotificationConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub file: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetadataConfig {
+    pub open_library_url: String,
+    pub request_timeout_seconds: u64,
+}
+
+impl Default for MetadataConfig {
+    fn default() -> Self {
+        MetadataConfig {
+            open_library_url: "https://openlibrary.org".to_string(),
+            request_timeout_seconds: 10,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub bcrypt_cost: u32,
+    pub salt_length: usize,
+    pub max_failed_logins: u32,
+    pub lockout_minutes: u64,
+    pub max_login_backoff_seconds: u64,
+    pub email_verification_hours: u64,
+    pub password_reset_minutes: u64,
+    pub require_admin_two_factor: bool,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            bcrypt_cost: bcrypt::DEFAULT_COST,
+            salt_length: 25,
+            max_failed_logins: 5,
+            lockout_minutes: 15,
+            max_login_backoff_seconds: 30,
+            email_verification_hours: 48,
+            password_reset_minutes: 60,
+            require_admin_two_factor: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationConfig {
+    // How often the server sends the notifications that are due; 0 leaves it to `rlms notifications send`.
+    pub interval_minutes: u64,
+    // How long before the due date the reminder goes out, unless a patron has chosen otherwise.
+    pub due_soon_days: u32,
+    // The days overdue at which each overdue notice is sent, the last being the final notice.
+    pub overdue_steps_days: Vec<u32>,
+    // Templates here replace the built-in ones of the same name (due_soon.txt, overdue.txt, hold_ready.txt).
+    pub template_directory: Option<String>,
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        NotificationConfig {
+            interval_minutes: 0,
+            due_soon_days: 3,
+            overdue_steps_days: vec![1, 7, 14],
+            template_directory: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebConfig {
+    pub bind_address: String,
+    pub port: u16,
+    // The catalogue name e-reader apps show for the OPDS feeds.
+    pub opds_title: String,
+    pub opds_page_size: usize,
+}
+
+impl Default for WebConfig {
+    fn default() -> Self {
+        WebConfig {
+            bind_address: "127.0.0.1".to_string(),
+            port: 8080,
+            opds_title: "rLMS".to_string(),
+            opds_page_size: 50,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: String,
+    pub file: Option<String>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: "warn".to_string(),
+            file: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailConfig {
+    pub transport: String,
+    pub from_address: String,
+    pub outbox_directory: String,
+    pub smtp_host: Option<String>,
+    pub smtp_port: Option<u16>,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    pub smtp_security: Option<String>,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        MailConfig {
+            transport: "file".to_string(),
+            from_address: "rLMS <rlms@localhost>".to_string(),
+            outbox_directory: "outbox".to_string(),
+            smtp_host: None,
+            smtp_port: None,
+            smtp_username: None,
+            smtp_password: None,
+            smtp_security: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct InterfaceConfig {
+    // Multiplies every pause between screens; 0 turns them off.
+    pub pause_scale: f64,
+    pub max_list_rows: usize,
+}
+
+impl Default for InterfaceConfig {
+    fn default() -> Self {
+        InterfaceConfig {
+            pause_scale: 1.0,
+            max_list_rows: 100,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackupConfig {
+    pub directory: String,
+    // The most recent backups to keep; older ones are deleted after each backup.
+    pub keep_last: usize,
+    // Also delete backups older than this; 0 keeps them regardless of age.
+    pub max_age_days: u64,
+    // How often the server makes a backup; 0 turns scheduled backups off.
+    pub interval_minutes: u64,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        BackupConfig {
+            directory: "backups".to_string(),
+            keep_last: 7,
+            max_age_days: 0,
+            interval_minutes: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CoverConfig {
+    // Download the cover when a book is added to the catalogue.
+    pub download: bool,
+    // small, medium or large; the nearest other size is used if it is missing.
+    pub preferred_size: String,
+    // Thumbnails keep the cover's proportions, so only the width is set.
+    pub thumbnail_width: u32,
+}
+
+impl Default for CoverConfig {
+    fn default() -> Self {
+        CoverConfig {
+            download: true,
+            preferred_size: "large".to_string(),
+            thumbnail_width: 120,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct BarcodeConfig {
+    // code128, or ean13 for scanners that only read retail barcodes.
+    pub symbology: String,
+    // Starts every barcode issued. EAN-13 numbers starting 20 to 29 are kept for in-store use.
+    pub prefix: String,
+}
+
+impl Default for BarcodeConfig {
+    fn default() -> Self {
+        BarcodeConfig {
+            symbology: "code128".to_string(),
+            prefix: "29".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct OaiConfig {
+    pub repository_name: String,
+    // Records are named oai:<repository_identifier>:<book_id>, so this should be a domain the library owns.
+    pub repository_identifier: String,
+    // Who harvesters should write to; mail.from_address if unset.
+    pub admin_email: Option<String>,
+    // Records in each ListRecords or ListIdentifiers response, before a resumption token.
+    pub page_size: usize,
+}
+
+impl Default for OaiConfig {
+    fn default() -> Self {
+        OaiConfig {
+            repository_name: "rLMS".to_string(),
+            repository_identifier: "rlms.localhost".to_string(),
+            admin_email: None,
+            page_size: 100,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SruConfig {
+    // The name of the database in the explain record.
+    pub title: String,
+    // Records returned when a search does not say how many it wants.
+    pub default_records: usize,
+    // The most records one response may hold, whatever a search asks for.
+    pub max_records: usize,
+}
+
+impl Default for SruConfig {
+    fn default() -> Self {
+        SruConfig {
+            title: "rLMS catalogue".to_string(),
+            default_records: 10,
+            max_records: 100,
+        }
+    }
 }
 
 impl Config {
-    pub fn load(path: &str) -> Option<Self> {
-        fs::read_to_string(path).ok().and_then(|data| serde_json::from_str(&data).ok())
+    /*
+     *  Returns Ok(None) if there is no configuration file yet. A file that
+     *  exists but cannot be read or parsed is an error, never silently
+     *  replaced by the defaults.
+     */
+    pub fn load(path: &str) -> Result<Option<Self>, Box<dyn Error>> {
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("Failed to read configuration file {}: {}", path, e).into()),
+        };
+
+        let mut value: Value = if is_toml(path) {
+            toml::from_str(&data).map_err(|e| format!("Failed to parse configuration file {}: {}", path, e))?
+        } else {
+            serde_json::from_str(&data).map_err(|e| format!("Failed to parse configuration file {}: {}", path, e))?
+        };
+        migrate_legacy_settings(&mut value);
+
+        let config = serde_json::from_value(value)
+            .map_err(|e| format!("Invalid configuration file {}: {}", path, e))?;
+        Ok(Some(config))
     }
+
     pub(crate) fn save(&self, path: &str) -> io::Result<()> {
-        let json = serde_json::to_string_pretty(self)?;
-        fs::write(path, json)?;
+        let data = if is_toml(path) {
+            toml::to_string_pretty(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        } else {
+            serde_json::to_string_pretty(self)?
+        };
+        fs::write(path, data)?;
         Ok(())
     }
 
-    fn exists(path: &str) -> bool {
-        fs::metadata(path).is_ok()
+    /// Applies RLMS_<SECTION>_<SETTING> environment variables on top of the loaded settings.
+    pub fn apply_env_overrides(&mut self) -> Result<(), Box<dyn Error>> {
+        let overrides: Vec<(String, String)> = env::vars()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+            .collect();
+        self.apply_overrides(&overrides)
+    }
+
+    fn apply_overrides(&mut self, overrides: &[(String, String)]) -> Result<(), Box<dyn Error>> {
+        let mut value = serde_json::to_value(&*self)?;
+        let sections = value.as_object_mut().ok_or("Configuration is not an object")?;
+
+        for (name, raw) in overrides {
+            let setting_path = name[ENV_PREFIX.len()..].to_lowercase();
+            // Variables for other purposes (e.g. RLMS_SEED_FILE) don't name a section.
+            let Some((section_name, section)) = sections
+                .iter_mut()
+                .find(|(section_name, _)| setting_path.starts_with(&format!("{}_", section_name)))
+            else {
+                continue;
+            };
+            let key = &setting_path[section_name.len() + 1..];
+            let section = section.as_object_mut().ok_or("Configuration section is not an object")?;
+            let current = section
+                .get(key)
+                .ok_or_else(|| format!("{} does not match any setting in the [{}] section", name, section_name))?;
+            let parsed = parse_override(current, raw)
+                .ok_or_else(|| format!("{} has an invalid value: {}", name, raw))?;
+            section.insert(key.to_string(), parsed);
+        }
+
+        *self = serde_json::from_value(value).map_err(|e| format!("Invalid environment override: {}", e))?;
+        Ok(())
+    }
+
+    /// Returns a description of every problem with the settings; empty means valid.
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        match self.database.file.as_deref() {
+            None => errors.push("database.file is not set".to_string()),
+            Some(file) if file.trim().is_empty() => errors.push("database.file must not be empty".to_string()),
+            Some(_) => {}
+        }
+
+        if !self.metadata.open_library_url.starts_with("http://")
+            && !self.metadata.open_library_url.starts_with("https://")
+        {
+            errors.push(format!(
+                "metadata.open_library_url must start with http:// or https:// (got \"{}\")",
+                self.metadata.open_library_url
+            ));
+        }
+        if self.metadata.request_timeout_seconds == 0 {
+            errors.push("metadata.request_timeout_seconds must be at least 1".to_string());
+        }
+
+        if !(4..=31).contains(&self.auth.bcrypt_cost) {
+            errors.push(format!("auth.bcrypt_cost must be between 4 and 31 (got {})", self.auth.bcrypt_cost));
+        }
+        if !(8..=200).contains(&self.auth.salt_length) {
+            errors.push(format!("auth.salt_length must be between 8 and 200 (got {})", self.auth.salt_length));
+        }
+        if self.auth.max_failed_logins == 0 {
+            errors.push("auth.max_failed_logins must be at least 1".to_string());
+        }
+        if self.auth.lockout_minutes == 0 {
+            errors.push("auth.lockout_minutes must be at least 1".to_string());
+        }
+        if self.auth.email_verification_hours == 0 {
+            errors.push("auth.email_verification_hours must be at least 1".to_string());
+        }
+        if self.auth.password_reset_minutes == 0 {
+            errors.push("auth.password_reset_minutes must be at least 1".to_string());
+        }
+
+        if !(1..=60).contains(&self.notifications.due_soon_days) {
+            errors.push(format!(
+                "notifications.due_soon_days must be between 1 and 60 (got {})",
+                self.notifications.due_soon_days
+            ));
+        }
+        if self.notifications.overdue_steps_days.is_empty() {
+            errors.push("notifications.overdue_steps_days must have at least one step".to_string());
+        } else if self.notifications.overdue_steps_days[0] == 0
+            || self.notifications.overdue_steps_days.windows(2).any(|steps| steps[0] >= steps[1])
+        {
+            errors.push(format!(
+                "notifications.overdue_steps_days must be increasing numbers of days from 1 (got {:?})",
+                self.notifications.overdue_steps_days
+            ));
+        }
+        if let Some(directory) = self.notifications.template_directory.as_deref() {
+            if !Path::new(directory).is_dir() {
+                errors.push(format!("notifications.template_directory is not a directory (got \"{}\")", directory));
+            }
+        }
+
+        if self.web.bind_address.trim().is_empty() {
+            errors.push("web.bind_address must not be empty".to_string());
+        }
+        if self.web.port == 0 {
+            errors.push("web.port must be between 1 and 65535".to_string());
+        }
+        if self.web.opds_title.trim().is_empty() {
+            errors.push("web.opds_title must not be empty".to_string());
+        }
+        if !(1..=500).contains(&self.web.opds_page_size) {
+            errors.push(format!("web.opds_page_size must be between 1 and 500 (got {})", self.web.opds_page_size));
+        }
+
+        if !["off", "error", "warn", "info", "debug", "trace"].contains(&self.logging.level.as_str()) {
+            errors.push(format!(
+                "logging.level must be one of off, error, warn, info, debug or trace (got \"{}\")",
+                self.logging.level
+            ));
+        }
+
+        match self.mail.transport.as_str() {
+            "smtp" => {
+                if self.mail.smtp_host.is_none() {
+                    errors.push("mail.smtp_host must be set when mail.transport is \"smtp\"".to_string());
+                }
+                if let Some(security) = self.mail.smtp_security.as_deref() {
+                    if !["tls", "starttls", "none"].contains(&security) {
+                        errors.push(format!(
+                            "mail.smtp_security must be tls, starttls or none (got \"{}\")",
+                            security
+                        ));
+                    }
+                }
+            }
+            "file" => {
+                if self.mail.outbox_directory.trim().is_empty() {
+                    errors.push("mail.outbox_directory must not be empty".to_string());
+                }
+            }
+            "memory" => {}
+            other => errors.push(format!("mail.transport must be smtp, file or memory (got \"{}\")", other)),
+        }
+        if self.mail.from_address.parse::<lettre::message::Mailbox>().is_err() {
+            errors.push(format!("mail.from_address is not a valid address (got \"{}\")", self.mail.from_address));
+        }
+
+        if !(0.0..=10.0).contains(&self.interface.pause_scale) {
+            errors.push(format!("interface.pause_scale must be between 0 and 10 (got {})", self.interface.pause_scale));
+        }
+        if self.interface.max_list_rows == 0 {
+            errors.push("interface.max_list_rows must be at least 1".to_string());
+        }
+
+        if self.backup.directory.trim().is_empty() {
+            errors.push("backup.directory must not be empty".to_string());
+        }
+        if self.backup.keep_last == 0 {
+            errors.push("backup.keep_last must be at least 1".to_string());
+        }
+
+        if !COVER_SIZES.contains(&self.covers.preferred_size.as_str()) {
+            errors.push(format!(
+                "covers.preferred_size must be small, medium or large (got \"{}\")",
+                self.covers.preferred_size
+            ));
+        }
+        if !(16..=1000).contains(&self.covers.thumbnail_width) {
+            errors.push(format!("covers.thumbnail_width must be between 16 and 1000 (got {})", self.covers.thumbnail_width));
+        }
+
+        if !BARCODE_SYMBOLOGIES.contains(&self.barcodes.symbology.as_str()) {
+            errors.push(format!(
+                "barcodes.symbology must be code128 or ean13 (got \"{}\")",
+                self.barcodes.symbology
+            ));
+        }
+        if !(1..=6).contains(&self.barcodes.prefix.len()) || !self.barcodes.prefix.chars().all(|c| c.is_ascii_alphanumeric()) {
+            errors.push(format!(
+                "barcodes.prefix must be 1 to 6 letters or digits (got \"{}\")",
+                self.barcodes.prefix
+            ));
+        } else if self.barcodes.symbology == "ean13" && !self.barcodes.prefix.chars().all(|c| c.is_ascii_digit()) {
+            errors.push(format!(
+                "barcodes.prefix must be digits only when barcodes.symbology is \"ean13\" (got \"{}\")",
+                self.barcodes.prefix
+            ));
+        }
+
+        if self.oai.repository_name.trim().is_empty() {
+            errors.push("oai.repository_name must not be empty".to_string());
+        }
+        if !is_repository_identifier(&self.oai.repository_identifier) {
+            errors.push(format!(
+                "oai.repository_identifier must be a domain name such as library.example.org (got \"{}\")",
+                self.oai.repository_identifier
+            ));
+        }
+        if let Some(admin_email) = self.oai.admin_email.as_deref() {
+            if admin_email.parse::<lettre::Address>().is_err() {
+                errors.push(format!("oai.admin_email is not a valid address (got \"{}\")", admin_email));
+            }
+        }
+        if !(1..=1000).contains(&self.oai.page_size) {
+            errors.push(format!("oai.page_size must be between 1 and 1000 (got {})", self.oai.page_size));
+        }
+
+        if self.sru.title.trim().is_empty() {
+            errors.push("sru.title must not be empty".to_string());
+        }
+        if !(1..=1000).contains(&self.sru.max_records) {
+            errors.push(format!("sru.max_records must be between 1 and 1000 (got {})", self.sru.max_records));
+        }
+        if self.sru.default_records == 0 || self.sru.default_records > self.sru.max_records {
+            errors.push(format!(
+                "sru.default_records must be between 1 and sru.max_records (got {})",
+                self.sru.default_records
+            ));
+        }
+
+        errors
+    }
+
+    pub fn get_lockout_seconds(&self) -> i64 {
+        (self.auth.lockout_minutes * 60) as i64
+    }
+    pub fn get_email_verification_seconds(&self) -> i64 {
+        (self.auth.email_verification_hours * 3600) as i64
+    }
+    pub fn get_password_reset_seconds(&self) -> i64 {
+        (self.auth.password_reset_minutes * 60) as i64
+    }
+}
+
+// Two or more labels separated by dots, each starting with a letter, as the oai-identifier scheme requires.
+fn is_repository_identifier(identifier: &str) -> bool {
+    let labels: Vec<&str> = identifier.split('.').collect();
+    labels.len() >= 2 && labels.iter().all(|label| {
+        label.starts_with(|c: char| c.is_ascii_alphabetic())
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
+}
+
+pub(crate) fn is_toml(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("toml"))
+}
+
+// Keeps the type of the current value, so RLMS_WEB_PORT=80 stays a number.
+fn parse_override(current: &Value, raw: &str) -> Option<Value> {
+    match current {
+        Value::Bool(_) => raw.parse::<bool>().ok().map(Value::Bool),
+        Value::Number(_) => serde_json::from_str::<Value>(raw).ok().filter(Value::is_number),
+        Value::String(_) => Some(Value::String(raw.to_string())),
+        // Lists are written as JSON, e.g. [1,7,14].
+        Value::Array(_) => serde_json::from_str::<Value>(raw).ok().filter(Value::is_array),
+        // Unset optional settings: accept numbers and booleans, otherwise a string.
+        _ => Some(serde_json::from_str::<Value>(raw)
+            .ok()
+            .filter(|value| value.is_number() || value.is_boolean())
+            .unwrap_or_else(|| Value::String(raw.to_string()))),
+    }
+}
+
+/*
+ *  Configuration files written before the settings were split into
+ *  sections kept everything at the top level. Move those settings to
+ *  where they live now so old files keep working.
+ */
+fn migrate_legacy_settings(value: &mut Value) {
+    const LEGACY_SETTINGS: [(&str, &str, &str); 8] = [
+        ("database_file", "database", "file"),
+        ("max_failed_logins", "auth", "max_failed_logins"),
+        ("lockout_minutes", "auth", "lockout_minutes"),
+        ("max_login_backoff_seconds", "auth", "max_login_backoff_seconds"),
+        ("email_verification_hours", "auth", "email_verification_hours"),
+        ("password_reset_minutes", "auth", "password_reset_minutes"),
+        ("require_admin_two_factor", "auth", "require_admin_two_factor"),
+        ("mail", "mail", ""),
+    ];
+    let Some(object) = value.as_object_mut() else { return; };
+
+    for (old_key, section, key) in LEGACY_SETTINGS {
+        if key.is_empty() {
+            // Sections that already had their current name; only a null needs removing.
+            if object.get(old_key).is_some_and(Value::is_null) {
+                object.remove(old_key);
+            }
+            continue;
+        }
+        let Some(old_value) = object.remove(old_key) else { continue; };
+        if old_value.is_null() { continue; }
+        let section = object
+            .entry(section)
+            .or_insert_with(|| Value::Object(Map::new()));
+        if let Some(section) = section.as_object_mut() {
+            section.entry(key).or_insert(old_value);
+        }
     }
 }
 
 pub fn setup_config_database_file(config: &mut Config, database_file: &str, path: &str) {
-    config.database_file = Some(database_file.to_string());
+    config.database.file = Some(database_file.to_string());
     if let Err(e) = config.save(path) {
         println!("Failed to save configuration: {}", e);
     }
 
 }
-
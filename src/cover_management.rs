@@ -0,0 +1,295 @@
+use std::error::Error;
+use std::io::Cursor;
+use image::{DynamicImage, ImageFormat};
+use image::imageops::FilterType;
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+use crate::book_object::Book;
+use crate::book_processing::get_book_info;
+use crate::configuration::{Config, CoverConfig, MetadataConfig, COVER_SIZES};
+use crate::utilities;
+
+// Anything bigger than this is not a cover, whatever the server says.
+const MAX_COVER_BYTES: usize = 10 * 1024 * 1024;
+pub const THUMBNAIL_CONTENT_TYPE: &str = "image/jpeg";
+
+/*
+ *  A cover as it is stored: the image as downloaded, so nothing is lost,
+ *  and a thumbnail made here at covers.thumbnail_width for lists and the
+ *  web pages. Open Library answers with a 1x1 image for a missing cover,
+ *  which is treated as no cover at all.
+ */
+pub struct CoverImage {
+    pub sha256: String,
+    pub content_type: String,
+    pub width: u32,
+    pub height: u32,
+    pub image: Vec<u8>,
+    pub thumbnail: Vec<u8>,
+}
+
+/// The preferred size if Open Library has it, otherwise the nearest, larger first.
+pub fn get_cover_url<'a>(book: &'a Book, preferred_size: &str) -> Option<&'a String> {
+    let preferred = COVER_SIZES.iter().position(|size| *size == preferred_size).unwrap_or(COVER_SIZES.len() - 1);
+    let mut sizes: Vec<usize> = (0..COVER_SIZES.len()).collect();
+    sizes.sort_by_key(|size| (size.abs_diff(preferred), std::cmp::Reverse(*size)));
+    sizes.into_iter().find_map(|size| book.get_cover_by_size(COVER_SIZES[size]))
+}
+
+pub fn make_cover_image(image: Vec<u8>, thumbnail_width: u32) -> Result<Option<CoverImage>, Box<dyn Error>> {
+    if image.len() > MAX_COVER_BYTES {
+        return Err(format!("The cover is too large ({} bytes)", image.len()).into());
+    }
+    let format = image::guess_format(&image).map_err(|_| "The cover is not a supported image")?;
+    let decoded = image::load_from_memory_with_format(&image, format)?;
+    if decoded.width() <= 1 || decoded.height() <= 1 {
+        return Ok(None);
+    }
+
+    // Small covers are not enlarged.
+    let thumbnail = if decoded.width() > thumbnail_width {
+        decoded.resize(thumbnail_width, u32::MAX, FilterType::Triangle)
+    } else {
+        decoded.clone()
+    };
+    let mut thumbnail_bytes = Vec::new();
+    DynamicImage::ImageRgb8(thumbnail.to_rgb8()).write_to(&mut Cursor::new(&mut thumbnail_bytes), ImageFormat::Jpeg)?;
+
+    Ok(Some(CoverImage {
+        sha256: get_sha256(&image),
+        content_type: format.to_mime_type().to_string(),
+        width: decoded.width(),
+        height: decoded.height(),
+        image,
+        thumbnail: thumbnail_bytes,
+    }))
+}
+
+fn get_sha256(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+async fn download_cover(url: &str, metadata: &MetadataConfig) -> Result<Vec<u8>, Box<dyn Error>> {
+    let client = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(metadata.request_timeout_seconds))
+        .build()?;
+    let mut response = client.get(url).send().await?;
+    if !response.status().is_success() {
+        return Err(format!("Failed to download the cover: HTTP {}", response.status()).into());
+    }
+    if let Some(length) = response.content_length().filter(|length| *length > MAX_COVER_BYTES as u64) {
+        return Err(format!("The cover is too large ({} bytes)", length).into());
+    }
+    // The length is only what the server claims, so the download is cut off at the limit as well.
+    let mut image = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if image.len() + chunk.len() > MAX_COVER_BYTES {
+            return Err(format!("The cover is larger than {} bytes", MAX_COVER_BYTES).into());
+        }
+        image.extend_from_slice(&chunk);
+    }
+    Ok(image)
+}
+
+pub fn save_cover(connection: &Connection, book_id: i32, source_url: Option<&str>, cover: &CoverImage) -> Result<(), rusqlite::Error> {
+    connection.execute(
+        "INSERT INTO book_covers (book_id, sha256, content_type, width, height, image, thumbnail, source_url, fetched_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+         ON CONFLICT (book_id) DO UPDATE SET
+            sha256 = excluded.sha256, content_type = excluded.content_type,
+            width = excluded.width, height = excluded.height,
+            image = excluded.image, thumbnail = excluded.thumbnail,
+            source_url = excluded.source_url, fetched_at = excluded.fetched_at",
+        params![
+            book_id,
+            cover.sha256,
+            cover.content_type,
+            cover.width,
+            cover.height,
+            cover.image,
+            cover.thumbnail,
+            source_url,
+            utilities::current_timestamp()
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn has_cover(connection: &Connection, book_id: i32) -> Result<bool, rusqlite::Error> {
+    connection.query_row(
+        "SELECT EXISTS (SELECT 1 FROM book_covers WHERE book_id = ?1)",
+        params![book_id],
+        |row| row.get(0),
+    )
+}
+
+/*
+ *  The stored cover, or its thumbnail, as (content type, bytes, sha256).
+ *  The thumbnail's hash is of its own bytes, as it changes with
+ *  covers.thumbnail_width while the original does not.
+ */
+pub fn get_cover(connection: &Connection, book_id: i32, thumbnail: bool) -> Result<Option<(String, Vec<u8>, String)>, rusqlite::Error> {
+    connection.query_row(
+        "SELECT content_type, image, thumbnail, sha256 FROM book_covers WHERE book_id = ?1",
+        params![book_id],
+        |row| {
+            if thumbnail {
+                let image: Vec<u8> = row.get(2)?;
+                let sha256 = get_sha256(&image);
+                Ok((THUMBNAIL_CONTENT_TYPE.to_string(), image, sha256))
+            } else {
+                Ok((row.get(0)?, row.get(1)?, row.get(3)?))
+            }
+        },
+    ).optional()
+}
+
+pub fn get_cover_content_type(connection: &Connection, book_id: i32) -> Result<Option<String>, rusqlite::Error> {
+    connection.query_row(
+        "SELECT content_type FROM book_covers WHERE book_id = ?1",
+        params![book_id],
+        |row| row.get(0),
+    ).optional()
+}
+
+/// The width and height of the stored cover and its size in bytes.
+pub fn get_cover_summary(connection: &Connection, book_id: i32) -> Result<Option<(u32, u32, usize)>, rusqlite::Error> {
+    connection.query_row(
+        "SELECT width, height, length(image) FROM book_covers WHERE book_id = ?1",
+        params![book_id],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    ).optional()
+}
+
+// Used for file names when covers are exported.
+pub fn get_file_extension(content_type: &str) -> &'static str {
+    match content_type {
+        "image/png" => "png",
+        "image/gif" => "gif",
+        _ => "jpg",
+    }
+}
+
+/*
+ *  Downloads the cover of a book fetched from Open Library and stores it,
+ *  unless one is already stored or downloading is turned off. Returns
+ *  whether a cover was stored.
+ */
+pub async fn store_cover_for_book(database_name: &str, config: &Config, book_id: i32, book: &Book) -> Result<bool, Box<dyn Error>> {
+    if !config.covers.download || has_cover(&Connection::open(database_name)?, book_id)? {
+        return Ok(false);
+    }
+    fetch_and_save_cover(database_name, &config.covers, &config.metadata, book_id, book).await
+}
+
+async fn fetch_and_save_cover(database_name: &str, covers: &CoverConfig, metadata: &MetadataConfig, book_id: i32, book: &Book) -> Result<bool, Box<dyn Error>> {
+    let Some(url) = get_cover_url(book, &covers.preferred_size) else { return Ok(false); };
+    let image = download_cover(url, metadata).await?;
+    let Some(cover) = make_cover_image(image, covers.thumbnail_width)? else { return Ok(false); };
+    save_cover(&Connection::open(database_name)?, book_id, Some(url), &cover)?;
+    Ok(true)
+}
+
+/*
+ *  Looks up every catalogue record with an ISBN but no stored cover and
+ *  downloads its cover, e.g. for books added before covers were kept.
+ *  Returns how many covers were stored, how many books have none on
+ *  Open Library and how many could not be looked up or downloaded.
+ */
+pub async fn fetch_missing_covers(database_name: &str, config: &Config) -> Result<(usize, usize, usize), Box<dyn Error>> {
+    let books: Vec<(i32, String)> = {
+        let connection = Connection::open(database_name)?;
+        let mut stmt = connection.prepare(
+            "SELECT book_id, isbn FROM books
+             WHERE isbn IS NOT NULL AND book_id NOT IN (SELECT book_id FROM book_covers)
+             ORDER BY book_id",
+        )?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect::<Result<_, _>>()?
+    };
+
+    let (mut stored, mut missing, mut failed) = (0, 0, 0);
+    for (book_id, isbn) in books {
+        let result = match get_book_info(&isbn, &config.metadata).await {
+            Ok(book) => fetch_and_save_cover(database_name, &config.covers, &config.metadata, book_id, &book).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(true) => stored += 1,
+            Ok(false) => missing += 1,
+            Err(e) => {
+                log::warn!("Could not store the cover of book {} (ISBN {}): {}", book_id, isbn, e);
+                failed += 1;
+            }
+        }
+    }
+    Ok((stored, missing, failed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+    use crate::book_object::Cover;
+    use crate::initialisation;
+
+    fn get_png(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(width, height))
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn covers_keep_the_original_and_get_a_jpeg_thumbnail() {
+        let png = get_png(400, 600);
+        let cover = make_cover_image(png.clone(), 180).unwrap().unwrap();
+        assert_eq!((cover.content_type.as_str(), cover.width, cover.height), ("image/png", 400, 600));
+        assert_eq!(cover.image, png);
+        assert_eq!(cover.sha256, get_sha256(&png));
+        let thumbnail = image::load_from_memory_with_format(&cover.thumbnail, ImageFormat::Jpeg).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (180, 270));
+
+        // Small covers are not enlarged.
+        let small = make_cover_image(get_png(100, 150), 180).unwrap().unwrap();
+        let thumbnail = image::load_from_memory(&small.thumbnail).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (100, 150));
+    }
+
+    #[test]
+    fn placeholders_are_no_cover_and_other_files_are_refused() {
+        assert!(make_cover_image(get_png(1, 1), 180).unwrap().is_none());
+        assert!(make_cover_image(b"<html>Not found</html>".to_vec(), 180).is_err());
+        assert!(make_cover_image(vec![0; MAX_COVER_BYTES + 1], 180).is_err());
+    }
+
+    #[test]
+    fn the_thumbnail_is_served_with_a_hash_of_its_own_bytes() {
+        let (_, connection) = initialisation::open_test_database("covers_stored");
+        connection.execute("INSERT INTO books (book_id, title, author) VALUES (1, 'The Hobbit', 'J. R. R. Tolkien')", []).unwrap();
+        assert!(!has_cover(&connection, 1).unwrap());
+        let cover = make_cover_image(get_png(400, 600), 180).unwrap().unwrap();
+        save_cover(&connection, 1, Some("https://covers.example.org/1-L.jpg"), &cover).unwrap();
+
+        let (content_type, image, sha256) = get_cover(&connection, 1, false).unwrap().unwrap();
+        assert_eq!((content_type.as_str(), image.len(), sha256.as_str()), ("image/png", cover.image.len(), cover.sha256.as_str()));
+        let (content_type, image, sha256) = get_cover(&connection, 1, true).unwrap().unwrap();
+        assert_eq!(content_type, THUMBNAIL_CONTENT_TYPE);
+        assert_eq!(sha256, get_sha256(&cover.thumbnail));
+        assert_eq!(image, cover.thumbnail);
+        assert_eq!(get_cover_summary(&connection, 1).unwrap(), Some((400, 600, cover.image.len())));
+    }
+
+    #[test]
+    fn the_nearest_cover_size_is_used_when_the_preferred_one_is_missing() {
+        let book = Book {
+            cover: Some(Cover { small: Some("S".to_string()), medium: None, large: Some("L".to_string()) }),
+            ..Book::default()
+        };
+        assert_eq!(get_cover_url(&book, "small").map(String::as_str), Some("S"));
+        // Both neighbours of medium are there; the larger one wins.
+        assert_eq!(get_cover_url(&book, "medium").map(String::as_str), Some("L"));
+        assert_eq!(get_cover_url(&Book::default(), "large"), None);
+    }
+}
@@ -0,0 +1,606 @@
+use rusqlite::types::Value;
+use crate::duplicate_processing;
+use crate::sru_object::{BooleanOperator, CqlQuery, Diagnostic, Relation, SearchClause, SearchIndex};
+
+/*
+ *  CQL, the query language of SRU, parsed and turned into a condition on
+ *  the books table. Text is matched with LIKE, so case is ignored; the
+ *  masks * and ? become % and _, and ^ anchors a term to the start or
+ *  end of the field. Anything the catalogue cannot search is reported
+ *  with the diagnostic the standard has for it.
+ */
+
+// The year in a free-text publish date: at the start of ISO dates ("2003-05-01"), at the end of others ("March 1965").
+const PUBLISH_YEAR: &str = "CASE \
+    WHEN books.publish_date GLOB '[0-9][0-9][0-9][0-9]*' THEN CAST(substr(books.publish_date, 1, 4) AS INTEGER) \
+    WHEN books.publish_date GLOB '*[0-9][0-9][0-9][0-9]' THEN CAST(substr(books.publish_date, -4) AS INTEGER) \
+    END";
+
+/*
+ *  Queries come from anyone who can reach the server, and both the parser
+ *  and SQLite nest once for each bracket or term, so how deep and how
+ *  long a query may be is limited well short of either running out.
+ */
+const MAX_QUERY_DEPTH: usize = 32;
+const MAX_QUERY_TERMS: usize = 100;
+// SQLite's parser stack holds 100 places, and the deepest single search clause needs about 30.
+const MAX_CONDITION_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Slash,
+    // =, ==, <>, <, <=, > or >=
+    Symbol(String),
+    Word(String),
+    Quoted(String),
+}
+
+fn tokenise(query: &str) -> Result<Vec<Token>, Diagnostic> {
+    let mut tokens = Vec::new();
+    let mut chars = query.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '/' => tokens.push(Token::Slash),
+            '=' | '<' | '>' => {
+                let mut symbol = c.to_string();
+                if let Some(next) = chars.next_if(|next| *next == '=' || (c == '<' && *next == '>')) {
+                    symbol.push(next);
+                }
+                tokens.push(Token::Symbol(symbol));
+            }
+            '"' => {
+                // Escapes are kept, as they matter when the term is matched.
+                let mut term = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            term.push('\\');
+                            term.extend(chars.next());
+                        }
+                        Some(c) => term.push(c),
+                        None => return Err(Diagnostic::new(10, "a quoted term is not closed")),
+                    }
+                }
+                tokens.push(Token::Quoted(term));
+            }
+            c => {
+                let mut word = c.to_string();
+                while let Some(next) = chars.next_if(|next| !next.is_whitespace() && !"()/=<>\"".contains(*next)) {
+                    word.push(next);
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    // Brackets open at the current position, and search clauses read so far.
+    depth: usize,
+    clauses: usize,
+}
+
+impl Parser {
+    fn peek(&self, offset: usize) -> Option<&Token> {
+        self.tokens.get(self.position + offset)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn parse_query(&mut self) -> Result<CqlQuery, Diagnostic> {
+        let mut query = self.parse_clause()?;
+        while let Some(Token::Word(word)) = self.peek(0) {
+            if word.eq_ignore_ascii_case("sortby") {
+                return Err(Diagnostic::new(80, ""));
+            }
+            let Some(operator) = BooleanOperator::from_name(word) else {
+                return Err(Diagnostic::new(10, &format!("expected a boolean operator, found \"{}\"", word)));
+            };
+            self.position += 1;
+            let modifiers = self.parse_modifiers()?;
+            let right = self.parse_clause()?;
+            query = CqlQuery::Boolean { operator, modifiers, left: Box::new(query), right: Box::new(right) };
+        }
+        Ok(query)
+    }
+
+    // A relation is a symbol, or a word such as "any" that is followed by something to search for.
+    fn is_relation_next(&self) -> bool {
+        match self.peek(0) {
+            Some(Token::Symbol(_)) => true,
+            Some(Token::Word(word)) => {
+                let name = word.to_lowercase();
+                let name = name.strip_prefix("cql.").unwrap_or(&name);
+                ["any", "all", "adj", "exact", "within", "encloses"].contains(&name)
+                    && matches!(self.peek(1), Some(Token::Word(_) | Token::Quoted(_) | Token::Slash))
+            }
+            _ => false,
+        }
+    }
+
+    fn parse_clause(&mut self) -> Result<CqlQuery, Diagnostic> {
+        match self.next() {
+            Some(Token::Open) => {
+                self.depth += 1;
+                if self.depth > MAX_QUERY_DEPTH {
+                    return Err(Diagnostic::new(10, &format!("brackets are nested more than {} deep", MAX_QUERY_DEPTH)));
+                }
+                let query = self.parse_query()?;
+                self.depth -= 1;
+                match self.next() {
+                    Some(Token::Close) => Ok(query),
+                    _ => Err(Diagnostic::new(10, "a bracket is not closed")),
+                }
+            }
+            Some(Token::Word(_) | Token::Quoted(_)) if self.clauses == MAX_QUERY_TERMS => {
+                Err(Diagnostic::new(38, &MAX_QUERY_TERMS.to_string()))
+            }
+            Some(Token::Word(index)) if self.is_relation_next() => {
+                self.clauses += 1;
+                let Some(Token::Symbol(relation) | Token::Word(relation)) = self.next() else {
+                    return Err(Diagnostic::new(10, "expected a relation"));
+                };
+                let modifiers = self.parse_modifiers()?;
+                let term = match self.next() {
+                    Some(Token::Word(term) | Token::Quoted(term)) => term,
+                    _ => return Err(Diagnostic::new(10, &format!("nothing to search {} for", index))),
+                };
+                Ok(CqlQuery::Clause(SearchClause { index: Some(index), relation: Some(relation), modifiers, term }))
+            }
+            Some(Token::Word(term) | Token::Quoted(term)) => {
+                self.clauses += 1;
+                Ok(CqlQuery::Clause(SearchClause { index: None, relation: None, modifiers: Vec::new(), term }))
+            }
+            Some(token) => Err(Diagnostic::new(10, &format!("unexpected {}", get_token_text(&token)))),
+            None => Err(Diagnostic::new(10, "the query ends too early")),
+        }
+    }
+
+    // Modifiers are "/name" or "/name=value"; only their names are kept.
+    fn parse_modifiers(&mut self) -> Result<Vec<String>, Diagnostic> {
+        let mut modifiers = Vec::new();
+        while self.peek(0) == Some(&Token::Slash) {
+            self.position += 1;
+            match self.next() {
+                Some(Token::Word(name)) => modifiers.push(name),
+                _ => return Err(Diagnostic::new(10, "a modifier has no name")),
+            }
+            if let Some(Token::Symbol(_)) = self.peek(0) {
+                self.position += 1;
+                if !matches!(self.next(), Some(Token::Word(_) | Token::Quoted(_))) {
+                    return Err(Diagnostic::new(10, "a modifier has no value"));
+                }
+            }
+        }
+        Ok(modifiers)
+    }
+}
+
+fn get_token_text(token: &Token) -> String {
+    match token {
+        Token::Open => "\"(\"".to_string(),
+        Token::Close => "\")\"".to_string(),
+        Token::Slash => "\"/\"".to_string(),
+        Token::Symbol(text) | Token::Word(text) | Token::Quoted(text) => format!("\"{}\"", text),
+    }
+}
+
+pub fn parse_query(query: &str) -> Result<CqlQuery, Diagnostic> {
+    let mut parser = Parser { tokens: tokenise(query)?, position: 0, depth: 0, clauses: 0 };
+    if parser.tokens.is_empty() {
+        return Err(Diagnostic::new(10, "the query is empty"));
+    }
+    let parsed = parser.parse_query()?;
+    match parser.peek(0) {
+        Some(token) => Err(Diagnostic::new(10, &format!("unexpected {}", get_token_text(token)))),
+        None => Ok(parsed),
+    }
+}
+
+/*
+ *  Turns a term into a LIKE pattern, escaped with a backslash. Unless the
+ *  whole field must match, the term may be anywhere in it, except where
+ *  it is anchored.
+ */
+fn get_like_pattern(term: &str, whole: bool) -> String {
+    let mut term = term;
+    let anchored_start = term.starts_with('^');
+    if anchored_start {
+        term = &term[1..];
+    }
+    // An escaped ^ is part of the term.
+    let anchored_end = term.strip_suffix('^')
+        .is_some_and(|rest| rest.chars().rev().take_while(|c| *c == '\\').count().is_multiple_of(2));
+    if anchored_end {
+        term = &term[..term.len() - 1];
+    }
+
+    let mut pattern = if whole || anchored_start { String::new() } else { "%".to_string() };
+    let mut escaped = false;
+    for c in term.chars() {
+        match c {
+            _ if escaped => {
+                if matches!(c, '%' | '_' | '\\') {
+                    pattern.push('\\');
+                }
+                pattern.push(c);
+                escaped = false;
+            }
+            '\\' => escaped = true,
+            '*' => pattern.push('%'),
+            '?' => pattern.push('_'),
+            '%' | '_' => {
+                pattern.push('\\');
+                pattern.push(c);
+            }
+            c => pattern.push(c),
+        }
+    }
+    if !whole && !anchored_end {
+        pattern.push('%');
+    }
+    pattern
+}
+
+// The condition for one term in one index; whole says whether it must match all of the field.
+fn get_term_condition(index: SearchIndex, term: &str, whole: bool, parameters: &mut Vec<Value>) -> String {
+    let fields: &[&str] = match index {
+        SearchIndex::Isbn => return duplicate_processing::get_isbn_condition(term, parameters),
+        SearchIndex::Identifier => {
+            let isbn = duplicate_processing::get_isbn_condition(term, parameters);
+            parameters.push(Value::Text(get_like_pattern(term, true)));
+            return format!(
+                "({} OR EXISTS (SELECT 1 FROM book_identifiers WHERE book_identifiers.book_id = books.book_id \
+                 AND book_identifiers.value LIKE ? ESCAPE '\\'))",
+                isbn
+            );
+        }
+        SearchIndex::AllRecords => return "1".to_string(),
+        SearchIndex::Title => &["books.title LIKE ? ESCAPE '\\'"],
+        SearchIndex::Creator => &["EXISTS (SELECT 1 FROM book_authors WHERE book_authors.book_id = books.book_id AND book_authors.name LIKE ? ESCAPE '\\')"],
+        SearchIndex::Subject => &["EXISTS (SELECT 1 FROM book_subjects WHERE book_subjects.book_id = books.book_id AND book_subjects.subject LIKE ? ESCAPE '\\')"],
+        SearchIndex::Publisher => &["books.publisher LIKE ? ESCAPE '\\'"],
+        SearchIndex::Date => &["books.publish_date LIKE ? ESCAPE '\\'"],
+        SearchIndex::ServerChoice => &[
+            "books.title LIKE ? ESCAPE '\\'",
+            "EXISTS (SELECT 1 FROM book_authors WHERE book_authors.book_id = books.book_id AND book_authors.name LIKE ? ESCAPE '\\')",
+            "EXISTS (SELECT 1 FROM book_subjects WHERE book_subjects.book_id = books.book_id AND book_subjects.subject LIKE ? ESCAPE '\\')",
+            "books.isbn LIKE ? ESCAPE '\\'",
+        ],
+    };
+    let pattern = get_like_pattern(term, whole);
+    parameters.extend(fields.iter().map(|_| Value::Text(pattern.clone())));
+    if fields.len() == 1 {
+        fields[0].to_string()
+    } else {
+        format!("({})", fields.join(" OR "))
+    }
+}
+
+fn get_clause_condition(clause: &SearchClause, parameters: &mut Vec<Value>) -> Result<String, Diagnostic> {
+    let index = match &clause.index {
+        Some(name) => SearchIndex::from_name(name).ok_or_else(|| Diagnostic::new(16, name))?,
+        None => SearchIndex::ServerChoice,
+    };
+    let relation = match &clause.relation {
+        Some(name) => Relation::from_name(name).ok_or_else(|| Diagnostic::new(19, name))?,
+        None => Relation::Phrase,
+    };
+    if let Some(modifier) = clause.modifiers.first() {
+        return Err(Diagnostic::new(20, modifier));
+    }
+
+    if relation.is_comparison() {
+        if index != SearchIndex::Date {
+            return Err(Diagnostic::new(19, clause.relation.as_deref().unwrap_or_default()));
+        }
+        let year: i64 = clause.term.trim().parse().map_err(|_| Diagnostic::new(36, &clause.term))?;
+        let operator = match relation {
+            Relation::Less => "<",
+            Relation::LessOrEqual => "<=",
+            Relation::Greater => ">",
+            _ => ">=",
+        };
+        parameters.push(Value::Integer(year));
+        return Ok(format!("({}) {} ?", PUBLISH_YEAR, operator));
+    }
+
+    let condition = match relation {
+        Relation::Phrase => get_term_condition(index, &clause.term, false, parameters),
+        Relation::Exact | Relation::NotEqual => get_term_condition(index, &clause.term, true, parameters),
+        _ => {
+            let words: Vec<String> = clause.term.split_whitespace()
+                .map(|word| get_term_condition(index, word, false, parameters))
+                .collect();
+            if words.is_empty() {
+                get_term_condition(index, "", false, parameters)
+            } else {
+                format!("({})", words.join(if relation == Relation::All { " AND " } else { " OR " }))
+            }
+        }
+    };
+    // A missing field is not equal to anything, but NOT NULL would leave the record out.
+    if relation == Relation::NotEqual {
+        return Ok(format!("NOT COALESCE({}, 0)", condition));
+    }
+    Ok(condition)
+}
+
+fn get_query_condition(query: &CqlQuery, parameters: &mut Vec<Value>) -> Result<String, Diagnostic> {
+    match query {
+        CqlQuery::Clause(clause) => get_clause_condition(clause, parameters),
+        CqlQuery::Boolean { operator, modifiers, left, right } => {
+            if let Some(modifier) = modifiers.first() {
+                return Err(Diagnostic::new(46, modifier));
+            }
+            if *operator == BooleanOperator::Prox {
+                return Err(Diagnostic::new(37, "prox"));
+            }
+            // Chains of the same operator are written out flat, since every bracket
+            // costs SQLite a level of its parser stack.
+            let left_is_or = matches!(**left, CqlQuery::Boolean { operator: BooleanOperator::Or, .. });
+            let left = get_query_condition(left, parameters)?;
+            let left = if left_is_or && *operator != BooleanOperator::Or { format!("({})", left) } else { left };
+            let right = match **right {
+                CqlQuery::Clause(_) => get_query_condition(right, parameters)?,
+                _ => format!("({})", get_query_condition(right, parameters)?),
+            };
+            Ok(match operator {
+                BooleanOperator::And => format!("{} AND {}", left, right),
+                BooleanOperator::Or => format!("{} OR {}", left, right),
+                // As with <>, records without the field are kept.
+                _ => format!("{} AND NOT COALESCE({}, 0)", left, right),
+            })
+        }
+    }
+}
+
+// How many terms the query searches for; "any" and "all" search for each of their words.
+fn count_terms(query: &CqlQuery) -> usize {
+    match query {
+        CqlQuery::Clause(clause) => match clause.relation.as_deref().and_then(Relation::from_name) {
+            Some(Relation::Any | Relation::All) => clause.term.split_whitespace().count().max(1),
+            _ => 1,
+        },
+        CqlQuery::Boolean { left, right, .. } => count_terms(left) + count_terms(right),
+    }
+}
+
+/*
+ *  Roughly how many places on SQLite's parser stack the brackets in the
+ *  condition for a query take. A bracket around the left side holds one,
+ *  and a bracketed right side holds the left side, the operator and the
+ *  bracket while it is read.
+ */
+fn get_condition_depth(query: &CqlQuery) -> usize {
+    match query {
+        CqlQuery::Clause(_) => 0,
+        CqlQuery::Boolean { operator, left, right, .. } => {
+            let left_is_or = matches!(**left, CqlQuery::Boolean { operator: BooleanOperator::Or, .. });
+            let left_depth = get_condition_depth(left) + usize::from(left_is_or && *operator != BooleanOperator::Or);
+            let right_depth = match **right {
+                CqlQuery::Clause(_) => 0,
+                _ if *operator == BooleanOperator::Not => get_condition_depth(right) + 6,
+                _ => get_condition_depth(right) + 3,
+            };
+            left_depth.max(right_depth)
+        }
+    }
+}
+
+/// The WHERE condition on books for a parsed query, with its parameters in order.
+pub fn get_sql_condition(query: &CqlQuery) -> Result<(String, Vec<Value>), Diagnostic> {
+    if count_terms(query) > MAX_QUERY_TERMS {
+        return Err(Diagnostic::new(38, &MAX_QUERY_TERMS.to_string()));
+    }
+    if get_condition_depth(query) > MAX_CONDITION_DEPTH {
+        return Err(Diagnostic::new(38, "the query is nested too deeply"));
+    }
+    let mut parameters = Vec::new();
+    let condition = get_query_condition(query, &mut parameters)?;
+    Ok((condition, parameters))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::{params_from_iter, Connection};
+    use crate::initialisation;
+
+    fn clause(index: Option<&str>, relation: Option<&str>, term: &str) -> CqlQuery {
+        CqlQuery::Clause(SearchClause {
+            index: index.map(str::to_string),
+            relation: relation.map(str::to_string),
+            modifiers: Vec::new(),
+            term: term.to_string(),
+        })
+    }
+
+    fn diagnostic_number(query: &str) -> u32 {
+        match parse_query(query).and_then(|parsed| get_sql_condition(&parsed)) {
+            Ok(_) => panic!("\"{}\" should not be accepted", query),
+            Err(diagnostic) => diagnostic.number,
+        }
+    }
+
+    fn search(connection: &Connection, query: &str) -> Vec<i32> {
+        let (condition, parameters) = get_sql_condition(&parse_query(query).unwrap()).unwrap();
+        let mut stmt = connection.prepare(&format!("SELECT book_id FROM books WHERE {} ORDER BY book_id", condition)).unwrap();
+        let rows = stmt.query_map(params_from_iter(parameters), |row| row.get(0)).unwrap();
+        rows.collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn tokenise_splits_symbols_words_and_quoted_terms() {
+        assert_eq!(
+            tokenise("dc.title==\"the \\\"hobbit\\\"\" and/x (a<>b)").unwrap(),
+            vec![
+                Token::Word("dc.title".to_string()),
+                Token::Symbol("==".to_string()),
+                Token::Quoted("the \\\"hobbit\\\"".to_string()),
+                Token::Word("and".to_string()),
+                Token::Slash,
+                Token::Word("x".to_string()),
+                Token::Open,
+                Token::Word("a".to_string()),
+                Token::Symbol("<>".to_string()),
+                Token::Word("b".to_string()),
+                Token::Close,
+            ]
+        );
+        assert_eq!(tokenise("\"open").unwrap_err().number, 10);
+    }
+
+    #[test]
+    fn parse_query_groups_booleans_to_the_left() {
+        let expected = CqlQuery::Boolean {
+            operator: BooleanOperator::And,
+            modifiers: Vec::new(),
+            left: Box::new(CqlQuery::Boolean {
+                operator: BooleanOperator::Or,
+                modifiers: Vec::new(),
+                left: Box::new(clause(None, None, "hobbit")),
+                right: Box::new(clause(Some("dc.creator"), Some("="), "tolkien")),
+            }),
+            right: Box::new(clause(Some("dc.date"), Some(">"), "1950")),
+        };
+        assert_eq!(parse_query("hobbit or dc.creator = tolkien and dc.date > 1950").unwrap(), expected);
+        assert_eq!(parse_query("(dc.title any \"ring hobbit\")").unwrap(), clause(Some("dc.title"), Some("any"), "ring hobbit"));
+        // "any" is only a relation when something to search for follows it.
+        assert_eq!(parse_query("any").unwrap(), clause(None, None, "any"));
+    }
+
+    #[test]
+    fn unsupported_queries_give_the_standard_diagnostics() {
+        assert_eq!(diagnostic_number(""), 10);
+        assert_eq!(diagnostic_number("(hobbit"), 10);
+        assert_eq!(diagnostic_number("hobbit tolkien"), 10);
+        assert_eq!(diagnostic_number("dc.title ="), 10);
+        assert_eq!(diagnostic_number("hobbit sortby dc.title"), 80);
+        assert_eq!(diagnostic_number("dc.colour = red"), 16);
+        assert_eq!(diagnostic_number("dc.title within hobbit"), 19);
+        assert_eq!(diagnostic_number("dc.title > hobbit"), 19);
+        assert_eq!(diagnostic_number("dc.title =/stem hobbit"), 20);
+        assert_eq!(diagnostic_number("dc.date > soon"), 36);
+        assert_eq!(diagnostic_number("hobbit prox tolkien"), 37);
+        assert_eq!(diagnostic_number("hobbit and/distance=1 tolkien"), 46);
+    }
+
+    #[test]
+    fn deeply_nested_queries_are_refused_before_the_stack_runs_out() {
+        let nested = |depth: usize| format!("{}hobbit{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(parse_query(&nested(MAX_QUERY_DEPTH)).is_ok());
+        assert_eq!(parse_query(&nested(MAX_QUERY_DEPTH + 1)).unwrap_err().number, 10);
+        assert_eq!(parse_query(&nested(100_000)).unwrap_err().number, 10);
+    }
+
+    #[test]
+    fn queries_with_too_many_terms_are_refused() {
+        let chain = |terms: usize| vec!["hobbit"; terms].join(" or ");
+        assert!(get_sql_condition(&parse_query(&chain(MAX_QUERY_TERMS)).unwrap()).is_ok());
+        assert_eq!(diagnostic_number(&chain(MAX_QUERY_TERMS + 1)), 38);
+        assert_eq!(diagnostic_number(&chain(5_000)), 38);
+
+        let words = |count: usize| format!("dc.title any \"{}\"", vec!["ring"; count].join(" "));
+        assert!(get_sql_condition(&parse_query(&words(MAX_QUERY_TERMS)).unwrap()).is_ok());
+        assert_eq!(diagnostic_number(&words(MAX_QUERY_TERMS + 1)), 38);
+        assert_eq!(diagnostic_number(&format!("{} and {}", words(60), words(60))), 38);
+    }
+
+    #[test]
+    fn the_longest_query_allowed_runs_in_sqlite() {
+        let (_, connection) = initialisation::open_test_database("cql_longest");
+        let runs = |query: &str| match get_sql_condition(&parse_query(query).expect(query)) {
+            Ok((condition, _)) => {
+                connection.prepare(&format!("SELECT book_id FROM books WHERE {}", condition)).unwrap();
+                true
+            }
+            Err(diagnostic) => {
+                assert_eq!(diagnostic.number, 38);
+                false
+            }
+        };
+        // Each level brackets the right side, and the innermost brackets hold a chain
+        // that switches operator at every term, which brackets its left side each time.
+        for levels in 0..=MAX_QUERY_DEPTH {
+            for operator in ["and", "or", "not"] {
+                let inner = MAX_QUERY_TERMS - levels;
+                let chain = (1..inner).map(|i| if i % 2 == 0 { "hobbit or" } else { "hobbit and" }).collect::<Vec<_>>().join(" ");
+                let query = format!(
+                    "{}{} hobbit{}",
+                    format!("hobbit {} (", operator).repeat(levels),
+                    chain,
+                    ")".repeat(levels)
+                );
+                runs(&query);
+            }
+        }
+        assert!(runs(&vec!["hobbit"; MAX_QUERY_TERMS].join(" not ")));
+        let alternating = (1..MAX_QUERY_TERMS).map(|i| if i % 2 == 0 { "hobbit or" } else { "hobbit not" }).collect::<Vec<_>>();
+        assert!(runs(&format!("{} hobbit", alternating.join(" "))));
+        assert!(runs(&format!("{}hobbit{}", "hobbit and (hobbit or (".repeat(8), "))".repeat(8))));
+        assert!(!runs(&format!("{}hobbit{}", "hobbit and (".repeat(MAX_QUERY_DEPTH), ")".repeat(MAX_QUERY_DEPTH))));
+    }
+
+    #[test]
+    fn like_patterns_translate_masks_anchors_and_escapes() {
+        assert_eq!(get_like_pattern("hobbit", false), "%hobbit%");
+        assert_eq!(get_like_pattern("hobbit", true), "hobbit");
+        assert_eq!(get_like_pattern("hob*t?", false), "%hob%t_%");
+        assert_eq!(get_like_pattern("^the", false), "the%");
+        assert_eq!(get_like_pattern("rings^", false), "%rings");
+        assert_eq!(get_like_pattern("100%_\\*", false), "%100\\%\\_*%");
+        assert_eq!(get_like_pattern("caret\\^", false), "%caret^%");
+    }
+
+    #[test]
+    fn sql_conditions_bind_terms_as_parameters() {
+        let (condition, parameters) = get_sql_condition(&parse_query("dc.title = \"it's\" not dc.date < 1950").unwrap()).unwrap();
+        assert_eq!(condition, format!("books.title LIKE ? ESCAPE '\\' AND NOT COALESCE(({}) < ?, 0)", PUBLISH_YEAR));
+        assert_eq!(parameters, vec![Value::Text("%it's%".to_string()), Value::Integer(1950)]);
+    }
+
+    #[test]
+    fn sql_conditions_find_the_right_books() {
+        let (_, connection) = initialisation::open_test_database("cql_search");
+        connection.execute_batch(
+            "INSERT INTO books (book_id, title, author, isbn, publisher, publish_date) VALUES
+                (1, 'The Hobbit', 'J. R. R. Tolkien', '978-0-306-40615-7', 'Allen & Unwin', '1937'),
+                (2, 'The Lord of the Rings', 'J. R. R. Tolkien', NULL, NULL, 'March 1954'),
+                (3, '100% Pure', 'Ann Other', '9791034304560', 'Penguin', '2003-05-01');
+             INSERT INTO book_authors (book_id, position, name) VALUES (1, 0, 'J. R. R. Tolkien'), (2, 0, 'J. R. R. Tolkien'), (3, 0, 'Ann Other');
+             INSERT INTO book_subjects (book_id, subject) VALUES (1, 'Fantasy'), (2, 'Fantasy'), (3, 'Cookery');
+             INSERT INTO book_identifiers (book_id, scheme, value) VALUES (2, 'oclc', '1234567');",
+        ).unwrap();
+
+        assert_eq!(search(&connection, "tolkien"), vec![1, 2]);
+        assert_eq!(search(&connection, "dc.title = \"lord of\""), vec![2]);
+        assert_eq!(search(&connection, "dc.title == \"the hobbit\""), vec![1]);
+        assert_eq!(search(&connection, "dc.title == hobbit"), Vec::<i32>::new());
+        assert_eq!(search(&connection, "dc.title any \"hobbit pure\""), vec![1, 3]);
+        assert_eq!(search(&connection, "dc.title all \"the rings\""), vec![2]);
+        assert_eq!(search(&connection, "dc.title = ^the"), vec![1, 2]);
+        assert_eq!(search(&connection, "dc.title = \"100\\%\""), vec![3]);
+        assert_eq!(search(&connection, "dc.publisher <> penguin"), vec![1, 2]);
+        assert_eq!(search(&connection, "dc.date >= 1954"), vec![2, 3]);
+        assert_eq!(search(&connection, "dc.date < 1950"), vec![1]);
+        assert_eq!(search(&connection, "bath.isbn = 0306406152"), vec![1]);
+        assert_eq!(search(&connection, "dc.identifier = 1234567"), vec![2]);
+        assert_eq!(search(&connection, "dc.identifier = 979-10-343-0456-0"), vec![3]);
+        assert_eq!(search(&connection, "fantasy not dc.date > 1950"), vec![1]);
+        assert_eq!(search(&connection, "cql.allRecords = 1 not cookery"), vec![1, 2]);
+    }
+}
@@ -0,0 +1,116 @@
+use serde::Serialize;
+
+// A catalogue record as shown when looking for duplicates.
+#[derive(Debug, Clone)]
+pub struct BookSummary {
+    pub book_id: i32,
+    pub title: String,
+    pub author: String,
+    pub isbn: Option<String>,
+    pub publish_date: Option<String>,
+    pub work_id: Option<i64>,
+    pub copies: i64,
+    // Users with the book in their collection.
+    pub collections: i64,
+}
+
+impl BookSummary {
+    pub fn print_summary(&self) {
+        println!(
+            "  Book ID {}: {} by {}, ISBN {}, published {}, {} copies, in {} collections",
+            self.book_id,
+            self.title,
+            self.author,
+            self.isbn.as_deref().unwrap_or("none"),
+            self.publish_date.as_deref().unwrap_or("unknown"),
+            self.copies,
+            self.collections
+        );
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchReason {
+    SameIsbn,
+    SameWork,
+    // Percentages, 0 to 100.
+    SimilarTitle(u8),
+    SimilarAuthor(u8),
+}
+
+impl MatchReason {
+    pub fn get_description(&self) -> String {
+        match self {
+            MatchReason::SameIsbn => "same ISBN".to_string(),
+            MatchReason::SameWork => "same work".to_string(),
+            MatchReason::SimilarTitle(100) => "same title".to_string(),
+            MatchReason::SimilarTitle(percent) => format!("titles {}% alike", percent),
+            MatchReason::SimilarAuthor(100) => "same author".to_string(),
+            MatchReason::SimilarAuthor(percent) => format!("authors {}% alike", percent),
+        }
+    }
+}
+
+/*
+ *  How sure the finder is that two records are the same book. The same
+ *  ISBN is all but certain; matching titles of two records that each
+ *  have a different ISBN are most likely separate editions of one work.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Confidence {
+    High,
+    Medium,
+    Low,
+}
+
+impl Confidence {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Confidence::High => "high",
+            Confidence::Medium => "medium",
+            Confidence::Low => "low",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DuplicateCandidate {
+    pub first: BookSummary,
+    pub second: BookSummary,
+    pub reasons: Vec<MatchReason>,
+    pub confidence: Confidence,
+}
+
+impl DuplicateCandidate {
+    pub fn print_candidate(&self, number: usize) {
+        let reasons: Vec<String> = self.reasons.iter().map(MatchReason::get_description).collect();
+        println!("{}. {} confidence: {}", number, self.confidence.get_name(), reasons.join(", "));
+        self.first.print_summary();
+        self.second.print_summary();
+    }
+}
+
+// What a merge moved from the duplicate to the surviving record.
+#[derive(Debug, Serialize)]
+pub struct MergeCounts {
+    pub copies: usize,
+    pub collections: usize,
+    // Users who had both records; their entries were combined.
+    pub collections_combined: usize,
+    pub reading_sessions: usize,
+    pub identifiers: usize,
+    pub fields_filled: Vec<String>,
+}
+
+// Spellings of one author's name, with the number of books under each, most used first.
+#[derive(Debug, Clone)]
+pub struct AuthorVariants {
+    pub names: Vec<(String, i64)>,
+}
+
+impl AuthorVariants {
+    pub fn print_variants(&self, number: usize) {
+        let names: Vec<String> = self.names.iter().map(|(name, books)| format!("\"{}\" ({} books)", name, books)).collect();
+        println!("{}. {}", number, names.join(", "));
+    }
+}
@@ -0,0 +1,697 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use rusqlite::{params, Connection};
+use rusqlite::types::Value;
+use serde_json::json;
+use crate::audit_management;
+use crate::book_processing::get_audit_target;
+use crate::catalogue_processing::{self, FieldChange};
+use crate::configuration::Config;
+use crate::duplicate_object::{AuthorVariants, BookSummary, Confidence, DuplicateCandidate, MatchReason, MergeCounts};
+use crate::provenance_management;
+use crate::user_object::User;
+use crate::utilities::{self, clear_screen, get_optional_input, get_yes_or_no};
+
+/*
+ *  Different ISBN formats, lookups repeated after a record was edited and
+ *  manual entry all leave records for the same book in the catalogue. The
+ *  finder compares ISBNs as ISBN-13, records linked to the same work and
+ *  the likeness of titles and authors once punctuation, case and spacing
+ *  are set aside. It only suggests; nothing is merged without asking.
+ */
+const SIMILARITY_THRESHOLD: f64 = 0.8;
+
+// Fields of the surviving record that are taken from the duplicate when the survivor has no value.
+const MERGE_FILLED_FIELDS: [&str; 6] = ["authors", "publishers", "publish_date", "number_of_pages", "subjects", "isbn"];
+
+/// Returns the ISBN as ISBN-13, or None if it is not a valid ISBN-10 or ISBN-13.
+pub fn normalise_isbn(isbn: &str) -> Option<String> {
+    let cleaned: String = isbn.chars()
+        .filter(|c| c.is_ascii_digit() || *c == 'X' || *c == 'x')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    let digits: Vec<u32> = cleaned.chars().map(|c| c.to_digit(10).unwrap_or(10)).collect();
+    match digits.len() {
+        10 => {
+            // Only the check digit may be an X.
+            if digits[..9].contains(&10) {
+                return None;
+            }
+            let sum: u32 = digits.iter().enumerate().map(|(i, digit)| digit * (10 - i as u32)).sum();
+            if !sum.is_multiple_of(11) {
+                return None;
+            }
+            let mut isbn13: Vec<u32> = vec![9, 7, 8];
+            isbn13.extend_from_slice(&digits[..9]);
+            let sum: u32 = isbn13.iter().enumerate().map(|(i, digit)| if i % 2 == 0 { *digit } else { digit * 3 }).sum();
+            isbn13.push((10 - sum % 10) % 10);
+            Some(isbn13.iter().map(|digit| digit.to_string()).collect())
+        }
+        13 if !digits.contains(&10) => {
+            let sum: u32 = digits.iter().enumerate().map(|(i, digit)| if i % 2 == 0 { *digit } else { digit * 3 }).sum();
+            if sum.is_multiple_of(10) { Some(cleaned) } else { None }
+        }
+        _ => None,
+    }
+}
+
+// The stored ISBN with hyphens and spaces taken out.
+const CLEANED_ISBN: &str = "REPLACE(REPLACE(UPPER(books.isbn), '-', ''), ' ', '')";
+
+// The forms an ISBN may be stored in: ISBN-13 and, for 978 numbers, ISBN-10. Anything else is taken as it is.
+fn get_isbn_forms(term: &str) -> Vec<String> {
+    let cleaned: String = term.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    let Some(isbn13) = normalise_isbn(&cleaned) else {
+        return vec![cleaned];
+    };
+    let mut forms = vec![isbn13.clone()];
+    if let Some(body) = isbn13.strip_prefix("978") {
+        let body = &body[..9];
+        let sum: u32 = body.chars().enumerate().map(|(i, c)| c.to_digit(10).unwrap_or(0) * (10 - i as u32)).sum();
+        let check = match (11 - sum % 11) % 11 {
+            10 => 'X',
+            digit => char::from_digit(digit, 10).unwrap_or('0'),
+        };
+        forms.push(format!("{}{}", body, check));
+    }
+    forms
+}
+
+/// The condition that books.isbn is the ISBN in one of its forms, however it was hyphenated or spaced.
+pub fn get_isbn_condition(term: &str, parameters: &mut Vec<Value>) -> String {
+    let forms = get_isbn_forms(term);
+    let placeholders = vec!["?"; forms.len()].join(", ");
+    parameters.extend(forms.into_iter().map(Value::Text));
+    format!("{} IN ({})", CLEANED_ISBN, placeholders)
+}
+
+/*
+ *  Titles are compared in lower case, without punctuation, anything in
+ *  brackets ("(Penguin Classics)") or a leading article, so "The
+ *  Hobbit" and "Hobbit, The" come out alike.
+ */
+pub fn normalise_title(title: &str) -> String {
+    let mut depth = 0;
+    let mut cleaned = String::new();
+    for c in title.chars() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth = (depth - 1).max(0),
+            _ if depth > 0 => {}
+            c if c.is_alphanumeric() => cleaned.extend(c.to_lowercase()),
+            _ => cleaned.push(' '),
+        }
+    }
+    let mut words: Vec<&str> = cleaned.split_whitespace().collect();
+    if words.len() > 1 && matches!(words[0], "the" | "a" | "an") {
+        words.remove(0);
+    }
+    if words.len() > 1 && matches!(words[words.len() - 1], "the" | "a" | "an") {
+        words.pop();
+    }
+    words.join(" ")
+}
+
+// "J.R.R. Tolkien", "J. R. R. Tolkien" and "Tolkien, J. R. R." all become "jrrtolkien".
+pub fn normalise_name(name: &str) -> String {
+    let name = match name.split_once(',') {
+        Some((last, first)) if !first.trim().is_empty() && !first.contains(',') => format!("{} {}", first, last),
+        _ => name.to_string(),
+    };
+    name.chars().filter(|c| c.is_alphanumeric()).flat_map(char::to_lowercase).collect()
+}
+
+/// How alike two strings are, from 0 to 1, by the pairs of letters they share.
+pub fn get_similarity(first: &str, second: &str) -> f64 {
+    if first == second {
+        return 1.0;
+    }
+    let get_pairs = |text: &str| {
+        let chars: Vec<char> = text.chars().collect();
+        let mut pairs: Vec<(char, char)> = chars.windows(2).map(|pair| (pair[0], pair[1])).collect();
+        pairs.sort_unstable();
+        pairs
+    };
+    let (first, second) = (get_pairs(first), get_pairs(second));
+    if first.is_empty() || second.is_empty() {
+        return 0.0;
+    }
+    let (mut i, mut j, mut shared) = (0, 0, 0);
+    while i < first.len() && j < second.len() {
+        match first[i].cmp(&second[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                shared += 1;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    2.0 * shared as f64 / (first.len() + second.len()) as f64
+}
+
+fn get_book_summaries(connection: &Connection, book_id: Option<i32>) -> Result<Vec<BookSummary>, rusqlite::Error> {
+    let mut stmt = connection.prepare(
+        "SELECT book_id, title, author, isbn, publish_date, work_id,
+                (SELECT COUNT(*) FROM holdings WHERE holdings.book_id = books.book_id),
+                (SELECT COUNT(*) FROM libraries WHERE libraries.book_id = books.book_id)
+         FROM books
+         WHERE ?1 IS NULL OR book_id = ?1
+         ORDER BY book_id",
+    )?;
+    let books = stmt.query_map(params![book_id], |row| {
+        Ok(BookSummary {
+            book_id: row.get(0)?,
+            title: row.get(1)?,
+            author: row.get(2)?,
+            isbn: row.get(3)?,
+            publish_date: row.get(4)?,
+            work_id: row.get(5)?,
+            copies: row.get(6)?,
+            collections: row.get(7)?,
+        })
+    })?;
+    books.collect()
+}
+
+pub fn get_book_summary(connection: &Connection, book_id: i32) -> Result<Option<BookSummary>, rusqlite::Error> {
+    Ok(get_book_summaries(connection, Some(book_id))?.into_iter().next())
+}
+
+// A record's ISBN, title and author as they are compared.
+struct MatchKeys {
+    isbn: Option<String>,
+    title: String,
+    author: String,
+}
+
+fn compare_books(first: (&BookSummary, &MatchKeys), second: (&BookSummary, &MatchKeys)) -> Option<DuplicateCandidate> {
+    let (first, first_keys) = first;
+    let (second, second_keys) = second;
+    let same_isbn = first_keys.isbn.is_some() && first_keys.isbn == second_keys.isbn;
+    let same_work = first.work_id.is_some() && first.work_id == second.work_id;
+    let title_similarity = get_similarity(&first_keys.title, &second_keys.title);
+    let author_similarity = get_similarity(&first_keys.author, &second_keys.author);
+    let similar_title = title_similarity >= SIMILARITY_THRESHOLD;
+    let similar_author = author_similarity >= SIMILARITY_THRESHOLD;
+
+    // Editions of one work share a work but not a title, so the work alone is not enough.
+    if !same_isbn && !(similar_title && (same_work || similar_author)) {
+        return None;
+    }
+
+    let mut reasons = Vec::new();
+    if same_isbn {
+        reasons.push(MatchReason::SameIsbn);
+    }
+    if same_work {
+        reasons.push(MatchReason::SameWork);
+    }
+    if similar_title {
+        reasons.push(MatchReason::SimilarTitle((title_similarity * 100.0).round() as u8));
+    }
+    if similar_author {
+        reasons.push(MatchReason::SimilarAuthor((author_similarity * 100.0).round() as u8));
+    }
+    let confidence = if same_isbn {
+        Confidence::High
+    } else if first_keys.isbn.is_some() && second_keys.isbn.is_some() {
+        Confidence::Low
+    } else {
+        Confidence::Medium
+    };
+    Some(DuplicateCandidate { first: first.clone(), second: second.clone(), reasons, confidence })
+}
+
+/*
+ *  Comparing every record with every other would be slow on a large
+ *  catalogue, so only records that share an ISBN, a work or the first
+ *  word of their title are compared. Most likely duplicates come first.
+ */
+pub fn find_duplicate_books(connection: &Connection) -> Result<Vec<DuplicateCandidate>, rusqlite::Error> {
+    let books = get_book_summaries(connection, None)?;
+    let keys: Vec<MatchKeys> = books.iter()
+        .map(|book| MatchKeys {
+            isbn: book.isbn.as_deref().and_then(normalise_isbn),
+            title: normalise_title(&book.title),
+            author: normalise_name(&book.author),
+        })
+        .collect();
+
+    let mut groups: HashMap<String, Vec<usize>> = HashMap::new();
+    for (index, (book, book_keys)) in books.iter().zip(&keys).enumerate() {
+        if let Some(isbn) = &book_keys.isbn {
+            groups.entry(format!("isbn:{}", isbn)).or_default().push(index);
+        }
+        if let Some(work_id) = book.work_id {
+            groups.entry(format!("work:{}", work_id)).or_default().push(index);
+        }
+        if let Some(word) = book_keys.title.split_whitespace().next() {
+            groups.entry(format!("title:{}", word)).or_default().push(index);
+        }
+    }
+    let mut pairs = BTreeSet::new();
+    for members in groups.values() {
+        for (position, first) in members.iter().enumerate() {
+            for second in &members[position + 1..] {
+                pairs.insert((*first, *second));
+            }
+        }
+    }
+
+    let mut candidates: Vec<DuplicateCandidate> = pairs.into_iter()
+        .filter_map(|(first, second)| compare_books((&books[first], &keys[first]), (&books[second], &keys[second])))
+        .collect();
+    candidates.sort_by_key(|candidate| (candidate.confidence, candidate.first.book_id, candidate.second.book_id));
+    Ok(candidates)
+}
+
+/*
+ *  Merges the duplicate into the surviving record and deletes it. Every
+ *  reference to the duplicate is moved: copies (and with them their
+ *  loans, which are recorded on the copy), holds, collections with their
+ *  reading sessions, shelves and tags, series, identifiers of schemes
+ *  the survivor lacks, the cover and the edit history. A user who had
+ *  both records keeps one entry, with the survivor's status, rating and
+ *  notes where it has them. The survivor keeps its own fields; those it
+ *  lacks are taken from the duplicate, keeping their source. The caller
+ *  checks that the two records exist and are not the same record.
+ */
+pub fn merge_books(connection: &mut Connection, user_id: i32, survivor_id: i32, duplicate_id: i32) -> Result<MergeCounts, rusqlite::Error> {
+    let transaction = connection.transaction()?;
+    let survivor = catalogue_processing::load_catalogue_record(&transaction, survivor_id)?;
+    let duplicate = catalogue_processing::load_catalogue_record(&transaction, duplicate_id)?;
+
+    let mut filled = Vec::new();
+    for field in MERGE_FILLED_FIELDS {
+        if catalogue_processing::get_field_value(&survivor, field).is_some() {
+            continue;
+        }
+        if let Some(value) = catalogue_processing::get_field_value(&duplicate, field) {
+            let source = provenance_management::get_field_source(&transaction, duplicate_id, field)?;
+            filled.push((FieldChange { field, old_value: None, new_value: Some(value) }, source));
+        }
+    }
+
+    let collections_combined = transaction.execute(
+        "UPDATE libraries SET
+            reading_status = COALESCE(reading_status, (SELECT reading_status FROM libraries AS duplicate WHERE duplicate.user_id = libraries.user_id AND duplicate.book_id = ?2)),
+            rating = COALESCE(rating, (SELECT rating FROM libraries AS duplicate WHERE duplicate.user_id = libraries.user_id AND duplicate.book_id = ?2)),
+            note = COALESCE(note, (SELECT note FROM libraries AS duplicate WHERE duplicate.user_id = libraries.user_id AND duplicate.book_id = ?2)),
+            review = COALESCE(review, (SELECT review FROM libraries AS duplicate WHERE duplicate.user_id = libraries.user_id AND duplicate.book_id = ?2))
+         WHERE book_id = ?1 AND user_id IN (SELECT user_id FROM libraries WHERE book_id = ?2)",
+        params![survivor_id, duplicate_id],
+    )?;
+    let reading_sessions = transaction.execute(
+        "UPDATE reading_sessions SET book_id = ?1 WHERE book_id = ?2",
+        params![survivor_id, duplicate_id],
+    )?;
+    transaction.execute(
+        "DELETE FROM libraries WHERE book_id = ?2 AND user_id IN (SELECT user_id FROM libraries WHERE book_id = ?1)",
+        params![survivor_id, duplicate_id],
+    )?;
+    let collections = transaction.execute("UPDATE libraries SET book_id = ?1 WHERE book_id = ?2", params![survivor_id, duplicate_id])?;
+    let copies = transaction.execute("UPDATE holdings SET book_id = ?1 WHERE book_id = ?2", params![survivor_id, duplicate_id])?;
+    transaction.execute("UPDATE holds SET book_id = ?1 WHERE book_id = ?2", params![survivor_id, duplicate_id])?;
+    // Identifiers of a scheme the survivor already has are the duplicate's own, e.g. its accession number.
+    let identifiers = transaction.execute(
+        "UPDATE book_identifiers SET book_id = ?1
+         WHERE book_id = ?2 AND scheme NOT IN (SELECT scheme FROM book_identifiers WHERE book_id = ?1)",
+        params![survivor_id, duplicate_id],
+    )?;
+    transaction.execute(
+        "INSERT OR IGNORE INTO book_labels (label_id, book_id) SELECT label_id, ?1 FROM book_labels WHERE book_id = ?2",
+        params![survivor_id, duplicate_id],
+    )?;
+    transaction.execute(
+        "INSERT OR IGNORE INTO book_series (book_id, series_id, position) SELECT ?1, series_id, position FROM book_series WHERE book_id = ?2",
+        params![survivor_id, duplicate_id],
+    )?;
+    transaction.execute(
+        "UPDATE book_covers SET book_id = ?1 WHERE book_id = ?2 AND NOT EXISTS (SELECT 1 FROM book_covers WHERE book_id = ?1)",
+        params![survivor_id, duplicate_id],
+    )?;
+    transaction.execute("UPDATE book_edits SET book_id = ?1 WHERE book_id = ?2", params![survivor_id, duplicate_id])?;
+    transaction.execute(
+        "UPDATE books SET work_id = COALESCE(work_id, (SELECT work_id FROM books WHERE book_id = ?2)) WHERE book_id = ?1",
+        params![survivor_id, duplicate_id],
+    )?;
+    for statement in [
+        "DELETE FROM book_identifiers WHERE book_id = ?1",
+        "DELETE FROM book_authors WHERE book_id = ?1",
+        "DELETE FROM book_subjects WHERE book_id = ?1",
+        "DELETE FROM book_labels WHERE book_id = ?1",
+        "DELETE FROM book_series WHERE book_id = ?1",
+        "DELETE FROM book_covers WHERE book_id = ?1",
+        "DELETE FROM book_field_sources WHERE book_id = ?1",
+        "DELETE FROM books WHERE book_id = ?1",
+    ] {
+        transaction.execute(statement, params![duplicate_id])?;
+    }
+
+    let mut counts = MergeCounts { copies, collections, collections_combined, reading_sessions, identifiers, fields_filled: Vec::new() };
+    // The duplicate is gone by now, so its ISBN is free for the survivor.
+    for (change, source) in &filled {
+        catalogue_processing::apply_record_changes(&transaction, survivor_id, std::slice::from_ref(change), source, Some(user_id))?;
+        counts.fields_filled.push(change.field.to_string());
+    }
+
+    let survivor_isbn = catalogue_processing::get_field_value(&survivor, "isbn")
+        .or_else(|| catalogue_processing::get_field_value(&duplicate, "isbn"));
+    audit_management::record_event(
+        &transaction,
+        Some(user_id),
+        audit_management::BOOKS_MERGED,
+        &get_audit_target(survivor_isbn.as_deref(), survivor_id),
+        json!({
+            "survivor_id": survivor_id,
+            "merged": {
+                "book_id": duplicate_id,
+                "title": duplicate.title,
+                "authors": catalogue_processing::get_field_value(&duplicate, "authors"),
+                "isbn": catalogue_processing::get_field_value(&duplicate, "isbn"),
+                "publish_date": catalogue_processing::get_field_value(&duplicate, "publish_date"),
+            },
+            "moved": &counts,
+        }),
+    )?;
+    transaction.commit()?;
+    Ok(counts)
+}
+
+/*
+ *  Groups the spellings of author names that differ only in punctuation,
+ *  case, spacing or "Surname, Forenames" order, e.g. "J.R.R. Tolkien"
+ *  and "J. R. R. Tolkien". Names that appear in one spelling only are
+ *  left out.
+ */
+pub fn find_author_variants(connection: &Connection) -> Result<Vec<AuthorVariants>, rusqlite::Error> {
+    let mut stmt = connection.prepare("SELECT name, COUNT(DISTINCT book_id) FROM book_authors GROUP BY name")?;
+    let names = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)))?;
+
+    let mut groups: HashMap<String, Vec<(String, i64)>> = HashMap::new();
+    for (name, books) in names.collect::<Result<Vec<_>, _>>()? {
+        let key = normalise_name(&name);
+        if !key.is_empty() {
+            groups.entry(key).or_default().push((name, books));
+        }
+    }
+    let mut variants: Vec<AuthorVariants> = groups.into_values()
+        .filter(|names| names.len() > 1)
+        .map(|mut names| {
+            names.sort_by(|first, second| second.1.cmp(&first.1).then_with(|| first.0.cmp(&second.0)));
+            AuthorVariants { names }
+        })
+        .collect();
+    variants.sort_by(|first, second| first.names[0].0.to_lowercase().cmp(&second.names[0].0.to_lowercase()));
+    Ok(variants)
+}
+
+/*
+ *  Renames every variant to the chosen spelling. Each record changed is
+ *  recorded in its edit history as a manual change, so a later refresh
+ *  from Open Library does not bring the old spelling back. Returns the
+ *  IDs of the books changed.
+ */
+pub fn merge_author_names(connection: &mut Connection, user_id: i32, variants: &[String], name: &str) -> Result<Vec<i32>, rusqlite::Error> {
+    let transaction = connection.transaction()?;
+    let mut book_ids = Vec::new();
+    for variant in variants.iter().filter(|variant| *variant != name) {
+        let mut stmt = transaction.prepare("SELECT DISTINCT book_id FROM book_authors WHERE name = ?1 ORDER BY book_id")?;
+        let ids = stmt.query_map(params![variant], |row| row.get::<_, i32>(0))?;
+        for book_id in ids.collect::<Result<Vec<_>, _>>()? {
+            if !book_ids.contains(&book_id) {
+                book_ids.push(book_id);
+            }
+        }
+        transaction.execute("UPDATE works SET author = ?1 WHERE author = ?2", params![name, variant])?;
+    }
+
+    for book_id in &book_ids {
+        let mut record = catalogue_processing::load_catalogue_record(&transaction, *book_id)?;
+        let old_value = catalogue_processing::get_field_value(&record, "authors");
+        for author in record.authors.iter_mut().filter(|author| variants.contains(&author.name)) {
+            author.name = name.to_string();
+        }
+        // A book listing two spellings of the same author lists them once.
+        let mut seen = Vec::new();
+        record.authors.retain(|author| {
+            let keep = !seen.contains(&author.name);
+            seen.push(author.name.clone());
+            keep
+        });
+        let change = FieldChange { field: "authors", old_value, new_value: catalogue_processing::get_field_value(&record, "authors") };
+        catalogue_processing::apply_record_changes(&transaction, *book_id, &[change], catalogue_processing::SOURCE_MANUAL, Some(user_id))?;
+    }
+
+    audit_management::record_event(
+        &transaction,
+        Some(user_id),
+        audit_management::AUTHORS_MERGED,
+        &format!("author:{}", name),
+        json!({ "variants": variants, "book_ids": book_ids }),
+    )?;
+    transaction.commit()?;
+    Ok(book_ids)
+}
+
+pub(crate) fn manage_duplicates(database_name: &str, user: &User, config: &Config) {
+    let mut connection = match Connection::open(database_name) {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("Failed to connect to the database: {}", e);
+            return;
+        }
+    };
+    let max_rows = config.interface.max_list_rows;
+
+    loop {
+        clear_screen();
+        print_duplicates_header();
+        println!(
+            "Choose from the options below:\n\
+            \t1. Find duplicate books\n\
+            \t2. Merge two books by ID\n\
+            \t3. Find author name variants\n\
+            \t0. Back"
+        );
+        let mut choice = String::new();
+        if io::stdin().read_line(&mut choice).is_err() {
+            println!("Failed to read input. Please try again.");
+            continue;
+        }
+
+        match choice.trim() {
+            "1" => match find_duplicate_books(&connection) {
+                Ok(candidates) if candidates.is_empty() => println!("No likely duplicates were found."),
+                Ok(candidates) => {
+                    for (index, candidate) in candidates.iter().take(max_rows).enumerate() {
+                        candidate.print_candidate(index + 1);
+                    }
+                    if candidates.len() > max_rows {
+                        println!("Only the first {} of {} are shown.", max_rows, candidates.len());
+                    }
+                    if let Some(number) = get_number_from_user("Number of the pair to merge, or press Enter to go back:", candidates.len().min(max_rows)) {
+                        let candidate = &candidates[number - 1];
+                        merge_books_with_user(&mut connection, user, &candidate.first, &candidate.second);
+                    }
+                }
+                Err(e) => println!("Failed to search for duplicates: {}", e),
+            },
+            "2" => {
+                let Some(first) = get_book_from_user(&connection, "ID of the first book:") else {
+                    utilities::pause(2);
+                    continue;
+                };
+                let Some(second) = get_book_from_user(&connection, "ID of the second book:") else {
+                    utilities::pause(2);
+                    continue;
+                };
+                if first.book_id == second.book_id {
+                    println!("A book cannot be merged with itself.");
+                } else {
+                    merge_books_with_user(&mut connection, user, &first, &second);
+                }
+            }
+            "3" => match find_author_variants(&connection) {
+                Ok(variants) if variants.is_empty() => println!("No author names with more than one spelling were found."),
+                Ok(variants) => {
+                    for (index, group) in variants.iter().take(max_rows).enumerate() {
+                        group.print_variants(index + 1);
+                    }
+                    if let Some(number) = get_number_from_user("Number of the names to merge, or press Enter to go back:", variants.len().min(max_rows)) {
+                        merge_author_names_with_user(&mut connection, user, &variants[number - 1]);
+                    }
+                }
+                Err(e) => println!("Failed to search the author names: {}", e),
+            },
+            "0" => return,
+            _ => println!("Invalid choice. Please try again."),
+        }
+        utilities::pause(2);
+    }
+}
+
+// Returns None when the user presses Enter or gives a number outside 1..=max.
+fn get_number_from_user(prompt: &str, max: usize) -> Option<usize> {
+    let input = get_optional_input(prompt)?;
+    match input.parse::<usize>() {
+        Ok(number) if (1..=max).contains(&number) => Some(number),
+        _ => {
+            println!("{} is not a number between 1 and {}.", input, max);
+            None
+        }
+    }
+}
+
+fn get_book_from_user(connection: &Connection, prompt: &str) -> Option<BookSummary> {
+    let input = get_optional_input(prompt)?;
+    let Ok(book_id) = input.parse::<i32>() else {
+        println!("{} is not a book ID.", input);
+        return None;
+    };
+    match get_book_summary(connection, book_id) {
+        Ok(Some(book)) => Some(book),
+        Ok(None) => {
+            println!("No book with ID {} exists.", book_id);
+            None
+        }
+        Err(e) => {
+            println!("Failed to read book {}: {}", book_id, e);
+            None
+        }
+    }
+}
+
+fn merge_books_with_user(connection: &mut Connection, user: &User, first: &BookSummary, second: &BookSummary) {
+    // Keep the record more is attached to, so less has to move.
+    let suggested = if second.copies + second.collections > first.copies + first.collections { 2 } else { 1 };
+    println!("1.");
+    first.print_summary();
+    println!("2.");
+    second.print_summary();
+    let kept = match get_optional_input(&format!("Which record should be kept, 1 or 2? [{}]", suggested)).as_deref() {
+        None => suggested,
+        Some("1") => 1,
+        Some("2") => 2,
+        Some(input) => {
+            println!("{} is not 1 or 2.", input);
+            return;
+        }
+    };
+    let (survivor, duplicate) = if kept == 1 { (first, second) } else { (second, first) };
+
+    println!(
+        "Merge book {} into book {}? Its copies, collections, shelves, tags and identifiers move to book {}, and book {} is deleted. (y/n)",
+        duplicate.book_id, survivor.book_id, survivor.book_id, duplicate.book_id
+    );
+    if !get_yes_or_no() {
+        return;
+    }
+    match merge_books(connection, user.get_user_id(), survivor.book_id, duplicate.book_id) {
+        Ok(counts) => {
+            println!(
+                "Merged. Moved {} copies, {} collection entries ({} combined with an existing one), {} reading sessions and {} identifiers.",
+                counts.copies, counts.collections, counts.collections_combined, counts.reading_sessions, counts.identifiers
+            );
+            if !counts.fields_filled.is_empty() {
+                println!("Taken from the duplicate: {}.", counts.fields_filled.join(", "));
+            }
+        }
+        Err(e) => println!("Failed to merge the books: {}", e),
+    }
+}
+
+fn merge_author_names_with_user(connection: &mut Connection, user: &User, group: &AuthorVariants) {
+    for (index, (name, books)) in group.names.iter().enumerate() {
+        println!("\t{}. {} ({} books)", index + 1, name, books);
+    }
+    let number = match get_optional_input("Number of the spelling to keep: [1]") {
+        None => 1,
+        Some(input) => match input.parse::<usize>() {
+            Ok(number) if (1..=group.names.len()).contains(&number) => number,
+            _ => {
+                println!("{} is not a number between 1 and {}.", input, group.names.len());
+                return;
+            }
+        },
+    };
+    let name = &group.names[number - 1].0;
+    let variants: Vec<String> = group.names.iter().map(|(variant, _)| variant.clone()).collect();
+
+    println!("Rename every other spelling to \"{}\"? (y/n)", name);
+    if !get_yes_or_no() {
+        return;
+    }
+    match merge_author_names(connection, user.get_user_id(), &variants, name) {
+        Ok(book_ids) => println!("Renamed the author of {} books.", book_ids.len()),
+        Err(e) => println!("Failed to merge the author names: {}", e),
+    }
+}
+
+fn print_duplicates_header() {
+    println!("################");
+    println!("## Duplicates ##");
+    println!("################");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialisation;
+
+    #[test]
+    fn normalise_isbn_converts_isbn10_and_checks_digits() {
+        assert_eq!(normalise_isbn("0-306-40615-2"), Some("9780306406157".to_string()));
+        assert_eq!(normalise_isbn("978-0-306-40615-7"), Some("9780306406157".to_string()));
+        assert_eq!(normalise_isbn("0-8044-2957-x"), Some("9780804429573".to_string()));
+        assert_eq!(normalise_isbn("0-306-40615-3"), None);
+        assert_eq!(normalise_isbn("978-0-306-40615-8"), None);
+        assert_eq!(normalise_isbn("X-306-40615-2"), None);
+        assert_eq!(normalise_isbn("97803064061X7"), None);
+        assert_eq!(normalise_isbn("12345"), None);
+    }
+
+    #[test]
+    fn isbn_forms_include_isbn10_for_978_numbers() {
+        assert_eq!(get_isbn_forms("0-306-40615-2"), vec!["9780306406157", "0306406152"]);
+        assert_eq!(get_isbn_forms("978 0 306 40615 7"), vec!["9780306406157", "0306406152"]);
+        assert_eq!(get_isbn_forms("9791034304560"), vec!["9791034304560"]);
+        assert_eq!(get_isbn_forms("not-an-isbn"), vec!["NOTANISBN"]);
+    }
+
+    #[test]
+    fn merge_books_moves_copies_holds_and_identifiers_the_survivor_lacks() {
+        let (_, mut connection) = initialisation::open_test_database("merge_books_identifiers");
+        connection.execute_batch(
+            "INSERT INTO users (user_id, email, firstname, lastname) VALUES (1, 'admin@example.org', 'Ada', 'Admin');
+             INSERT INTO books (book_id, title, author, isbn) VALUES (1, 'The Hobbit', 'J. R. R. Tolkien', '9780306406157');
+             INSERT INTO books (book_id, title, author, isbn) VALUES (2, 'Hobbit, The', 'J.R.R. Tolkien', NULL);
+             INSERT INTO book_identifiers (book_id, scheme, value) VALUES (1, 'oclc', '111'), (2, 'oclc', '222'), (2, 'lccn', '333');
+             INSERT INTO holdings (holding_id, book_id, created_at) VALUES (10, 2, 0);
+             INSERT INTO holds (hold_id, book_id, user_id, placed_at) VALUES (20, 2, 1, 0);",
+        ).unwrap();
+
+        let counts = merge_books(&mut connection, 1, 1, 2).unwrap();
+        assert_eq!(counts.identifiers, 1);
+        assert_eq!(counts.copies, 1);
+
+        let mut stmt = connection.prepare("SELECT book_id, scheme, value FROM book_identifiers ORDER BY scheme").unwrap();
+        let identifiers: Vec<(i32, String, String)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(identifiers, vec![(1, "lccn".to_string(), "333".to_string()), (1, "oclc".to_string(), "111".to_string())]);
+        let books: i64 = connection.query_row("SELECT COUNT(*) FROM books", [], |row| row.get(0)).unwrap();
+        assert_eq!(books, 1);
+        let holding_book: i32 = connection.query_row("SELECT book_id FROM holdings WHERE holding_id = 10", [], |row| row.get(0)).unwrap();
+        assert_eq!(holding_book, 1);
+        let hold_book: i32 = connection.query_row("SELECT book_id FROM holds WHERE hold_id = 20", [], |row| row.get(0)).unwrap();
+        assert_eq!(hold_book, 1);
+    }
+}
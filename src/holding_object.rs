@@ -0,0 +1,72 @@
+/*
+ *  A holding is one physical copy of a catalogue record in `books`.
+ *  The catalogue record describes the edition and is shared by everyone;
+ *  the holding describes a particular copy and who owns it. A copy with
+ *  no owner belongs to the library itself.
+ */
+pub const CONDITIONS: [&str; 6] = ["new", "fine", "very good", "good", "fair", "poor"];
+// Statuses are stored by name; "on-loan" is set by circulation and "lost" by a stocktake.
+pub const HOLDING_STATUSES: [&str; 3] = ["available", "on-loan", "lost"];
+
+#[derive(Debug, Default, Clone)]
+pub struct Holding {
+    pub holding_id: Option<i64>,
+    pub book_id: i32,
+    pub owner_user_id: Option<i32>,
+    pub condition: Option<String>,
+    pub acquired_on: Option<String>,
+    pub price_paid_cents: Option<i64>,
+    // Free text from before locations were kept, or for personal copies.
+    pub location: Option<String>,
+    pub notes: Option<String>,
+    pub location_id: Option<i64>,
+    // e.g. "Main > Reading Room > Case 3 > Shelf 2", filled in when the holding is read.
+    pub location_path: Option<String>,
+    pub call_number: Option<String>,
+    // Issued when the copy's label is first printed.
+    pub barcode: Option<String>,
+    // One of HOLDING_STATUSES; None until the copy has been saved.
+    pub status: Option<String>,
+}
+
+impl Holding {
+    pub fn new(book_id: i32, owner_user_id: Option<i32>) -> Self {
+        Holding {
+            book_id,
+            owner_user_id,
+            ..Holding::default()
+        }
+    }
+
+    pub fn is_library_owned(&self) -> bool { self.owner_user_id.is_none() }
+
+    pub fn get_price_paid(&self) -> Option<String> {
+        self.price_paid_cents
+            .map(|cents| format!("{}.{:02}", cents / 100, cents % 100))
+    }
+
+    pub fn print_holding_info(&self) {
+        match self.holding_id {
+            Some(id) => println!("  Copy ID: {}", id),
+            None => println!("  Copy ID: Not available"),
+        }
+        println!("  Owner: {}", if self.is_library_owned() { "Library" } else { "Personal" });
+        println!("  Status: {}", self.status.as_deref().unwrap_or(HOLDING_STATUSES[0]));
+        println!("  Condition: {}", self.condition.as_deref().unwrap_or("Not recorded"));
+        println!("  Acquired On: {}", self.acquired_on.as_deref().unwrap_or("Not recorded"));
+        println!("  Price Paid: {}", self.get_price_paid().as_deref().unwrap_or("Not recorded"));
+        println!(
+            "  Location: {}",
+            self.location_path.as_deref().or(self.location.as_deref()).unwrap_or("Not recorded")
+        );
+        if let Some(call_number) = &self.call_number {
+            println!("  Call Number: {}", call_number);
+        }
+        if let Some(barcode) = &self.barcode {
+            println!("  Barcode: {}", barcode);
+        }
+        if let Some(notes) = &self.notes {
+            println!("  Notes: {}", notes);
+        }
+    }
+}
@@ -0,0 +1,475 @@
+use std::error::Error;
+use std::fs;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::Serialize;
+use crate::call_number_processing;
+use crate::cover_management;
+use crate::holding_object::{Holding, CONDITIONS};
+use crate::label_processing;
+use crate::location_processing;
+use crate::reading_object::READING_STATUSES;
+use crate::reading_processing;
+use crate::user_object::User;
+use crate::utilities::{self, clear_screen, get_optional_input, get_yes_or_no};
+
+const HOLDING_COLUMNS: &str =
+    "holding_id, book_id, owner_user_id, condition, acquired_on, price_paid_cents, location, notes, location_id, call_number, barcode, status";
+
+fn holding_from_row(row: &Row) -> Result<Holding, rusqlite::Error> {
+    Ok(Holding {
+        holding_id: row.get(0)?,
+        book_id: row.get(1)?,
+        owner_user_id: row.get(2)?,
+        condition: row.get(3)?,
+        acquired_on: row.get(4)?,
+        price_paid_cents: row.get(5)?,
+        location: row.get(6)?,
+        notes: row.get(7)?,
+        location_id: row.get(8)?,
+        location_path: None,
+        call_number: row.get(9)?,
+        barcode: row.get(10)?,
+        status: row.get(11)?,
+    })
+}
+
+// Fills in the path of each holding's location from the location tree.
+fn add_location_paths(connection: &Connection, holdings: &mut [Holding]) -> Result<(), rusqlite::Error> {
+    if holdings.iter().all(|holding| holding.location_id.is_none()) {
+        return Ok(());
+    }
+    let locations = location_processing::get_locations(connection)?;
+    for holding in holdings {
+        holding.location_path = locations.iter()
+            .find(|location| Some(location.location_id) == holding.location_id)
+            .map(|location| location.get_path());
+    }
+    Ok(())
+}
+
+pub fn create_holding(connection: &Connection, holding: &Holding) -> Result<i64, rusqlite::Error> {
+    connection.execute(
+        "INSERT INTO holdings (book_id, owner_user_id, condition, acquired_on, price_paid_cents, location, notes, created_at,
+                               location_id, call_number, call_number_sort)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        params![
+            holding.book_id,
+            holding.owner_user_id,
+            holding.condition,
+            holding.acquired_on,
+            holding.price_paid_cents,
+            holding.location,
+            holding.notes,
+            utilities::current_timestamp(),
+            holding.location_id,
+            holding.call_number,
+            holding.call_number.as_deref().map(call_number_processing::get_sort_key)
+        ],
+    )?;
+    Ok(connection.last_insert_rowid())
+}
+
+/// The copies of a book owned by this user, or by the library if owner_user_id is None.
+pub fn get_holdings_for_book(connection: &Connection, book_id: i32, owner_user_id: Option<i32>) -> Result<Vec<Holding>, rusqlite::Error> {
+    let mut stmt = connection.prepare(&format!(
+        "SELECT {} FROM holdings WHERE book_id = ?1 AND owner_user_id IS ?2 ORDER BY holding_id",
+        HOLDING_COLUMNS
+    ))?;
+    let mut holdings = stmt.query_map(params![book_id, owner_user_id], holding_from_row)?.collect::<Result<Vec<Holding>, _>>()?;
+    add_location_paths(connection, &mut holdings)?;
+    Ok(holdings)
+}
+
+pub fn get_holding(connection: &Connection, holding_id: i64) -> Result<Option<Holding>, rusqlite::Error> {
+    let holding = connection.query_row(
+        &format!("SELECT {} FROM holdings WHERE holding_id = ?1", HOLDING_COLUMNS),
+        params![holding_id],
+        holding_from_row,
+    ).optional()?;
+    let mut holdings: Vec<Holding> = holding.into_iter().collect();
+    add_location_paths(connection, &mut holdings)?;
+    Ok(holdings.pop())
+}
+
+/// How many of the user's copies of a book are out on loan.
+pub fn count_copies_on_loan(connection: &Connection, book_id: i32, owner_user_id: i32) -> Result<i64, rusqlite::Error> {
+    connection.query_row(
+        "SELECT COUNT(*) FROM loans
+         JOIN holdings ON holdings.holding_id = loans.holding_id
+         WHERE holdings.book_id = ?1 AND holdings.owner_user_id = ?2 AND loans.returned_at IS NULL",
+        params![book_id, owner_user_id],
+        |row| row.get(0),
+    )
+}
+
+/*
+ *  Foreign keys are not enforced, so whatever refers to the copies is
+ *  dealt with here as the schema says it should be: their loans go with
+ *  them, and stocktake scans and holds no longer point at them.
+ */
+pub fn delete_holdings_for_book(connection: &Connection, book_id: i32, owner_user_id: i32) -> Result<usize, rusqlite::Error> {
+    let copies = "SELECT holding_id FROM holdings WHERE book_id = ?1 AND owner_user_id = ?2";
+    connection.execute(&format!("DELETE FROM loans WHERE holding_id IN ({})", copies), params![book_id, owner_user_id])?;
+    connection.execute(
+        &format!("UPDATE stocktake_scans SET holding_id = NULL WHERE holding_id IN ({})", copies),
+        params![book_id, owner_user_id],
+    )?;
+    connection.execute(&format!("UPDATE holds SET holding_id = NULL WHERE holding_id IN ({})", copies), params![book_id, owner_user_id])?;
+    connection.execute(
+        "DELETE FROM holdings WHERE book_id = ?1 AND owner_user_id = ?2",
+        params![book_id, owner_user_id],
+    )
+}
+
+/// Accepts amounts such as 12, 12.5 or 12.50 and returns them in cents.
+pub fn parse_price(input: &str) -> Option<i64> {
+    let (whole, fraction) = match input.trim().split_once('.') {
+        Some((whole, fraction)) => (whole, fraction),
+        None => (input.trim(), ""),
+    };
+    if whole.is_empty() || fraction.len() > 2 || !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let whole: i64 = whole.parse().ok().filter(|whole| *whole >= 0)?;
+    let fraction: i64 = format!("{:0<2}", fraction).parse().ok()?;
+    whole.checked_mul(100)?.checked_add(fraction)
+}
+
+/*
+ *  Asks for the details of a copy. Every question can be skipped by
+ *  pressing enter, in which case that detail is simply not recorded.
+ */
+pub(crate) fn get_holding_details_from_user(holding: &mut Holding) {
+    holding.condition = loop {
+        match get_optional_input(&format!("Condition ({}):", CONDITIONS.join(", "))) {
+            None => break None,
+            Some(condition) => {
+                let condition = condition.to_lowercase();
+                if CONDITIONS.contains(&condition.as_str()) {
+                    break Some(condition);
+                }
+                println!("Invalid condition. Please choose one of: {}", CONDITIONS.join(", "));
+            }
+        }
+    };
+    holding.acquired_on = loop {
+        match get_optional_input("Acquired on (YYYY-MM-DD):") {
+            None => break None,
+            Some(date) if utilities::parse_date_to_timestamp(&date, false).is_some() => break Some(date),
+            Some(_) => println!("Invalid date. Please use the format YYYY-MM-DD."),
+        }
+    };
+    holding.price_paid_cents = loop {
+        match get_optional_input("Price paid (e.g. 12.50):") {
+            None => break None,
+            Some(price) => match parse_price(&price) {
+                Some(cents) => break Some(cents),
+                None => println!("Invalid price. Please enter an amount such as 12.50."),
+            },
+        }
+    };
+    holding.location = get_optional_input("Location (e.g. Living room, shelf 3):");
+    holding.notes = get_optional_input("Notes:");
+}
+
+// A book in the user's collection, as found by search_collection and exported from it.
+#[derive(Debug, Serialize)]
+pub(crate) struct CollectionEntry {
+    pub(crate) book_id: i32,
+    title: String,
+    author: String,
+    isbn: Option<String>,
+    work_id: Option<i64>,
+    work_title: Option<String>,
+    reading_status: Option<String>,
+    rating: Option<u8>,
+    times_read: u32,
+    last_started_on: Option<String>,
+    last_finished_on: Option<String>,
+    note: Option<String>,
+    review: Option<String>,
+    shelves: Option<String>,
+    tags: Option<String>,
+    // Set when the collection is exported, see export_collection.
+    cover_file: Option<String>,
+    // None for books added before the date was recorded.
+    #[serde(skip)]
+    pub(crate) added_at: Option<i64>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct CollectionFilter {
+    pub(crate) search: String,
+    // One of READING_STATUSES, or "none" for books without a status.
+    pub(crate) reading_status: Option<String>,
+    pub(crate) min_rating: Option<u8>,
+    pub(crate) shelf: Option<String>,
+    pub(crate) tag: Option<String>,
+    // Any of the book's authors, or one of its subjects, exactly as stored.
+    pub(crate) author: Option<String>,
+    pub(crate) subject: Option<String>,
+}
+
+impl CollectionEntry {
+    fn get_reading_summary(&self) -> String {
+        let status = self.reading_status.as_deref().unwrap_or("no status");
+        match self.rating {
+            Some(rating) => format!("{}, rated {}/5", status, rating),
+            None => status.to_string(),
+        }
+    }
+
+    fn print_labels(&self) {
+        if let Some(shelves) = &self.shelves {
+            println!("  Shelves: {}", shelves);
+        }
+        if let Some(tags) = &self.tags {
+            println!("  Tags: {}", tags);
+        }
+    }
+}
+
+/*
+ *  Lists the books in the user's collection whose title, author or ISBN
+ *  contains the search text, together with the user's own copies of each.
+ *  An empty search lists the whole collection. The list can be narrowed
+ *  by reading status, rating, shelf and tag, editions of the same work
+ *  can be collapsed into one entry, and the matching books can be tagged
+ *  or shelved together and exported.
+ */
+pub(crate) fn search_collection(database_name: &str, user: &User, max_rows: usize) -> bool {
+    clear_screen();
+    print_search_header();
+    let filter = CollectionFilter {
+        search: get_optional_input("Search by title, author or ISBN (leave blank to list all):")
+            .unwrap_or_default(),
+        reading_status: loop {
+            let prompt = format!("Filter by reading status ({}, none; leave blank for any):", READING_STATUSES.join(", "));
+            match get_optional_input(&prompt) {
+                None => break None,
+                Some(status) => match reading_processing::parse_status_filter(&status) {
+                    Some(status) => break Some(status),
+                    None => println!("Invalid status. Please choose one of: {}, none", READING_STATUSES.join(", ")),
+                },
+            }
+        },
+        min_rating: loop {
+            match get_optional_input("Minimum rating from 1 to 5 (leave blank for any):") {
+                None => break None,
+                Some(rating) => match rating.parse::<u8>() {
+                    Ok(rating) if (1..=5).contains(&rating) => break Some(rating),
+                    _ => println!("Invalid rating. Please enter a whole number from 1 to 5."),
+                },
+            }
+        },
+        shelf: get_optional_input("Filter by shelf (leave blank for any):"),
+        tag: get_optional_input("Filter by tag (leave blank for any):"),
+        ..CollectionFilter::default()
+    };
+
+    let mut connection = match Connection::open(database_name) {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("Failed to connect to the database: {}", e);
+            return false;
+        }
+    };
+    let mut books = match find_books_in_collection(&connection, user.get_user_id(), &filter) {
+        Ok(books) => books,
+        Err(e) => {
+            println!("Error searching your books: {}", e);
+            return false;
+        }
+    };
+    let group_by_work = books.iter().any(|book| book.work_id.is_some()) && {
+        println!("Group editions of the same work together? (y/n)");
+        get_yes_or_no()
+    };
+
+    if group_by_work {
+        print_collection_by_work(&connection, user, &books, max_rows);
+    } else {
+        for book in books.iter().take(max_rows) {
+            println!(
+                "ID: {}, Title: {}, Author: {}, ISBN: {}, Reading: {}",
+                book.book_id, book.title, book.author, book.isbn.as_deref().unwrap_or("Not available"), book.get_reading_summary()
+            );
+            book.print_labels();
+            match get_holdings_for_book(&connection, book.book_id, Some(user.get_user_id())) {
+                Ok(holdings) if holdings.is_empty() => println!("  You have no copies of this book recorded."),
+                Ok(holdings) => holdings.iter().for_each(Holding::print_holding_info),
+                Err(e) => println!("  Error retrieving your copies: {}", e),
+            }
+        }
+        if books.len() > max_rows {
+            println!("Only the first {} of {} matching books are shown.", max_rows, books.len());
+        }
+    }
+    println!("{} matching book(s).", books.len());
+    if books.is_empty() { return true; }
+
+    println!("Would you like to add all of these books to, or remove them from, a shelf or tag? (y/n)");
+    if get_yes_or_no() {
+        let book_ids: Vec<i32> = books.iter().map(|book| book.book_id).collect();
+        label_processing::label_books_from_user(&mut connection, user.get_user_id(), &book_ids);
+    }
+
+    println!("Would you like to export these books? (y/n)");
+    if !get_yes_or_no() { return true; }
+    let format = loop {
+        match get_optional_input("Export format (csv/json):").as_deref() {
+            Some("csv") => break "csv",
+            Some("json") => break "json",
+            _ => println!("Invalid format. Please enter csv or json."),
+        }
+    };
+    match export_collection(&connection, &mut books, format) {
+        Ok(file_name) => {
+            println!("Your books were exported to {}", file_name);
+            true
+        }
+        Err(e) => {
+            println!("Failed to export your books: {}", e);
+            false
+        }
+    }
+}
+
+/*
+ *  Stored covers are copied into a folder named after the export, and
+ *  each book's cover_file is the path to its cover, so the export can
+ *  be used without rLMS or a connection to Open Library.
+ */
+fn export_collection(connection: &Connection, books: &mut [CollectionEntry], format: &str) -> Result<String, Box<dyn Error>> {
+    let export_name = format!("collection_{}", utilities::current_timestamp());
+    let file_name = format!("{}.{}", export_name, format);
+    let covers_directory = format!("{}_covers", export_name);
+    for book in books.iter_mut() {
+        if let Some((content_type, image, _)) = cover_management::get_cover(connection, book.book_id, false)? {
+            fs::create_dir_all(&covers_directory)?;
+            let cover_file = format!("{}/{}.{}", covers_directory, book.book_id, cover_management::get_file_extension(&content_type));
+            fs::write(&cover_file, image)?;
+            book.cover_file = Some(cover_file);
+        }
+    }
+    match format {
+        "csv" => {
+            let mut writer = csv::Writer::from_path(&file_name)?;
+            for book in books {
+                writer.serialize(book)?;
+            }
+            writer.flush()?;
+        }
+        "json" => fs::write(&file_name, serde_json::to_string_pretty(books)?)?,
+        other => return Err(format!("Unknown export format: {}", other).into()),
+    }
+    Ok(file_name)
+}
+
+fn print_collection_by_work(connection: &Connection, user: &User, books: &[CollectionEntry], max_rows: usize) {
+    // Books without a work are a group of their own. Groups keep the order of the search.
+    let mut groups: Vec<Vec<&CollectionEntry>> = Vec::new();
+    for book in books {
+        match groups.iter_mut().find(|group| book.work_id.is_some() && group[0].work_id == book.work_id) {
+            Some(group) => group.push(book),
+            None => groups.push(vec![book]),
+        }
+    }
+
+    for group in groups.iter().take(max_rows) {
+        let first = group[0];
+        println!(
+            "Work: {}, Author: {}, Editions: {}",
+            first.work_title.as_deref().unwrap_or(&first.title),
+            first.author,
+            group.len()
+        );
+        for book in group {
+            let copies = get_holdings_for_book(connection, book.book_id, Some(user.get_user_id()))
+                .map(|holdings| holdings.len())
+                .unwrap_or(0);
+            println!(
+                "  ID: {}, Title: {}, ISBN: {}, Your copies: {}, Reading: {}",
+                book.book_id, book.title, book.isbn.as_deref().unwrap_or("Not available"), copies, book.get_reading_summary()
+            );
+            book.print_labels();
+        }
+    }
+    if groups.len() > max_rows {
+        println!("Only the first {} of {} matching works are shown.", max_rows, groups.len());
+    }
+}
+
+pub(crate) fn find_books_in_collection(connection: &Connection, user_id: i32, filter: &CollectionFilter) -> Result<Vec<CollectionEntry>, rusqlite::Error> {
+    let mut stmt = connection.prepare(
+        "SELECT books.book_id, books.title, books.author, books.isbn, books.work_id, works.title,
+                libraries.reading_status, libraries.rating, libraries.note, libraries.review,
+                (SELECT COUNT(*) FROM reading_sessions AS sessions
+                 WHERE sessions.user_id = libraries.user_id AND sessions.book_id = libraries.book_id
+                   AND sessions.outcome = 'read'),
+                (SELECT MAX(sessions.started_on) FROM reading_sessions AS sessions
+                 WHERE sessions.user_id = libraries.user_id AND sessions.book_id = libraries.book_id),
+                (SELECT MAX(sessions.finished_on) FROM reading_sessions AS sessions
+                 WHERE sessions.user_id = libraries.user_id AND sessions.book_id = libraries.book_id),
+                (SELECT group_concat(labels.name, '; ') FROM labels
+                 JOIN book_labels ON book_labels.label_id = labels.label_id
+                 WHERE labels.user_id = libraries.user_id AND labels.kind = 'shelf' AND book_labels.book_id = books.book_id),
+                (SELECT group_concat(labels.name, '; ') FROM labels
+                 JOIN book_labels ON book_labels.label_id = labels.label_id
+                 WHERE labels.user_id = libraries.user_id AND labels.kind = 'tag' AND book_labels.book_id = books.book_id),
+                libraries.added_at
+         FROM books
+         JOIN libraries ON libraries.book_id = books.book_id
+         LEFT JOIN works ON works.work_id = books.work_id
+         WHERE libraries.user_id = ?1
+           AND (books.title LIKE '%' || ?2 || '%'
+                OR books.author LIKE '%' || ?2 || '%'
+                OR books.isbn LIKE '%' || ?2 || '%')
+           AND (?3 IS NULL OR COALESCE(libraries.reading_status, 'none') = ?3)
+           AND (?4 IS NULL OR libraries.rating >= ?4)
+           AND (?5 IS NULL OR EXISTS (
+                SELECT 1 FROM labels JOIN book_labels ON book_labels.label_id = labels.label_id
+                WHERE labels.user_id = ?1 AND labels.kind = 'shelf' AND labels.name = ?5 COLLATE NOCASE
+                  AND book_labels.book_id = books.book_id))
+           AND (?6 IS NULL OR EXISTS (
+                SELECT 1 FROM labels JOIN book_labels ON book_labels.label_id = labels.label_id
+                WHERE labels.user_id = ?1 AND labels.kind = 'tag' AND labels.name = ?6 COLLATE NOCASE
+                  AND book_labels.book_id = books.book_id))
+           AND (?7 IS NULL OR books.author = ?7 COLLATE NOCASE OR EXISTS (
+                SELECT 1 FROM book_authors WHERE book_authors.book_id = books.book_id AND book_authors.name = ?7 COLLATE NOCASE))
+           AND (?8 IS NULL OR EXISTS (
+                SELECT 1 FROM book_subjects WHERE book_subjects.book_id = books.book_id AND book_subjects.subject = ?8 COLLATE NOCASE))
+         ORDER BY books.title",
+    )?;
+    let params = params![
+        user_id, filter.search, filter.reading_status, filter.min_rating, filter.shelf, filter.tag, filter.author, filter.subject
+    ];
+    let books = stmt.query_map(params, |row| {
+        Ok(CollectionEntry {
+            book_id: row.get(0)?,
+            title: row.get(1)?,
+            author: row.get(2)?,
+            isbn: row.get(3)?,
+            work_id: row.get(4)?,
+            work_title: row.get(5)?,
+            reading_status: row.get(6)?,
+            rating: row.get(7)?,
+            note: row.get(8)?,
+            review: row.get(9)?,
+            times_read: row.get(10)?,
+            last_started_on: row.get(11)?,
+            last_finished_on: row.get(12)?,
+            shelves: row.get(13)?,
+            tags: row.get(14)?,
+            cover_file: None,
+            added_at: row.get(15)?,
+        })
+    })?;
+    books.collect()
+}
+
+fn print_search_header() {
+    println!("#######################");
+    println!("## Search Your Books ##");
+    println!("#######################");
+}
@@ -4,13 +4,21 @@ use rusqlite::Connection;
 use crate::utilities;
 use crate::configuration::setup_config_database_file;
 use crate::configuration::Config;
+use crate::role_object::Role;
+
+/*
+ *  Stored in the database as PRAGMA user_version. Increase it whenever
+ *  update_existing_tables learns to create something new, so that a
+ *  backup can be checked against the version of rLMS restoring it.
+ */
+pub const SCHEMA_VERSION: i32 = 15;
 
 pub fn check_initial(config: &mut Config, config_path: &str) -> bool {
     let mut return_value: bool = true;
     println!("Checking if initial setup...");
     utilities::pause(2);
 
-    let mut database_name = get_database_name(config, &config_path);
+    let mut database_name = get_database_name(config, config_path);
     database_name.push_str(".sqlite");
     utilities::pause(1);
     println!("Your database file will be:\n\t{}", database_name);
@@ -29,7 +37,7 @@ pub fn check_initial(config: &mut Config, config_path: &str) -> bool {
     println!("========================================");
     println!("===   Create Initial Administrator   ===");
     println!("========================================");
-    match create_initial_administrator(&database_name) {
+    match create_initial_administrator(&database_name, config) {
         Ok(_) => {
             println!("Initial administrator account created successfully!");
             utilities::pause(1);
@@ -84,6 +92,14 @@ pub fn create_initial_tables(db_name: &str) -> bool {
             };
             println!("Successfully created admins table.");
 
+            println!("Creating works table...");
+            utilities::pause(1);
+            if !create_work_table(&connection) {
+                println!("Could not create works table.");
+                return false;
+            };
+            println!("Successfully created works table.");
+
             println!("Creating books table...");
             utilities::pause(1);
             if !create_book_table(&connection) {
@@ -92,6 +108,22 @@ pub fn create_initial_tables(db_name: &str) -> bool {
             };
             println!("Successfully created books table.");
 
+            println!("Creating book details tables...");
+            utilities::pause(1);
+            if !create_book_detail_tables(&connection) {
+                println!("Could not create book details tables.");
+                return false;
+            };
+            println!("Successfully created book details tables.");
+
+            println!("Creating book edit history tables...");
+            utilities::pause(1);
+            if !create_book_edit_tables(&connection) {
+                println!("Could not create book edit history tables.");
+                return false;
+            };
+            println!("Successfully created book edit history tables.");
+
             println!("Creating libraries table...");
             utilities::pause(1);
             if !create_libary_table(&connection){
@@ -100,6 +132,126 @@ pub fn create_initial_tables(db_name: &str) -> bool {
             };
             println!("Successfully created libraries table.");
 
+            println!("Creating reading sessions table...");
+            utilities::pause(1);
+            if !create_reading_session_table(&connection) {
+                println!("Could not create reading sessions table.");
+                return false;
+            };
+            println!("Successfully created reading sessions table.");
+
+            println!("Creating shelves and tags tables...");
+            utilities::pause(1);
+            if !create_label_tables(&connection) {
+                println!("Could not create shelves and tags tables.");
+                return false;
+            };
+            println!("Successfully created shelves and tags tables.");
+
+            println!("Creating series tables...");
+            utilities::pause(1);
+            if !create_series_tables(&connection) {
+                println!("Could not create series tables.");
+                return false;
+            };
+            println!("Successfully created series tables.");
+
+            println!("Creating record change tracking...");
+            utilities::pause(1);
+            if !create_record_change_tracking(&connection) {
+                println!("Could not create record change tracking.");
+                return false;
+            };
+            println!("Successfully created record change tracking.");
+
+            println!("Creating book covers table...");
+            utilities::pause(1);
+            if !create_book_cover_table(&connection) {
+                println!("Could not create book covers table.");
+                return false;
+            };
+            println!("Successfully created book covers table.");
+
+            println!("Creating locations table...");
+            utilities::pause(1);
+            if !create_location_table(&connection) {
+                println!("Could not create locations table.");
+                return false;
+            };
+            println!("Successfully created locations table.");
+
+            println!("Creating stocktake tables...");
+            utilities::pause(1);
+            if !create_stocktake_tables(&connection) {
+                println!("Could not create stocktake tables.");
+                return false;
+            };
+            println!("Successfully created stocktake tables.");
+
+            println!("Creating user roles table...");
+            utilities::pause(1);
+            if !create_user_role_table(&connection) {
+                println!("Could not create user roles table.");
+                return false;
+            };
+            println!("Successfully created user roles table.");
+
+            println!("Creating user tokens table...");
+            utilities::pause(1);
+            if !create_user_token_table(&connection) {
+                println!("Could not create user tokens table.");
+                return false;
+            };
+            println!("Successfully created user tokens table.");
+
+            println!("Creating two-factor tables...");
+            utilities::pause(1);
+            if !create_two_factor_tables(&connection) {
+                println!("Could not create two-factor tables.");
+                return false;
+            };
+            println!("Successfully created two-factor tables.");
+
+            println!("Creating audit log table...");
+            utilities::pause(1);
+            if !create_audit_log_table(&connection) {
+                println!("Could not create audit log table.");
+                return false;
+            };
+            println!("Successfully created audit log table.");
+
+            println!("Creating holdings table...");
+            utilities::pause(1);
+            if !create_holding_table(&connection) {
+                println!("Could not create holdings table.");
+                return false;
+            };
+            println!("Successfully created holdings table.");
+
+            println!("Creating loans and holds tables...");
+            utilities::pause(1);
+            if !create_circulation_tables(&connection) {
+                println!("Could not create loans and holds tables.");
+                return false;
+            };
+            println!("Successfully created loans and holds tables.");
+
+            println!("Creating notification tables...");
+            utilities::pause(1);
+            if !create_notification_tables(&connection) {
+                println!("Could not create notification tables.");
+                return false;
+            };
+            println!("Successfully created notification tables.");
+
+            println!("Creating login attempts table...");
+            utilities::pause(1);
+            if !create_login_attempt_table(&connection) {
+                println!("Could not create login attempts table.");
+                return false;
+            };
+            println!("Successfully created login attempts table.");
+
             true
         }
         Err(e) => {
@@ -109,15 +261,516 @@ pub fn create_initial_tables(db_name: &str) -> bool {
     }
 }
 
-fn create_book_table(connection: &Connection) -> bool {
+/*
+ *  Databases created by an older version of rLMS are missing the tables
+ *  added since. This is run on every start-up and quietly creates
+ *  anything that is missing, so it must only ever contain
+ *  "IF NOT EXISTS" style statements.
+ */
+pub fn update_existing_tables(db_name: &str) -> bool {
+    match Connection::open(db_name) {
+        Ok(mut connection) => {
+            if !create_login_attempt_table(&connection) {
+                println!("Could not create login attempts table.");
+                return false;
+            }
+            if !create_user_role_table(&connection) || !migrate_admins_to_roles(&connection) {
+                println!("Could not create user roles table.");
+                return false;
+            }
+            if !create_user_token_table(&connection) {
+                println!("Could not create user tokens table.");
+                return false;
+            }
+            // Accounts from before email verification existed are trusted,
+            // hence the default of 1 here and 0 in create_user_table.
+            if !add_column_if_missing(&connection, "users", "email_verified", "INTEGER NOT NULL DEFAULT 1") {
+                println!("Could not add email verification to users table.");
+                return false;
+            }
+            if !create_two_factor_tables(&connection) {
+                println!("Could not create two-factor tables.");
+                return false;
+            }
+            if !create_audit_log_table(&connection) {
+                println!("Could not create audit log table.");
+                return false;
+            }
+            if !create_holding_table(&connection) || !migrate_libraries_to_holdings(&connection) {
+                println!("Could not create holdings table.");
+                return false;
+            }
+            if !create_work_table(&connection)
+                || !add_column_if_missing(&connection, "books", "work_id", "INTEGER REFERENCES works(work_id) ON DELETE SET NULL")
+                || !create_book_work_index(&connection)
+            {
+                println!("Could not create works table.");
+                return false;
+            }
+            if !make_book_isbn_optional(&mut connection)
+                || !create_book_work_index(&connection)
+                || !create_book_detail_tables(&connection)
+                || !migrate_book_authors(&connection)
+            {
+                println!("Could not update books table for manual cataloguing.");
+                return false;
+            }
+            if !create_book_edit_tables(&connection) {
+                println!("Could not create book edit history tables.");
+                return false;
+            }
+            if !add_column_if_missing(&connection, "libraries", "reading_status", "TEXT")
+                || !add_column_if_missing(&connection, "libraries", "rating", "INTEGER CHECK (rating BETWEEN 1 AND 5)")
+                || !add_column_if_missing(&connection, "libraries", "note", "TEXT")
+                || !add_column_if_missing(&connection, "libraries", "review", "TEXT")
+                || !create_reading_session_table(&connection)
+            {
+                println!("Could not add reading logs to libraries table.");
+                return false;
+            }
+            if !create_label_tables(&connection) {
+                println!("Could not create shelves and tags tables.");
+                return false;
+            }
+            if !create_series_tables(&connection) {
+                println!("Could not create series tables.");
+                return false;
+            }
+            if !create_book_cover_table(&connection) {
+                println!("Could not create book covers table.");
+                return false;
+            }
+            if !create_location_table(&connection)
+                || !add_column_if_missing(&connection, "holdings", "location_id", "INTEGER REFERENCES locations(location_id) ON UPDATE CASCADE")
+                || !add_column_if_missing(&connection, "holdings", "call_number", "TEXT")
+                || !add_column_if_missing(&connection, "holdings", "call_number_sort", "TEXT")
+                || connection.execute(
+                    "CREATE INDEX IF NOT EXISTS idx_holdings_location ON holdings (location_id, call_number_sort)",
+                    [],
+                ).is_err()
+            {
+                println!("Could not add locations and call numbers to holdings table.");
+                return false;
+            }
+            // Barcodes are issued later, so uniqueness is kept by an index rather than the column.
+            if !add_column_if_missing(&connection, "holdings", "barcode", "TEXT")
+                || !add_column_if_missing(&connection, "users", "barcode", "TEXT")
+                || connection.execute_batch(
+                    "CREATE UNIQUE INDEX IF NOT EXISTS idx_holdings_barcode ON holdings (barcode);
+                     CREATE UNIQUE INDEX IF NOT EXISTS idx_users_barcode ON users (barcode);",
+                ).is_err()
+            {
+                println!("Could not add barcodes to holdings and users tables.");
+                return false;
+            }
+            if !add_column_if_missing(&connection, "holdings", "status", "TEXT NOT NULL DEFAULT 'available'")
+                || !create_stocktake_tables(&connection)
+            {
+                println!("Could not add stocktakes and copy statuses.");
+                return false;
+            }
+            // Books added before this column existed have no date, and sort as the oldest.
+            if !add_column_if_missing(&connection, "libraries", "added_at", "INTEGER") {
+                println!("Could not add the date added to libraries table.");
+                return false;
+            }
+            if !add_column_if_missing(&connection, "books", "modified_at", "INTEGER")
+                || !create_record_change_tracking(&connection)
+            {
+                println!("Could not add change tracking to books table.");
+                return false;
+            }
+            if !create_circulation_tables(&connection) || !create_notification_tables(&connection) {
+                println!("Could not create loans, holds and notification tables.");
+                return false;
+            }
+            if !set_schema_version(&connection) {
+                println!("Could not record the schema version.");
+                return false;
+            }
+            true
+        }
+        Err(e) => {
+            eprintln!("Failed to establish database connection: {}", e);
+            false
+        }
+    }
+}
+
+pub fn get_schema_version(connection: &Connection) -> Result<i32, rusqlite::Error> {
+    connection.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+// Never lowers the version, in case a newer rLMS has used this database.
+fn set_schema_version(connection: &Connection) -> bool {
+    match get_schema_version(connection) {
+        Ok(version) if version >= SCHEMA_VERSION => true,
+        Ok(_) => connection.pragma_update(None, "user_version", SCHEMA_VERSION).is_ok(),
+        Err(_e) => false,
+    }
+}
+
+fn create_work_table(connection: &Connection) -> bool {
     let result = connection.execute(
-        "CREATE TABLE IF NOT EXISTS books (
+        "CREATE TABLE IF NOT EXISTS works (
+            work_id INTEGER PRIMARY KEY,
+            work_key TEXT NOT NULL UNIQUE,
+            title VARCHAR(200) NOT NULL,
+            author VARCHAR(200) NOT NULL,
+            created_at INTEGER NOT NULL
+        );",
+        [],
+    );
+
+    match result {
+        Ok(_) => true,
+        Err(_e) => false,
+    }
+}
+
+fn create_book_work_index(connection: &Connection) -> bool {
+    connection
+        .execute("CREATE INDEX IF NOT EXISTS idx_books_work ON books (work_id)", [])
+        .is_ok()
+}
+
+fn create_holding_table(connection: &Connection) -> bool {
+    // owner_user_id is NULL for copies that belong to the library.
+    let result = connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS holdings (
+            holding_id INTEGER PRIMARY KEY,
+            book_id INTEGER NOT NULL,
+            owner_user_id INTEGER,
+            condition TEXT,
+            acquired_on TEXT,
+            price_paid_cents INTEGER,
+            location TEXT,
+            notes TEXT,
+            created_at INTEGER NOT NULL,
+            location_id INTEGER,
+            call_number TEXT,
+            call_number_sort TEXT,
+            barcode TEXT,
+            status TEXT NOT NULL DEFAULT 'available',
+            FOREIGN KEY (book_id) REFERENCES books(book_id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE,
+            FOREIGN KEY (owner_user_id) REFERENCES users(user_id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE,
+            FOREIGN KEY (location_id) REFERENCES locations(location_id)
+            ON UPDATE CASCADE
+        );
+        CREATE INDEX IF NOT EXISTS idx_holdings_book ON holdings (book_id);
+        CREATE INDEX IF NOT EXISTS idx_holdings_owner ON holdings (owner_user_id);",
+    );
+
+    match result {
+        Ok(_) => true,
+        Err(_e) => false,
+    }
+}
+
+// Before holdings existed, a book in someone's library meant they owned a copy of it.
+fn migrate_libraries_to_holdings(connection: &Connection) -> bool {
+    match get_schema_version(connection) {
+        Ok(version) if version >= 2 => true,
+        // An upgrade that stopped before the version was recorded runs this again, so copies already made are skipped.
+        Ok(_) => connection
+            .execute(
+                "INSERT INTO holdings (book_id, owner_user_id, created_at)
+                 SELECT book_id, user_id, strftime('%s', 'now') FROM libraries
+                 WHERE NOT EXISTS (
+                     SELECT 1 FROM holdings
+                     WHERE holdings.book_id = libraries.book_id AND holdings.owner_user_id = libraries.user_id
+                 )",
+                [],
+            )
+            .is_ok(),
+        Err(_e) => false,
+    }
+}
+
+fn add_column_if_missing(connection: &Connection, table: &str, column: &str, definition: &str) -> bool {
+    let exists = connection.query_row(
+        &format!("SELECT COUNT(*) FROM pragma_table_info('{}') WHERE name = ?1", table),
+        params![column],
+        |row| row.get::<_, i32>(0),
+    );
+
+    match exists {
+        Ok(0) => connection
+            .execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])
+            .is_ok(),
+        Ok(_) => true,
+        Err(_e) => false,
+    }
+}
+
+fn create_audit_log_table(connection: &Connection) -> bool {
+    // No foreign key on actor_user_id: entries must outlive the users they mention.
+    let result = connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS audit_log (
+            audit_id INTEGER PRIMARY KEY,
+            occurred_at INTEGER NOT NULL,
+            actor_user_id INTEGER,
+            actor_email VARCHAR(200),
+            action VARCHAR(100) NOT NULL,
+            target VARCHAR(200) NOT NULL,
+            diff TEXT NOT NULL DEFAULT '{}'
+        );
+        CREATE INDEX IF NOT EXISTS idx_audit_log_occurred_at ON audit_log (occurred_at);
+        CREATE INDEX IF NOT EXISTS idx_audit_log_action ON audit_log (action);
+        CREATE TRIGGER IF NOT EXISTS audit_log_no_update BEFORE UPDATE ON audit_log
+        BEGIN
+            SELECT RAISE(ABORT, 'audit_log is append-only');
+        END;
+        CREATE TRIGGER IF NOT EXISTS audit_log_no_delete BEFORE DELETE ON audit_log
+        BEGIN
+            SELECT RAISE(ABORT, 'audit_log is append-only');
+        END;",
+    );
+
+    match result {
+        Ok(_) => true,
+        Err(_e) => false,
+    }
+}
+
+fn create_two_factor_tables(connection: &Connection) -> bool {
+    let result = connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS two_factor (
+            user_id INTEGER UNIQUE,
+            secret VARCHAR(64) NOT NULL,
+            enabled INTEGER NOT NULL DEFAULT 0,
+            last_used_step INTEGER NOT NULL DEFAULT 0,
+            created_at INTEGER NOT NULL,
+            FOREIGN KEY (user_id) REFERENCES users(user_id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+        );
+        CREATE TABLE IF NOT EXISTS recovery_codes (
+            code_id INTEGER PRIMARY KEY,
+            user_id INTEGER NOT NULL,
+            code_hash VARCHAR(64) NOT NULL,
+            used_at INTEGER,
+            FOREIGN KEY (user_id) REFERENCES users(user_id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+        );",
+    );
+
+    match result {
+        Ok(_) => true,
+        Err(_e) => false,
+    }
+}
+
+fn create_user_token_table(connection: &Connection) -> bool {
+    let result = connection.execute(
+        "CREATE TABLE IF NOT EXISTS user_tokens (
+            token_id INTEGER PRIMARY KEY,
+            user_id INTEGER NOT NULL,
+            purpose VARCHAR(50) NOT NULL,
+            token_hash VARCHAR(64) NOT NULL UNIQUE,
+            created_at INTEGER NOT NULL,
+            expires_at INTEGER NOT NULL,
+            used_at INTEGER,
+            FOREIGN KEY (user_id) REFERENCES users(user_id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+        );",
+        [],
+    );
+
+    match result {
+        Ok(_) => true,
+        Err(_e) => false,
+    }
+}
+
+fn create_user_role_table(connection: &Connection) -> bool {
+    let result = connection.execute(
+        "CREATE TABLE IF NOT EXISTS user_roles (
+            user_id INTEGER UNIQUE,
+            role VARCHAR(50) NOT NULL,
+            FOREIGN KEY (user_id) REFERENCES users(user_id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+        );",
+        [],
+    );
+
+    match result {
+        Ok(_) => true,
+        Err(_e) => false,
+    }
+}
+
+// Gives every user from before roles existed a role: members of the old
+// admins table become sysadmins, everyone else a patron.
+fn migrate_admins_to_roles(connection: &Connection) -> bool {
+    let result = connection.execute_batch(
+        "INSERT OR IGNORE INTO user_roles (user_id, role)
+            SELECT user_id, 'sysadmin' FROM admins WHERE user_id IS NOT NULL;
+        INSERT OR IGNORE INTO user_roles (user_id, role)
+            SELECT user_id, 'patron' FROM users;",
+    );
+
+    match result {
+        Ok(_) => true,
+        Err(_e) => false,
+    }
+}
+
+fn create_login_attempt_table(connection: &Connection) -> bool {
+    // Attempts are keyed by the email that was typed rather than by user_id
+    // so that unknown emails are throttled exactly like real accounts.
+    let result = connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS login_attempts (
+            attempt_id INTEGER PRIMARY KEY,
+            email VARCHAR(200) NOT NULL,
+            attempted_at INTEGER NOT NULL,
+            outcome VARCHAR(20) NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS idx_login_attempts_email
+            ON login_attempts (email, attempted_at);",
+    );
+
+    match result {
+        Ok(_) => true,
+        Err(_e) => false,
+    }
+}
+
+// Shared by create_book_table and make_book_isbn_optional, which rebuilds older tables.
+const BOOK_TABLE_DEFINITION: &str = "(
             book_id INTEGER PRIMARY KEY,
             title VARCHAR(200) NOT NULL,
             author VARCHAR(200) NOT NULL,
-            isbn VARCHAR(200) NOT NULL UNIQUE
-            );",
+            isbn VARCHAR(200) UNIQUE,
+            work_id INTEGER REFERENCES works(work_id) ON DELETE SET NULL,
+            publisher TEXT,
+            publish_date TEXT,
+            number_of_pages INTEGER,
+            source TEXT NOT NULL DEFAULT 'openlibrary',
+            modified_at INTEGER
+            )";
+
+fn create_book_table(connection: &Connection) -> bool {
+    let result = connection.execute(
+        &format!("CREATE TABLE IF NOT EXISTS books {};", BOOK_TABLE_DEFINITION),
+        [],
+    );
+
+    match result {
+        Ok(_) => true,
+        Err(_e) => false,
+    }
+}
+
+/*
+ *  Books catalogued by hand may have no ISBN, but the books table used
+ *  to require one. SQLite cannot drop a NOT NULL constraint, so the table
+ *  is rebuilt under a new name and renamed back; the foreign keys in other
+ *  tables refer to it by name and so keep working.
+ */
+fn make_book_isbn_optional(connection: &mut Connection) -> bool {
+    let isbn_required = connection.query_row(
+        "SELECT \"notnull\" FROM pragma_table_info('books') WHERE name = 'isbn'",
         [],
+        |row| row.get::<_, bool>(0),
+    );
+    match isbn_required {
+        Ok(false) => return true,
+        Ok(true) => {}
+        Err(_e) => return false,
+    }
+
+    rebuild_book_table(connection).is_ok()
+}
+
+fn rebuild_book_table(connection: &mut Connection) -> Result<(), rusqlite::Error> {
+    let transaction = connection.transaction()?;
+    transaction.execute_batch(&format!(
+        "CREATE TABLE books_rebuilt {};
+         INSERT INTO books_rebuilt (book_id, title, author, isbn, work_id)
+         SELECT book_id, title, author, isbn, work_id FROM books;
+         DROP TABLE books;
+         ALTER TABLE books_rebuilt RENAME TO books;",
+        BOOK_TABLE_DEFINITION
+    ))?;
+    transaction.commit()
+}
+
+fn create_book_detail_tables(connection: &Connection) -> bool {
+    let result = connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS book_authors (
+            book_id INTEGER NOT NULL,
+            position INTEGER NOT NULL,
+            name VARCHAR(200) NOT NULL,
+            PRIMARY KEY (book_id, position),
+            FOREIGN KEY (book_id) REFERENCES books(book_id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+        );
+        CREATE TABLE IF NOT EXISTS book_subjects (
+            book_id INTEGER NOT NULL,
+            subject VARCHAR(200) NOT NULL,
+            PRIMARY KEY (book_id, subject),
+            FOREIGN KEY (book_id) REFERENCES books(book_id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+        );
+        CREATE TABLE IF NOT EXISTS book_identifiers (
+            book_id INTEGER NOT NULL,
+            scheme TEXT NOT NULL,
+            value TEXT NOT NULL,
+            UNIQUE (scheme, value),
+            FOREIGN KEY (book_id) REFERENCES books(book_id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+        );
+        CREATE INDEX IF NOT EXISTS idx_book_identifiers_book ON book_identifiers (book_id);",
+    );
+
+    match result {
+        Ok(_) => true,
+        Err(_e) => false,
+    }
+}
+
+/*
+ *  book_field_sources holds the source of each field changed since its
+ *  record was saved; book_edits is the history of those changes.
+ */
+fn create_book_edit_tables(connection: &Connection) -> bool {
+    let result = connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS book_field_sources (
+            book_id INTEGER NOT NULL,
+            field TEXT NOT NULL,
+            source TEXT NOT NULL,
+            updated_at INTEGER NOT NULL,
+            PRIMARY KEY (book_id, field),
+            FOREIGN KEY (book_id) REFERENCES books(book_id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+        );
+        CREATE TABLE IF NOT EXISTS book_edits (
+            edit_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            book_id INTEGER NOT NULL,
+            field TEXT NOT NULL,
+            old_value TEXT,
+            new_value TEXT,
+            source TEXT NOT NULL,
+            editor_user_id INTEGER,
+            editor_email VARCHAR(100),
+            edited_at INTEGER NOT NULL,
+            FOREIGN KEY (book_id) REFERENCES books(book_id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+        );
+        CREATE INDEX IF NOT EXISTS idx_book_edits_book ON book_edits (book_id);",
     );
 
     match result {
@@ -126,11 +779,31 @@ fn create_book_table(connection: &Connection) -> bool {
     }
 }
 
+// Until authors had their own table only the first author was kept, in books.author.
+fn migrate_book_authors(connection: &Connection) -> bool {
+    match get_schema_version(connection) {
+        Ok(version) if version >= 4 => true,
+        Ok(_) => connection
+            .execute(
+                "INSERT OR IGNORE INTO book_authors (book_id, position, name)
+                 SELECT book_id, 0, author FROM books",
+                [],
+            )
+            .is_ok(),
+        Err(_e) => false,
+    }
+}
+
 fn create_libary_table(connection: &Connection) -> bool {
     let result = connection.execute(
         "CREATE TABLE IF NOT EXISTS libraries (
             user_id INTEGER,
             book_id INTEGER,
+            reading_status TEXT,
+            rating INTEGER CHECK (rating BETWEEN 1 AND 5),
+            note TEXT,
+            review TEXT,
+            added_at INTEGER,
             PRIMARY KEY (user_id, book_id),
             FOREIGN KEY (user_id) REFERENCES users(user_id)
             ON DELETE CASCADE
@@ -148,6 +821,333 @@ fn create_libary_table(connection: &Connection) -> bool {
     }
 }
 
+/*
+ *  One row per time a user starts (or finishes) a book in their
+ *  collection. outcome is NULL while the book is being read, then
+ *  'read' or 'abandoned'. Dates are YYYY-MM-DD.
+ */
+fn create_reading_session_table(connection: &Connection) -> bool {
+    let result = connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS reading_sessions (
+            session_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            book_id INTEGER NOT NULL,
+            started_on TEXT,
+            finished_on TEXT,
+            outcome TEXT,
+            FOREIGN KEY (user_id, book_id) REFERENCES libraries(user_id, book_id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+        );
+        CREATE INDEX IF NOT EXISTS idx_reading_sessions_user_book ON reading_sessions (user_id, book_id);",
+    );
+
+    match result {
+        Ok(_) => true,
+        Err(_e) => false,
+    }
+}
+
+// Shelves and tags: kind is 'shelf' or 'tag', see LabelKind.
+fn create_label_tables(connection: &Connection) -> bool {
+    let result = connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS labels (
+            label_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            kind TEXT NOT NULL,
+            name VARCHAR(100) NOT NULL,
+            created_at INTEGER NOT NULL,
+            UNIQUE (user_id, kind, name COLLATE NOCASE),
+            FOREIGN KEY (user_id) REFERENCES users(user_id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+        );
+        CREATE TABLE IF NOT EXISTS book_labels (
+            label_id INTEGER NOT NULL,
+            book_id INTEGER NOT NULL,
+            PRIMARY KEY (label_id, book_id),
+            FOREIGN KEY (label_id) REFERENCES labels(label_id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE,
+            FOREIGN KEY (book_id) REFERENCES books(book_id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+        );
+        CREATE INDEX IF NOT EXISTS idx_book_labels_book ON book_labels (book_id);",
+    );
+
+    match result {
+        Ok(_) => true,
+        Err(_e) => false,
+    }
+}
+
+// Series are shared across the catalogue; positions can be fractional, e.g. 2.5 for a novella.
+fn create_series_tables(connection: &Connection) -> bool {
+    let result = connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS series (
+            series_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name VARCHAR(255) NOT NULL UNIQUE COLLATE NOCASE,
+            created_at INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS book_series (
+            book_id INTEGER NOT NULL,
+            series_id INTEGER NOT NULL,
+            position REAL,
+            PRIMARY KEY (book_id, series_id),
+            FOREIGN KEY (book_id) REFERENCES books(book_id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE,
+            FOREIGN KEY (series_id) REFERENCES series(series_id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+        );
+        CREATE INDEX IF NOT EXISTS idx_book_series_series ON book_series (series_id, position);",
+    );
+
+    match result {
+        Ok(_) => true,
+        Err(_e) => false,
+    }
+}
+
+// The tables whose rows are part of a book's record, as well as books itself.
+const BOOK_RECORD_TABLES: [&str; 4] = ["book_authors", "book_subjects", "book_identifiers", "book_series"];
+
+/*
+ *  OAI-PMH harvesters ask for the records changed since they last came,
+ *  so books.modified_at must move whenever anything in a record does,
+ *  and deleted books must be remembered. Triggers do both, so no code
+ *  that edits a record can forget to. Records from before this existed
+ *  count as changed when they were last edited, or else now.
+ */
+fn create_record_change_tracking(connection: &Connection) -> bool {
+    const NOW: &str = "CAST(strftime('%s', 'now') AS INTEGER)";
+    let mut sql = format!(
+        "CREATE TABLE IF NOT EXISTS deleted_books (
+            book_id INTEGER PRIMARY KEY,
+            deleted_at INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS idx_books_modified_at ON books (modified_at);
+        UPDATE books SET modified_at = COALESCE(
+            (SELECT MAX(edited_at) FROM book_edits WHERE book_edits.book_id = books.book_id),
+            {now}
+        ) WHERE modified_at IS NULL;
+        CREATE TRIGGER IF NOT EXISTS books_track_insert AFTER INSERT ON books
+        BEGIN
+            UPDATE books SET modified_at = {now} WHERE book_id = NEW.book_id;
+            DELETE FROM deleted_books WHERE book_id = NEW.book_id;
+        END;
+        CREATE TRIGGER IF NOT EXISTS books_track_update AFTER UPDATE ON books
+        WHEN NEW.modified_at IS OLD.modified_at
+        BEGIN
+            UPDATE books SET modified_at = {now} WHERE book_id = NEW.book_id;
+        END;
+        CREATE TRIGGER IF NOT EXISTS books_track_delete AFTER DELETE ON books
+        BEGIN
+            INSERT OR REPLACE INTO deleted_books (book_id, deleted_at) VALUES (OLD.book_id, {now});
+        END;",
+        now = NOW
+    );
+    for table in BOOK_RECORD_TABLES {
+        for (event, row) in [("INSERT", "NEW"), ("UPDATE", "NEW"), ("DELETE", "OLD")] {
+            sql.push_str(&format!(
+                "CREATE TRIGGER IF NOT EXISTS {table}_track_{name} AFTER {event} ON {table}
+                BEGIN
+                    UPDATE books SET modified_at = {now} WHERE book_id = {row}.book_id;
+                END;",
+                table = table,
+                name = event.to_lowercase(),
+                event = event,
+                now = NOW,
+                row = row
+            ));
+        }
+    }
+
+    match connection.execute_batch(&sql) {
+        Ok(_) => true,
+        Err(_e) => false,
+    }
+}
+
+/*
+ *  Covers are kept in the database, so they are in every backup and
+ *  still show when offline. sha256 is the hash of the image as it was
+ *  downloaded and doubles as its ETag; the thumbnail is always a JPEG.
+ */
+fn create_book_cover_table(connection: &Connection) -> bool {
+    let result = connection.execute(
+        "CREATE TABLE IF NOT EXISTS book_covers (
+            book_id INTEGER PRIMARY KEY,
+            sha256 TEXT NOT NULL,
+            content_type TEXT NOT NULL,
+            width INTEGER NOT NULL,
+            height INTEGER NOT NULL,
+            image BLOB NOT NULL,
+            thumbnail BLOB NOT NULL,
+            source_url TEXT,
+            fetched_at INTEGER NOT NULL,
+            FOREIGN KEY (book_id) REFERENCES books(book_id)
+            ON DELETE CASCADE
+            ON UPDATE CASCADE
+        )",
+        [],
+    );
+
+    match result {
+        Ok(_) => true,
+        Err(_e) => false,
+    }
+}
+
+/*
+ *  Where copies are kept: branch, room, bookcase, shelf, each inside the
+ *  one above it (see LocationKind). Names only need to be unique among
+ *  their siblings, e.g. every bookcase can have a "Shelf 1".
+ */
+fn create_location_table(connection: &Connection) -> bool {
+    let result = connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS locations (
+            location_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            parent_id INTEGER,
+            kind TEXT NOT NULL,
+            name VARCHAR(100) NOT NULL,
+            created_at INTEGER NOT NULL,
+            FOREIGN KEY (parent_id) REFERENCES locations(location_id)
+            ON UPDATE CASCADE
+        );
+        CREATE UNIQUE INDEX IF NOT EXISTS idx_locations_name ON locations (COALESCE(parent_id, 0), name COLLATE NOCASE);",
+    );
+
+    match result {
+        Ok(_) => true,
+        Err(_e) => false,
+    }
+}
+
+/*
+ *  A stocktake compares the copies scanned in a location with the copies
+ *  recorded there. Each barcode is kept once per stocktake, with the copy
+ *  it belonged to when scanned, or NULL if it matched nothing.
+ */
+fn create_stocktake_tables(connection: &Connection) -> bool {
+    let result = connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS stocktakes (
+            stocktake_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            location_id INTEGER NOT NULL,
+            started_by INTEGER,
+            started_at INTEGER NOT NULL,
+            finished_at INTEGER,
+            FOREIGN KEY (location_id) REFERENCES locations(location_id)
+            ON UPDATE CASCADE,
+            FOREIGN KEY (started_by) REFERENCES users(user_id)
+            ON DELETE SET NULL
+        );
+        CREATE TABLE IF NOT EXISTS stocktake_scans (
+            stocktake_id INTEGER NOT NULL,
+            barcode TEXT NOT NULL,
+            holding_id INTEGER,
+            scanned_at INTEGER NOT NULL,
+            PRIMARY KEY (stocktake_id, barcode),
+            FOREIGN KEY (stocktake_id) REFERENCES stocktakes(stocktake_id)
+            ON DELETE CASCADE,
+            FOREIGN KEY (holding_id) REFERENCES holdings(holding_id)
+            ON DELETE SET NULL
+        );",
+    );
+
+    match result {
+        Ok(_) => true,
+        Err(_e) => false,
+    }
+}
+
+/*
+ *  A loan is open until the copy is returned; renewing it moves due_at.
+ *  A hold is on a book rather than a copy: when a copy is set aside for
+ *  the patron it is recorded with ready_at and, if the patron must
+ *  collect it by then, expires_at. closed_at is set when the hold is
+ *  collected or cancelled.
+ */
+fn create_circulation_tables(connection: &Connection) -> bool {
+    let result = connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS loans (
+            loan_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            holding_id INTEGER NOT NULL,
+            user_id INTEGER NOT NULL,
+            checked_out_at INTEGER NOT NULL,
+            due_at INTEGER NOT NULL,
+            returned_at INTEGER,
+            renewals INTEGER NOT NULL DEFAULT 0,
+            FOREIGN KEY (holding_id) REFERENCES holdings(holding_id)
+            ON DELETE CASCADE,
+            FOREIGN KEY (user_id) REFERENCES users(user_id)
+            ON DELETE CASCADE
+        );
+        CREATE INDEX IF NOT EXISTS idx_loans_open ON loans (returned_at, due_at);
+        CREATE TABLE IF NOT EXISTS holds (
+            hold_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            book_id INTEGER NOT NULL,
+            user_id INTEGER NOT NULL,
+            placed_at INTEGER NOT NULL,
+            holding_id INTEGER,
+            ready_at INTEGER,
+            expires_at INTEGER,
+            closed_at INTEGER,
+            FOREIGN KEY (book_id) REFERENCES books(book_id)
+            ON DELETE CASCADE,
+            FOREIGN KEY (user_id) REFERENCES users(user_id)
+            ON DELETE CASCADE,
+            FOREIGN KEY (holding_id) REFERENCES holdings(holding_id)
+            ON DELETE SET NULL
+        );",
+    );
+
+    match result {
+        Ok(_) => true,
+        Err(_e) => false,
+    }
+}
+
+/*
+ *  Patrons without a row in notification_preferences get every
+ *  notification. The log keeps one row per notice sent: the loan or hold
+ *  it was about, the due date or ready date it was about (so a renewed
+ *  loan or a hold readied again is notified afresh) and, for overdue
+ *  notices, which escalation step it was.
+ */
+fn create_notification_tables(connection: &Connection) -> bool {
+    let result = connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS notification_preferences (
+            user_id INTEGER PRIMARY KEY,
+            due_soon INTEGER NOT NULL DEFAULT 1,
+            overdue INTEGER NOT NULL DEFAULT 1,
+            hold_ready INTEGER NOT NULL DEFAULT 1,
+            due_soon_days INTEGER,
+            FOREIGN KEY (user_id) REFERENCES users(user_id)
+            ON DELETE CASCADE
+        );
+        CREATE TABLE IF NOT EXISTS notification_log (
+            notification_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            kind TEXT NOT NULL,
+            record_id INTEGER NOT NULL,
+            occasion INTEGER NOT NULL,
+            step INTEGER NOT NULL DEFAULT 0,
+            sent_at INTEGER NOT NULL,
+            UNIQUE (kind, record_id, occasion, step),
+            FOREIGN KEY (user_id) REFERENCES users(user_id)
+            ON DELETE CASCADE
+        );",
+    );
+
+    match result {
+        Ok(_) => true,
+        Err(_e) => false,
+    }
+}
+
 fn create_admin_table(connection: &Connection) -> bool {
     let result = connection.execute(
         "CREATE TABLE IF NOT EXISTS admins (
@@ -205,7 +1205,9 @@ fn create_user_table(connection: &Connection) -> bool {
             user_id INTEGER PRIMARY KEY,
             email VARCHAR(200) NOT NULL UNIQUE,
             firstname VARCHAR(255) NOT NULL,
-            lastname VARCHAR(255) NOT NULL
+            lastname VARCHAR(255) NOT NULL,
+            email_verified INTEGER NOT NULL DEFAULT 0,
+            barcode TEXT
         );",
         [],
     );
@@ -219,7 +1221,7 @@ fn create_user_table(connection: &Connection) -> bool {
 fn get_database_name(config: &mut Config, config_path: &str) -> String {
     println!("This program uses SQLite to store user data, and we need\nto create a new database");
     utilities::pause(1);
-    let mut database = String::new();
+    let database: String;
 
     loop {
         println!("Enter a name for your database: ");
@@ -250,7 +1252,7 @@ fn is_valid_database_name(db_name: &str) -> bool {
         && !db_name.contains('.')
 }
 
-fn create_initial_administrator(db_name: &str) -> anyhow::Result<(), Box<dyn Error>> {
+fn create_initial_administrator(db_name: &str, config: &Config) -> anyhow::Result<(), Box<dyn Error>> {
     let connection = Connection::open(db_name)?;
     let admin_email = match utilities::get_email_from_user(db_name) {
         Ok(email) => email,
@@ -265,12 +1267,12 @@ fn create_initial_administrator(db_name: &str) -> anyhow::Result<(), Box<dyn Err
     let admin_firstname = utilities::get_name_from_user("firstname");
     let admin_lastname = utilities::get_name_from_user("lastname");
     let admin_password = utilities::get_password_from_user();
-    let admin_salt = utilities::generate_salt(25).trim().to_string();
-    let admin_hashed = utilities::hash_password(&admin_password, &admin_salt).unwrap();
+    let admin_salt = utilities::generate_salt(config.auth.salt_length).trim().to_string();
+    let admin_hashed = utilities::hash_password(&admin_password, &admin_salt, config.auth.bcrypt_cost)?;
 
     // enter admin data into users entity
     connection.execute(
-        "INSERT INTO users (email, firstname, lastname) VALUES (?1, ?2, ?3)",
+        "INSERT INTO users (email, firstname, lastname, email_verified) VALUES (?1, ?2, ?3, 1)",
         params![admin_email, admin_firstname, admin_lastname],
     )?;
 
@@ -295,5 +1297,49 @@ fn create_initial_administrator(db_name: &str) -> anyhow::Result<(), Box<dyn Err
         params![admin_email],
     )?;
 
+    // enter admin data into user_roles entity
+    connection.execute(
+        "INSERT INTO user_roles (user_id, role) VALUES (
+                (SELECT user_id FROM users WHERE email = ?1), ?2)",
+        params![admin_email, Role::Sysadmin.get_name()],
+    )?;
+
     Ok(())
-}
\ No newline at end of file
+}
+/*
+ *  A private in-memory database with every table, for tests. It lasts
+ *  as long as the returned connection, and the returned name opens
+ *  further connections to it for code that takes a database name.
+ */
+#[cfg(test)]
+pub fn open_test_database(name: &str) -> (String, Connection) {
+    let database_name = format!("file:{}?mode=memory&cache=shared", name);
+    let connection = Connection::open(&database_name).expect("could not open the test database");
+    let created = create_user_table(&connection)
+        && create_password_table(&connection)
+        && create_salt_table(&connection)
+        && create_admin_table(&connection)
+        && create_work_table(&connection)
+        && create_book_table(&connection)
+        && create_book_detail_tables(&connection)
+        && create_book_edit_tables(&connection)
+        && create_libary_table(&connection)
+        && create_reading_session_table(&connection)
+        && create_label_tables(&connection)
+        && create_series_tables(&connection)
+        && create_record_change_tracking(&connection)
+        && create_book_cover_table(&connection)
+        && create_location_table(&connection)
+        && create_stocktake_tables(&connection)
+        && create_user_role_table(&connection)
+        && create_user_token_table(&connection)
+        && create_two_factor_tables(&connection)
+        && create_audit_log_table(&connection)
+        && create_holding_table(&connection)
+        && create_circulation_tables(&connection)
+        && create_notification_tables(&connection)
+        && create_login_attempt_table(&connection)
+        && set_schema_version(&connection);
+    assert!(created, "could not create the test database's tables");
+    (database_name, connection)
+}
@@ -0,0 +1,40 @@
+/* NOTE:
+ * Shelves and tags are both labels a user puts on books in their own
+ * collection; a book can be on several shelves and have several tags.
+ * The kind is stored by name in the labels table, so the names
+ * returned by get_name() must never change once released.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LabelKind {
+    Shelf,
+    Tag,
+}
+
+impl LabelKind {
+    pub fn from_name(name: &str) -> Option<LabelKind> {
+        match name.trim().to_lowercase().as_str() {
+            "shelf" => Some(LabelKind::Shelf),
+            "tag" => Some(LabelKind::Tag),
+            _ => None,
+        }
+    }
+
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            LabelKind::Shelf => "shelf",
+            LabelKind::Tag => "tag",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub name: String,
+    pub book_count: u32,
+}
+
+impl Label {
+    pub fn print_label_info(&self) {
+        println!("  {} ({} book{})", self.name, self.book_count, if self.book_count == 1 { "" } else { "s" });
+    }
+}
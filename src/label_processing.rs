@@ -0,0 +1,375 @@
+use std::io;
+use rusqlite::{params, Connection, OptionalExtension};
+use crate::book_processing;
+use crate::label_object::{Label, LabelKind};
+use crate::user_object::User;
+use crate::utilities::{self, clear_screen, get_optional_input, get_yes_or_no};
+
+/*
+ *  Shelves and tags belong to one user and only ever hold books in that
+ *  user's collection. Names are matched without regard to case, so
+ *  "Office" and "office" are the same shelf.
+ */
+pub fn find_label(connection: &Connection, user_id: i32, kind: LabelKind, name: &str) -> Result<Option<i64>, rusqlite::Error> {
+    connection.query_row(
+        "SELECT label_id FROM labels WHERE user_id = ?1 AND kind = ?2 AND name = ?3 COLLATE NOCASE",
+        params![user_id, kind.get_name(), name.trim()],
+        |row| row.get(0),
+    ).optional()
+}
+
+pub fn get_or_create_label(connection: &Connection, user_id: i32, kind: LabelKind, name: &str) -> Result<i64, rusqlite::Error> {
+    if let Some(label_id) = find_label(connection, user_id, kind, name)? {
+        return Ok(label_id);
+    }
+    connection.execute(
+        "INSERT INTO labels (user_id, kind, name, created_at) VALUES (?1, ?2, ?3, ?4)",
+        params![user_id, kind.get_name(), name.trim(), utilities::current_timestamp()],
+    )?;
+    Ok(connection.last_insert_rowid())
+}
+
+pub fn get_labels(connection: &Connection, user_id: i32, kind: LabelKind) -> Result<Vec<Label>, rusqlite::Error> {
+    let mut stmt = connection.prepare(
+        "SELECT labels.name, COUNT(book_labels.book_id)
+         FROM labels
+         LEFT JOIN book_labels ON book_labels.label_id = labels.label_id
+         WHERE labels.user_id = ?1 AND labels.kind = ?2
+         GROUP BY labels.label_id
+         ORDER BY labels.name COLLATE NOCASE",
+    )?;
+    let labels = stmt.query_map(params![user_id, kind.get_name()], |row| {
+        Ok(Label { name: row.get(0)?, book_count: row.get(1)? })
+    })?;
+    labels.collect()
+}
+
+/// The names of the user's shelves or tags that the book has.
+pub fn get_labels_for_book(connection: &Connection, user_id: i32, kind: LabelKind, book_id: i32) -> Result<Vec<String>, rusqlite::Error> {
+    let mut stmt = connection.prepare(
+        "SELECT labels.name FROM labels
+         JOIN book_labels ON book_labels.label_id = labels.label_id
+         WHERE labels.user_id = ?1 AND labels.kind = ?2 AND book_labels.book_id = ?3
+         ORDER BY labels.name COLLATE NOCASE",
+    )?;
+    let names = stmt.query_map(params![user_id, kind.get_name(), book_id], |row| row.get(0))?;
+    names.collect()
+}
+
+/// Puts the books on the shelf or tag, skipping any not in the user's collection. Returns how many were added.
+pub fn add_books_to_label(connection: &Connection, user_id: i32, label_id: i64, book_ids: &[i32]) -> Result<usize, rusqlite::Error> {
+    let mut added = 0;
+    for book_id in book_ids {
+        added += connection.execute(
+            "INSERT OR IGNORE INTO book_labels (label_id, book_id)
+             SELECT ?2, book_id FROM libraries WHERE user_id = ?1 AND book_id = ?3",
+            params![user_id, label_id, book_id],
+        )?;
+    }
+    Ok(added)
+}
+
+pub fn remove_books_from_label(connection: &Connection, label_id: i64, book_ids: &[i32]) -> Result<usize, rusqlite::Error> {
+    let mut removed = 0;
+    for book_id in book_ids {
+        removed += connection.execute(
+            "DELETE FROM book_labels WHERE label_id = ?1 AND book_id = ?2",
+            params![label_id, book_id],
+        )?;
+    }
+    Ok(removed)
+}
+
+/// Removes the book from all of the user's shelves and tags, e.g. when it leaves their collection.
+pub fn remove_book_from_all_labels(connection: &Connection, user_id: i32, book_id: i32) -> Result<usize, rusqlite::Error> {
+    connection.execute(
+        "DELETE FROM book_labels
+         WHERE book_id = ?2 AND label_id IN (SELECT label_id FROM labels WHERE user_id = ?1)",
+        params![user_id, book_id],
+    )
+}
+
+pub fn rename_label(connection: &Connection, label_id: i64, new_name: &str) -> Result<(), rusqlite::Error> {
+    connection.execute("UPDATE labels SET name = ?2 WHERE label_id = ?1", params![label_id, new_name.trim()])?;
+    Ok(())
+}
+
+/*
+ *  Moves every book from one shelf or tag to another and removes the
+ *  first. Books that already had both simply keep the second.
+ */
+pub fn merge_labels(connection: &mut Connection, from_label_id: i64, into_label_id: i64) -> Result<usize, rusqlite::Error> {
+    let transaction = connection.transaction()?;
+    let moved = transaction.execute(
+        "INSERT OR IGNORE INTO book_labels (label_id, book_id)
+         SELECT ?2, book_id FROM book_labels WHERE label_id = ?1",
+        params![from_label_id, into_label_id],
+    )?;
+    transaction.execute("DELETE FROM book_labels WHERE label_id = ?1", params![from_label_id])?;
+    transaction.execute("DELETE FROM labels WHERE label_id = ?1", params![from_label_id])?;
+    transaction.commit()?;
+    Ok(moved)
+}
+
+pub fn delete_label(connection: &mut Connection, label_id: i64) -> Result<(), rusqlite::Error> {
+    let transaction = connection.transaction()?;
+    transaction.execute("DELETE FROM book_labels WHERE label_id = ?1", params![label_id])?;
+    transaction.execute("DELETE FROM labels WHERE label_id = ?1", params![label_id])?;
+    transaction.commit()
+}
+
+/*
+ *  Adds the books to, or removes them from, the named shelf or tag. Used
+ *  for a single book and for every result of a collection search alike.
+ *  A shelf or tag that does not exist yet is created when adding.
+ */
+pub fn label_books(connection: &mut Connection, user_id: i32, kind: LabelKind, name: &str, book_ids: &[i32], add: bool) -> Result<usize, rusqlite::Error> {
+    let transaction = connection.transaction()?;
+    let changed = if add {
+        let label_id = get_or_create_label(&transaction, user_id, kind, name)?;
+        add_books_to_label(&transaction, user_id, label_id, book_ids)?
+    } else {
+        match find_label(&transaction, user_id, kind, name)? {
+            Some(label_id) => remove_books_from_label(&transaction, label_id, book_ids)?,
+            None => 0,
+        }
+    };
+    transaction.commit()?;
+    Ok(changed)
+}
+
+pub(crate) fn get_label_kind_from_user() -> LabelKind {
+    loop {
+        if let Some(kind) = get_optional_input("Shelf or tag? (shelf/tag)").as_deref().and_then(LabelKind::from_name) {
+            return kind;
+        }
+        println!("Invalid choice. Please enter shelf or tag.");
+    }
+}
+
+fn get_label_name_from_user(prompt: &str) -> String {
+    loop {
+        match get_optional_input(prompt) {
+            Some(name) if name.chars().count() <= 100 => return name,
+            Some(_) => println!("Names can be at most 100 characters long."),
+            None => println!("A name is required."),
+        }
+    }
+}
+
+/*
+ *  Asks whether to add or remove, which shelf or tag, and applies it to
+ *  all the given books. Returns false if nothing was changed.
+ */
+pub(crate) fn label_books_from_user(connection: &mut Connection, user_id: i32, book_ids: &[i32]) -> bool {
+    let add = loop {
+        match get_optional_input("Add or remove? (add/remove)").as_deref() {
+            Some("add") => break true,
+            Some("remove") => break false,
+            _ => println!("Invalid choice. Please enter add or remove."),
+        }
+    };
+    let kind = get_label_kind_from_user();
+    let name = get_label_name_from_user(&format!("Name of the {}:", kind.get_name()));
+    match label_books(connection, user_id, kind, &name, book_ids, add) {
+        Ok(changed) => {
+            println!(
+                "{} book(s) {} {} \"{}\".",
+                changed,
+                if add { "added to" } else { "removed from" },
+                kind.get_name(),
+                name
+            );
+            changed > 0
+        }
+        Err(e) => {
+            println!("Failed to update the {}: {}", kind.get_name(), e);
+            false
+        }
+    }
+}
+
+pub(crate) fn manage_labels(database_name: &str, user: &User) -> bool {
+    let mut connection = match Connection::open(database_name) {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("Failed to connect to the database: {}", e);
+            return false;
+        }
+    };
+    let user_id = user.get_user_id();
+    let mut changed = false;
+
+    loop {
+        clear_screen();
+        print_labels_header();
+        println!(
+            "Choose from the options below:\n\
+            \t1. List shelves and tags\n\
+            \t2. Shelve or tag a book\n\
+            \t3. Rename a shelf or tag\n\
+            \t4. Merge two shelves or tags\n\
+            \t5. Delete a shelf or tag\n\
+            \t0. Back"
+        );
+        let mut choice = String::new();
+        if io::stdin().read_line(&mut choice).is_err() {
+            println!("Failed to read input. Please try again.");
+            continue;
+        }
+
+        match choice.trim() {
+            "1" => {
+                for kind in [LabelKind::Shelf, LabelKind::Tag] {
+                    match get_labels(&connection, user_id, kind) {
+                        Ok(labels) if labels.is_empty() => println!("You have no {}s.", kind.get_name()),
+                        Ok(labels) => {
+                            println!("Your {}s:", kind.get_name());
+                            labels.iter().for_each(Label::print_label_info);
+                        }
+                        Err(e) => println!("Error retrieving your {}s: {}", kind.get_name(), e),
+                    }
+                }
+            }
+            "2" => {
+                let book_id = book_processing::get_book_id_from_user(&connection);
+                changed |= label_books_from_user(&mut connection, user_id, &[book_id]);
+            }
+            "3" => {
+                let kind = get_label_kind_from_user();
+                let Some(label_id) = get_existing_label_from_user(&connection, user_id, kind) else { continue; };
+                let new_name = get_label_name_from_user(&format!("New name of the {}:", kind.get_name()));
+                match find_label(&connection, user_id, kind, &new_name) {
+                    Ok(Some(existing_id)) if existing_id != label_id => {
+                        println!("You already have a {} called \"{}\". Merge them instead.", kind.get_name(), new_name);
+                    }
+                    Ok(_) => match rename_label(&connection, label_id, &new_name) {
+                        Ok(()) => {
+                            println!("Renamed to \"{}\".", new_name);
+                            changed = true;
+                        }
+                        Err(e) => println!("Failed to rename the {}: {}", kind.get_name(), e),
+                    },
+                    Err(e) => println!("Failed to search your {}s: {}", kind.get_name(), e),
+                }
+            }
+            "4" => {
+                let kind = get_label_kind_from_user();
+                println!("The {} to merge from (it will be removed):", kind.get_name());
+                let Some(from_label_id) = get_existing_label_from_user(&connection, user_id, kind) else { continue; };
+                println!("The {} to merge into:", kind.get_name());
+                let Some(into_label_id) = get_existing_label_from_user(&connection, user_id, kind) else { continue; };
+                if from_label_id == into_label_id {
+                    println!("A {} cannot be merged into itself.", kind.get_name());
+                } else {
+                    match merge_labels(&mut connection, from_label_id, into_label_id) {
+                        Ok(moved) => {
+                            println!("Merged. {} book(s) moved.", moved);
+                            changed = true;
+                        }
+                        Err(e) => println!("Failed to merge: {}", e),
+                    }
+                }
+            }
+            "5" => {
+                let kind = get_label_kind_from_user();
+                let Some(label_id) = get_existing_label_from_user(&connection, user_id, kind) else { continue; };
+                println!("Delete this {}? The books themselves are not affected. (y/n)", kind.get_name());
+                if get_yes_or_no() {
+                    match delete_label(&mut connection, label_id) {
+                        Ok(()) => {
+                            println!("Deleted.");
+                            changed = true;
+                        }
+                        Err(e) => println!("Failed to delete the {}: {}", kind.get_name(), e),
+                    }
+                }
+            }
+            "0" => return changed,
+            _ => println!("Invalid choice. Please try again."),
+        }
+        utilities::pause(2);
+    }
+}
+
+fn get_existing_label_from_user(connection: &Connection, user_id: i32, kind: LabelKind) -> Option<i64> {
+    let name = get_label_name_from_user(&format!("Name of the {}:", kind.get_name()));
+    match find_label(connection, user_id, kind, &name) {
+        Ok(Some(label_id)) => Some(label_id),
+        Ok(None) => {
+            println!("You have no {} called \"{}\".", kind.get_name(), name);
+            utilities::pause(2);
+            None
+        }
+        Err(e) => {
+            println!("Failed to search your {}s: {}", kind.get_name(), e);
+            None
+        }
+    }
+}
+
+fn print_labels_header() {
+    println!("######################");
+    println!("## Shelves and Tags ##");
+    println!("######################");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialisation;
+
+    // Users 1 and 2 both have book 1; only user 1 has book 2.
+    fn open_collections(name: &str) -> Connection {
+        let (_, connection) = initialisation::open_test_database(name);
+        connection.execute_batch(
+            "INSERT INTO books (book_id, title, author) VALUES (1, 'The Hobbit', 'J. R. R. Tolkien'), (2, 'Emma', 'Jane Austen');
+             INSERT INTO libraries (user_id, book_id) VALUES (1, 1), (1, 2), (2, 1);",
+        ).unwrap();
+        connection
+    }
+
+    #[test]
+    fn labels_belong_to_one_user_and_only_hold_their_books() {
+        let mut connection = open_collections("labels_scoped");
+        assert_eq!(label_books(&mut connection, 2, LabelKind::Shelf, "Office", &[1, 2], true).unwrap(), 1);
+        assert_eq!(label_books(&mut connection, 1, LabelKind::Shelf, "office ", &[1, 2], true).unwrap(), 2);
+
+        let office = find_label(&connection, 1, LabelKind::Shelf, "OFFICE").unwrap().unwrap();
+        assert_ne!(Some(office), find_label(&connection, 2, LabelKind::Shelf, "Office").unwrap());
+        assert_eq!(find_label(&connection, 1, LabelKind::Tag, "Office").unwrap(), None);
+        assert_eq!(get_labels_for_book(&connection, 2, LabelKind::Shelf, 2).unwrap(), Vec::<String>::new());
+
+        remove_book_from_all_labels(&connection, 1, 1).unwrap();
+        assert_eq!(get_labels_for_book(&connection, 1, LabelKind::Shelf, 1).unwrap(), Vec::<String>::new());
+        assert_eq!(get_labels_for_book(&connection, 2, LabelKind::Shelf, 1).unwrap(), vec!["Office"]);
+    }
+
+    #[test]
+    fn merging_moves_the_books_and_removes_the_first_label() {
+        let mut connection = open_collections("labels_merged");
+        label_books(&mut connection, 1, LabelKind::Tag, "fantasy", &[1], true).unwrap();
+        label_books(&mut connection, 1, LabelKind::Tag, "Fantasy Fiction", &[1, 2], true).unwrap();
+        let from = find_label(&connection, 1, LabelKind::Tag, "fantasy").unwrap().unwrap();
+        let into = find_label(&connection, 1, LabelKind::Tag, "fantasy fiction").unwrap().unwrap();
+
+        // Book 1 already had both tags, so no book is added to the second.
+        assert_eq!(merge_labels(&mut connection, from, into).unwrap(), 0);
+        let labels = get_labels(&connection, 1, LabelKind::Tag).unwrap();
+        assert_eq!(labels.iter().map(|label| (label.name.as_str(), label.book_count)).collect::<Vec<_>>(), vec![("Fantasy Fiction", 2)]);
+
+        rename_label(&connection, into, " Fantasy ").unwrap();
+        assert_eq!(get_labels_for_book(&connection, 1, LabelKind::Tag, 2).unwrap(), vec!["Fantasy"]);
+        delete_label(&mut connection, into).unwrap();
+        assert!(get_labels(&connection, 1, LabelKind::Tag).unwrap().is_empty());
+        let left: i64 = connection.query_row("SELECT COUNT(*) FROM book_labels", [], |row| row.get(0)).unwrap();
+        assert_eq!(left, 0);
+    }
+
+    #[test]
+    fn removing_from_a_label_that_does_not_exist_changes_nothing() {
+        let mut connection = open_collections("labels_missing");
+        assert_eq!(label_books(&mut connection, 1, LabelKind::Shelf, "Attic", &[1], false).unwrap(), 0);
+        assert!(get_labels(&connection, 1, LabelKind::Shelf).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,381 @@
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use rusqlite::{params, Connection};
+use crate::barcode_management::{self, BarcodeKind, BarcodeOwner, Symbology};
+use crate::book_processing;
+use crate::configuration::Config;
+use crate::holding_processing;
+use crate::location_processing;
+use crate::utilities::{self, clear_screen, escape_xml, get_optional_input};
+
+/* NOTE:
+ *  These are printed labels for sticking on copies and patron cards,
+ *  not the shelves and tags users file their books under, which are
+ *  in label_processing. Sheets are drawn as SVG, one file per page,
+ *  in millimetres, so they print at the right size from any browser.
+ */
+pub struct SheetLayout {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub page_width: f64,
+    pub page_height: f64,
+    pub columns: usize,
+    pub rows: usize,
+    pub label_width: f64,
+    pub label_height: f64,
+    pub left_margin: f64,
+    pub top_margin: f64,
+    // From the left of one label to the left of the next, and likewise down the page.
+    pub column_pitch: f64,
+    pub row_pitch: f64,
+}
+
+impl SheetLayout {
+    pub fn get_labels_per_sheet(&self) -> usize {
+        self.columns * self.rows
+    }
+}
+
+// Common Avery sheets. Item labels default to the first, patron cards to the first card sheet.
+pub const SHEET_LAYOUTS: [SheetLayout; 5] = [
+    SheetLayout {
+        name: "L7160", description: "A4, 21 labels of 63.5 x 38.1 mm",
+        page_width: 210.0, page_height: 297.0, columns: 3, rows: 7,
+        label_width: 63.5, label_height: 38.1, left_margin: 7.25, top_margin: 15.15, column_pitch: 66.04, row_pitch: 38.1,
+    },
+    SheetLayout {
+        name: "L7163", description: "A4, 14 labels of 99.1 x 38.1 mm",
+        page_width: 210.0, page_height: 297.0, columns: 2, rows: 7,
+        label_width: 99.1, label_height: 38.1, left_margin: 4.65, top_margin: 15.15, column_pitch: 101.6, row_pitch: 38.1,
+    },
+    SheetLayout {
+        name: "5160", description: "US Letter, 30 labels of 2.625 x 1 in",
+        page_width: 215.9, page_height: 279.4, columns: 3, rows: 10,
+        label_width: 66.675, label_height: 25.4, left_margin: 4.7625, top_margin: 12.7, column_pitch: 69.85, row_pitch: 25.4,
+    },
+    SheetLayout {
+        name: "C32011", description: "A4, 10 cards of 85 x 54 mm",
+        page_width: 210.0, page_height: 297.0, columns: 2, rows: 5,
+        label_width: 85.0, label_height: 54.0, left_margin: 15.0, top_margin: 13.5, column_pitch: 95.0, row_pitch: 54.0,
+    },
+    SheetLayout {
+        name: "5371", description: "US Letter, 10 cards of 3.5 x 2 in",
+        page_width: 215.9, page_height: 279.4, columns: 2, rows: 5,
+        label_width: 88.9, label_height: 50.8, left_margin: 19.05, top_margin: 12.7, column_pitch: 88.9, row_pitch: 50.8,
+    },
+];
+const DEFAULT_ITEM_LAYOUT: usize = 0;
+const DEFAULT_CARD_LAYOUT: usize = 3;
+
+// Scanners need a gap of at least ten modules either side of the bars.
+const QUIET_ZONE_MODULES: usize = 10;
+// Wider bars than this only make the barcode harder to fit without scanning any better.
+const MAX_MODULE_WIDTH: f64 = 0.5;
+
+// One label: lines of text, the first in bold, above a barcode.
+pub struct Label {
+    pub lines: Vec<String>,
+    pub barcode: String,
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let mut truncated: String = text.chars().take(max_chars.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+/*
+ *  Draws one label with its top left corner at (x, y). Text is sized
+ *  from the label's height and cut short to fit its width, and the
+ *  barcode takes the rest of the label, with its number underneath.
+ */
+fn draw_label(svg: &mut String, layout: &SheetLayout, x: f64, y: f64, label: &Label) -> Result<(), Box<dyn Error>> {
+    let padding = (layout.label_height * 0.08).clamp(1.5, 3.0);
+    let font_size = (layout.label_height * 0.09).clamp(2.2, 4.0);
+    let line_height = font_size * 1.25;
+    let inner_width = layout.label_width - 2.0 * padding;
+    // An average character is a little over half as wide as it is high.
+    let max_chars = (inner_width / (font_size * 0.55)) as usize;
+
+    let mut text_bottom = y + padding;
+    for (index, line) in label.lines.iter().enumerate() {
+        text_bottom += line_height;
+        writeln!(
+            svg,
+            r#"<text x="{:.2}" y="{:.2}" font-size="{:.2}"{}>{}</text>"#,
+            x + padding,
+            text_bottom - line_height * 0.2,
+            font_size,
+            if index == 0 { r#" font-weight="bold""# } else { "" },
+            escape_xml(&truncate(line, max_chars))
+        )?;
+    }
+
+    let (_, modules) = barcode_management::encode(&label.barcode)?;
+    let module_width = (inner_width / (modules.len() + 2 * QUIET_ZONE_MODULES) as f64).min(MAX_MODULE_WIDTH);
+    let number_size = font_size * 0.8;
+    let bars_left = x + (layout.label_width - module_width * modules.len() as f64) / 2.0;
+    let bars_top = text_bottom + padding * 0.5;
+    let bars_height = (y + layout.label_height - padding - number_size * 1.2 - bars_top).max(3.0);
+    let mut start = 0;
+    while start < modules.len() {
+        let end = modules[start..].iter().position(|&bar| bar != modules[start]).map_or(modules.len(), |run| start + run);
+        if modules[start] {
+            writeln!(
+                svg,
+                r#"<rect x="{:.3}" y="{:.2}" width="{:.3}" height="{:.2}"/>"#,
+                bars_left + start as f64 * module_width,
+                bars_top,
+                (end - start) as f64 * module_width,
+                bars_height
+            )?;
+        }
+        start = end;
+    }
+    writeln!(
+        svg,
+        r#"<text x="{:.2}" y="{:.2}" font-size="{:.2}" text-anchor="middle">{}</text>"#,
+        x + layout.label_width / 2.0,
+        y + layout.label_height - padding,
+        number_size,
+        escape_xml(&label.barcode)
+    )?;
+    Ok(())
+}
+
+/*
+ *  Lays the labels out in rows from the top left, starting at the given
+ *  position (from 1) so a partly used sheet can be fed in again. Returns
+ *  one SVG document for each page.
+ */
+pub fn render_sheets(layout: &SheetLayout, labels: &[Label], first_position: usize) -> Result<Vec<String>, Box<dyn Error>> {
+    let per_sheet = layout.get_labels_per_sheet();
+    let skipped = first_position.saturating_sub(1) % per_sheet;
+    let mut pages: Vec<String> = Vec::new();
+    for (index, label) in labels.iter().enumerate() {
+        let position = skipped + index;
+        if position / per_sheet == pages.len() {
+            pages.push(format!(
+                "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}mm\" height=\"{1}mm\" viewBox=\"0 0 {0} {1}\">\n<g font-family=\"sans-serif\">\n",
+                layout.page_width, layout.page_height
+            ));
+        }
+        let slot = position % per_sheet;
+        let x = layout.left_margin + (slot % layout.columns) as f64 * layout.column_pitch;
+        let y = layout.top_margin + (slot / layout.columns) as f64 * layout.row_pitch;
+        let page = pages.last_mut().ok_or("No page to draw on")?;
+        draw_label(page, layout, x, y, label)?;
+    }
+    for page in pages.iter_mut() {
+        page.push_str("</g>\n</svg>\n");
+    }
+    Ok(pages)
+}
+
+// Writes each page to <name>_<timestamp>/page_<n>.svg; returns the folder.
+fn write_sheets(name: &str, pages: &[String]) -> Result<String, Box<dyn Error>> {
+    let directory = format!("{}_{}", name, utilities::current_timestamp());
+    fs::create_dir_all(&directory)?;
+    for (index, page) in pages.iter().enumerate() {
+        fs::write(format!("{}/page_{}.svg", directory, index + 1), page)?;
+    }
+    Ok(directory)
+}
+
+// Labels for copies in shelf order: the call number in bold, then the title.
+fn get_item_labels(connection: &Connection, config: &Config, copies: &[(i64, Option<String>, String)]) -> Result<Vec<Label>, Box<dyn Error>> {
+    copies.iter()
+        .map(|(holding_id, call_number, title)| {
+            let barcode = barcode_management::get_or_assign_barcode(connection, &config.barcodes, BarcodeKind::Item, *holding_id)?;
+            let lines = match call_number {
+                Some(call_number) => vec![call_number.clone(), title.clone()],
+                None => vec![title.clone()],
+            };
+            Ok(Label { lines, barcode })
+        })
+        .collect()
+}
+
+fn get_patron_labels(connection: &Connection, config: &Config, email: Option<&str>) -> Result<Vec<Label>, Box<dyn Error>> {
+    let patrons = connection
+        .prepare(
+            "SELECT user_id, firstname, lastname FROM users
+             WHERE ?1 IS NULL OR email = ?1 COLLATE NOCASE
+             ORDER BY lastname COLLATE NOCASE, firstname COLLATE NOCASE",
+        )?
+        .query_map(params![email], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    patrons.into_iter()
+        .map(|(user_id, firstname, lastname)| {
+            let barcode = barcode_management::get_or_assign_barcode(connection, &config.barcodes, BarcodeKind::Patron, user_id)?;
+            Ok(Label { lines: vec!["Library Card".to_string(), format!("{} {}", firstname, lastname)], barcode })
+        })
+        .collect()
+}
+
+fn get_layout_from_user(default: usize) -> &'static SheetLayout {
+    for (index, layout) in SHEET_LAYOUTS.iter().enumerate() {
+        println!("\t{}. {} ({})", index + 1, layout.name, layout.description);
+    }
+    loop {
+        let Some(input) = get_optional_input(&format!("Label sheet [{}]:", default + 1)) else {
+            return &SHEET_LAYOUTS[default];
+        };
+        match input.parse::<usize>() {
+            Ok(choice) if (1..=SHEET_LAYOUTS.len()).contains(&choice) => return &SHEET_LAYOUTS[choice - 1],
+            _ => println!("Please enter a number from 1 to {}.", SHEET_LAYOUTS.len()),
+        }
+    }
+}
+
+fn get_first_position_from_user(layout: &SheetLayout) -> usize {
+    let per_sheet = layout.get_labels_per_sheet();
+    loop {
+        let Some(input) = get_optional_input(&format!("Start at which label on the first sheet (1-{}) [1]:", per_sheet)) else {
+            return 1;
+        };
+        match input.parse::<usize>() {
+            Ok(position) if (1..=per_sheet).contains(&position) => return position,
+            _ => println!("Please enter a number from 1 to {}.", per_sheet),
+        }
+    }
+}
+
+fn print_labels(name: &str, labels: Result<Vec<Label>, Box<dyn Error>>, default_layout: usize) {
+    let labels = match labels {
+        Ok(labels) if labels.is_empty() => {
+            println!("There is nothing to print.");
+            return;
+        }
+        Ok(labels) => labels,
+        Err(e) => {
+            println!("Failed to prepare the labels: {}", e);
+            return;
+        }
+    };
+    let layout = get_layout_from_user(default_layout);
+    let first_position = get_first_position_from_user(layout);
+    match render_sheets(layout, &labels, first_position).and_then(|pages| Ok((write_sheets(name, &pages)?, pages.len()))) {
+        Ok((directory, pages)) => println!(
+            "{} label(s) on {} sheet(s) were saved to {}. Print them at actual size.",
+            labels.len(), pages, directory
+        ),
+        Err(e) => println!("Failed to draw the labels: {}", e),
+    }
+}
+
+fn look_up_barcode(connection: &Connection) {
+    let Some(value) = get_optional_input("Scan or enter the barcode:") else { return; };
+    match barcode_management::find_barcode(connection, &value) {
+        Ok(Some(BarcodeOwner::Item(holding_id))) => match holding_processing::get_holding(connection, holding_id) {
+            Ok(Some(holding)) => {
+                let title: String = connection
+                    .query_row("SELECT title FROM books WHERE book_id = ?1", params![holding.book_id], |row| row.get(0))
+                    .unwrap_or_default();
+                println!("Copy of {} (Book ID {}):", title, holding.book_id);
+                holding.print_holding_info();
+            }
+            Ok(None) => println!("The copy with this barcode no longer exists."),
+            Err(e) => println!("Failed to read the copy: {}", e),
+        },
+        Ok(Some(BarcodeOwner::Patron(user_id))) => {
+            let patron = connection.query_row(
+                "SELECT email, firstname, lastname FROM users WHERE user_id = ?1",
+                params![user_id],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?)),
+            );
+            match patron {
+                Ok((email, firstname, lastname)) => println!("Patron: {} {} ({}), ID {}", firstname, lastname, email, user_id),
+                Err(e) => println!("Failed to read the patron: {}", e),
+            }
+        }
+        Ok(None) => println!("No copy or patron has the barcode {}.", value),
+        Err(e) => println!("Failed to search the barcodes: {}", e),
+    }
+}
+
+pub(crate) fn manage_barcodes(database_name: &str, config: &Config) {
+    let connection = match Connection::open(database_name) {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("Failed to connect to the database: {}", e);
+            return;
+        }
+    };
+
+    loop {
+        clear_screen();
+        print_barcodes_header();
+        println!(
+            "Choose from the options below:\n\
+            \t1. Issue missing barcodes\n\
+            \t2. Item labels for a location\n\
+            \t3. Item labels for a book's library copies\n\
+            \t4. Patron cards\n\
+            \t5. Look up a barcode\n\
+            \t0. Back"
+        );
+        let mut choice = String::new();
+        if io::stdin().read_line(&mut choice).is_err() {
+            println!("Failed to read input. Please try again.");
+            continue;
+        }
+
+        match choice.trim() {
+            "1" => match barcode_management::assign_missing_barcodes(database_name, &config.barcodes) {
+                Ok((items, patrons)) => println!(
+                    "Issued {} item barcode(s) and {} patron barcode(s) as {} with the prefix {}.",
+                    items,
+                    patrons,
+                    Symbology::from_name(&config.barcodes.symbology).unwrap_or(Symbology::Code128).get_name(),
+                    config.barcodes.prefix
+                ),
+                Err(e) => println!("Failed to issue barcodes: {}", e),
+            },
+            "2" => {
+                let Some(location) = location_processing::get_location_from_user(&connection, "Location path or ID:") else {
+                    utilities::pause(2);
+                    continue;
+                };
+                let labels = location_processing::get_shelf_list(&connection, location.location_id)
+                    .map_err(Box::<dyn Error>::from)
+                    .and_then(|entries| {
+                        let copies: Vec<_> = entries.into_iter().map(|entry| (entry.holding_id, entry.call_number, entry.title)).collect();
+                        get_item_labels(&connection, config, &copies)
+                    });
+                print_labels("item_labels", labels, DEFAULT_ITEM_LAYOUT);
+            }
+            "3" => {
+                let book_id = book_processing::get_book_id_from_user(&connection);
+                let labels = holding_processing::get_holdings_for_book(&connection, book_id, None)
+                    .and_then(|holdings| {
+                        let title: String = connection.query_row("SELECT title FROM books WHERE book_id = ?1", params![book_id], |row| row.get(0))?;
+                        Ok(holdings.into_iter().map(|holding| (holding.holding_id.unwrap_or_default(), holding.call_number, title.clone())).collect::<Vec<_>>())
+                    })
+                    .map_err(Box::<dyn Error>::from)
+                    .and_then(|copies| get_item_labels(&connection, config, &copies));
+                print_labels("item_labels", labels, DEFAULT_ITEM_LAYOUT);
+            }
+            "4" => {
+                let email = get_optional_input("Email of the patron (leave blank for every patron):");
+                let labels = get_patron_labels(&connection, config, email.as_deref());
+                print_labels("patron_cards", labels, DEFAULT_CARD_LAYOUT);
+            }
+            "5" => look_up_barcode(&connection),
+            "0" => return,
+            _ => println!("Invalid choice. Please try again."),
+        }
+        utilities::pause(2);
+    }
+}
+
+fn print_barcodes_header() {
+    println!("#########################");
+    println!("## Barcodes and Labels ##");
+    println!("#########################");
+}
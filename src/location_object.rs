@@ -0,0 +1,74 @@
+/* NOTE:
+ * Locations nest in a fixed order: a branch holds rooms, a room holds
+ * bookcases and a bookcase holds shelves, so the kind of a new location
+ * follows from where it is added. Kinds are stored by name in the
+ * locations table, so the names returned by get_name() must never
+ * change once released.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocationKind {
+    Branch,
+    Room,
+    Bookcase,
+    Shelf,
+}
+
+impl LocationKind {
+    pub fn from_name(name: &str) -> Option<LocationKind> {
+        match name.trim().to_lowercase().as_str() {
+            "branch" => Some(LocationKind::Branch),
+            "room" => Some(LocationKind::Room),
+            "bookcase" => Some(LocationKind::Bookcase),
+            "shelf" => Some(LocationKind::Shelf),
+            _ => None,
+        }
+    }
+
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            LocationKind::Branch => "branch",
+            LocationKind::Room => "room",
+            LocationKind::Bookcase => "bookcase",
+            LocationKind::Shelf => "shelf",
+        }
+    }
+
+    // The kind of the locations inside this one; shelves hold only books.
+    pub fn get_child_kind(&self) -> Option<LocationKind> {
+        match self {
+            LocationKind::Branch => Some(LocationKind::Room),
+            LocationKind::Room => Some(LocationKind::Bookcase),
+            LocationKind::Bookcase => Some(LocationKind::Shelf),
+            LocationKind::Shelf => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub location_id: i64,
+    pub parent_id: Option<i64>,
+    pub kind: LocationKind,
+    // The names from the branch down to this location.
+    pub path: Vec<String>,
+    // Copies kept here, not counting those in the locations inside it.
+    pub copies: u32,
+}
+
+impl Location {
+    pub fn get_path(&self) -> String {
+        self.path.join(" > ")
+    }
+
+    pub fn print_location_info(&self) {
+        println!(
+            "{}{} ({}, ID {}): {} cop{}",
+            "  ".repeat(self.path.len().saturating_sub(1)),
+            self.path.last().map_or("", String::as_str),
+            self.kind.get_name(),
+            self.location_id,
+            self.copies,
+            if self.copies == 1 { "y" } else { "ies" }
+        );
+    }
+}
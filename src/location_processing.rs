@@ -0,0 +1,539 @@
+use std::collections::HashMap;
+use std::io;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::json;
+use crate::audit_management;
+use crate::call_number_processing;
+use crate::catalogue_processing::get_field_from_user;
+use crate::holding_object::Holding;
+use crate::holding_processing;
+use crate::location_object::{Location, LocationKind};
+use crate::user_object::User;
+use crate::utilities::{self, clear_screen, get_optional_input, get_yes_or_no};
+
+// Separates the names in a path typed by the user, e.g. "Main / Reading Room / Case 3 / Shelf 2".
+const PATH_SEPARATORS: [char; 2] = ['/', '>'];
+// Joins names inside SQL, so paths can be split again here; it cannot be typed into a name.
+const SQL_PATH_SEPARATOR: char = '\u{1f}';
+
+// A copy on a shelf list, in call number order.
+pub(crate) struct ShelfListEntry {
+    pub(crate) holding_id: i64,
+    pub(crate) location_id: i64,
+    pub(crate) call_number: Option<String>,
+    pub(crate) title: String,
+    pub(crate) author: String,
+}
+
+pub fn find_location(connection: &Connection, parent_id: Option<i64>, name: &str) -> Result<Option<i64>, rusqlite::Error> {
+    connection.query_row(
+        "SELECT location_id FROM locations WHERE parent_id IS ?1 AND name = ?2 COLLATE NOCASE",
+        params![parent_id, name.trim()],
+        |row| row.get(0),
+    ).optional()
+}
+
+pub fn add_location(connection: &Connection, parent_id: Option<i64>, kind: LocationKind, name: &str) -> Result<i64, rusqlite::Error> {
+    connection.execute(
+        "INSERT INTO locations (parent_id, kind, name, created_at) VALUES (?1, ?2, ?3, ?4)",
+        params![parent_id, kind.get_name(), name.trim(), utilities::current_timestamp()],
+    )?;
+    Ok(connection.last_insert_rowid())
+}
+
+/// Every location, each followed by the ones inside it, in alphabetical order.
+pub fn get_locations(connection: &Connection) -> Result<Vec<Location>, rusqlite::Error> {
+    let mut stmt = connection.prepare(
+        "WITH RECURSIVE tree (location_id, parent_id, kind, path, sort_path) AS (
+            SELECT location_id, parent_id, kind, name, lower(name) FROM locations WHERE parent_id IS NULL
+            UNION ALL
+            SELECT locations.location_id, locations.parent_id, locations.kind,
+                   tree.path || char(31) || locations.name, tree.sort_path || char(31) || lower(locations.name)
+            FROM locations JOIN tree ON locations.parent_id = tree.location_id
+         )
+         SELECT tree.location_id, tree.parent_id, tree.kind, tree.path,
+                (SELECT COUNT(*) FROM holdings WHERE holdings.location_id = tree.location_id)
+         FROM tree ORDER BY tree.sort_path",
+    )?;
+    let locations = stmt.query_map([], |row| {
+        Ok(Location {
+            location_id: row.get(0)?,
+            parent_id: row.get(1)?,
+            kind: LocationKind::from_name(&row.get::<_, String>(2)?).unwrap_or(LocationKind::Shelf),
+            path: row.get::<_, String>(3)?.split(SQL_PATH_SEPARATOR).map(str::to_string).collect(),
+            copies: row.get(4)?,
+        })
+    })?;
+    locations.collect()
+}
+
+pub fn get_location(connection: &Connection, location_id: i64) -> Result<Option<Location>, rusqlite::Error> {
+    Ok(get_locations(connection)?.into_iter().find(|location| location.location_id == location_id))
+}
+
+/// Finds a location by its path, e.g. "Main / Reading Room", ignoring case and extra spaces.
+pub fn resolve_location_path(connection: &Connection, path: &str) -> Result<Option<i64>, rusqlite::Error> {
+    let mut location_id = None;
+    for name in path.split(PATH_SEPARATORS).map(str::trim).filter(|name| !name.is_empty()) {
+        match find_location(connection, location_id, name)? {
+            Some(found) => location_id = Some(found),
+            None => return Ok(None),
+        }
+    }
+    Ok(location_id)
+}
+
+pub fn rename_location(connection: &Connection, location_id: i64, name: &str) -> Result<(), rusqlite::Error> {
+    connection.execute(
+        "UPDATE locations SET name = ?2 WHERE location_id = ?1",
+        params![location_id, name.trim()],
+    )?;
+    Ok(())
+}
+
+/// Deletes a location if nothing is inside it; returns false if something is.
+pub fn delete_location(connection: &Connection, location_id: i64) -> Result<bool, rusqlite::Error> {
+    let in_use: bool = connection.query_row(
+        "SELECT EXISTS (SELECT 1 FROM locations WHERE parent_id = ?1)
+             OR EXISTS (SELECT 1 FROM holdings WHERE location_id = ?1)",
+        params![location_id],
+        |row| row.get(0),
+    )?;
+    if in_use {
+        return Ok(false);
+    }
+    connection.execute("DELETE FROM locations WHERE location_id = ?1", params![location_id])?;
+    Ok(true)
+}
+
+/*
+ *  Records where a copy is and its call number, keeping the call
+ *  number's sort key up to date with it. The change is audited, since
+ *  moving a library copy is how books go missing.
+ */
+pub fn set_holding_location(connection: &mut Connection, user_id: i32, holding: &Holding, location_id: Option<i64>, call_number: Option<&str>) -> Result<(), rusqlite::Error> {
+    let transaction = connection.transaction()?;
+    let holding_id = holding.holding_id.unwrap_or_default();
+    transaction.execute(
+        "UPDATE holdings SET location_id = ?2, call_number = ?3, call_number_sort = ?4 WHERE holding_id = ?1",
+        params![holding_id, location_id, call_number, call_number.map(call_number_processing::get_sort_key)],
+    )?;
+    let get_path = |location_id: Option<i64>| -> Result<Option<String>, rusqlite::Error> {
+        Ok(match location_id {
+            Some(location_id) => get_location(&transaction, location_id)?.map(|location| location.get_path()),
+            None => None,
+        })
+    };
+    audit_management::record_event(
+        &transaction,
+        Some(user_id),
+        audit_management::HOLDING_LOCATED,
+        &format!("holding:{}", holding_id),
+        json!({
+            "holding_id": holding_id,
+            "book_id": holding.book_id,
+            "location": { "old": get_path(holding.location_id)?, "new": get_path(location_id)? },
+            "call_number": { "old": holding.call_number, "new": call_number },
+        }),
+    )?;
+    transaction.commit()
+}
+
+// The copies in a location and every location inside it, in call number order; copies without one come last.
+pub(crate) fn get_shelf_list(connection: &Connection, location_id: i64) -> Result<Vec<ShelfListEntry>, rusqlite::Error> {
+    let mut stmt = connection.prepare(
+        "WITH RECURSIVE inside (location_id) AS (
+            SELECT ?1
+            UNION ALL
+            SELECT locations.location_id FROM locations JOIN inside ON locations.parent_id = inside.location_id
+         )
+         SELECT holdings.holding_id, holdings.location_id, holdings.call_number, books.title, books.author
+         FROM holdings JOIN books ON books.book_id = holdings.book_id
+         WHERE holdings.location_id IN (SELECT location_id FROM inside)
+         ORDER BY holdings.call_number_sort IS NULL, holdings.call_number_sort, books.title COLLATE NOCASE, holdings.holding_id",
+    )?;
+    let entries = stmt.query_map(params![location_id], |row| {
+        Ok(ShelfListEntry {
+            holding_id: row.get(0)?,
+            location_id: row.get(1)?,
+            call_number: row.get(2)?,
+            title: row.get(3)?,
+            author: row.get(4)?,
+        })
+    })?;
+    entries.collect()
+}
+
+// A location given by its ID, or by its path for anything that is not a number.
+fn find_location_by_input(connection: &Connection, input: &str) -> Result<Option<Location>, rusqlite::Error> {
+    let location_id = match input.trim().parse::<i64>() {
+        Ok(location_id) => Some(location_id),
+        Err(_) => resolve_location_path(connection, input)?,
+    };
+    match location_id {
+        Some(location_id) => get_location(connection, location_id),
+        None => Ok(None),
+    }
+}
+
+/*
+ *  Asks for a location by its path or ID. A blank answer gives None,
+ *  and so does a location that does not exist, after saying so.
+ */
+pub(crate) fn get_location_from_user(connection: &Connection, prompt: &str) -> Option<Location> {
+    let input = get_optional_input(prompt)?;
+    match find_location_by_input(connection, &input) {
+        Ok(Some(location)) => Some(location),
+        Ok(None) => {
+            println!("There is no location \"{}\". Use List locations to see them.", input);
+            None
+        }
+        Err(e) => {
+            println!("Failed to search the locations: {}", e);
+            None
+        }
+    }
+}
+
+fn get_location_name_from_user(prompt: &str) -> Option<String> {
+    loop {
+        let name = get_optional_input(prompt)?;
+        if name.contains(PATH_SEPARATORS) || name.contains(SQL_PATH_SEPARATOR) {
+            println!("A name cannot contain / or >, as they separate the names in a path.");
+            continue;
+        }
+        return Some(name);
+    }
+}
+
+fn get_holding_from_user(connection: &Connection, user: &User) -> Option<Holding> {
+    let holding_id = loop {
+        match get_optional_input("Copy ID (shown in View Book Details):")?.parse::<i64>() {
+            Ok(holding_id) => break holding_id,
+            Err(_) => println!("Invalid copy ID. Please enter a number."),
+        }
+    };
+    match holding_processing::get_holding(connection, holding_id) {
+        // Only the library's own copies, and the user's, can be placed.
+        Ok(Some(holding)) if holding.owner_user_id.is_none_or(|owner| owner == user.get_user_id()) => Some(holding),
+        Ok(_) => {
+            println!("There is no library copy, or copy of yours, with ID {}.", holding_id);
+            None
+        }
+        Err(e) => {
+            println!("Failed to read the copy: {}", e);
+            None
+        }
+    }
+}
+
+fn place_holding_from_user(connection: &mut Connection, user: &User) -> bool {
+    let Some(holding) = get_holding_from_user(connection, user) else { return false; };
+    holding.print_holding_info();
+
+    println!("Press enter to keep the value in [brackets], or enter - to clear it.");
+    let location_id = loop {
+        let prompt = "Location path or ID, e.g. Main / Reading Room / Case 3 / Shelf 2";
+        let Some(input) = get_field_from_user(prompt, holding.location_path.as_deref()) else { break None; };
+        if holding.location_path.as_deref() == Some(input.as_str()) {
+            break holding.location_id;
+        }
+        match find_location_by_input(connection, &input) {
+            Ok(Some(location)) => break Some(location.location_id),
+            Ok(None) => println!("There is no location \"{}\". Please try again.", input),
+            Err(e) => {
+                println!("Failed to search the locations: {}", e);
+                return false;
+            }
+        }
+    };
+    let call_number = get_field_from_user("Call number, e.g. 823.912 TOL or PR6039.O32 L6 1991", holding.call_number.as_deref());
+    if location_id == holding.location_id && call_number == holding.call_number {
+        println!("No changes were made.");
+        return false;
+    }
+
+    match set_holding_location(connection, user.get_user_id(), &holding, location_id, call_number.as_deref()) {
+        Ok(()) => {
+            if let Some(call_number) = &call_number {
+                println!("Filed as a {} call number.", call_number_processing::detect_scheme(call_number).get_name());
+            }
+            true
+        }
+        Err(e) => {
+            println!("Failed to place the copy: {}", e);
+            false
+        }
+    }
+}
+
+/*
+ *  Prints the copies on a shelf, or in any other location and those
+ *  inside it, in the order they should stand. Copies on a shelf below
+ *  the chosen location show which shelf they are on.
+ */
+fn print_shelf_list(connection: &Connection, location: &Location, max_rows: usize) {
+    let entries = match get_shelf_list(connection, location.location_id) {
+        Ok(entries) => entries,
+        Err(e) => {
+            println!("Failed to read the shelf list: {}", e);
+            return;
+        }
+    };
+    let paths: HashMap<i64, String> = get_locations(connection)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|inner| (inner.location_id, inner.path[location.path.len().min(inner.path.len())..].join(" > ")))
+        .collect();
+
+    println!("Shelf list for {} ({} {}):", location.get_path(), entries.len(), if entries.len() == 1 { "copy" } else { "copies" });
+    for entry in entries.iter().take(max_rows) {
+        let place = paths.get(&entry.location_id).filter(|place| !place.is_empty());
+        println!(
+            "  {:<24} {} by {} (Copy ID {}){}",
+            entry.call_number.as_deref().unwrap_or("[no call number]"),
+            entry.title,
+            entry.author,
+            entry.holding_id,
+            place.map(|place| format!(", {}", place)).unwrap_or_default()
+        );
+    }
+    if entries.len() > max_rows {
+        println!("Only the first {} of {} copies are shown.", max_rows, entries.len());
+    }
+}
+
+/// The shelf list report on its own, for the admin panel.
+pub(crate) fn view_shelf_list(database_name: &str, max_rows: usize) -> bool {
+    clear_screen();
+    print_shelf_list_header();
+    let connection = match Connection::open(database_name) {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("Failed to connect to the database: {}", e);
+            return false;
+        }
+    };
+    let Some(location) = get_location_from_user(&connection, "Shelf (or other location) path or ID:") else { return false; };
+    print_shelf_list(&connection, &location, max_rows);
+    true
+}
+
+pub(crate) fn manage_locations(database_name: &str, user: &User, max_rows: usize) -> bool {
+    let mut connection = match Connection::open(database_name) {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("Failed to connect to the database: {}", e);
+            return false;
+        }
+    };
+    let mut changed = false;
+
+    loop {
+        clear_screen();
+        print_locations_header();
+        println!(
+            "Choose from the options below:\n\
+            \t1. List locations\n\
+            \t2. Add a location\n\
+            \t3. Rename a location\n\
+            \t4. Delete an empty location\n\
+            \t5. Place a copy (location and call number)\n\
+            \t6. Shelf list\n\
+            \t0. Back"
+        );
+        let mut choice = String::new();
+        if io::stdin().read_line(&mut choice).is_err() {
+            println!("Failed to read input. Please try again.");
+            continue;
+        }
+
+        match choice.trim() {
+            "1" => match get_locations(&connection) {
+                Ok(locations) if locations.is_empty() => println!("No locations have been added yet."),
+                Ok(locations) => locations.iter().for_each(Location::print_location_info),
+                Err(e) => println!("Error retrieving the locations: {}", e),
+            },
+            "2" => {
+                let parent = match get_optional_input("Add inside which location (path or ID; leave blank to add a new branch)?") {
+                    None => None,
+                    Some(input) => match find_location_by_input(&connection, &input) {
+                        Ok(Some(parent)) => Some(parent),
+                        Ok(None) => {
+                            println!("There is no location \"{}\".", input);
+                            utilities::pause(2);
+                            continue;
+                        }
+                        Err(e) => {
+                            println!("Failed to search the locations: {}", e);
+                            utilities::pause(2);
+                            continue;
+                        }
+                    },
+                };
+                let kind = match &parent {
+                    None => LocationKind::Branch,
+                    Some(parent) => match parent.kind.get_child_kind() {
+                        Some(kind) => kind,
+                        None => {
+                            println!("A shelf cannot hold other locations.");
+                            utilities::pause(2);
+                            continue;
+                        }
+                    },
+                };
+                let parent_id = parent.as_ref().map(|parent| parent.location_id);
+                let Some(name) = get_location_name_from_user(&format!("Name of the new {}:", kind.get_name())) else { continue; };
+                match find_location(&connection, parent_id, &name) {
+                    Ok(Some(_)) => println!("There is already a location called \"{}\" there.", name),
+                    Ok(None) => match add_location(&connection, parent_id, kind, &name) {
+                        Ok(location_id) => {
+                            println!("Added the {} \"{}\" (ID {}).", kind.get_name(), name, location_id);
+                            changed = true;
+                        }
+                        Err(e) => println!("Failed to add the location: {}", e),
+                    },
+                    Err(e) => println!("Failed to search the locations: {}", e),
+                }
+            }
+            "3" => {
+                let Some(location) = get_location_from_user(&connection, "Location to rename (path or ID):") else {
+                    utilities::pause(2);
+                    continue;
+                };
+                let Some(name) = get_location_name_from_user("New name:") else { continue; };
+                match find_location(&connection, location.parent_id, &name) {
+                    Ok(Some(existing_id)) if existing_id != location.location_id => {
+                        println!("There is already a location called \"{}\" there.", name);
+                    }
+                    Ok(_) => match rename_location(&connection, location.location_id, &name) {
+                        Ok(()) => {
+                            println!("Renamed to \"{}\".", name);
+                            changed = true;
+                        }
+                        Err(e) => println!("Failed to rename the location: {}", e),
+                    },
+                    Err(e) => println!("Failed to search the locations: {}", e),
+                }
+            }
+            "4" => {
+                let Some(location) = get_location_from_user(&connection, "Location to delete (path or ID):") else {
+                    utilities::pause(2);
+                    continue;
+                };
+                println!("Delete {}? (y/n)", location.get_path());
+                if get_yes_or_no() {
+                    match delete_location(&connection, location.location_id) {
+                        Ok(true) => {
+                            println!("Deleted.");
+                            changed = true;
+                        }
+                        Ok(false) => println!("Only empty locations can be deleted. Move its copies and locations elsewhere first."),
+                        Err(e) => println!("Failed to delete the location: {}", e),
+                    }
+                }
+            }
+            "5" => {
+                if place_holding_from_user(&mut connection, user) {
+                    println!("Copy placed.");
+                    changed = true;
+                }
+            }
+            "6" => {
+                if let Some(location) = get_location_from_user(&connection, "Shelf (or other location) path or ID:") {
+                    print_shelf_list(&connection, &location, max_rows);
+                }
+            }
+            "0" => return changed,
+            _ => println!("Invalid choice. Please try again."),
+        }
+        utilities::pause(2);
+    }
+}
+
+fn print_locations_header() {
+    println!("################################");
+    println!("## Locations and Call Numbers ##");
+    println!("################################");
+}
+
+fn print_shelf_list_header() {
+    println!("################");
+    println!("## Shelf List ##");
+    println!("################");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialisation;
+
+    // Main > Reading Room > Case 3 > Shelf 1 and Shelf 2, returning the IDs in that order.
+    fn add_locations(connection: &Connection) -> Vec<i64> {
+        let branch = add_location(connection, None, LocationKind::Branch, "Main").unwrap();
+        let room = add_location(connection, Some(branch), LocationKind::Room, "Reading Room").unwrap();
+        let bookcase = add_location(connection, Some(room), LocationKind::Bookcase, "Case 3").unwrap();
+        let first = add_location(connection, Some(bookcase), LocationKind::Shelf, "Shelf 1").unwrap();
+        let second = add_location(connection, Some(bookcase), LocationKind::Shelf, "Shelf 2").unwrap();
+        vec![branch, room, bookcase, first, second]
+    }
+
+    #[test]
+    fn paths_are_found_whatever_their_case_and_spacing() {
+        let (_, connection) = initialisation::open_test_database("locations_paths");
+        let ids = add_locations(&connection);
+        assert_eq!(resolve_location_path(&connection, " main/reading room >CASE 3 / shelf 2").unwrap(), Some(ids[4]));
+        assert_eq!(resolve_location_path(&connection, "Main / Shelf 2").unwrap(), None);
+        assert_eq!(get_location(&connection, ids[3]).unwrap().unwrap().path, vec!["Main", "Reading Room", "Case 3", "Shelf 1"]);
+        // Names are unique among the locations in the same place only.
+        assert!(add_location(&connection, Some(ids[2]), LocationKind::Shelf, "shelf 1").is_err());
+        add_location(&connection, None, LocationKind::Branch, "Shelf 1").unwrap();
+    }
+
+    #[test]
+    fn only_empty_locations_can_be_deleted() {
+        let (_, connection) = initialisation::open_test_database("locations_deleted");
+        let ids = add_locations(&connection);
+        connection.execute_batch(&format!(
+            "INSERT INTO books (book_id, title, author) VALUES (1, 'The Hobbit', 'J. R. R. Tolkien');
+             INSERT INTO holdings (book_id, created_at, location_id) VALUES (1, 0, {});",
+            ids[3]
+        )).unwrap();
+
+        assert!(!delete_location(&connection, ids[2]).unwrap());
+        assert!(!delete_location(&connection, ids[3]).unwrap());
+        assert!(delete_location(&connection, ids[4]).unwrap());
+        assert_eq!(get_locations(&connection).unwrap().len(), 4);
+
+        connection.execute("UPDATE holdings SET location_id = NULL", []).unwrap();
+        for location_id in ids[..4].iter().rev() {
+            assert!(delete_location(&connection, *location_id).unwrap());
+        }
+        assert!(get_locations(&connection).unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_shelf_list_covers_every_location_inside_in_call_number_order() {
+        let (_, mut connection) = initialisation::open_test_database("locations_shelf_list");
+        let ids = add_locations(&connection);
+        connection.execute_batch(
+            "INSERT INTO books (book_id, title, author) VALUES (1, 'A', 'X'), (2, 'B', 'Y'), (3, 'C', 'Z');
+             INSERT INTO holdings (holding_id, book_id, created_at) VALUES (1, 1, 0), (2, 2, 0), (3, 3, 0);",
+        ).unwrap();
+        for (holding_id, location_id, call_number) in [(1, ids[3], Some("QA76.9 .D3 2003")), (2, ids[4], Some("QA76.13 .B2")), (3, ids[4], None)] {
+            let holding = holding_processing::get_holding(&connection, holding_id).unwrap().unwrap();
+            set_holding_location(&mut connection, 1, &holding, Some(location_id), call_number).unwrap();
+        }
+
+        let holding_ids = |location_id: i64| -> Vec<i64> {
+            get_shelf_list(&connection, location_id).unwrap().iter().map(|entry| entry.holding_id).collect()
+        };
+        // QA76.13 comes before QA76.9, as the class number is a decimal.
+        assert_eq!(holding_ids(ids[0]), vec![2, 1, 3]);
+        assert_eq!(holding_ids(ids[4]), vec![2, 3]);
+        let moves: i64 = connection.query_row(
+            "SELECT COUNT(*) FROM audit_log WHERE action = ?1", params![audit_management::HOLDING_LOCATED], |row| row.get(0),
+        ).unwrap();
+        assert_eq!(moves, 3);
+    }
+}
@@ -0,0 +1,186 @@
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use lettre::message::header::ContentType;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use crate::configuration::{Config, MailConfig};
+use crate::utilities;
+
+#[derive(Debug, Clone)]
+pub struct EmailMessage {
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl EmailMessage {
+    pub fn to_lettre_message(&self) -> Result<Message, Box<dyn Error>> {
+        let message = Message::builder()
+            .from(self.from.parse()?)
+            .to(self.to.parse()?)
+            .subject(self.subject.as_str())
+            .header(ContentType::TEXT_PLAIN)
+            .body(self.body.clone())?;
+        Ok(message)
+    }
+}
+
+/*
+ *  Anything that can deliver an email. Which transport is used is decided
+ *  by the "transport" setting of the mail section of the configuration:
+ *      smtp   - send through an SMTP relay
+ *      file   - write .eml files to a directory (the default)
+ *      memory - keep messages in memory, for tests
+ */
+pub trait Mailer {
+    fn send(&self, message: &EmailMessage) -> Result<(), Box<dyn Error>>;
+}
+
+pub struct SmtpMailer {
+    transport: SmtpTransport,
+}
+
+impl SmtpMailer {
+    pub fn new(mail_config: &MailConfig) -> Result<Self, Box<dyn Error>> {
+        let host = mail_config.smtp_host.as_deref().ok_or("No SMTP host configured")?;
+        let mut builder = match mail_config.smtp_security.as_deref().unwrap_or("starttls") {
+            "tls" => SmtpTransport::relay(host)?,
+            "starttls" => SmtpTransport::starttls_relay(host)?,
+            "none" => SmtpTransport::builder_dangerous(host),
+            other => return Err(format!("Unknown SMTP security setting: {}", other).into()),
+        };
+        if let Some(port) = mail_config.smtp_port {
+            builder = builder.port(port);
+        }
+        if let (Some(username), Some(password)) = (&mail_config.smtp_username, &mail_config.smtp_password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        Ok(SmtpMailer { transport: builder.build() })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, message: &EmailMessage) -> Result<(), Box<dyn Error>> {
+        self.transport.send(&message.to_lettre_message()?)?;
+        Ok(())
+    }
+}
+
+pub struct FileMailer {
+    directory: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(directory: &str) -> Self {
+        FileMailer { directory: PathBuf::from(directory) }
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, message: &EmailMessage) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(&self.directory)?;
+        let file_name = format!(
+            "{}-{}.eml",
+            utilities::current_timestamp(),
+            utilities::generate_token(8)
+        );
+        fs::write(self.directory.join(file_name), message.to_lettre_message()?.formatted())?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryMailer {
+    sent: Mutex<Vec<EmailMessage>>,
+}
+
+impl MemoryMailer {
+    // Only the tests, which construct a MemoryMailer directly, read back what was sent.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn get_sent(&self) -> Vec<EmailMessage> {
+        self.sent.lock().map(|sent| sent.clone()).unwrap_or_default()
+    }
+}
+
+impl Mailer for MemoryMailer {
+    fn send(&self, message: &EmailMessage) -> Result<(), Box<dyn Error>> {
+        self.sent.lock().map_err(|e| e.to_string())?.push(message.clone());
+        Ok(())
+    }
+}
+
+pub fn mailer_from_config(config: &Config) -> Result<Box<dyn Mailer>, Box<dyn Error>> {
+    let mail_config = &config.mail;
+    match mail_config.transport.as_str() {
+        "smtp" => Ok(Box::new(SmtpMailer::new(mail_config)?)),
+        "file" => Ok(Box::new(FileMailer::new(&mail_config.outbox_directory))),
+        "memory" => Ok(Box::new(MemoryMailer::default())),
+        other => Err(format!("Unknown mail transport: {}", other).into()),
+    }
+}
+
+pub fn send_email(config: &Config, to: &str, subject: &str, body: &str) -> Result<(), Box<dyn Error>> {
+    let message = EmailMessage {
+        from: config.mail.from_address.clone(),
+        to: to.to_string(),
+        subject: subject.to_string(),
+        body: body.to_string(),
+    };
+    mailer_from_config(config)?.send(&message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(to: &str) -> EmailMessage {
+        EmailMessage {
+            from: "rLMS <library@example.org>".to_string(),
+            to: to.to_string(),
+            subject: "Verify your email address".to_string(),
+            body: "Your code is 1234.".to_string(),
+        }
+    }
+
+    #[test]
+    fn memory_mailer_keeps_messages_in_order() {
+        let mailer = MemoryMailer::default();
+        mailer.send(&message("first@example.org")).unwrap();
+        mailer.send(&message("second@example.org")).unwrap();
+        let sent = mailer.get_sent();
+        assert_eq!(sent.iter().map(|message| message.to.as_str()).collect::<Vec<&str>>(), vec!["first@example.org", "second@example.org"]);
+        assert_eq!(sent[0].subject, "Verify your email address");
+    }
+
+    #[test]
+    fn file_mailer_writes_one_eml_file_per_message() {
+        let directory = std::env::temp_dir().join(format!("rlms-outbox-{}", utilities::generate_token(8)));
+        let mailer = FileMailer::new(&directory.to_string_lossy());
+        mailer.send(&message("reader@example.org")).unwrap();
+        let files: Vec<PathBuf> = fs::read_dir(&directory).unwrap().map(|entry| entry.unwrap().path()).collect();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].extension().and_then(|extension| extension.to_str()), Some("eml"));
+        let contents = fs::read_to_string(&files[0]).unwrap();
+        assert!(contents.contains("To: reader@example.org"), "{}", contents);
+        assert!(contents.contains("Subject: Verify your email address"), "{}", contents);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn invalid_addresses_are_refused() {
+        assert!(message("not an address").to_lettre_message().is_err());
+        assert!(FileMailer::new(&std::env::temp_dir().to_string_lossy()).send(&message("not an address")).is_err());
+    }
+
+    #[test]
+    fn mailer_from_config_rejects_unknown_transports() {
+        let mut config = Config::default();
+        config.mail.transport = "memory".to_string();
+        assert!(mailer_from_config(&config).is_ok());
+        config.mail.transport = "pigeon".to_string();
+        assert!(mailer_from_config(&config).is_err());
+    }
+}
@@ -5,70 +5,178 @@ mod user_management;
 mod user_object;
 mod book_processing;
 mod book_object;
+mod holding_object;
+mod holding_processing;
+mod location_object;
+mod location_processing;
+mod call_number_processing;
+mod barcode_management;
+mod label_sheet_processing;
+mod stocktake_object;
+mod stocktake_processing;
+mod statistics_object;
+mod statistics_processing;
+mod duplicate_object;
+mod duplicate_processing;
+mod opds_object;
+mod opds_processing;
+mod oai_object;
+mod oai_processing;
+mod sru_object;
+mod cql_processing;
+mod sru_processing;
+mod notification_object;
+mod notification_processing;
+mod reading_object;
+mod label_object;
+mod label_processing;
+mod series_processing;
+mod reading_processing;
+mod work_processing;
+mod catalogue_processing;
+mod cover_management;
+mod provenance_management;
 mod user_processing;
+mod admin_processing;
+mod role_object;
+mod mailer;
+mod token_management;
+mod totp_management;
+mod audit_management;
+mod commands;
+mod seed_management;
+mod backup_management;
+mod web_server;
 
 use std::io::Write;
 use anyhow::Result;
 use configuration::Config;
-use crate::utilities::{clear_screen, get_menu_choice, pause, process_user_menu_choice};
+use crate::utilities::{clear_screen, get_menu_choice, get_menu_items, pause, process_menu_choice};
 use crate::user_object::User;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(commands::run(&args).await);
+    }
+
     clear_screen();
     std::io::stdout().flush()?;
 
     // Load configuration
     let config_path = utilities::get_config_path();
-    let mut config: Config = Config::default();
-    if let Some(loaded_config) = Config::load(config_path.to_str().unwrap()) {
-        println!("Configuration file found");
-        println!("Loaded configuration: {:?}", loaded_config);
-        config = loaded_config;
-    } else {
-        println!("Configuration file not found. Running initialisation...");
-        pause(2);
-        initialisation::check_initial(&mut config, config_path.to_str().unwrap());
-        config.save(config_path.to_str().unwrap())?;
+    let config_path = config_path.to_str().unwrap();
+    let mut config: Config = match Config::load(config_path) {
+        Ok(Some(loaded_config)) => {
+            println!("Configuration file found");
+            loaded_config
+        }
+        Ok(None) if seed_management::seed_requested() => {
+            println!("Configuration file not found. Running setup from the seed...");
+            let seed_path = std::env::var("RLMS_SEED_FILE").ok();
+            match seed_management::run_headless_setup(config_path, seed_path.as_deref()) {
+                Ok(report) => seed_management::print_report(&report),
+                Err(e) => {
+                    println!("Setup failed: {}", e);
+                    println!("Program terminating");
+                    std::process::exit(1);
+                }
+            }
+            Config::load(config_path)?.unwrap_or_default()
+        }
+        Ok(None) => {
+            println!("Configuration file not found. Running initialisation...");
+            pause(2);
+            let mut config = Config::default();
+            initialisation::check_initial(&mut config, config_path);
+            config.save(config_path)?;
+            config
+        }
+        Err(e) => {
+            println!("{}", e);
+            println!("Program terminating");
+            std::process::exit(1);
+        }
+    };
+
+    // Overrides are applied after saving so they are never written to the file.
+    if let Err(e) = config.apply_env_overrides() {
+        println!("{}", e);
+        println!("Program terminating");
+        std::process::exit(1);
+    }
+    let errors = config.validate();
+    if !errors.is_empty() {
+        println!("The configuration has {} problem(s):", errors.len());
+        for error in errors {
+            println!("\t{}", error);
+        }
+        println!("Program terminating");
+        std::process::exit(1);
+    }
+    utilities::set_pause_scale(config.interface.pause_scale);
+    if let Err(e) = utilities::init_logging(&config.logging) {
+        println!("Failed to set up logging: {}", e);
+    }
+
+    if !initialisation::update_existing_tables(
+        config.database.file.as_deref().expect("Failed to read configuration file.")
+    ) {
+        println!("Failed to update the database tables.");
+        println!("Program terminating");
+        std::process::exit(1);
+    }
+    if let Err(e) = user_management::init_dummy_password_hash(&config) {
+        println!("Failed to prepare for logins: {}", e);
+        println!("Program terminating");
+        std::process::exit(1);
     }
 
     let mut logged_in: bool = false;
     clear_screen();
     let mut user: User = User::default();
     while !logged_in {
-        let choice = get_menu_choice("login");
+        let choice = get_menu_choice("login", &[]);
         match choice {
             1 => {
-                let mut count: u32 = 1;
-                loop {
-                    if count >= 3 {
-                        println!("Too many login attempts.");
-                        break;
-                    }
-                    let (user_check, is_valid) = user_management::login_user(
-                        config.database_file.as_deref().expect("Failed to read configuration file.")
-                    );
-                    if is_valid {
-                        user = user_check;
-                        logged_in = true;
-                        println!("Login successful!");
-                        break;
-                    } else {
-                        count += 1;
-                        println!("Invalid credentials. Attempt {}/3.", count);
-                    }
+                // Failed attempts and lockouts are tracked per account in the
+                // database by login_user, so there is no local counter here.
+                let (user_check, is_valid) = user_management::login_user(
+                    config.database.file.as_deref().expect("Failed to read configuration file."),
+                    &config
+                );
+                if is_valid {
+                    user = user_check;
+                    logged_in = true;
+                    println!("Login successful!");
+                } else {
+                    pause(2);
                 }
             },
             2 => {
                 user_management::register_user(
-                    config.database_file.as_deref().expect("Failed to read configuration file.")
+                    config.database.file.as_deref().expect("Failed to read configuration file."),
+                    &config,
+                    None
                 );
             },
             3 => {
+                if user_management::reset_forgotten_password(
+                    config.database.file.as_deref().expect("Failed to read configuration file."),
+                    &config
+                ) {
+                    println!("Password reset successfully. You can now log in.");
+                } else {
+                    println!("Password was not reset.");
+                }
+                pause(2);
+            },
+            4 => {
                 println!("Exiting program...");
                 std::process::exit(0);
             },
-            0_usize | 4_usize.. => {
+            0_usize | 5_usize.. => {
                 println!("Exiting program...");
                 std::process::exit(0);
             }
@@ -78,16 +186,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut run_program: bool = true;
     while run_program {
         clear_screen();
-        if user.get_is_admin() {
-            utilities::print_admin_menu(true);
-            // Implement admin menu functionality here
-            // For example:
-            // run_program = process_admin_menu_choice(get_menu_choice("admin")).await;
-        } else {
-            utilities::print_user_menu(true);
-            let choice = get_menu_choice("user");
-            run_program = process_user_menu_choice(choice, &user, config.database_file.as_deref().expect("Failed to read configuration file.")).await;
-        }
+        // Recalculated each time, as verifying an email address unlocks more of the menu.
+        let items = get_menu_items(&user);
+        let choice = get_menu_choice("user", &items);
+        run_program = process_menu_choice(choice, &items, &mut user, config.database.file.as_deref().expect("Failed to read configuration file."), &config).await;
     }
     println!("Exiting program...");
     Ok(())
@@ -0,0 +1,158 @@
+use crate::utilities;
+
+/*
+ *  What patrons are told about: a loan coming due, a loan overdue (sent
+ *  again at each of notifications.overdue_steps_days) and a held book
+ *  waiting to be collected.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NotificationKind {
+    DueSoon,
+    Overdue,
+    HoldReady,
+}
+
+impl NotificationKind {
+    pub const ALL: [NotificationKind; 3] = [NotificationKind::DueSoon, NotificationKind::Overdue, NotificationKind::HoldReady];
+
+    // As stored in notification_log.
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            NotificationKind::DueSoon => "due-soon",
+            NotificationKind::Overdue => "overdue",
+            NotificationKind::HoldReady => "hold-ready",
+        }
+    }
+
+    pub fn get_template_name(&self) -> &'static str {
+        match self {
+            NotificationKind::DueSoon => "due_soon.txt",
+            NotificationKind::Overdue => "overdue.txt",
+            NotificationKind::HoldReady => "hold_ready.txt",
+        }
+    }
+
+    pub fn get_description(&self) -> &'static str {
+        match self {
+            NotificationKind::DueSoon => "Reminders before a loan is due",
+            NotificationKind::Overdue => "Overdue notices",
+            NotificationKind::HoldReady => "Holds ready to collect",
+        }
+    }
+}
+
+// A patron's choices; patrons who have not chosen get every notification.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NotificationPreferences {
+    pub due_soon: bool,
+    pub overdue: bool,
+    pub hold_ready: bool,
+    // Days before the due date for the reminder; None for notifications.due_soon_days.
+    pub due_soon_days: Option<u32>,
+}
+
+impl Default for NotificationPreferences {
+    fn default() -> Self {
+        NotificationPreferences { due_soon: true, overdue: true, hold_ready: true, due_soon_days: None }
+    }
+}
+
+impl NotificationPreferences {
+    pub fn is_enabled(&self, kind: NotificationKind) -> bool {
+        match kind {
+            NotificationKind::DueSoon => self.due_soon,
+            NotificationKind::Overdue => self.overdue,
+            NotificationKind::HoldReady => self.hold_ready,
+        }
+    }
+
+    pub fn set_enabled(&mut self, kind: NotificationKind, enabled: bool) {
+        match kind {
+            NotificationKind::DueSoon => self.due_soon = enabled,
+            NotificationKind::Overdue => self.overdue = enabled,
+            NotificationKind::HoldReady => self.hold_ready = enabled,
+        }
+    }
+
+    pub fn print_preferences(&self, default_due_soon_days: u32) {
+        for kind in NotificationKind::ALL {
+            println!("{:<32} {}", format!("{}:", kind.get_description()), if self.is_enabled(kind) { "On" } else { "Off" });
+        }
+        match self.due_soon_days {
+            Some(days) => println!("{:<32} {} day(s) before", "Reminder sent:", days),
+            None => println!("{:<32} {} day(s) before (the library's default)", "Reminder sent:", default_due_soon_days),
+        }
+    }
+}
+
+/*
+ *  A notification that is due to be sent. record_id is the loan or hold
+ *  it is about and occasion its due date or ready date; with the kind and
+ *  step they are what the log records to avoid sending it twice.
+ */
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub kind: NotificationKind,
+    pub user_id: i32,
+    pub email: String,
+    pub firstname: String,
+    pub lastname: String,
+    pub record_id: i64,
+    pub occasion: i64,
+    // The overdue escalation step, from 1; 0 for the other kinds.
+    pub step: u32,
+    pub title: String,
+    pub authors: Option<String>,
+    pub barcode: Option<String>,
+    pub call_number: Option<String>,
+    // When a hold must be collected by, if there is a limit.
+    pub expires_at: Option<i64>,
+}
+
+impl Notification {
+    pub fn print_notification_info(&self) {
+        let about = match self.kind {
+            NotificationKind::DueSoon => format!("due {}", utilities::format_timestamp(self.occasion)),
+            NotificationKind::Overdue => format!("overdue since {}, notice {}", utilities::format_timestamp(self.occasion), self.step),
+            NotificationKind::HoldReady => format!("ready since {}", utilities::format_timestamp(self.occasion)),
+        };
+        println!("  {:<11} {} <{}>: {} ({})", self.kind.get_name(), self.firstname, self.email, self.title, about);
+    }
+}
+
+// What a run of the notification engine did.
+#[derive(Debug, Default)]
+pub struct NotificationReport {
+    pub due_soon: usize,
+    pub overdue: usize,
+    pub hold_ready: usize,
+    // Notifications that could not be rendered or delivered; they are tried again next time.
+    pub failed: usize,
+}
+
+impl NotificationReport {
+    pub fn count(&mut self, kind: NotificationKind) {
+        match kind {
+            NotificationKind::DueSoon => self.due_soon += 1,
+            NotificationKind::Overdue => self.overdue += 1,
+            NotificationKind::HoldReady => self.hold_ready += 1,
+        }
+    }
+
+    pub fn get_sent(&self) -> usize {
+        self.due_soon + self.overdue + self.hold_ready
+    }
+
+    pub fn print_report(&self) {
+        println!(
+            "Sent {} notification(s): {} due-soon reminder(s), {} overdue notice(s), {} hold-ready alert(s).",
+            self.get_sent(),
+            self.due_soon,
+            self.overdue,
+            self.hold_ready
+        );
+        if self.failed > 0 {
+            println!("{} notification(s) could not be sent and will be tried again.", self.failed);
+        }
+    }
+}
@@ -0,0 +1,558 @@
+use std::error::Error;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use tera::{Context, Tera};
+use crate::configuration::{Config, NotificationConfig};
+use crate::mailer::{self, EmailMessage, Mailer};
+use crate::notification_object::{Notification, NotificationKind, NotificationPreferences, NotificationReport};
+use crate::user_object::User;
+use crate::utilities::{self, clear_screen, get_optional_input};
+
+const SECONDS_PER_DAY: i64 = 86400;
+
+/*
+ *  The built-in templates, by the name a template in
+ *  notifications.template_directory replaces them with. Each renders the
+ *  whole message: a "Subject:" line, then the body. They are given
+ *      firstname, lastname, email, title, authors, barcode, call_number
+ *  and, depending on the kind,
+ *      due_date, days (until due, or overdue), step, steps, final_notice
+ *      ready_date, pickup_by
+ */
+const DEFAULT_TEMPLATES: [(&str, &str); 3] = [
+    (
+        "due_soon.txt",
+        "Subject: {{ title }} is due {% if days == 1 %}tomorrow{% else %}in {{ days }} days{% endif %}
+
+Hello {{ firstname }},
+
+This is a reminder that {{ title }}{% if authors %} by {{ authors }}{% endif %} is due back on {{ due_date }}.
+{% if barcode %}Item barcode: {{ barcode }}
+{% endif %}
+Please return or renew it by then.
+
+rLMS
+",
+    ),
+    (
+        "overdue.txt",
+        "Subject: {% if final_notice %}Final notice: {% endif %}{{ title }} is overdue
+
+Hello {{ firstname }},
+
+{{ title }}{% if authors %} by {{ authors }}{% endif %} was due back on {{ due_date }} and is now {{ days }} day(s) overdue.
+{% if barcode %}Item barcode: {{ barcode }}
+{% endif %}
+{% if final_notice %}This is the last reminder we will send. Please return it as soon as possible or contact the library.{% else %}Please return or renew it as soon as you can.{% endif %}
+
+rLMS
+",
+    ),
+    (
+        "hold_ready.txt",
+        "Subject: {{ title }} is ready to collect
+
+Hello {{ firstname }},
+
+The copy of {{ title }}{% if authors %} by {{ authors }}{% endif %} you placed a hold on has been set aside for you.
+{% if call_number %}Ask for it at the desk: {{ call_number }}{% if barcode %} ({{ barcode }}){% endif %}
+{% endif %}{% if pickup_by %}
+Please collect it by {{ pickup_by }}, after which it will go to the next reader.
+{% endif %}
+rLMS
+",
+    ),
+];
+
+pub fn get_preferences(connection: &Connection, user_id: i32) -> Result<NotificationPreferences, rusqlite::Error> {
+    let preferences = connection.query_row(
+        "SELECT due_soon, overdue, hold_ready, due_soon_days FROM notification_preferences WHERE user_id = ?1",
+        params![user_id],
+        |row| {
+            Ok(NotificationPreferences {
+                due_soon: row.get(0)?,
+                overdue: row.get(1)?,
+                hold_ready: row.get(2)?,
+                due_soon_days: row.get(3)?,
+            })
+        },
+    ).optional()?;
+    Ok(preferences.unwrap_or_default())
+}
+
+pub fn save_preferences(connection: &Connection, user_id: i32, preferences: &NotificationPreferences) -> Result<(), rusqlite::Error> {
+    connection.execute(
+        "INSERT INTO notification_preferences (user_id, due_soon, overdue, hold_ready, due_soon_days)
+         VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT (user_id) DO UPDATE SET
+            due_soon = excluded.due_soon,
+            overdue = excluded.overdue,
+            hold_ready = excluded.hold_ready,
+            due_soon_days = excluded.due_soon_days",
+        params![user_id, preferences.due_soon, preferences.overdue, preferences.hold_ready, preferences.due_soon_days],
+    )?;
+    Ok(())
+}
+
+/*
+ *  The patron and book columns every query below ends with, read by
+ *  read_notification. Patrons whose address has not been verified are
+ *  left out, so notices never go to an address that may not be theirs.
+ */
+const PATRON_AND_BOOK_COLUMNS: &str =
+    "u.user_id, u.email, u.firstname, u.lastname, b.title,
+     (SELECT GROUP_CONCAT(name, ', ') FROM (SELECT name FROM book_authors WHERE book_id = b.book_id ORDER BY position)),
+     h.barcode, h.call_number";
+
+fn read_notification(row: &Row, kind: NotificationKind) -> Result<Notification, rusqlite::Error> {
+    Ok(Notification {
+        kind,
+        record_id: row.get(0)?,
+        occasion: row.get(1)?,
+        expires_at: row.get(2)?,
+        user_id: row.get(3)?,
+        email: row.get(4)?,
+        firstname: row.get(5)?,
+        lastname: row.get(6)?,
+        title: row.get(7)?,
+        authors: row.get(8)?,
+        barcode: row.get(9)?,
+        call_number: row.get(10)?,
+        step: 0,
+    })
+}
+
+// Every notification that is due and has not been sent, oldest first within each kind.
+pub fn find_pending_notifications(connection: &Connection, config: &NotificationConfig, now: i64) -> Result<Vec<Notification>, rusqlite::Error> {
+    let mut notifications = Vec::new();
+
+    let mut stmt = connection.prepare(&format!(
+        "SELECT l.loan_id, l.due_at, NULL, {}
+         FROM loans l
+         JOIN holdings h ON h.holding_id = l.holding_id
+         JOIN books b ON b.book_id = h.book_id
+         JOIN users u ON u.user_id = l.user_id
+         LEFT JOIN notification_preferences p ON p.user_id = u.user_id
+         WHERE l.returned_at IS NULL AND u.email_verified = 1 AND COALESCE(p.due_soon, 1) = 1
+           AND l.due_at > ?1 AND l.due_at <= ?1 + COALESCE(p.due_soon_days, ?2) * {}
+           AND NOT EXISTS (
+               SELECT 1 FROM notification_log n
+               WHERE n.kind = ?3 AND n.record_id = l.loan_id AND n.occasion = l.due_at
+           )
+         ORDER BY l.due_at, l.loan_id",
+        PATRON_AND_BOOK_COLUMNS, SECONDS_PER_DAY
+    ))?;
+    let due_soon = stmt.query_map(
+        params![now, config.due_soon_days, NotificationKind::DueSoon.get_name()],
+        |row| read_notification(row, NotificationKind::DueSoon),
+    )?;
+    for notification in due_soon {
+        notifications.push(notification?);
+    }
+
+    // Only the latest step reached is sent, so a loan found weeks overdue gets one notice rather than several.
+    let first_step = config.overdue_steps_days.first().copied().unwrap_or(1) as i64;
+    let mut stmt = connection.prepare(&format!(
+        "SELECT l.loan_id, l.due_at, NULL, {},
+            (SELECT MAX(n.step) FROM notification_log n
+             WHERE n.kind = ?2 AND n.record_id = l.loan_id AND n.occasion = l.due_at)
+         FROM loans l
+         JOIN holdings h ON h.holding_id = l.holding_id
+         JOIN books b ON b.book_id = h.book_id
+         JOIN users u ON u.user_id = l.user_id
+         LEFT JOIN notification_preferences p ON p.user_id = u.user_id
+         WHERE l.returned_at IS NULL AND u.email_verified = 1 AND COALESCE(p.overdue, 1) = 1
+           AND l.due_at <= ?1
+         ORDER BY l.due_at, l.loan_id",
+        PATRON_AND_BOOK_COLUMNS
+    ))?;
+    let overdue = stmt.query_map(
+        params![now - first_step * SECONDS_PER_DAY, NotificationKind::Overdue.get_name()],
+        |row| Ok((read_notification(row, NotificationKind::Overdue)?, row.get::<_, Option<u32>>(11)?)),
+    )?;
+    for overdue in overdue {
+        let (mut notification, last_step) = overdue?;
+        let days_overdue = (now - notification.occasion) / SECONDS_PER_DAY;
+        notification.step = config.overdue_steps_days.iter().filter(|days| days_overdue >= **days as i64).count() as u32;
+        if notification.step > last_step.unwrap_or(0) {
+            notifications.push(notification);
+        }
+    }
+
+    let mut stmt = connection.prepare(&format!(
+        "SELECT hd.hold_id, hd.ready_at, hd.expires_at, {}
+         FROM holds hd
+         JOIN books b ON b.book_id = hd.book_id
+         JOIN users u ON u.user_id = hd.user_id
+         LEFT JOIN holdings h ON h.holding_id = hd.holding_id
+         LEFT JOIN notification_preferences p ON p.user_id = u.user_id
+         WHERE hd.ready_at <= ?1 AND hd.closed_at IS NULL AND (hd.expires_at IS NULL OR hd.expires_at > ?1)
+           AND u.email_verified = 1 AND COALESCE(p.hold_ready, 1) = 1
+           AND NOT EXISTS (
+               SELECT 1 FROM notification_log n
+               WHERE n.kind = ?2 AND n.record_id = hd.hold_id AND n.occasion = hd.ready_at
+           )
+         ORDER BY hd.ready_at, hd.hold_id",
+        PATRON_AND_BOOK_COLUMNS
+    ))?;
+    let hold_ready = stmt.query_map(
+        params![now, NotificationKind::HoldReady.get_name()],
+        |row| read_notification(row, NotificationKind::HoldReady),
+    )?;
+    for notification in hold_ready {
+        notifications.push(notification?);
+    }
+
+    Ok(notifications)
+}
+
+// The built-in templates, with any the library has written in their place.
+pub fn load_templates(config: &NotificationConfig) -> Result<Tera, tera::Error> {
+    let mut tera = Tera::default();
+    tera.add_raw_templates(DEFAULT_TEMPLATES)?;
+    if let Some(directory) = config.template_directory.as_deref() {
+        for kind in NotificationKind::ALL {
+            let path = Path::new(directory).join(kind.get_template_name());
+            if path.is_file() {
+                tera.add_template_file(&path, Some(kind.get_template_name()))?;
+            }
+        }
+    }
+    Ok(tera)
+}
+
+// The subject and body of a notification.
+pub fn render_notification(tera: &Tera, config: &NotificationConfig, notification: &Notification, now: i64) -> Result<(String, String), Box<dyn Error>> {
+    let date = |timestamp: i64| utilities::format_timestamp(timestamp).chars().take(10).collect::<String>();
+    let mut context = Context::new();
+    context.insert("firstname", &notification.firstname);
+    context.insert("lastname", &notification.lastname);
+    context.insert("email", &notification.email);
+    context.insert("title", &notification.title);
+    context.insert("authors", &notification.authors);
+    context.insert("barcode", &notification.barcode);
+    context.insert("call_number", &notification.call_number);
+    match notification.kind {
+        NotificationKind::DueSoon => {
+            context.insert("due_date", &date(notification.occasion));
+            // Whole days, rounded up, so a loan due this evening is due in 1 day.
+            context.insert("days", &((notification.occasion - now + SECONDS_PER_DAY - 1) / SECONDS_PER_DAY));
+        }
+        NotificationKind::Overdue => {
+            context.insert("due_date", &date(notification.occasion));
+            context.insert("days", &((now - notification.occasion) / SECONDS_PER_DAY));
+            context.insert("step", &notification.step);
+            context.insert("steps", &config.overdue_steps_days.len());
+            context.insert("final_notice", &(notification.step as usize >= config.overdue_steps_days.len()));
+        }
+        NotificationKind::HoldReady => {
+            context.insert("ready_date", &date(notification.occasion));
+            context.insert("pickup_by", &notification.expires_at.map(date));
+        }
+    }
+
+    let rendered = tera.render(notification.kind.get_template_name(), &context)?;
+    let (subject, body) = rendered.split_once('\n').unwrap_or((rendered.as_str(), ""));
+    let subject = subject.strip_prefix("Subject:")
+        .ok_or_else(|| format!("{} must start with a \"Subject:\" line", notification.kind.get_template_name()))?;
+    Ok((subject.trim().to_string(), body.trim_start_matches(['\r', '\n']).to_string()))
+}
+
+/*
+ *  Sends every notification that is due through the mail transport and
+ *  logs each one delivered. One that cannot be rendered or delivered is
+ *  not logged, so it is tried again on the next run.
+ */
+pub fn send_notifications(database_name: &str, config: &Config, mailer: &dyn Mailer) -> Result<NotificationReport, Box<dyn Error>> {
+    let connection = Connection::open(database_name)?;
+    let tera = load_templates(&config.notifications)?;
+    let now = utilities::current_timestamp();
+    let mut report = NotificationReport::default();
+
+    for notification in find_pending_notifications(&connection, &config.notifications, now)? {
+        let message = match render_notification(&tera, &config.notifications, &notification, now) {
+            Ok((subject, body)) => EmailMessage {
+                from: config.mail.from_address.clone(),
+                to: notification.email.clone(),
+                subject,
+                body,
+            },
+            Err(e) => {
+                log::error!("Could not render the {} notification for {}: {}", notification.kind.get_name(), notification.email, e);
+                report.failed += 1;
+                continue;
+            }
+        };
+        if let Err(e) = mailer.send(&message) {
+            log::error!("Could not send the {} notification to {}: {}", notification.kind.get_name(), notification.email, e);
+            report.failed += 1;
+            continue;
+        }
+        connection.execute(
+            "INSERT OR IGNORE INTO notification_log (user_id, kind, record_id, occasion, step, sent_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![notification.user_id, notification.kind.get_name(), notification.record_id, notification.occasion, notification.step, now],
+        )?;
+        report.count(notification.kind);
+    }
+    Ok(report)
+}
+
+/*
+ *  Runs for as long as the server does, sending notifications every
+ *  notifications.interval_minutes. The first run is at start-up; the log
+ *  keeps restarts from sending anything twice.
+ */
+pub async fn run_scheduled_notifications(database_name: String, config: Config) {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.notifications.interval_minutes * 60));
+    loop {
+        interval.tick().await;
+        let database_name = database_name.clone();
+        let config = config.clone();
+        match tokio::task::spawn_blocking(move || {
+            mailer::mailer_from_config(&config)
+                .and_then(|mailer| send_notifications(&database_name, &config, mailer.as_ref()))
+                .map_err(|e| e.to_string())
+        }).await {
+            Ok(Ok(report)) if report.get_sent() > 0 || report.failed > 0 => log::info!(
+                "Sent {} notification(s), {} failed",
+                report.get_sent(),
+                report.failed
+            ),
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => log::error!("Scheduled notifications failed: {}", e),
+            Err(e) => log::error!("Scheduled notification task failed: {}", e),
+        }
+    }
+}
+
+// Lets a user choose which notifications they get. Returns true if anything was changed.
+pub fn manage_notification_preferences(database_name: &str, user: &User, config: &NotificationConfig) -> bool {
+    let connection = match Connection::open(database_name) {
+        Ok(connection) => connection,
+        Err(e) => {
+            println!("Failed to open the database: {}", e);
+            return false;
+        }
+    };
+    let mut changed = false;
+
+    loop {
+        clear_screen();
+        print_notification_preferences_header();
+        let mut preferences = match get_preferences(&connection, user.get_user_id()) {
+            Ok(preferences) => preferences,
+            Err(e) => {
+                println!("Failed to read your notification preferences: {}", e);
+                return changed;
+            }
+        };
+        preferences.print_preferences(config.due_soon_days);
+        println!(
+            "Choose from the options below:\n\
+            \t1. Turn due-date reminders {}\n\
+            \t2. Turn overdue notices {}\n\
+            \t3. Turn hold-ready alerts {}\n\
+            \t4. Change when reminders are sent\n\
+            \t0. Back",
+            if preferences.due_soon { "off" } else { "on" },
+            if preferences.overdue { "off" } else { "on" },
+            if preferences.hold_ready { "off" } else { "on" }
+        );
+        let mut choice = String::new();
+        if io::stdin().read_line(&mut choice).is_err() {
+            println!("Failed to read input. Please try again.");
+            continue;
+        }
+
+        match choice.trim() {
+            "1" | "2" | "3" => {
+                let kind = match choice.trim() {
+                    "1" => NotificationKind::DueSoon,
+                    "2" => NotificationKind::Overdue,
+                    _ => NotificationKind::HoldReady,
+                };
+                preferences.set_enabled(kind, !preferences.is_enabled(kind));
+            }
+            "4" => {
+                preferences.due_soon_days = loop {
+                    match get_optional_input("Days before the due date, from 1 to 60 (leave blank for the library's default):") {
+                        None => break None,
+                        Some(days) => match days.parse::<u32>() {
+                            Ok(days) if (1..=60).contains(&days) => break Some(days),
+                            _ => println!("Invalid number of days. Please enter a whole number from 1 to 60."),
+                        },
+                    }
+                };
+            }
+            "0" => return changed,
+            _ => {
+                println!("Invalid choice. Please try again.");
+                continue;
+            }
+        }
+        match save_preferences(&connection, user.get_user_id(), &preferences) {
+            Ok(()) => changed = true,
+            Err(e) => {
+                println!("Failed to save your notification preferences: {}", e);
+                utilities::pause(2);
+            }
+        }
+    }
+}
+
+fn print_notification_preferences_header() {
+    println!("##############################");
+    println!("## Notification Preferences ##");
+    println!("##############################");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialisation;
+    use crate::mailer::MemoryMailer;
+
+    const DAY: i64 = SECONDS_PER_DAY;
+
+    /*
+     *  Three patrons: 1 gets everything, 2 has not verified their address
+     *  and 3 has turned off loan notices. Each has a copy of The Hobbit.
+     */
+    fn open_database(name: &str) -> (String, Connection) {
+        let (database_name, connection) = initialisation::open_test_database(name);
+        connection.execute_batch(
+            "INSERT INTO users (user_id, email, firstname, lastname, email_verified) VALUES
+                (1, 'pat@example.org', 'Pat', 'Reader', 1),
+                (2, 'unverified@example.org', 'Una', 'Verified', 0),
+                (3, 'quiet@example.org', 'Quinn', 'Quiet', 1);
+             INSERT INTO notification_preferences (user_id, due_soon, overdue, hold_ready) VALUES (3, 0, 0, 1);
+             INSERT INTO books (book_id, title, author) VALUES (1, 'The Hobbit', 'J. R. R. Tolkien');
+             INSERT INTO book_authors (book_id, position, name) VALUES (1, 0, 'J. R. R. Tolkien');
+             INSERT INTO holdings (holding_id, book_id, created_at, barcode) VALUES
+                (1, 1, 0, '291000000010'), (2, 1, 0, '291000000027'), (3, 1, 0, '291000000034'),
+                (4, 1, 0, '291000000041'), (5, 1, 0, '291000000058'), (6, 1, 0, '291000000065');",
+        ).unwrap();
+        (database_name, connection)
+    }
+
+    fn add_loan(connection: &Connection, loan_id: i64, user_id: i32, due_at: i64) {
+        connection.execute(
+            "INSERT INTO loans (loan_id, holding_id, user_id, checked_out_at, due_at) VALUES (?1, ?1, ?2, ?3, ?4)",
+            params![loan_id, user_id, due_at - 21 * DAY, due_at],
+        ).unwrap();
+    }
+
+    fn pending(connection: &Connection, now: i64) -> Vec<(&'static str, i64, u32)> {
+        find_pending_notifications(connection, &NotificationConfig::default(), now)
+            .unwrap()
+            .iter()
+            .map(|notification| (notification.kind.get_name(), notification.record_id, notification.step))
+            .collect()
+    }
+
+    fn log_sent(connection: &Connection, kind: NotificationKind, record_id: i64, occasion: i64, step: u32) {
+        connection.execute(
+            "INSERT INTO notification_log (user_id, kind, record_id, occasion, step, sent_at) VALUES (1, ?1, ?2, ?3, ?4, 0)",
+            params![kind.get_name(), record_id, occasion, step],
+        ).unwrap();
+    }
+
+    #[test]
+    fn due_soon_reminders_follow_the_window_and_preferences() {
+        let (_, connection) = open_database("notifications_due_soon");
+        let now = 1_000 * DAY;
+        add_loan(&connection, 1, 1, now + 2 * DAY);
+        add_loan(&connection, 2, 1, now + 10 * DAY);
+        add_loan(&connection, 3, 2, now + DAY);
+        add_loan(&connection, 4, 3, now + DAY);
+        add_loan(&connection, 5, 1, now + DAY);
+        connection.execute("UPDATE loans SET returned_at = ?1 WHERE loan_id = 5", params![now]).unwrap();
+        assert_eq!(pending(&connection, now), vec![("due-soon", 1, 0)]);
+
+        // A patron's own window replaces the library's.
+        connection.execute("INSERT INTO notification_preferences (user_id, due_soon_days) VALUES (1, 14)", []).unwrap();
+        assert_eq!(pending(&connection, now), vec![("due-soon", 1, 0), ("due-soon", 2, 0)]);
+
+        log_sent(&connection, NotificationKind::DueSoon, 1, now + 2 * DAY, 0);
+        assert_eq!(pending(&connection, now), vec![("due-soon", 2, 0)]);
+    }
+
+    #[test]
+    fn overdue_notices_escalate_one_step_at_a_time() {
+        let (_, connection) = open_database("notifications_overdue");
+        let due_at = 1_000 * DAY;
+        add_loan(&connection, 1, 1, due_at);
+        add_loan(&connection, 2, 3, due_at);
+
+        assert_eq!(pending(&connection, due_at + DAY / 2), vec![]);
+        assert_eq!(pending(&connection, due_at + DAY), vec![("overdue", 1, 1)]);
+        log_sent(&connection, NotificationKind::Overdue, 1, due_at, 1);
+        assert_eq!(pending(&connection, due_at + 6 * DAY), vec![]);
+        assert_eq!(pending(&connection, due_at + 7 * DAY), vec![("overdue", 1, 2)]);
+        // A loan found long overdue gets the latest notice only, not each one it missed.
+        assert_eq!(pending(&connection, due_at + 30 * DAY), vec![("overdue", 1, 3)]);
+        log_sent(&connection, NotificationKind::Overdue, 1, due_at, 3);
+        assert_eq!(pending(&connection, due_at + 60 * DAY), vec![]);
+    }
+
+    #[test]
+    fn hold_ready_alerts_skip_closed_and_expired_holds() {
+        let (_, connection) = open_database("notifications_hold_ready");
+        let now = 1_000 * DAY;
+        connection.execute_batch(&format!(
+            "INSERT INTO holds (hold_id, book_id, user_id, placed_at, holding_id, ready_at, expires_at, closed_at) VALUES
+                (1, 1, 1, 0, 1, {ready}, {expires}, NULL),
+                (2, 1, 1, 0, 2, {ready}, {expired}, NULL),
+                (3, 1, 1, 0, 3, {ready}, NULL, {ready}),
+                (4, 1, 1, 0, NULL, NULL, NULL, NULL),
+                (5, 1, 3, 0, 4, {ready}, NULL, NULL);",
+            ready = now - DAY,
+            expires = now + 6 * DAY,
+            expired = now - 1
+        )).unwrap();
+        assert_eq!(pending(&connection, now), vec![("hold-ready", 1, 0), ("hold-ready", 5, 0)]);
+    }
+
+    #[test]
+    fn send_notifications_sends_each_notice_once_per_occasion_and_step() {
+        let (database_name, connection) = open_database("notifications_send");
+        let now = utilities::current_timestamp();
+        add_loan(&connection, 1, 1, now + 2 * DAY);
+        add_loan(&connection, 2, 1, now - 8 * DAY - 60);
+        add_loan(&connection, 3, 2, now + DAY);
+        connection.execute(
+            "INSERT INTO holds (hold_id, book_id, user_id, placed_at, holding_id, ready_at) VALUES (1, 1, 1, 0, 4, ?1)",
+            params![now - 60],
+        ).unwrap();
+        let mut config = Config::default();
+        config.mail.from_address = "library@example.org".to_string();
+        let mailer = MemoryMailer::default();
+
+        let report = send_notifications(&database_name, &config, &mailer).unwrap();
+        assert_eq!((report.due_soon, report.overdue, report.hold_ready, report.failed), (1, 1, 1, 0));
+        let sent = mailer.get_sent();
+        assert_eq!(sent.len(), 3);
+        assert!(sent.iter().all(|message| message.to == "pat@example.org" && message.from == "library@example.org"));
+        let subjects: Vec<&str> = sent.iter().map(|message| message.subject.as_str()).collect();
+        assert!(subjects[0].starts_with("The Hobbit is due"), "{:?}", subjects);
+        assert_eq!(subjects[1], "The Hobbit is overdue");
+        assert_eq!(subjects[2], "The Hobbit is ready to collect");
+        assert!(sent[1].body.contains("291000000027"), "{}", sent[1].body);
+
+        // Nothing is sent twice.
+        let report = send_notifications(&database_name, &config, &mailer).unwrap();
+        assert_eq!(report.get_sent(), 0);
+        assert_eq!(mailer.get_sent().len(), 3);
+        let logged: i64 = connection.query_row("SELECT COUNT(*) FROM notification_log", [], |row| row.get(0)).unwrap();
+        assert_eq!(logged, 3);
+
+        // A renewal gives the loan a new due date, which is reminded of afresh.
+        connection.execute("UPDATE loans SET due_at = ?1, renewals = 1 WHERE loan_id = 1", params![now + DAY]).unwrap();
+        let report = send_notifications(&database_name, &config, &mailer).unwrap();
+        assert_eq!((report.due_soon, report.get_sent()), (1, 1));
+        assert_eq!(mailer.get_sent().len(), 4);
+    }
+}
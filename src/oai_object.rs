@@ -0,0 +1,142 @@
+use data_encoding::BASE64URL_NOPAD;
+
+/*
+ *  The metadata formats records can be harvested in. Every record is
+ *  available in both, built from the catalogue when it is asked for.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MetadataFormat {
+    DublinCore,
+    MarcXml,
+}
+
+impl MetadataFormat {
+    pub const ALL: [MetadataFormat; 2] = [MetadataFormat::DublinCore, MetadataFormat::MarcXml];
+
+    pub fn from_prefix(prefix: &str) -> Option<Self> {
+        MetadataFormat::ALL.into_iter().find(|format| format.get_prefix() == prefix)
+    }
+
+    pub fn get_prefix(&self) -> &'static str {
+        match self {
+            MetadataFormat::DublinCore => "oai_dc",
+            MetadataFormat::MarcXml => "marc21",
+        }
+    }
+
+    pub fn get_schema(&self) -> &'static str {
+        match self {
+            MetadataFormat::DublinCore => "http://www.openarchives.org/OAI/2.0/oai_dc.xsd",
+            MetadataFormat::MarcXml => "http://www.loc.gov/standards/marcxml/schema/MARC21slim.xsd",
+        }
+    }
+
+    pub fn get_namespace(&self) -> &'static str {
+        match self {
+            MetadataFormat::DublinCore => "http://www.openarchives.org/OAI/2.0/oai_dc/",
+            MetadataFormat::MarcXml => "http://www.loc.gov/MARC21/slim",
+        }
+    }
+}
+
+// The errors the protocol defines. Each is answered in the response body, not with an HTTP status.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OaiError {
+    BadArgument(String),
+    BadResumptionToken,
+    BadVerb(String),
+    CannotDisseminateFormat(String),
+    IdDoesNotExist(String),
+    NoRecordsMatch,
+    NoSetHierarchy,
+}
+
+impl OaiError {
+    pub fn get_code(&self) -> &'static str {
+        match self {
+            OaiError::BadArgument(_) => "badArgument",
+            OaiError::BadResumptionToken => "badResumptionToken",
+            OaiError::BadVerb(_) => "badVerb",
+            OaiError::CannotDisseminateFormat(_) => "cannotDisseminateFormat",
+            OaiError::IdDoesNotExist(_) => "idDoesNotExist",
+            OaiError::NoRecordsMatch => "noRecordsMatch",
+            OaiError::NoSetHierarchy => "noSetHierarchy",
+        }
+    }
+
+    pub fn get_message(&self) -> String {
+        match self {
+            OaiError::BadArgument(message) => message.clone(),
+            OaiError::BadResumptionToken => "The resumption token is invalid".to_string(),
+            OaiError::BadVerb(message) => message.clone(),
+            OaiError::CannotDisseminateFormat(prefix) => format!("Records are not available as {}", prefix),
+            OaiError::IdDoesNotExist(identifier) => format!("No record is named {}", identifier),
+            OaiError::NoRecordsMatch => "No records match the request".to_string(),
+            OaiError::NoSetHierarchy => "This repository does not support sets".to_string(),
+        }
+    }
+
+    // badVerb and badArgument answers must not echo the arguments back, as they were not understood.
+    pub fn is_request_error(&self) -> bool {
+        matches!(self, OaiError::BadVerb(_) | OaiError::BadArgument(_))
+    }
+}
+
+// A record's header: which book, when it last changed and whether it has since been deleted.
+#[derive(Debug, Clone)]
+pub struct RecordHeader {
+    pub book_id: i32,
+    pub datestamp: i64,
+    pub deleted: bool,
+}
+
+/*
+ *  What a ListRecords or ListIdentifiers harvest asked for, and how far
+ *  it has got. Records are listed in order of book ID, so a harvest can
+ *  carry on from the last one it was sent however long it waits; the
+ *  resumption token is this, encoded, and the server keeps nothing.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListRequest {
+    pub format: MetadataFormat,
+    pub from: Option<i64>,
+    pub until: Option<i64>,
+    // The last book ID already sent; 0 to start.
+    pub after: i32,
+    // How many records were sent before this response.
+    pub cursor: usize,
+}
+
+impl ListRequest {
+    pub fn to_token(&self) -> String {
+        let bound = |timestamp: Option<i64>| timestamp.map(|timestamp| timestamp.to_string()).unwrap_or_default();
+        let token = format!("{}:{}:{}:{}:{}", self.format.get_prefix(), bound(self.from), bound(self.until), self.after, self.cursor);
+        BASE64URL_NOPAD.encode(token.as_bytes())
+    }
+
+    pub fn from_token(token: &str) -> Option<Self> {
+        let token = String::from_utf8(BASE64URL_NOPAD.decode(token.as_bytes()).ok()?).ok()?;
+        let parts: Vec<&str> = token.split(':').collect();
+        let [prefix, from, until, after, cursor] = parts[..] else {
+            return None;
+        };
+        let bound = |value: &str| if value.is_empty() { Some(None) } else { value.parse().ok().map(Some) };
+        Some(ListRequest {
+            format: MetadataFormat::from_prefix(prefix)?,
+            from: bound(from)?,
+            until: bound(until)?,
+            after: after.parse().ok()?,
+            cursor: cursor.parse().ok()?,
+        })
+    }
+}
+
+// One page of a harvest.
+#[derive(Debug)]
+pub struct RecordList {
+    pub headers: Vec<RecordHeader>,
+    // Records matching the request across all pages.
+    pub total: usize,
+    // Where the next page starts, if there is one.
+    pub next: Option<ListRequest>,
+}
@@ -0,0 +1,609 @@
+use std::collections::HashMap;
+use chrono::{DateTime, NaiveDateTime};
+use rusqlite::{params, Connection, OptionalExtension};
+use crate::book_object::Book;
+use crate::configuration::Config;
+use crate::oai_object::{ListRequest, MetadataFormat, OaiError, RecordHeader, RecordList};
+use crate::utilities::{self, escape_xml};
+use crate::{catalogue_processing, duplicate_processing, statistics_processing};
+
+/*
+ *  An OAI-PMH 2.0 provider, so union catalogues can harvest the
+ *  catalogue. Every book is a record, named oai:<repository>:<book_id>,
+ *  whose datestamp is books.modified_at; deleted books are kept as
+ *  deleted records for good. There are no sets.
+ */
+const OAI_NAMESPACES: &str = "xmlns=\"http://www.openarchives.org/OAI/2.0/\" \
+    xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" \
+    xsi:schemaLocation=\"http://www.openarchives.org/OAI/2.0/ http://www.openarchives.org/OAI/2.0/OAI-PMH.xsd\"";
+
+const GRANULARITY: &str = "YYYY-MM-DDThh:mm:ssZ";
+
+// Records and deleted records together, each with its datestamp.
+const RECORD_HEADERS: &str = "(SELECT book_id, modified_at AS datestamp, 0 AS deleted FROM books
+     UNION ALL
+     SELECT book_id, deleted_at, 1 FROM deleted_books)";
+
+// The answer to a verb, or the protocol error to give instead.
+type VerbResult = Result<Result<String, OaiError>, rusqlite::Error>;
+
+/// Answers a request with the given arguments, from the query string or a form. Protocol errors are part of the answer.
+pub fn handle_request(connection: &Connection, config: &Config, base_url: &str, arguments: &[(String, String)]) -> Result<String, rusqlite::Error> {
+    let base_url = format!("{}/oai", base_url);
+    let result = match get_arguments(arguments) {
+        Ok((verb, arguments)) => match verb {
+            "Identify" => identify(connection, config, &base_url, &arguments)?,
+            "ListMetadataFormats" => list_metadata_formats(connection, config, &arguments)?,
+            "ListSets" => list_sets(&arguments),
+            "GetRecord" => get_record(connection, config, &arguments)?,
+            "ListIdentifiers" => list_records(connection, config, &arguments, false)?,
+            "ListRecords" => list_records(connection, config, &arguments, true)?,
+            verb => Err(OaiError::BadVerb(format!("{} is not an OAI-PMH verb", verb))),
+        },
+        Err(e) => Err(e),
+    };
+
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<OAI-PMH {}>\n  <responseDate>{}</responseDate>\n",
+        OAI_NAMESPACES,
+        format_datestamp(utilities::current_timestamp())
+    );
+    let echo_arguments = !matches!(&result, Err(e) if e.is_request_error());
+    xml.push_str("  <request");
+    if echo_arguments {
+        for (key, value) in arguments {
+            xml.push_str(&format!(" {}=\"{}\"", key, escape_xml(value)));
+        }
+    }
+    xml.push_str(&format!(">{}</request>\n", escape_xml(&base_url)));
+    match result {
+        Ok(body) => xml.push_str(&body),
+        Err(e) => xml.push_str(&format!("  <error code=\"{}\">{}</error>\n", e.get_code(), escape_xml(&e.get_message()))),
+    }
+    xml.push_str("</OAI-PMH>\n");
+    Ok(xml)
+}
+
+// Splits off the verb. No argument may be given twice.
+fn get_arguments(arguments: &[(String, String)]) -> Result<(&str, HashMap<&str, &str>), OaiError> {
+    let mut verb = None;
+    let mut others = HashMap::new();
+    for (key, value) in arguments {
+        if key == "verb" {
+            if verb.replace(value.as_str()).is_some() {
+                return Err(OaiError::BadVerb("The verb argument is repeated".to_string()));
+            }
+        } else if others.insert(key.as_str(), value.as_str()).is_some() {
+            return Err(OaiError::BadArgument(format!("The {} argument is repeated", key)));
+        }
+    }
+    let verb = verb.ok_or_else(|| OaiError::BadVerb("The verb argument is missing".to_string()))?;
+    Ok((verb, others))
+}
+
+fn check_arguments(arguments: &HashMap<&str, &str>, verb: &str, required: &[&str], optional: &[&str]) -> Result<(), OaiError> {
+    if let Some(key) = arguments.keys().find(|key| !required.contains(key) && !optional.contains(key)) {
+        return Err(OaiError::BadArgument(format!("{} is not an argument of {}", key, verb)));
+    }
+    if let Some(key) = required.iter().find(|key| !arguments.contains_key(*key)) {
+        return Err(OaiError::BadArgument(format!("{} needs the {} argument", verb, key)));
+    }
+    Ok(())
+}
+
+fn format_datestamp(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .map(|date_time| date_time.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+        .unwrap_or_default()
+}
+
+// A day or a second, with whether it was a day. A day taken as an upper bound lasts until its end.
+fn parse_datestamp(value: &str, end_of_day: bool) -> Option<(i64, bool)> {
+    if value.len() == 10 {
+        return utilities::parse_date_to_timestamp(value, end_of_day).map(|timestamp| (timestamp, true));
+    }
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%SZ")
+        .ok()
+        .map(|date_time| (date_time.and_utc().timestamp(), false))
+}
+
+fn get_admin_email(config: &Config) -> String {
+    match &config.oai.admin_email {
+        Some(admin_email) => admin_email.clone(),
+        None => config.mail.from_address.parse::<lettre::message::Mailbox>()
+            .map(|mailbox| mailbox.email.to_string())
+            .unwrap_or_else(|_| config.mail.from_address.clone()),
+    }
+}
+
+fn get_identifier(config: &Config, book_id: i32) -> String {
+    format!("oai:{}:{}", config.oai.repository_identifier, book_id)
+}
+
+fn parse_identifier(config: &Config, identifier: &str) -> Option<i32> {
+    identifier
+        .strip_prefix(&format!("oai:{}:", config.oai.repository_identifier))
+        .and_then(|book_id| book_id.parse().ok())
+}
+
+fn get_record_header(connection: &Connection, book_id: i32) -> Result<Option<RecordHeader>, rusqlite::Error> {
+    connection.query_row(
+        &format!("SELECT book_id, datestamp, deleted FROM {} WHERE book_id = ?1", RECORD_HEADERS),
+        params![book_id],
+        |row| Ok(RecordHeader { book_id: row.get(0)?, datestamp: row.get(1)?, deleted: row.get(2)? }),
+    ).optional()
+}
+
+// The header of the record the identifier names, or the error to give if there is none.
+fn find_record_header(connection: &Connection, config: &Config, identifier: &str) -> Result<Result<RecordHeader, OaiError>, rusqlite::Error> {
+    let header = match parse_identifier(config, identifier) {
+        Some(book_id) => get_record_header(connection, book_id)?,
+        None => None,
+    };
+    Ok(header.ok_or_else(|| OaiError::IdDoesNotExist(identifier.to_string())))
+}
+
+// One page of the records a harvest asked for, in order of book ID.
+fn get_record_list(connection: &Connection, request: &ListRequest, page_size: usize) -> Result<RecordList, rusqlite::Error> {
+    let conditions = "(?1 IS NULL OR datestamp >= ?1) AND (?2 IS NULL OR datestamp <= ?2)";
+    let total: i64 = connection.query_row(
+        &format!("SELECT COUNT(*) FROM {} WHERE {}", RECORD_HEADERS, conditions),
+        params![request.from, request.until],
+        |row| row.get(0),
+    )?;
+    let mut stmt = connection.prepare(&format!(
+        "SELECT book_id, datestamp, deleted FROM {} WHERE {} AND book_id > ?3 ORDER BY book_id LIMIT ?4",
+        RECORD_HEADERS, conditions
+    ))?;
+    let mut headers = stmt
+        .query_map(params![request.from, request.until, request.after, page_size as i64 + 1], |row| {
+            Ok(RecordHeader { book_id: row.get(0)?, datestamp: row.get(1)?, deleted: row.get(2)? })
+        })?
+        .collect::<Result<Vec<RecordHeader>, rusqlite::Error>>()?;
+
+    let next = if headers.len() > page_size {
+        headers.truncate(page_size);
+        headers.last().map(|last| ListRequest {
+            after: last.book_id,
+            cursor: request.cursor.saturating_add(page_size),
+            ..request.clone()
+        })
+    } else {
+        None
+    };
+    Ok(RecordList { headers, total: total as usize, next })
+}
+
+fn identify(connection: &Connection, config: &Config, base_url: &str, arguments: &HashMap<&str, &str>) -> VerbResult {
+    if let Err(e) = check_arguments(arguments, "Identify", &[], &[]) {
+        return Ok(Err(e));
+    }
+    let earliest: Option<i64> = connection.query_row(&format!("SELECT MIN(datestamp) FROM {}", RECORD_HEADERS), [], |row| row.get(0))?;
+    let identifier = &config.oai.repository_identifier;
+
+    let mut xml = String::from("  <Identify>\n");
+    xml.push_str(&format!("    <repositoryName>{}</repositoryName>\n", escape_xml(&config.oai.repository_name)));
+    xml.push_str(&format!("    <baseURL>{}</baseURL>\n", escape_xml(base_url)));
+    xml.push_str("    <protocolVersion>2.0</protocolVersion>\n");
+    xml.push_str(&format!("    <adminEmail>{}</adminEmail>\n", escape_xml(&get_admin_email(config))));
+    xml.push_str(&format!(
+        "    <earliestDatestamp>{}</earliestDatestamp>\n",
+        format_datestamp(earliest.unwrap_or_else(utilities::current_timestamp))
+    ));
+    xml.push_str("    <deletedRecord>persistent</deletedRecord>\n");
+    xml.push_str(&format!("    <granularity>{}</granularity>\n", GRANULARITY));
+    xml.push_str("    <description>\n");
+    xml.push_str(
+        "      <oai-identifier xmlns=\"http://www.openarchives.org/OAI/2.0/oai-identifier\" \
+        xsi:schemaLocation=\"http://www.openarchives.org/OAI/2.0/oai-identifier http://www.openarchives.org/OAI/2.0/oai-identifier.xsd\">\n",
+    );
+    xml.push_str("        <scheme>oai</scheme>\n");
+    xml.push_str(&format!("        <repositoryIdentifier>{}</repositoryIdentifier>\n", escape_xml(identifier)));
+    xml.push_str("        <delimiter>:</delimiter>\n");
+    xml.push_str(&format!("        <sampleIdentifier>{}</sampleIdentifier>\n", escape_xml(&get_identifier(config, 1))));
+    xml.push_str("      </oai-identifier>\n");
+    xml.push_str("    </description>\n");
+    xml.push_str("  </Identify>\n");
+    Ok(Ok(xml))
+}
+
+fn list_metadata_formats(connection: &Connection, config: &Config, arguments: &HashMap<&str, &str>) -> VerbResult {
+    if let Err(e) = check_arguments(arguments, "ListMetadataFormats", &[], &["identifier"]) {
+        return Ok(Err(e));
+    }
+    // Every record is available in every format, so the identifier only needs to exist.
+    if let Some(identifier) = arguments.get("identifier") {
+        if let Err(e) = find_record_header(connection, config, identifier)? {
+            return Ok(Err(e));
+        }
+    }
+
+    let mut xml = String::from("  <ListMetadataFormats>\n");
+    for format in MetadataFormat::ALL {
+        xml.push_str("    <metadataFormat>\n");
+        xml.push_str(&format!("      <metadataPrefix>{}</metadataPrefix>\n", format.get_prefix()));
+        xml.push_str(&format!("      <schema>{}</schema>\n", format.get_schema()));
+        xml.push_str(&format!("      <metadataNamespace>{}</metadataNamespace>\n", format.get_namespace()));
+        xml.push_str("    </metadataFormat>\n");
+    }
+    xml.push_str("  </ListMetadataFormats>\n");
+    Ok(Ok(xml))
+}
+
+fn list_sets(arguments: &HashMap<&str, &str>) -> Result<String, OaiError> {
+    check_arguments(arguments, "ListSets", &[], &["resumptionToken"])?;
+    // No list of sets was ever handed out, so no token can be valid.
+    if arguments.contains_key("resumptionToken") {
+        return Err(OaiError::BadResumptionToken);
+    }
+    Err(OaiError::NoSetHierarchy)
+}
+
+fn get_record(connection: &Connection, config: &Config, arguments: &HashMap<&str, &str>) -> VerbResult {
+    if let Err(e) = check_arguments(arguments, "GetRecord", &["identifier", "metadataPrefix"], &[]) {
+        return Ok(Err(e));
+    }
+    let header = match find_record_header(connection, config, arguments["identifier"])? {
+        Ok(header) => header,
+        Err(e) => return Ok(Err(e)),
+    };
+    let Some(format) = MetadataFormat::from_prefix(arguments["metadataPrefix"]) else {
+        return Ok(Err(OaiError::CannotDisseminateFormat(arguments["metadataPrefix"].to_string())));
+    };
+    let record = render_record(connection, config, &header, format, "    ")?;
+    Ok(Ok(format!("  <GetRecord>\n{}  </GetRecord>\n", record)))
+}
+
+// Reads what a new harvest asked for; a resumed one brings it in its token.
+fn get_list_request(arguments: &HashMap<&str, &str>, verb: &str) -> Result<ListRequest, OaiError> {
+    if let Some(token) = arguments.get("resumptionToken") {
+        check_arguments(arguments, verb, &["resumptionToken"], &[])?;
+        return ListRequest::from_token(token).ok_or(OaiError::BadResumptionToken);
+    }
+    check_arguments(arguments, verb, &["metadataPrefix"], &["from", "until", "set"])?;
+    let from = match arguments.get("from") {
+        Some(from) => Some(parse_datestamp(from, false).ok_or_else(|| OaiError::BadArgument(format!("from is not a date: {}", from)))?),
+        None => None,
+    };
+    let until = match arguments.get("until") {
+        Some(until) => Some(parse_datestamp(until, true).ok_or_else(|| OaiError::BadArgument(format!("until is not a date: {}", until)))?),
+        None => None,
+    };
+    if let (Some((_, from_is_day)), Some((_, until_is_day))) = (from, until) {
+        if from_is_day != until_is_day {
+            return Err(OaiError::BadArgument("from and until must both be days or both be seconds".to_string()));
+        }
+    }
+    if arguments.contains_key("set") {
+        return Err(OaiError::NoSetHierarchy);
+    }
+    let prefix = arguments["metadataPrefix"];
+    let format = MetadataFormat::from_prefix(prefix).ok_or_else(|| OaiError::CannotDisseminateFormat(prefix.to_string()))?;
+    Ok(ListRequest {
+        format,
+        from: from.map(|(timestamp, _)| timestamp),
+        until: until.map(|(timestamp, _)| timestamp),
+        after: 0,
+        cursor: 0,
+    })
+}
+
+fn list_records(connection: &Connection, config: &Config, arguments: &HashMap<&str, &str>, with_metadata: bool) -> VerbResult {
+    let verb = if with_metadata { "ListRecords" } else { "ListIdentifiers" };
+    let request = match get_list_request(arguments, verb) {
+        Ok(request) => request,
+        Err(e) => return Ok(Err(e)),
+    };
+    // A token can claim any cursor, but the one for the page after must still be countable.
+    if request.cursor.checked_add(config.oai.page_size).is_none() {
+        return Ok(Err(OaiError::BadResumptionToken));
+    }
+    let list = get_record_list(connection, &request, config.oai.page_size)?;
+    if list.headers.is_empty() {
+        return Ok(Err(OaiError::NoRecordsMatch));
+    }
+
+    let mut xml = format!("  <{}>\n", verb);
+    for header in &list.headers {
+        if with_metadata {
+            xml.push_str(&render_record(connection, config, header, request.format, "    ")?);
+        } else {
+            xml.push_str(&render_header(config, header, "    "));
+        }
+    }
+    // The last page of a resumed harvest has an empty token, to say it is complete.
+    match &list.next {
+        Some(next) => xml.push_str(&format!(
+            "    <resumptionToken completeListSize=\"{}\" cursor=\"{}\">{}</resumptionToken>\n",
+            list.total, request.cursor, next.to_token()
+        )),
+        None if request.after > 0 => xml.push_str(&format!(
+            "    <resumptionToken completeListSize=\"{}\" cursor=\"{}\"/>\n",
+            list.total, request.cursor
+        )),
+        None => {}
+    }
+    xml.push_str(&format!("  </{}>\n", verb));
+    Ok(Ok(xml))
+}
+
+fn render_header(config: &Config, header: &RecordHeader, indent: &str) -> String {
+    format!(
+        "{indent}<header{}>\n{indent}  <identifier>{}</identifier>\n{indent}  <datestamp>{}</datestamp>\n{indent}</header>\n",
+        if header.deleted { " status=\"deleted\"" } else { "" },
+        escape_xml(&get_identifier(config, header.book_id)),
+        format_datestamp(header.datestamp),
+        indent = indent
+    )
+}
+
+// A record with its metadata in the given format. Deleted records only have a header.
+fn render_record(connection: &Connection, config: &Config, header: &RecordHeader, format: MetadataFormat, indent: &str) -> Result<String, rusqlite::Error> {
+    let inner = format!("{}  ", indent);
+    let mut xml = format!("{}<record>\n", indent);
+    xml.push_str(&render_header(config, header, &inner));
+    if !header.deleted {
+        let book = catalogue_processing::load_catalogue_record(connection, header.book_id)?;
+        let metadata_indent = format!("{}  ", inner);
+        xml.push_str(&format!("{}<metadata>\n", inner));
+        xml.push_str(&match format {
+            MetadataFormat::DublinCore => render_dublin_core(&book, &metadata_indent),
+            MetadataFormat::MarcXml => render_marc(&book, header.book_id, header.datestamp, &metadata_indent),
+        });
+        xml.push_str(&format!("{}</metadata>\n", inner));
+    }
+    xml.push_str(&format!("{}</record>\n", indent));
+    Ok(xml)
+}
+
+fn get_series_names(book: &Book) -> Vec<String> {
+    book.series.iter()
+        .map(|series| match series.position {
+            Some(position) => format!("{} #{}", series.name, position),
+            None => series.name.clone(),
+        })
+        .collect()
+}
+
+/// The Dublin Core elements of a record, without the element that holds them, which differs between OAI-PMH and SRU.
+pub(crate) fn get_dublin_core_elements(book: &Book) -> Vec<(&'static str, String)> {
+    let mut elements: Vec<(&'static str, String)> = vec![("title", book.title.trim().to_string())];
+    for author in &book.authors {
+        elements.push(("creator", author.name.clone()));
+    }
+    for subject in book.subjects.iter().flatten() {
+        elements.push(("subject", subject.name.clone()));
+    }
+    for publisher in book.publishers.iter().flatten() {
+        elements.push(("publisher", publisher.name.clone()));
+    }
+    if !book.publish_date.trim().is_empty() {
+        elements.push(("date", book.publish_date.trim().to_string()));
+    }
+    elements.push(("type", "Text".to_string()));
+    if let Some(pages) = book.number_of_pages {
+        elements.push(("format", format!("{} pages", pages)));
+    }
+    if let Some(isbn) = duplicate_processing::normalise_isbn(&book.isbn) {
+        elements.push(("identifier", format!("urn:isbn:{}", isbn)));
+    }
+    for identifier in &book.identifiers {
+        match identifier.scheme.as_str() {
+            "lccn" => elements.push(("identifier", format!("info:lccn/{}", identifier.value))),
+            "oclc" => elements.push(("identifier", format!("info:oclcnum/{}", identifier.value))),
+            _ => {}
+        }
+    }
+    for series in get_series_names(book) {
+        elements.push(("relation", series));
+    }
+    elements
+}
+
+fn render_dublin_core(book: &Book, indent: &str) -> String {
+    let mut xml = format!(
+        "{}<oai_dc:dc xmlns:oai_dc=\"{}\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\" xsi:schemaLocation=\"{} {}\">\n",
+        indent,
+        MetadataFormat::DublinCore.get_namespace(),
+        MetadataFormat::DublinCore.get_namespace(),
+        MetadataFormat::DublinCore.get_schema()
+    );
+    for (element, value) in get_dublin_core_elements(book) {
+        xml.push_str(&format!("{}  <dc:{1}>{2}</dc:{1}>\n", indent, element, escape_xml(&value)));
+    }
+    xml.push_str(&format!("{}</oai_dc:dc>\n", indent));
+    xml
+}
+
+// MARC enters people surname first. Names without a comma are taken to end with the surname.
+fn get_marc_name(name: &str) -> (char, String) {
+    if name.contains(',') {
+        return ('1', name.to_string());
+    }
+    match name.trim().rsplit_once(' ') {
+        Some((forenames, surname)) => ('1', format!("{}, {}", surname, forenames)),
+        None => ('0', name.trim().to_string()),
+    }
+}
+
+fn push_datafield(xml: &mut String, indent: &str, tag: &str, indicators: (char, char), subfields: &[(char, &str)]) {
+    xml.push_str(&format!("{}  <marc:datafield tag=\"{}\" ind1=\"{}\" ind2=\"{}\">\n", indent, tag, indicators.0, indicators.1));
+    for (code, value) in subfields {
+        xml.push_str(&format!("{}    <marc:subfield code=\"{}\">{}</marc:subfield>\n", indent, code, escape_xml(value)));
+    }
+    xml.push_str(&format!("{}  </marc:datafield>\n", indent));
+}
+
+/*
+ *  A minimal-level MARC 21 record. The catalogue does not know the
+ *  language or the place of publication, so the fixed fields say as much
+ *  rather than guess.
+ */
+pub(crate) fn render_marc(book: &Book, book_id: i32, modified_at: i64, indent: &str) -> String {
+    let modified = DateTime::from_timestamp(modified_at, 0).unwrap_or_default();
+    let year = statistics_processing::get_publish_year(&book.publish_date);
+    let fixed_fields = format!(
+        "{}{}{}    xx {}und d",
+        modified.format("%y%m%d"),
+        if year.is_some() { 's' } else { 'n' },
+        year.map_or("uuuu".to_string(), |year| year.to_string()),
+        "|".repeat(17)
+    );
+
+    let mut xml = format!(
+        "{}<marc:record xmlns:marc=\"{}\" xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" xsi:schemaLocation=\"{} {}\">\n",
+        indent,
+        MetadataFormat::MarcXml.get_namespace(),
+        MetadataFormat::MarcXml.get_namespace(),
+        MetadataFormat::MarcXml.get_schema()
+    );
+    xml.push_str(&format!("{}  <marc:leader>00000nam a22000007  4500</marc:leader>\n", indent));
+    xml.push_str(&format!("{}  <marc:controlfield tag=\"001\">{}</marc:controlfield>\n", indent, book_id));
+    xml.push_str(&format!("{}  <marc:controlfield tag=\"005\">{}.0</marc:controlfield>\n", indent, modified.format("%Y%m%d%H%M%S")));
+    xml.push_str(&format!("{}  <marc:controlfield tag=\"008\">{}</marc:controlfield>\n", indent, fixed_fields));
+
+    for identifier in book.identifiers.iter().filter(|identifier| identifier.scheme == "lccn") {
+        push_datafield(&mut xml, indent, "010", (' ', ' '), &[('a', &identifier.value)]);
+    }
+    if !book.isbn.trim().is_empty() {
+        push_datafield(&mut xml, indent, "020", (' ', ' '), &[('a', book.isbn.trim())]);
+    }
+    for identifier in book.identifiers.iter().filter(|identifier| identifier.scheme == "oclc") {
+        push_datafield(&mut xml, indent, "035", (' ', ' '), &[('a', &format!("(OCoLC){}", identifier.value))]);
+    }
+    if let Some(author) = book.authors.first() {
+        let (indicator, name) = get_marc_name(&author.name);
+        push_datafield(&mut xml, indent, "100", (indicator, ' '), &[('a', &name)]);
+    }
+    let title_indicator = if book.authors.is_empty() { '0' } else { '1' };
+    push_datafield(&mut xml, indent, "245", (title_indicator, '0'), &[('a', book.title.trim())]);
+
+    let mut publication: Vec<(char, &str)> = book.publishers.iter().flatten().map(|publisher| ('b', publisher.name.as_str())).collect();
+    if !book.publish_date.trim().is_empty() {
+        publication.push(('c', book.publish_date.trim()));
+    }
+    if !publication.is_empty() {
+        push_datafield(&mut xml, indent, "264", (' ', '1'), &publication);
+    }
+    if let Some(pages) = book.number_of_pages {
+        push_datafield(&mut xml, indent, "300", (' ', ' '), &[('a', &format!("{} pages", pages))]);
+    }
+    for series in &book.series {
+        match series.position {
+            Some(position) => push_datafield(&mut xml, indent, "490", ('0', ' '), &[('a', &series.name), ('v', &position.to_string())]),
+            None => push_datafield(&mut xml, indent, "490", ('0', ' '), &[('a', &series.name)]),
+        }
+    }
+    for subject in book.subjects.iter().flatten() {
+        push_datafield(&mut xml, indent, "650", (' ', '4'), &[('a', &subject.name)]);
+    }
+    for author in book.authors.iter().skip(1) {
+        let (indicator, name) = get_marc_name(&author.name);
+        push_datafield(&mut xml, indent, "700", (indicator, ' '), &[('a', &name)]);
+    }
+    xml.push_str(&format!("{}</marc:record>\n", indent));
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialisation;
+
+    // Midday on 1 to 4 January 2024.
+    const DAY: i64 = 86_400;
+    const FIRST_DAY: i64 = 1_704_067_200 + DAY / 2;
+
+    fn open_catalogue(name: &str) -> (Connection, Config) {
+        let (_, connection) = initialisation::open_test_database(name);
+        connection.execute_batch(&format!(
+            "INSERT INTO books (book_id, title, author) VALUES
+                (1, 'The Hobbit', 'J. R. R. Tolkien'), (2, 'Dune', 'Frank Herbert'), (3, 'Emma', 'Jane Austen');
+             -- Adding a book stamps it with the time now, so the dates are set after.
+             UPDATE books SET modified_at = CASE book_id WHEN 1 THEN {first} WHEN 2 THEN {second} ELSE {third} END;
+             INSERT INTO deleted_books (book_id, deleted_at) VALUES (4, {fourth});",
+            first = FIRST_DAY,
+            second = FIRST_DAY + DAY,
+            third = FIRST_DAY + 2 * DAY,
+            fourth = FIRST_DAY + 3 * DAY,
+        )).unwrap();
+        let mut config = Config::default();
+        config.oai.repository_identifier = "library.example.org".to_string();
+        config.oai.page_size = 2;
+        (connection, config)
+    }
+
+    fn respond(connection: &Connection, config: &Config, arguments: &[(&str, &str)]) -> String {
+        let arguments: Vec<(String, String)> = arguments.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+        handle_request(connection, config, "http://localhost", &arguments).unwrap()
+    }
+
+    fn get_identifiers(xml: &str) -> Vec<i32> {
+        xml.split("<identifier>oai:library.example.org:")
+            .skip(1)
+            .map(|rest| rest[..rest.find('<').unwrap()].parse().unwrap())
+            .collect()
+    }
+
+    fn get_token(xml: &str) -> Option<&str> {
+        let start = xml.find("<resumptionToken")?;
+        let rest = &xml[start..];
+        let token = &rest[rest.find('>')? + 1..];
+        Some(&token[..token.find('<')?])
+    }
+
+    #[test]
+    fn resumption_tokens_carry_a_harvest_to_the_end() {
+        let (connection, config) = open_catalogue("oai_resume");
+        let first = respond(&connection, &config, &[("verb", "ListIdentifiers"), ("metadataPrefix", "oai_dc")]);
+        assert_eq!(get_identifiers(&first), vec![1, 2]);
+        assert!(first.contains("completeListSize=\"4\" cursor=\"0\""));
+        let token = get_token(&first).unwrap();
+        assert_eq!(ListRequest::from_token(token).unwrap().to_token(), token);
+
+        let last = respond(&connection, &config, &[("verb", "ListIdentifiers"), ("resumptionToken", token)]);
+        assert_eq!(get_identifiers(&last), vec![3, 4]);
+        assert!(last.contains("<resumptionToken completeListSize=\"4\" cursor=\"2\"/>"));
+    }
+
+    #[test]
+    fn tokens_that_do_not_decode_or_count_too_far_are_refused() {
+        let (connection, config) = open_catalogue("oai_bad_token");
+        let overflowing = ListRequest { format: MetadataFormat::DublinCore, from: None, until: None, after: 2, cursor: usize::MAX - 1 };
+        for token in ["not a token", &overflowing.to_token()] {
+            let xml = respond(&connection, &config, &[("verb", "ListRecords"), ("resumptionToken", token)]);
+            assert!(xml.contains("<error code=\"badResumptionToken\">"), "{}", xml);
+        }
+    }
+
+    #[test]
+    fn from_and_until_take_in_whole_days() {
+        let (connection, config) = open_catalogue("oai_dates");
+        let arguments = [("verb", "ListIdentifiers"), ("metadataPrefix", "oai_dc"), ("from", "2024-01-02"), ("until", "2024-01-03")];
+        assert_eq!(get_identifiers(&respond(&connection, &config, &arguments)), vec![2, 3]);
+
+        let seconds = format_datestamp(FIRST_DAY + 2 * DAY);
+        let arguments = [("verb", "ListIdentifiers"), ("metadataPrefix", "oai_dc"), ("from", seconds.as_str())];
+        assert_eq!(get_identifiers(&respond(&connection, &config, &arguments)), vec![3, 4]);
+
+        let arguments = [("verb", "ListIdentifiers"), ("metadataPrefix", "oai_dc"), ("from", "2024-01-02"), ("until", seconds.as_str())];
+        assert!(respond(&connection, &config, &arguments).contains("<error code=\"badArgument\">"));
+        let arguments = [("verb", "ListIdentifiers"), ("metadataPrefix", "oai_dc"), ("from", "2025-01-01")];
+        assert!(respond(&connection, &config, &arguments).contains("<error code=\"noRecordsMatch\">"));
+    }
+
+    #[test]
+    fn deleted_books_keep_a_header_without_metadata() {
+        let (connection, config) = open_catalogue("oai_deleted");
+        let arguments = [("verb", "GetRecord"), ("identifier", "oai:library.example.org:4"), ("metadataPrefix", "oai_dc")];
+        let xml = respond(&connection, &config, &arguments);
+        assert!(xml.contains("<header status=\"deleted\">"));
+        assert!(xml.contains(&format!("<datestamp>{}</datestamp>", format_datestamp(FIRST_DAY + 3 * DAY))));
+        assert!(!xml.contains("<metadata>"));
+
+        let arguments = [("verb", "GetRecord"), ("identifier", "oai:library.example.org:3"), ("metadataPrefix", "oai_dc")];
+        let xml = respond(&connection, &config, &arguments);
+        assert!(xml.contains("<header>") && xml.contains("<metadata>"));
+        assert!(xml.contains("<dc:title>Emma</dc:title>"));
+    }
+}
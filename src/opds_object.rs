@@ -0,0 +1,117 @@
+use serde::Deserialize;
+use crate::book_object::Book;
+use crate::utilities;
+
+/*
+ *  The OPDS feeds come in two formats with the same content: OPDS 1.2,
+ *  which is Atom, under /opds/v1 and OPDS 2.0, which is JSON, under
+ *  /opds/v2. Feeds are built once as a Feed and then written out in the
+ *  format that was asked for.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FeedFormat {
+    Atom,
+    Json,
+}
+
+impl FeedFormat {
+    pub fn from_version(version: &str) -> Option<Self> {
+        match version {
+            "v1" => Some(FeedFormat::Atom),
+            "v2" => Some(FeedFormat::Json),
+            _ => None,
+        }
+    }
+
+    pub fn get_prefix(&self) -> &'static str {
+        match self {
+            FeedFormat::Atom => "/opds/v1",
+            FeedFormat::Json => "/opds/v2",
+        }
+    }
+
+    pub fn get_feed_type(&self, acquisition: bool) -> &'static str {
+        match (self, acquisition) {
+            (FeedFormat::Atom, false) => "application/atom+xml;profile=opds-catalog;kind=navigation",
+            (FeedFormat::Atom, true) => "application/atom+xml;profile=opds-catalog;kind=acquisition",
+            (FeedFormat::Json, _) => "application/opds+json",
+        }
+    }
+
+    pub fn get_entry_type(&self) -> &'static str {
+        match self {
+            FeedFormat::Atom => "application/atom+xml;type=entry;profile=opds-catalog",
+            FeedFormat::Json => "application/opds-publication+json",
+        }
+    }
+}
+
+// The query string a feed of books can be narrowed with. Values must match exactly, except query.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct FeedQuery {
+    pub query: Option<String>,
+    pub author: Option<String>,
+    pub subject: Option<String>,
+    pub shelf: Option<String>,
+    // Counted from 1.
+    pub page: Option<usize>,
+}
+
+// A link from a navigation feed to another feed.
+#[derive(Debug, Clone)]
+pub struct NavigationEntry {
+    pub title: String,
+    // Below the prefix of the format, e.g. "books?author=Homer".
+    pub path: String,
+    // How many books (or authors, subjects, shelves) the feed lists, if known.
+    pub count: Option<usize>,
+    pub acquisition: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Publication {
+    pub book_id: i32,
+    pub book: Book,
+    // The content type of the stored cover, if there is one.
+    pub cover_type: Option<String>,
+    pub added_at: Option<i64>,
+}
+
+#[derive(Debug)]
+pub struct Feed {
+    pub title: String,
+    // Below the prefix of the format, without the query.
+    pub path: String,
+    // The query the feed was asked for with, apart from the page.
+    pub query: Vec<(&'static str, String)>,
+    pub acquisition: bool,
+    pub navigation: Vec<NavigationEntry>,
+    pub publications: Vec<Publication>,
+    // Entries across all pages.
+    pub total: usize,
+    pub page: usize,
+    pub page_size: usize,
+}
+
+impl Feed {
+    pub fn get_last_page(&self) -> usize {
+        self.total.div_ceil(self.page_size).max(1)
+    }
+
+    // The feed's address below the server, for the given page or, with None, without a page.
+    pub fn get_href(&self, format: FeedFormat, page: Option<usize>) -> String {
+        let mut query: Vec<String> = self.query.iter()
+            .map(|(key, value)| format!("{}={}", key, utilities::percent_encode(value)))
+            .collect();
+        if let Some(page) = page {
+            query.push(format!("page={}", page));
+        }
+        let path = if self.path.is_empty() { String::new() } else { format!("/{}", self.path) };
+        if query.is_empty() {
+            format!("{}{}", format.get_prefix(), path)
+        } else {
+            format!("{}{}?{}", format.get_prefix(), path, query.join("&"))
+        }
+    }
+}
@@ -0,0 +1,486 @@
+use std::cmp::Reverse;
+use chrono::{DateTime, NaiveDate, SecondsFormat};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::{json, Map, Value};
+use crate::holding_processing::{self, CollectionFilter};
+use crate::label_object::LabelKind;
+use crate::opds_object::{Feed, FeedFormat, FeedQuery, NavigationEntry, Publication};
+use crate::utilities::{self, escape_xml};
+use crate::{catalogue_processing, cover_management, duplicate_processing, label_processing, statistics_processing};
+
+/*
+ *  OPDS catalogue feeds of a user's collection, for e-reader apps. Every
+ *  feed is of the collection of the user who authenticated, and books
+ *  are found with the same search as "Search Your Books". The books are
+ *  printed copies, so entries have covers and details but nothing to
+ *  download.
+ */
+const ATOM_NAMESPACES: &str = "xmlns=\"http://www.w3.org/2005/Atom\" \
+    xmlns:dc=\"http://purl.org/dc/terms/\" \
+    xmlns:opds=\"http://opds-spec.org/2010/catalog\" \
+    xmlns:opensearch=\"http://a9.com/-/spec/opensearch/1.1/\" \
+    xmlns:thr=\"http://purl.org/syndication/thread/1.0\"";
+
+pub const OPENSEARCH_TYPE: &str = "application/opensearchdescription+xml";
+
+// The lists the root feed leads to, with the filter each of their entries sets.
+const LISTS: [(&str, &str, &str); 3] = [("authors", "Authors", "author"), ("subjects", "Subjects", "subject"), ("shelves", "Shelves", "shelf")];
+
+fn get_list_counts(connection: &Connection, user_id: i32, list: &str) -> Result<Vec<(String, usize)>, rusqlite::Error> {
+    if list == "shelves" {
+        let shelves = label_processing::get_labels(connection, user_id, LabelKind::Shelf)?;
+        return Ok(shelves.into_iter().map(|shelf| (shelf.name, shelf.book_count as usize)).collect());
+    }
+    // Books catalogued without an author list still have books.author, as in find_books_in_collection.
+    let sql = match list {
+        "authors" => {
+            "SELECT names.name, COUNT(DISTINCT names.book_id) FROM (
+                SELECT book_id, name FROM book_authors
+                UNION ALL
+                SELECT book_id, author FROM books WHERE book_id NOT IN (SELECT book_id FROM book_authors)
+             ) AS names
+             JOIN libraries ON libraries.book_id = names.book_id
+             WHERE libraries.user_id = ?1
+             GROUP BY names.name
+             ORDER BY names.name COLLATE NOCASE"
+        }
+        _ => {
+            "SELECT book_subjects.subject, COUNT(DISTINCT book_subjects.book_id) FROM book_subjects
+             JOIN libraries ON libraries.book_id = book_subjects.book_id
+             WHERE libraries.user_id = ?1
+             GROUP BY book_subjects.subject
+             ORDER BY book_subjects.subject COLLATE NOCASE"
+        }
+    };
+    let mut stmt = connection.prepare(sql)?;
+    let counts = stmt.query_map(params![user_id], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as usize)))?;
+    counts.collect()
+}
+
+pub fn get_root_feed(connection: &Connection, user_id: i32, title: &str) -> Result<Feed, rusqlite::Error> {
+    let books: i64 = connection.query_row("SELECT COUNT(*) FROM libraries WHERE user_id = ?1", params![user_id], |row| row.get(0))?;
+    let mut navigation = vec![
+        NavigationEntry { title: "All books".to_string(), path: "books".to_string(), count: Some(books as usize), acquisition: true },
+        NavigationEntry { title: "Recently added".to_string(), path: "recent".to_string(), count: None, acquisition: true },
+    ];
+    for (list, list_title, _) in LISTS {
+        let count = get_list_counts(connection, user_id, list)?.len();
+        navigation.push(NavigationEntry { title: list_title.to_string(), path: list.to_string(), count: Some(count), acquisition: false });
+    }
+    Ok(Feed {
+        title: title.to_string(),
+        path: String::new(),
+        query: Vec::new(),
+        acquisition: false,
+        // The start feed always fits on one page.
+        total: navigation.len(),
+        page_size: navigation.len(),
+        navigation,
+        publications: Vec::new(),
+        page: 1,
+    })
+}
+
+/// The position of the first entry on a page, counted from 1, or None for a page too far to count to.
+pub fn get_page_start(page: usize, page_size: usize) -> Option<usize> {
+    page.checked_sub(1)?.checked_mul(page_size)?.checked_add(1)
+}
+
+/// The authors, subjects or shelves in the user's collection, each leading to its books. None for any other list.
+pub fn get_list_feed(connection: &Connection, user_id: i32, list: &str, page: usize, page_size: usize) -> Result<Option<Feed>, rusqlite::Error> {
+    let Some((list, title, filter)) = LISTS.into_iter().find(|(name, _, _)| *name == list) else {
+        return Ok(None);
+    };
+    let counts = get_list_counts(connection, user_id, list)?;
+    let navigation = counts.iter()
+        .skip(get_page_start(page, page_size).map_or(usize::MAX, |start| start - 1))
+        .take(page_size)
+        .map(|(name, count)| NavigationEntry {
+            title: name.clone(),
+            path: format!("books?{}={}", filter, utilities::percent_encode(name)),
+            count: Some(*count),
+            acquisition: true,
+        })
+        .collect();
+    Ok(Some(Feed {
+        title: title.to_string(),
+        path: list.to_string(),
+        query: Vec::new(),
+        acquisition: false,
+        navigation,
+        publications: Vec::new(),
+        total: counts.len(),
+        page,
+        page_size,
+    }))
+}
+
+/*
+ *  The books in the user's collection that match the query, by title, or
+ *  most recently added first. Books added before the date was recorded
+ *  come last in the recent feed.
+ */
+pub fn get_books_feed(connection: &Connection, user_id: i32, query: &FeedQuery, recent: bool, page_size: usize) -> Result<Feed, rusqlite::Error> {
+    let filter = CollectionFilter {
+        search: query.query.clone().unwrap_or_default(),
+        shelf: query.shelf.clone(),
+        author: query.author.clone(),
+        subject: query.subject.clone(),
+        ..CollectionFilter::default()
+    };
+    let mut books = holding_processing::find_books_in_collection(connection, user_id, &filter)?;
+    if recent {
+        books.sort_by_key(|book| Reverse(book.added_at));
+    }
+
+    let title = match (&query.author, &query.subject, &query.shelf, &query.query) {
+        _ if recent => "Recently added".to_string(),
+        (Some(author), _, _, _) => format!("Books by {}", author),
+        (_, Some(subject), _, _) => format!("Subject: {}", subject),
+        (_, _, Some(shelf), _) => format!("Shelf: {}", shelf),
+        (_, _, _, Some(search)) => format!("Search results for \"{}\"", search),
+        _ => "All books".to_string(),
+    };
+    let page = query.page.unwrap_or(1).max(1);
+    let mut publications = Vec::new();
+    for book in books.iter().skip(get_page_start(page, page_size).map_or(usize::MAX, |start| start - 1)).take(page_size) {
+        publications.push(load_publication(connection, book.book_id, book.added_at)?);
+    }
+    let pairs = [("query", &query.query), ("author", &query.author), ("subject", &query.subject), ("shelf", &query.shelf)];
+    Ok(Feed {
+        title,
+        path: if recent { "recent" } else { "books" }.to_string(),
+        query: pairs.into_iter().filter_map(|(key, value)| Some((key, value.clone()?))).collect(),
+        acquisition: true,
+        navigation: Vec::new(),
+        publications,
+        total: books.len(),
+        page,
+        page_size,
+    })
+}
+
+fn load_publication(connection: &Connection, book_id: i32, added_at: Option<i64>) -> Result<Publication, rusqlite::Error> {
+    Ok(Publication {
+        book_id,
+        book: catalogue_processing::load_catalogue_record(connection, book_id)?,
+        cover_type: cover_management::get_cover_content_type(connection, book_id)?,
+        added_at,
+    })
+}
+
+/// The book as a single entry, or None if it is not in the user's collection.
+pub fn get_publication(connection: &Connection, user_id: i32, book_id: i32) -> Result<Option<Publication>, rusqlite::Error> {
+    let added_at: Option<Option<i64>> = connection.query_row(
+        "SELECT added_at FROM libraries WHERE user_id = ?1 AND book_id = ?2",
+        params![user_id, book_id],
+        |row| row.get(0),
+    ).optional()?;
+    match added_at {
+        Some(added_at) => Ok(Some(load_publication(connection, book_id, added_at)?)),
+        None => Ok(None),
+    }
+}
+
+fn format_date(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .map(|date_time| date_time.to_rfc3339_opts(SecondsFormat::Secs, true))
+        .unwrap_or_default()
+}
+
+// A full date if the record has one, otherwise only the year, as free-text dates such as "Spring 1991" are not valid in either format.
+fn get_published(publication: &Publication) -> Option<String> {
+    let publish_date = publication.book.publish_date.trim();
+    if NaiveDate::parse_from_str(publish_date, "%Y-%m-%d").is_ok() {
+        return Some(publish_date.to_string());
+    }
+    statistics_processing::get_publish_year(publish_date).map(|year| year.to_string())
+}
+
+fn get_identifier(publication: &Publication) -> String {
+    match duplicate_processing::normalise_isbn(&publication.book.isbn) {
+        Some(isbn) => format!("urn:isbn:{}", isbn),
+        None => format!("urn:rlms:book:{}", publication.book_id),
+    }
+}
+
+fn get_series_names(publication: &Publication) -> Vec<String> {
+    publication.book.series.iter()
+        .map(|series| match series.position {
+            Some(position) => format!("{} #{}", series.name, position),
+            None => series.name.clone(),
+        })
+        .collect()
+}
+
+pub fn render_feed(feed: &Feed, format: FeedFormat, base_url: &str, catalogue_title: &str) -> String {
+    match format {
+        FeedFormat::Atom => render_atom_feed(feed, base_url, catalogue_title),
+        FeedFormat::Json => render_json_feed(feed, base_url).to_string(),
+    }
+}
+
+pub fn render_publication(publication: &Publication, format: FeedFormat, base_url: &str) -> String {
+    match format {
+        FeedFormat::Atom => format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}",
+            render_atom_entry(publication, base_url, Some(ATOM_NAMESPACES))
+        ),
+        FeedFormat::Json => render_json_publication(publication, base_url).to_string(),
+    }
+}
+
+// The first, previous, next and last pages, for feeds longer than a page.
+fn get_page_links(feed: &Feed) -> Vec<(&'static str, usize)> {
+    let last = feed.get_last_page();
+    if last == 1 {
+        return Vec::new();
+    }
+    let mut links = vec![("first", 1)];
+    if feed.page > 1 {
+        links.push(("previous", (feed.page - 1).min(last)));
+    }
+    if feed.page < last {
+        links.push(("next", feed.page + 1));
+    }
+    links.push(("last", last));
+    links
+}
+
+fn atom_link(rel: &str, href: &str, link_type: &str) -> String {
+    format!("  <link rel=\"{}\" href=\"{}\" type=\"{}\"/>\n", escape_xml(rel), escape_xml(href), escape_xml(link_type))
+}
+
+fn render_atom_feed(feed: &Feed, base_url: &str, catalogue_title: &str) -> String {
+    let format = FeedFormat::Atom;
+    let feed_type = format.get_feed_type(feed.acquisition);
+    let now = format_date(utilities::current_timestamp());
+    let mut xml = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<feed {}>\n", ATOM_NAMESPACES);
+    xml.push_str(&format!("  <id>{}</id>\n", escape_xml(&format!("{}{}", base_url, feed.get_href(format, None)))));
+    xml.push_str(&format!("  <title>{}</title>\n", escape_xml(&feed.title)));
+    xml.push_str(&format!("  <updated>{}</updated>\n", now));
+    xml.push_str(&format!("  <author><name>{}</name></author>\n", escape_xml(catalogue_title)));
+
+    let page = if feed.page > 1 { Some(feed.page) } else { None };
+    xml.push_str(&atom_link("self", &format!("{}{}", base_url, feed.get_href(format, page)), feed_type));
+    xml.push_str(&atom_link("start", &format!("{}{}", base_url, format.get_prefix()), format.get_feed_type(false)));
+    if !feed.path.is_empty() {
+        xml.push_str(&atom_link("up", &format!("{}{}", base_url, format.get_prefix()), format.get_feed_type(false)));
+    }
+    xml.push_str(&atom_link("search", &format!("{}/opds/opensearch.xml", base_url), OPENSEARCH_TYPE));
+    for (rel, page) in get_page_links(feed) {
+        xml.push_str(&atom_link(rel, &format!("{}{}", base_url, feed.get_href(format, Some(page))), feed_type));
+    }
+    if feed.acquisition {
+        xml.push_str(&format!("  <opensearch:totalResults>{}</opensearch:totalResults>\n", feed.total));
+        xml.push_str(&format!("  <opensearch:itemsPerPage>{}</opensearch:itemsPerPage>\n", feed.page_size));
+        xml.push_str(&format!("  <opensearch:startIndex>{}</opensearch:startIndex>\n", get_page_start(feed.page, feed.page_size).unwrap_or(usize::MAX)));
+    }
+
+    for entry in &feed.navigation {
+        let href = format!("{}{}/{}", base_url, format.get_prefix(), entry.path);
+        let entry_type = format.get_feed_type(entry.acquisition);
+        xml.push_str("  <entry>\n");
+        xml.push_str(&format!("    <title>{}</title>\n", escape_xml(&entry.title)));
+        xml.push_str(&format!("    <id>{}</id>\n", escape_xml(&href)));
+        xml.push_str(&format!("    <updated>{}</updated>\n", now));
+        match entry.count {
+            Some(count) => {
+                let noun = match (entry.acquisition, count == 1) {
+                    (true, true) => "book",
+                    (true, false) => "books",
+                    (false, true) => "entry",
+                    (false, false) => "entries",
+                };
+                xml.push_str(&format!("    <content type=\"text\">{} {}</content>\n", count, noun));
+                xml.push_str(&format!(
+                    "    <link rel=\"subsection\" href=\"{}\" type=\"{}\" thr:count=\"{}\"/>\n",
+                    escape_xml(&href), entry_type, count
+                ));
+            }
+            None => xml.push_str(&format!("    <link rel=\"subsection\" href=\"{}\" type=\"{}\"/>\n", escape_xml(&href), entry_type)),
+        }
+        xml.push_str("  </entry>\n");
+    }
+    for publication in &feed.publications {
+        xml.push_str(&render_atom_entry(publication, base_url, None));
+    }
+    xml.push_str("</feed>\n");
+    xml
+}
+
+// A standalone entry declares the namespaces itself and links to itself as "self" rather than "alternate".
+fn render_atom_entry(publication: &Publication, base_url: &str, namespaces: Option<&str>) -> String {
+    let format = FeedFormat::Atom;
+    let book = &publication.book;
+    let href = format!("{}{}/books/{}", base_url, format.get_prefix(), publication.book_id);
+    let updated = format_date(publication.added_at.unwrap_or_else(utilities::current_timestamp));
+
+    let mut xml = match namespaces {
+        Some(namespaces) => format!("<entry {}>\n", namespaces),
+        None => "  <entry>\n".to_string(),
+    };
+    xml.push_str(&format!("    <title>{}</title>\n", escape_xml(book.title.trim())));
+    // Two records may share an ISBN until they are merged, and Atom ids must be unique, so the ISBN goes in dc:identifier.
+    xml.push_str(&format!("    <id>urn:rlms:book:{}</id>\n", publication.book_id));
+    xml.push_str(&format!("    <updated>{}</updated>\n", updated));
+    for author in &book.authors {
+        xml.push_str(&format!("    <author><name>{}</name></author>\n", escape_xml(&author.name)));
+    }
+    for publisher in book.publishers.iter().flatten() {
+        xml.push_str(&format!("    <dc:publisher>{}</dc:publisher>\n", escape_xml(&publisher.name)));
+    }
+    if let Some(published) = get_published(publication) {
+        xml.push_str(&format!("    <dc:issued>{}</dc:issued>\n", published));
+    }
+    if let Some(isbn) = duplicate_processing::normalise_isbn(&book.isbn) {
+        xml.push_str(&format!("    <dc:identifier>urn:isbn:{}</dc:identifier>\n", isbn));
+    }
+    for subject in book.subjects.iter().flatten() {
+        xml.push_str(&format!("    <category term=\"{0}\" label=\"{0}\"/>\n", escape_xml(&subject.name)));
+    }
+    let mut summary = get_series_names(publication);
+    if let Some(pages) = book.number_of_pages {
+        summary.push(format!("{} pages", pages));
+    }
+    if !summary.is_empty() {
+        xml.push_str(&format!("    <summary type=\"text\">{}</summary>\n", escape_xml(&summary.join(". "))));
+    }
+    if let Some(cover_type) = &publication.cover_type {
+        xml.push_str(&format!(
+            "    <link rel=\"http://opds-spec.org/image\" href=\"{}/covers/{}\" type=\"{}\"/>\n",
+            escape_xml(base_url), publication.book_id, escape_xml(cover_type)
+        ));
+        xml.push_str(&format!(
+            "    <link rel=\"http://opds-spec.org/image/thumbnail\" href=\"{}/covers/{}/thumbnail\" type=\"{}\"/>\n",
+            escape_xml(base_url), publication.book_id, cover_management::THUMBNAIL_CONTENT_TYPE
+        ));
+    }
+    let rel = if namespaces.is_some() { "self" } else { "alternate" };
+    xml.push_str(&format!("    <link rel=\"{}\" href=\"{}\" type=\"{}\"/>\n", rel, escape_xml(&href), format.get_entry_type()));
+    xml.push_str(if namespaces.is_some() { "</entry>\n" } else { "  </entry>\n" });
+    xml
+}
+
+fn json_link(rel: &str, href: String, link_type: &str) -> Value {
+    json!({ "rel": rel, "href": href, "type": link_type })
+}
+
+fn render_json_feed(feed: &Feed, base_url: &str) -> Value {
+    let format = FeedFormat::Json;
+    let feed_type = format.get_feed_type(feed.acquisition);
+    let page = if feed.page > 1 { Some(feed.page) } else { None };
+    let mut links = vec![
+        json_link("self", format!("{}{}", base_url, feed.get_href(format, page)), feed_type),
+        json_link("start", format!("{}{}", base_url, format.get_prefix()), feed_type),
+        json!({
+            "rel": "search",
+            "href": format!("{}{}/books{{?query}}", base_url, format.get_prefix()),
+            "type": feed_type,
+            "templated": true,
+        }),
+    ];
+    if !feed.path.is_empty() {
+        links.push(json_link("up", format!("{}{}", base_url, format.get_prefix()), feed_type));
+    }
+    for (rel, page) in get_page_links(feed) {
+        links.push(json_link(rel, format!("{}{}", base_url, feed.get_href(format, Some(page))), feed_type));
+    }
+
+    let mut document = json!({
+        "metadata": {
+            "title": feed.title,
+            "numberOfItems": feed.total,
+            "itemsPerPage": feed.page_size,
+            "currentPage": feed.page,
+        },
+        "links": links,
+    });
+    if feed.acquisition {
+        let publications: Vec<Value> = feed.publications.iter().map(|publication| render_json_publication(publication, base_url)).collect();
+        document["publications"] = json!(publications);
+    } else {
+        let navigation: Vec<Value> = feed.navigation.iter()
+            .map(|entry| {
+                let mut link = json!({
+                    "rel": "subsection",
+                    "href": format!("{}{}/{}", base_url, format.get_prefix(), entry.path),
+                    "title": entry.title,
+                    "type": feed_type,
+                });
+                if let Some(count) = entry.count {
+                    link["properties"] = json!({ "numberOfItems": count });
+                }
+                link
+            })
+            .collect();
+        document["navigation"] = json!(navigation);
+    }
+    document
+}
+
+fn render_json_publication(publication: &Publication, base_url: &str) -> Value {
+    let format = FeedFormat::Json;
+    let book = &publication.book;
+    let mut metadata = Map::new();
+    metadata.insert("@type".to_string(), json!("http://schema.org/Book"));
+    metadata.insert("title".to_string(), json!(book.title.trim()));
+    metadata.insert("identifier".to_string(), json!(get_identifier(publication)));
+    metadata.insert("author".to_string(), json!(book.authors.iter().map(|author| json!({ "name": author.name })).collect::<Vec<Value>>()));
+    if let Some(publishers) = book.publishers.as_ref().filter(|publishers| !publishers.is_empty()) {
+        metadata.insert("publisher".to_string(), json!(publishers.iter().map(|publisher| json!({ "name": publisher.name })).collect::<Vec<Value>>()));
+    }
+    if let Some(published) = get_published(publication) {
+        metadata.insert("published".to_string(), json!(published));
+    }
+    if let Some(added_at) = publication.added_at {
+        metadata.insert("modified".to_string(), json!(format_date(added_at)));
+    }
+    if let Some(pages) = book.number_of_pages {
+        metadata.insert("numberOfPages".to_string(), json!(pages));
+    }
+    if let Some(subjects) = book.subjects.as_ref().filter(|subjects| !subjects.is_empty()) {
+        metadata.insert("subject".to_string(), json!(subjects.iter().map(|subject| json!({ "name": subject.name })).collect::<Vec<Value>>()));
+    }
+    if !book.series.is_empty() {
+        let series: Vec<Value> = book.series.iter()
+            .map(|series| match series.position {
+                Some(position) => json!({ "name": series.name, "position": position }),
+                None => json!({ "name": series.name }),
+            })
+            .collect();
+        metadata.insert("belongsTo".to_string(), json!({ "series": series }));
+    }
+
+    let mut images = Vec::new();
+    if let Some(cover_type) = &publication.cover_type {
+        images.push(json!({ "href": format!("{}/covers/{}", base_url, publication.book_id), "type": cover_type }));
+        images.push(json!({
+            "href": format!("{}/covers/{}/thumbnail", base_url, publication.book_id),
+            "type": cover_management::THUMBNAIL_CONTENT_TYPE,
+        }));
+    }
+    json!({
+        "metadata": metadata,
+        "links": [json_link("self", format!("{}{}/books/{}", base_url, format.get_prefix(), publication.book_id), format.get_entry_type())],
+        "images": images,
+    })
+}
+
+// Searches the collection in either format; apps pick the one they read.
+pub fn render_opensearch_description(base_url: &str, catalogue_title: &str) -> String {
+    // OpenSearch allows at most 16 characters here.
+    let short_name: String = catalogue_title.chars().take(16).collect();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<OpenSearchDescription xmlns=\"http://a9.com/-/spec/opensearch/1.1/\">\n");
+    xml.push_str(&format!("  <ShortName>{}</ShortName>\n", escape_xml(&short_name)));
+    xml.push_str(&format!("  <Description>Search your collection in {}</Description>\n", escape_xml(catalogue_title)));
+    xml.push_str("  <InputEncoding>UTF-8</InputEncoding>\n  <OutputEncoding>UTF-8</OutputEncoding>\n");
+    for format in [FeedFormat::Atom, FeedFormat::Json] {
+        xml.push_str(&format!(
+            "  <Url type=\"{}\" template=\"{}{}/books?query={{searchTerms}}\"/>\n",
+            format.get_feed_type(true), escape_xml(base_url), format.get_prefix()
+        ));
+    }
+    xml.push_str("</OpenSearchDescription>\n");
+    xml
+}
@@ -0,0 +1,198 @@
+use std::io;
+use rusqlite::{params, Connection};
+use crate::configuration::Config;
+use crate::utilities::{self, clear_screen};
+
+#[derive(Debug)]
+pub struct BookEdit {
+    pub edited_at: String,
+    pub field: String,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+    pub source: String,
+    pub editor_email: Option<String>,
+}
+
+/*
+ *  Each field of a catalogue record remembers where its value came from.
+ *  A field without a row in book_field_sources still has the value it was
+ *  saved with, so its source is that of the record (books.source). Fields
+ *  whose source is "manual" were set by hand and are left alone when the
+ *  record is refreshed from Open Library.
+ */
+pub fn get_field_source(connection: &Connection, book_id: i32, field: &str) -> Result<String, rusqlite::Error> {
+    connection.query_row(
+        "SELECT COALESCE(
+            (SELECT source FROM book_field_sources WHERE book_id = ?1 AND field = ?2),
+            (SELECT source FROM books WHERE book_id = ?1))",
+        params![book_id, field],
+        |row| row.get(0),
+    )
+}
+
+/// The fields of a record that differ from its source because they were changed by hand.
+pub fn get_manual_fields(connection: &Connection, book_id: i32) -> Result<Vec<String>, rusqlite::Error> {
+    let mut stmt = connection.prepare(
+        "SELECT book_field_sources.field FROM book_field_sources
+         JOIN books ON books.book_id = book_field_sources.book_id
+         WHERE book_field_sources.book_id = ?1
+           AND book_field_sources.source = 'manual'
+           AND books.source != 'manual'
+         ORDER BY book_field_sources.field",
+    )?;
+    let fields = stmt.query_map(params![book_id], |row| row.get(0))?;
+    fields.collect()
+}
+
+/*
+ *  Records one changed field in the edit history and makes the new source
+ *  the field's source. The editor's email is copied into the history, as
+ *  in the audit log, so it still makes sense after the user is removed.
+ */
+pub fn record_edit(
+    connection: &Connection,
+    book_id: i32,
+    field: &str,
+    old_value: Option<&str>,
+    new_value: Option<&str>,
+    source: &str,
+    editor_user_id: Option<i32>,
+) -> Result<(), rusqlite::Error> {
+    let now = utilities::current_timestamp();
+    connection.execute(
+        "INSERT INTO book_edits (book_id, field, old_value, new_value, source, editor_user_id, editor_email, edited_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, (SELECT email FROM users WHERE user_id = ?6), ?7)",
+        params![book_id, field, old_value, new_value, source, editor_user_id, now],
+    )?;
+    connection.execute(
+        "INSERT INTO book_field_sources (book_id, field, source, updated_at) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT (book_id, field) DO UPDATE SET source = excluded.source, updated_at = excluded.updated_at",
+        params![book_id, field, source, now],
+    )?;
+    Ok(())
+}
+
+pub fn get_edit_history(connection: &Connection, book_id: i32) -> Result<Vec<BookEdit>, rusqlite::Error> {
+    let mut stmt = connection.prepare(
+        "SELECT edited_at, field, old_value, new_value, source, editor_email
+         FROM book_edits WHERE book_id = ?1 ORDER BY edit_id",
+    )?;
+    let edits = stmt.query_map(params![book_id], |row| {
+        Ok(BookEdit {
+            edited_at: utilities::format_timestamp(row.get(0)?),
+            field: row.get(1)?,
+            old_value: row.get(2)?,
+            new_value: row.get(3)?,
+            source: row.get(4)?,
+            editor_email: row.get(5)?,
+        })
+    })?;
+    edits.collect()
+}
+
+pub(crate) fn view_edit_history(database_name: &str, config: &Config) -> bool {
+    clear_screen();
+    print_edit_history_header();
+    let connection = match Connection::open(database_name) {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("Failed to connect to the database: {}", e);
+            return false;
+        }
+    };
+
+    let book_id = loop {
+        println!("Enter the ID of the book: ");
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input).is_err() {
+            println!("Failed to read input. Please try again.");
+            continue;
+        }
+        match input.trim().parse::<i32>() {
+            Ok(book_id) => break book_id,
+            Err(_) => println!("Invalid ID. Please enter a valid book ID."),
+        }
+    };
+
+    let edits = match get_edit_history(&connection, book_id) {
+        Ok(edits) => edits,
+        Err(e) => {
+            println!("Failed to read the edit history: {}", e);
+            return false;
+        }
+    };
+
+    // As with the audit log, only the most recent edits are printed.
+    let skipped = edits.len().saturating_sub(config.interface.max_list_rows);
+    if skipped > 0 {
+        println!("{} older edits are not shown.", skipped);
+    }
+    for edit in &edits[skipped..] {
+        println!(
+            "[{}] {} changed from \"{}\" to \"{}\" by {} ({})",
+            edit.edited_at,
+            edit.field,
+            edit.old_value.as_deref().unwrap_or(""),
+            edit.new_value.as_deref().unwrap_or(""),
+            edit.editor_email.as_deref().unwrap_or("(nobody)"),
+            edit.source
+        );
+    }
+    println!("{} edit(s) to book {}.", edits.len(), book_id);
+    true
+}
+
+fn print_edit_history_header() {
+    println!("#######################");
+    println!("## Book Edit History ##");
+    println!("#######################");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book_object::{Author, Book};
+    use crate::catalogue_processing::{self, SOURCE_MANUAL, SOURCE_OPEN_LIBRARY};
+    use crate::initialisation;
+
+    #[test]
+    fn a_hand_edit_becomes_the_fields_source_and_is_kept_in_the_history() {
+        let (_, connection) = initialisation::open_test_database("provenance_edit");
+        connection.execute(
+            "INSERT INTO users (user_id, email, firstname, lastname) VALUES (1, 'cat@example.org', 'Cat', 'Aloguer')", [],
+        ).unwrap();
+        let book = Book {
+            title: "The Hobit".to_string(),
+            isbn: "9780261102217".to_string(),
+            authors: vec![Author { name: "J. R. R. Tolkien".to_string() }],
+            ..Book::default()
+        };
+        let book_id = catalogue_processing::save_catalogue_record(&connection, &book, SOURCE_OPEN_LIBRARY).unwrap();
+        assert_eq!(get_field_source(&connection, book_id, "title").unwrap(), SOURCE_OPEN_LIBRARY);
+
+        let corrected = Book { title: "The Hobbit".to_string(), ..book.clone() };
+        let changes = catalogue_processing::get_changed_fields(&book, &corrected);
+        catalogue_processing::apply_record_changes(&connection, book_id, &changes, SOURCE_MANUAL, Some(1)).unwrap();
+
+        assert_eq!(get_field_source(&connection, book_id, "title").unwrap(), SOURCE_MANUAL);
+        assert_eq!(get_field_source(&connection, book_id, "authors").unwrap(), SOURCE_OPEN_LIBRARY);
+        assert_eq!(get_manual_fields(&connection, book_id).unwrap(), vec!["title"]);
+        let history = get_edit_history(&connection, book_id).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(
+            (history[0].old_value.as_deref(), history[0].new_value.as_deref(), history[0].editor_email.as_deref()),
+            (Some("The Hobit"), Some("The Hobbit"), Some("cat@example.org"))
+        );
+    }
+
+    #[test]
+    fn records_catalogued_by_hand_have_no_fields_that_differ_from_their_source() {
+        let (_, connection) = initialisation::open_test_database("provenance_manual_record");
+        let book = Book { title: "Parish Walks".to_string(), ..Book::default() };
+        let book_id = catalogue_processing::save_catalogue_record(&connection, &book, SOURCE_MANUAL).unwrap();
+        let changes = catalogue_processing::get_changed_fields(&book, &Book { title: "Parish Rambles".to_string(), ..Book::default() });
+        catalogue_processing::apply_record_changes(&connection, book_id, &changes, SOURCE_MANUAL, None).unwrap();
+        assert!(get_manual_fields(&connection, book_id).unwrap().is_empty());
+        assert_eq!(get_edit_history(&connection, book_id).unwrap()[0].editor_email, None);
+    }
+}
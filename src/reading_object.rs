@@ -0,0 +1,56 @@
+/*
+ *  A user's reading of a book in their collection. The status and the
+ *  rating, note and review live on the user's `libraries` row; each time
+ *  the book is started is a reading session, so re-reads keep their own
+ *  dates. A session without an outcome is the one in progress.
+ */
+pub const READING_STATUSES: [&str; 4] = ["want-to-read", "reading", "read", "abandoned"];
+
+#[derive(Debug, Default, Clone)]
+pub struct ReadingSession {
+    pub started_on: Option<String>,
+    pub finished_on: Option<String>,
+    pub outcome: Option<String>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ReadingState {
+    pub reading_status: Option<String>,
+    pub rating: Option<u8>,
+    pub note: Option<String>,
+    pub review: Option<String>,
+    pub sessions: Vec<ReadingSession>,
+}
+
+impl ReadingState {
+    pub fn times_read(&self) -> usize {
+        self.sessions.iter().filter(|session| session.outcome.as_deref() == Some("read")).count()
+    }
+
+    pub fn current_session(&self) -> Option<&ReadingSession> {
+        self.sessions.iter().find(|session| session.outcome.is_none())
+    }
+
+    pub fn print_reading_info(&self) {
+        println!("Reading Status: {}", self.reading_status.as_deref().unwrap_or("Not set"));
+        match self.rating {
+            Some(rating) => println!("Rating: {}/5", rating),
+            None => println!("Rating: Not rated"),
+        }
+        println!("Times Read: {}", self.times_read());
+        for session in &self.sessions {
+            println!(
+                "  {} to {}: {}",
+                session.started_on.as_deref().unwrap_or("?"),
+                session.finished_on.as_deref().unwrap_or("now"),
+                session.outcome.as_deref().unwrap_or("reading")
+            );
+        }
+        if let Some(note) = &self.note {
+            println!("Private Note: {}", note);
+        }
+        if let Some(review) = &self.review {
+            println!("Review: {}", review);
+        }
+    }
+}
@@ -0,0 +1,334 @@
+use std::io;
+use chrono::{Local, NaiveDate};
+use rusqlite::{params, Connection, OptionalExtension};
+use crate::book_processing;
+use crate::reading_object::{ReadingSession, ReadingState, READING_STATUSES};
+use crate::user_object::User;
+use crate::utilities::{clear_screen, get_optional_input};
+
+/// The user's reading of a book, or None if the book is not in their collection.
+pub fn get_reading_state(connection: &Connection, user_id: i32, book_id: i32) -> Result<Option<ReadingState>, rusqlite::Error> {
+    let state = connection.query_row(
+        "SELECT reading_status, rating, note, review FROM libraries WHERE user_id = ?1 AND book_id = ?2",
+        params![user_id, book_id],
+        |row| {
+            Ok(ReadingState {
+                reading_status: row.get(0)?,
+                rating: row.get(1)?,
+                note: row.get(2)?,
+                review: row.get(3)?,
+                sessions: Vec::new(),
+            })
+        },
+    ).optional()?;
+    let Some(mut state) = state else { return Ok(None); };
+
+    let mut stmt = connection.prepare(
+        "SELECT started_on, finished_on, outcome FROM reading_sessions
+         WHERE user_id = ?1 AND book_id = ?2
+         ORDER BY COALESCE(started_on, finished_on), session_id",
+    )?;
+    let sessions = stmt.query_map(params![user_id, book_id], |row| {
+        Ok(ReadingSession {
+            started_on: row.get(0)?,
+            finished_on: row.get(1)?,
+            outcome: row.get(2)?,
+        })
+    })?;
+    state.sessions = sessions.collect::<Result<_, _>>()?;
+    Ok(Some(state))
+}
+
+/*
+ *  Sets the reading status. Starting a book opens a new session unless
+ *  one is already in progress, so reading a book again is just setting it
+ *  to "reading" again. Finishing or abandoning it closes the session in
+ *  progress, or records one without a start date if there is none.
+ */
+pub fn set_reading_status(connection: &mut Connection, user_id: i32, book_id: i32, status: &str, date: &str) -> Result<(), rusqlite::Error> {
+    // The session and the library's status change together or not at all.
+    let transaction = connection.transaction()?;
+    let in_progress: Option<i64> = transaction.query_row(
+        "SELECT session_id FROM reading_sessions WHERE user_id = ?1 AND book_id = ?2 AND outcome IS NULL",
+        params![user_id, book_id],
+        |row| row.get(0),
+    ).optional()?;
+
+    match (status, in_progress) {
+        ("reading", None) => {
+            transaction.execute(
+                "INSERT INTO reading_sessions (user_id, book_id, started_on) VALUES (?1, ?2, ?3)",
+                params![user_id, book_id, date],
+            )?;
+        }
+        ("read" | "abandoned", Some(session_id)) => {
+            transaction.execute(
+                "UPDATE reading_sessions SET finished_on = ?2, outcome = ?3 WHERE session_id = ?1",
+                params![session_id, date, status],
+            )?;
+        }
+        ("read" | "abandoned", None) => {
+            transaction.execute(
+                "INSERT INTO reading_sessions (user_id, book_id, finished_on, outcome) VALUES (?1, ?2, ?3, ?4)",
+                params![user_id, book_id, date, status],
+            )?;
+        }
+        _ => {}
+    }
+    transaction.execute(
+        "UPDATE libraries SET reading_status = ?3 WHERE user_id = ?1 AND book_id = ?2",
+        params![user_id, book_id, status],
+    )?;
+    transaction.commit()
+}
+
+/// Records a reading that has already finished, e.g. one from before rLMS was used.
+pub fn add_past_reading(connection: &mut Connection, user_id: i32, book_id: i32, started_on: Option<&str>, finished_on: &str) -> Result<(), rusqlite::Error> {
+    let transaction = connection.transaction()?;
+    transaction.execute(
+        "INSERT INTO reading_sessions (user_id, book_id, started_on, finished_on, outcome) VALUES (?1, ?2, ?3, ?4, 'read')",
+        params![user_id, book_id, started_on, finished_on],
+    )?;
+    transaction.execute(
+        "UPDATE libraries SET reading_status = COALESCE(reading_status, 'read') WHERE user_id = ?1 AND book_id = ?2",
+        params![user_id, book_id],
+    )?;
+    transaction.commit()
+}
+
+pub fn set_rating(connection: &Connection, user_id: i32, book_id: i32, rating: Option<u8>) -> Result<(), rusqlite::Error> {
+    connection.execute(
+        "UPDATE libraries SET rating = ?3 WHERE user_id = ?1 AND book_id = ?2",
+        params![user_id, book_id, rating],
+    )?;
+    Ok(())
+}
+
+pub fn set_note(connection: &Connection, user_id: i32, book_id: i32, note: Option<&str>) -> Result<(), rusqlite::Error> {
+    connection.execute(
+        "UPDATE libraries SET note = ?3 WHERE user_id = ?1 AND book_id = ?2",
+        params![user_id, book_id, note],
+    )?;
+    Ok(())
+}
+
+pub fn set_review(connection: &Connection, user_id: i32, book_id: i32, review: Option<&str>) -> Result<(), rusqlite::Error> {
+    connection.execute(
+        "UPDATE libraries SET review = ?3 WHERE user_id = ?1 AND book_id = ?2",
+        params![user_id, book_id, review],
+    )?;
+    Ok(())
+}
+
+/// Accepts one of READING_STATUSES, or "none" for books with no status yet.
+pub fn parse_status_filter(input: &str) -> Option<String> {
+    let status = input.trim().to_lowercase();
+    (status == "none" || READING_STATUSES.contains(&status.as_str())).then_some(status)
+}
+
+/*
+ *  The reading log for one book in the user's collection: status and
+ *  dates, rating, a private note and a review. Changes are saved as soon
+ *  as they are made.
+ */
+pub(crate) fn update_reading(database_name: &str, user: &User) -> bool {
+    clear_screen();
+    print_reading_log_header();
+    let mut connection = match Connection::open(database_name) {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("Failed to connect to the database: {}", e);
+            return false;
+        }
+    };
+    let user_id = user.get_user_id();
+    let book_id = book_processing::get_book_id_from_user(&connection);
+
+    let mut changed = false;
+    loop {
+        let state = match get_reading_state(&connection, user_id, book_id) {
+            Ok(Some(state)) => state,
+            Ok(None) => {
+                println!("Book {} is not in your collection. Add a copy of it first.", book_id);
+                return false;
+            }
+            Err(e) => {
+                println!("Failed to read your reading log: {}", e);
+                return false;
+            }
+        };
+        state.print_reading_info();
+        println!(
+            "Choose from the options below:\n\
+            \t1. Set reading status\n\
+            \t2. Record a past reading\n\
+            \t3. Rate this book\n\
+            \t4. Private note\n\
+            \t5. Review\n\
+            \t0. Back"
+        );
+        let mut choice = String::new();
+        if io::stdin().read_line(&mut choice).is_err() {
+            println!("Failed to read input. Please try again.");
+            continue;
+        }
+
+        let result = match choice.trim() {
+            "1" => {
+                let status = loop {
+                    match get_optional_input(&format!("Reading status ({}):", READING_STATUSES.join(", "))) {
+                        Some(status) if READING_STATUSES.contains(&status.to_lowercase().as_str()) => break status.to_lowercase(),
+                        _ => println!("Invalid status. Please choose one of: {}", READING_STATUSES.join(", ")),
+                    }
+                };
+                let date = match status.as_str() {
+                    "want-to-read" => get_today(),
+                    "reading" => get_date_from_user("Date started (YYYY-MM-DD, leave blank for today):"),
+                    _ => get_date_from_user("Date finished (YYYY-MM-DD, leave blank for today):"),
+                };
+                let started_on = state.current_session().and_then(|session| session.started_on.as_deref());
+                if let Some(started_on) = started_on.filter(|_| status == "read" || status == "abandoned") {
+                    if date.as_str() < started_on {
+                        println!("The finish date cannot be before the start date ({}).", started_on);
+                        continue;
+                    }
+                }
+                set_reading_status(&mut connection, user_id, book_id, &status, &date)
+            }
+            "2" => {
+                let started_on = get_optional_date_from_user("Date started (YYYY-MM-DD, leave blank if not known):");
+                let finished_on = get_date_from_user("Date finished (YYYY-MM-DD, leave blank for today):");
+                if started_on.as_deref().is_some_and(|started_on| started_on > finished_on.as_str()) {
+                    println!("The finish date cannot be before the start date.");
+                    continue;
+                }
+                add_past_reading(&mut connection, user_id, book_id, started_on.as_deref(), &finished_on)
+            }
+            "3" => {
+                let rating = loop {
+                    match get_optional_input("Rating from 1 to 5 (leave blank to remove the rating):") {
+                        None => break None,
+                        Some(rating) => match rating.parse::<u8>() {
+                            Ok(rating) if (1..=5).contains(&rating) => break Some(rating),
+                            _ => println!("Invalid rating. Please enter a whole number from 1 to 5."),
+                        },
+                    }
+                };
+                set_rating(&connection, user_id, book_id, rating)
+            }
+            "4" => {
+                let note = get_optional_input("Private note, only visible to you (leave blank to remove it):");
+                set_note(&connection, user_id, book_id, note.as_deref())
+            }
+            "5" => {
+                let review = get_optional_input("Review (leave blank to remove it):");
+                set_review(&connection, user_id, book_id, review.as_deref())
+            }
+            "0" => return changed,
+            _ => {
+                println!("Invalid choice. Please try again.");
+                continue;
+            }
+        };
+        match result {
+            Ok(()) => {
+                changed = true;
+                println!("Reading log updated.");
+            }
+            Err(e) => println!("Failed to update your reading log: {}", e),
+        }
+    }
+}
+
+fn get_today() -> String {
+    Local::now().format("%Y-%m-%d").to_string()
+}
+
+fn get_date_from_user(prompt: &str) -> String {
+    get_optional_date_from_user(prompt).unwrap_or_else(get_today)
+}
+
+// Dates are stored as YYYY-MM-DD so that they sort, and compare, as text.
+fn get_optional_date_from_user(prompt: &str) -> Option<String> {
+    loop {
+        let date = get_optional_input(prompt)?;
+        match NaiveDate::parse_from_str(&date, "%Y-%m-%d") {
+            Ok(date) => return Some(date.format("%Y-%m-%d").to_string()),
+            Err(_) => println!("Invalid date. Please use the format YYYY-MM-DD."),
+        }
+    }
+}
+
+fn print_reading_log_header() {
+    println!("#################");
+    println!("## Reading Log ##");
+    println!("#################");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialisation;
+
+    fn open_library(name: &str) -> Connection {
+        let (_, connection) = initialisation::open_test_database(name);
+        connection.execute_batch(
+            "INSERT INTO books (book_id, title, author) VALUES (1, 'The Hobbit', 'J. R. R. Tolkien');
+             INSERT INTO libraries (user_id, book_id) VALUES (1, 1);",
+        ).unwrap();
+        connection
+    }
+
+    #[test]
+    fn reading_a_book_again_keeps_each_reading_with_its_own_dates() {
+        let mut connection = open_library("reading_sessions");
+        set_reading_status(&mut connection, 1, 1, "reading", "2024-01-01").unwrap();
+        // Setting it to reading while it is being read does not start another session.
+        set_reading_status(&mut connection, 1, 1, "reading", "2024-01-05").unwrap();
+        set_reading_status(&mut connection, 1, 1, "read", "2024-01-10").unwrap();
+        set_reading_status(&mut connection, 1, 1, "reading", "2025-06-01").unwrap();
+
+        let state = get_reading_state(&connection, 1, 1).unwrap().unwrap();
+        assert_eq!(state.reading_status.as_deref(), Some("reading"));
+        assert_eq!(state.sessions.len(), 2);
+        assert_eq!(
+            (state.sessions[0].started_on.as_deref(), state.sessions[0].finished_on.as_deref()),
+            (Some("2024-01-01"), Some("2024-01-10"))
+        );
+        assert_eq!(state.current_session().unwrap().started_on.as_deref(), Some("2025-06-01"));
+        assert_eq!(state.times_read(), 1);
+    }
+
+    #[test]
+    fn finishing_a_book_that_was_never_started_records_a_reading_without_a_start() {
+        let mut connection = open_library("reading_finished");
+        set_reading_status(&mut connection, 1, 1, "abandoned", "2024-02-01").unwrap();
+        add_past_reading(&mut connection, 1, 1, Some("1999-07-01"), "1999-07-14").unwrap();
+
+        let state = get_reading_state(&connection, 1, 1).unwrap().unwrap();
+        // A past reading does not replace the status the user has set.
+        assert_eq!(state.reading_status.as_deref(), Some("abandoned"));
+        assert_eq!(state.sessions[0].outcome.as_deref(), Some("read"));
+        assert_eq!(state.sessions[1].started_on, None);
+        assert_eq!(state.sessions[1].outcome.as_deref(), Some("abandoned"));
+        assert!(state.current_session().is_none());
+    }
+
+    #[test]
+    fn ratings_must_be_from_one_to_five_and_books_outside_the_collection_have_no_state() {
+        let connection = open_library("reading_rating");
+        set_rating(&connection, 1, 1, Some(5)).unwrap();
+        assert!(set_rating(&connection, 1, 1, Some(6)).is_err());
+        set_review(&connection, 1, 1, Some("Splendid")).unwrap();
+        let state = get_reading_state(&connection, 1, 1).unwrap().unwrap();
+        assert_eq!((state.rating, state.review.as_deref()), (Some(5), Some("Splendid")));
+        assert!(get_reading_state(&connection, 2, 1).unwrap().is_none());
+    }
+
+    #[test]
+    fn status_filters_accept_the_known_statuses_and_none() {
+        assert_eq!(parse_status_filter(" Want-To-Read ").as_deref(), Some("want-to-read"));
+        assert_eq!(parse_status_filter("none").as_deref(), Some("none"));
+        assert_eq!(parse_status_filter("finished"), None);
+    }
+}
@@ -0,0 +1,109 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    ManageOwnCollection,
+    AddToCatalogue,
+    EditCatalogue,
+    CheckOutToOthers,
+    ManageUsers,
+    ViewReports,
+    ManageSystem,
+}
+
+impl Permission {
+    pub fn description(&self) -> &'static str {
+        match self {
+            Permission::ManageOwnCollection => "manage your own collection",
+            Permission::AddToCatalogue => "add books to the shared catalogue",
+            Permission::EditCatalogue => "edit the shared catalogue",
+            Permission::CheckOutToOthers => "check out books to others",
+            Permission::ManageUsers => "manage users",
+            Permission::ViewReports => "view reports",
+            Permission::ManageSystem => "manage the system",
+        }
+    }
+}
+
+/* NOTE:
+ * Roles are stored by name in the user_roles table, so the names
+ * returned by get_name() must never change once released.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Role {
+    #[default]
+    Patron,
+    Cataloguer,
+    CirculationDesk,
+    Librarian,
+    Sysadmin,
+}
+
+impl Role {
+    pub const ALL: [Role; 5] = [
+        Role::Patron,
+        Role::Cataloguer,
+        Role::CirculationDesk,
+        Role::Librarian,
+        Role::Sysadmin,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Role> {
+        match name.trim().to_lowercase().as_str() {
+            "patron" => Some(Role::Patron),
+            "cataloguer" => Some(Role::Cataloguer),
+            "circulation" => Some(Role::CirculationDesk),
+            "librarian" => Some(Role::Librarian),
+            "sysadmin" => Some(Role::Sysadmin),
+            _ => None,
+        }
+    }
+
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Role::Patron => "patron",
+            Role::Cataloguer => "cataloguer",
+            Role::CirculationDesk => "circulation",
+            Role::Librarian => "librarian",
+            Role::Sysadmin => "sysadmin",
+        }
+    }
+
+    pub fn get_display_name(&self) -> &'static str {
+        match self {
+            Role::Patron => "Patron",
+            Role::Cataloguer => "Volunteer / Cataloguer",
+            Role::CirculationDesk => "Circulation Desk",
+            Role::Librarian => "Librarian",
+            Role::Sysadmin => "System Administrator",
+        }
+    }
+
+    pub fn get_permissions(&self) -> &'static [Permission] {
+        use Permission::*;
+        match self {
+            Role::Patron => &[ManageOwnCollection, AddToCatalogue],
+            Role::Cataloguer => &[ManageOwnCollection, AddToCatalogue, EditCatalogue],
+            Role::CirculationDesk => &[ManageOwnCollection, CheckOutToOthers],
+            Role::Librarian => &[
+                ManageOwnCollection,
+                AddToCatalogue,
+                EditCatalogue,
+                CheckOutToOthers,
+                ManageUsers,
+                ViewReports,
+            ],
+            Role::Sysadmin => &[
+                ManageOwnCollection,
+                AddToCatalogue,
+                EditCatalogue,
+                CheckOutToOthers,
+                ManageUsers,
+                ViewReports,
+                ManageSystem,
+            ],
+        }
+    }
+
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.get_permissions().contains(&permission)
+    }
+}
@@ -0,0 +1,352 @@
+use std::env;
+use std::error::Error;
+use std::fs;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use serde::Deserialize;
+use serde_json::json;
+use validator::ValidateEmail;
+use crate::audit_management;
+use crate::book_object::{Author, Book};
+use crate::book_processing::is_valid_isbn;
+use crate::catalogue_processing;
+use crate::configuration::{self, Config};
+use crate::holding_object::Holding;
+use crate::holding_processing;
+use crate::initialisation;
+use crate::role_object::Role;
+use crate::utilities;
+
+/*
+ *  A seed describes the initial state of a new installation so that it
+ *  can be set up without anyone answering prompts, e.g. when building a
+ *  container image. It is read from the file named by RLMS_SEED_FILE
+ *  (TOML or JSON, chosen by extension) and/or the RLMS_ADMIN_* variables.
+ *
+ *  Passwords are never given in plain text. Each user has a bcrypt hash
+ *  of their password followed by their salt, exactly as rLMS stores it;
+ *  the salt may be left out if the hash was made from the password alone.
+ *
+ *  Each library entry also records a copy of the book owned by that user.
+ *
+ *  Applying a seed is idempotent: users, books and library entries that
+ *  already exist are left untouched, so running it again changes nothing.
+ */
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Seed {
+    pub database_file: Option<String>,
+    pub admin: Option<SeedUser>,
+    pub users: Vec<SeedUser>,
+    pub books: Vec<SeedBook>,
+    pub libraries: Vec<SeedLibraryEntry>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SeedUser {
+    pub email: String,
+    pub firstname: String,
+    pub lastname: String,
+    pub password_hash: String,
+    #[serde(default)]
+    pub salt: String,
+    #[serde(default)]
+    pub role: Option<String>,
+    #[serde(default = "default_email_verified")]
+    pub email_verified: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SeedBook {
+    pub isbn: String,
+    pub title: String,
+    pub author: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SeedLibraryEntry {
+    pub email: String,
+    pub isbn: String,
+}
+
+#[derive(Debug, Default)]
+pub struct SeedReport {
+    pub users_created: usize,
+    pub users_skipped: usize,
+    pub books_created: usize,
+    pub books_skipped: usize,
+    pub library_entries_created: usize,
+    pub library_entries_skipped: usize,
+}
+
+fn default_email_verified() -> bool {
+    true
+}
+
+/// True if the environment asks for a headless setup instead of the prompts.
+pub fn seed_requested() -> bool {
+    env::var_os("RLMS_SEED_FILE").is_some() || env::var_os("RLMS_ADMIN_EMAIL").is_some()
+}
+
+pub fn load_seed(path: Option<&str>) -> Result<Seed, Box<dyn Error>> {
+    let mut seed = match path {
+        Some(path) => {
+            let data = fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read seed file {}: {}", path, e))?;
+            if configuration::is_toml(path) {
+                toml::from_str(&data).map_err(|e| format!("Failed to parse seed file {}: {}", path, e))?
+            } else {
+                serde_json::from_str(&data).map_err(|e| format!("Failed to parse seed file {}: {}", path, e))?
+            }
+        }
+        None => Seed::default(),
+    };
+    apply_admin_env_overrides(&mut seed);
+    validate_seed(&seed)?;
+    Ok(seed)
+}
+
+const ADMIN_ENV_VARIABLES: [&str; 5] = [
+    "RLMS_ADMIN_EMAIL",
+    "RLMS_ADMIN_FIRSTNAME",
+    "RLMS_ADMIN_LASTNAME",
+    "RLMS_ADMIN_PASSWORD_HASH",
+    "RLMS_ADMIN_SALT",
+];
+
+fn apply_admin_env_overrides(seed: &mut Seed) {
+    if !ADMIN_ENV_VARIABLES.iter().any(|name| env::var_os(name).is_some()) {
+        return;
+    }
+    let admin = seed.admin.get_or_insert_with(|| SeedUser {
+        email_verified: true,
+        ..SeedUser::default()
+    });
+    let fields = [
+        &mut admin.email,
+        &mut admin.firstname,
+        &mut admin.lastname,
+        &mut admin.password_hash,
+        &mut admin.salt,
+    ];
+    for (name, field) in ADMIN_ENV_VARIABLES.iter().zip(fields) {
+        if let Ok(value) = env::var(name) {
+            *field = value;
+        }
+    }
+}
+
+fn validate_seed(seed: &Seed) -> Result<(), Box<dyn Error>> {
+    let mut errors = Vec::new();
+    if let Some(admin) = &seed.admin {
+        validate_user(admin, "admin", &mut errors);
+        if admin.role.as_deref().is_some_and(|role| role != Role::Sysadmin.get_name()) {
+            errors.push("admin: the initial administrator is always a sysadmin, leave role out".to_string());
+        }
+    }
+    for (index, user) in seed.users.iter().enumerate() {
+        validate_user(user, &format!("users[{}]", index), &mut errors);
+    }
+    for (index, book) in seed.books.iter().enumerate() {
+        if !is_valid_isbn(book.isbn.trim()) {
+            errors.push(format!("books[{}]: invalid ISBN {}", index, book.isbn));
+        }
+        if book.title.trim().is_empty() || book.author.trim().is_empty() {
+            errors.push(format!("books[{}]: title and author must not be empty", index));
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("The seed has {} problem(s):\n\t{}", errors.len(), errors.join("\n\t")).into())
+    }
+}
+
+fn validate_user(user: &SeedUser, label: &str, errors: &mut Vec<String>) {
+    if !user.email.validate_email() {
+        errors.push(format!("{}: invalid email \"{}\"", label, user.email));
+    }
+    if user.firstname.trim().is_empty() || user.lastname.trim().is_empty() {
+        errors.push(format!("{}: firstname and lastname must not be empty", label));
+    }
+    if user.password_hash.parse::<bcrypt::HashParts>().is_err() {
+        errors.push(format!("{}: password_hash is not a bcrypt hash", label));
+    }
+    if let Some(role) = user.role.as_deref() {
+        if Role::from_name(role).is_none() {
+            errors.push(format!("{}: unknown role \"{}\"", label, role));
+        }
+    }
+}
+
+/*
+ *  Sets up a new installation, or tops up an existing one, without any
+ *  prompts. The configuration file is written if it does not exist yet;
+ *  the database file comes from the existing configuration, the seed or
+ *  RLMS_DATABASE_FILE, in that order.
+ */
+pub fn run_headless_setup(config_path: &str, seed_path: Option<&str>) -> Result<SeedReport, Box<dyn Error>> {
+    let seed = load_seed(seed_path)?;
+    let stored_config = Config::load(config_path)?;
+    let config_exists = stored_config.is_some();
+    let mut config = stored_config.unwrap_or_default();
+
+    let mut effective_config = config.clone();
+    effective_config.apply_env_overrides()?;
+    if config.database.file.is_none() {
+        config.database.file = seed.database_file.clone().or(effective_config.database.file.clone());
+        effective_config.database.file = config.database.file.clone();
+    }
+    let errors = effective_config.validate();
+    if !errors.is_empty() {
+        return Err(format!("The configuration has {} problem(s):\n\t{}", errors.len(), errors.join("\n\t")).into());
+    }
+    let database_name = effective_config.database.file.clone().unwrap_or_default();
+
+    // Nobody is watching, so skip the pauses between the table messages.
+    utilities::set_pause_scale(0.0);
+    if !initialisation::create_initial_tables(&database_name) || !initialisation::update_existing_tables(&database_name) {
+        return Err(format!("Failed to create the tables in {}", database_name).into());
+    }
+    if !config_exists {
+        config.save(config_path)?;
+        println!("Configuration written to {}", config_path);
+    }
+
+    apply_seed(&database_name, &seed)
+}
+
+pub fn apply_seed(database_name: &str, seed: &Seed) -> Result<SeedReport, Box<dyn Error>> {
+    let mut connection = Connection::open(database_name)?;
+    let transaction = connection.transaction()?;
+    let mut report = SeedReport::default();
+
+    let users = seed.admin.iter().map(|admin| (admin, Role::Sysadmin))
+        .chain(seed.users.iter().map(|user| {
+            (user, user.role.as_deref().and_then(Role::from_name).unwrap_or_default())
+        }));
+    for (user, role) in users {
+        if insert_user(&transaction, user, role)? {
+            report.users_created += 1;
+        } else {
+            report.users_skipped += 1;
+        }
+    }
+
+    for book in &seed.books {
+        let book = Book {
+            isbn: book.isbn.trim().to_string(),
+            title: book.title.trim().to_string(),
+            authors: vec![Author { name: book.author.trim().to_string() }],
+            ..Book::default()
+        };
+        if catalogue_processing::find_existing_record(&transaction, &book)?.is_some() {
+            report.books_skipped += 1;
+        } else {
+            catalogue_processing::save_catalogue_record(&transaction, &book, catalogue_processing::SOURCE_MANUAL)?;
+            report.books_created += 1;
+        }
+    }
+
+    for entry in &seed.libraries {
+        let email = entry.email.trim().to_lowercase();
+        let user_id: i32 = transaction
+            .query_row("SELECT user_id FROM users WHERE email = ?1", params![email], |row| row.get(0))
+            .optional()?
+            .ok_or_else(|| format!("Library entry for unknown user {}", email))?;
+        // The same lookup as for the books above, so an ISBN written another way still matches.
+        let book = Book { isbn: entry.isbn.trim().to_string(), ..Book::default() };
+        let (book_id, _) = catalogue_processing::find_existing_record(&transaction, &book)?
+            .ok_or_else(|| format!("Library entry for unknown book {}", entry.isbn))?;
+        let inserted = transaction.execute(
+            "INSERT OR IGNORE INTO libraries (user_id, book_id, added_at) VALUES (?1, ?2, ?3)",
+            params![user_id, book_id, utilities::current_timestamp()],
+        )?;
+        if inserted == 1 {
+            holding_processing::create_holding(&transaction, &Holding::new(book_id, Some(user_id)))?;
+            report.library_entries_created += 1;
+        } else {
+            report.library_entries_skipped += 1;
+        }
+    }
+
+    transaction.commit()?;
+    Ok(report)
+}
+
+// Returns false if a user with this email already exists; they are not changed.
+fn insert_user(transaction: &Transaction, user: &SeedUser, role: Role) -> Result<bool, Box<dyn Error>> {
+    let email = user.email.trim().to_lowercase();
+    let existing: Option<i32> = transaction
+        .query_row("SELECT user_id FROM users WHERE email = ?1", params![email], |row| row.get(0))
+        .optional()?;
+    if existing.is_some() {
+        return Ok(false);
+    }
+
+    transaction.execute(
+        "INSERT INTO users (email, firstname, lastname, email_verified) VALUES (?1, ?2, ?3, ?4)",
+        params![email, user.firstname.trim(), user.lastname.trim(), user.email_verified],
+    )?;
+    let user_id = transaction.last_insert_rowid();
+    transaction.execute("INSERT INTO salts (user_id, salt) VALUES (?1, ?2)", params![user_id, user.salt])?;
+    transaction.execute("INSERT INTO passwords (user_id, password) VALUES (?1, ?2)", params![user_id, user.password_hash])?;
+    transaction.execute("INSERT INTO user_roles (user_id, role) VALUES (?1, ?2)", params![user_id, role.get_name()])?;
+    if role == Role::Sysadmin {
+        transaction.execute("INSERT INTO admins (user_id) VALUES (?1)", params![user_id])?;
+    }
+    audit_management::record_event(
+        transaction,
+        None,
+        audit_management::USER_REGISTERED,
+        &email,
+        json!({ "source": "seed", "role": role.get_name() }),
+    )?;
+    Ok(true)
+}
+
+pub fn print_report(report: &SeedReport) {
+    println!("Users: {} created, {} already present", report.users_created, report.users_skipped);
+    println!("Books: {} created, {} already present", report.books_created, report.books_skipped);
+    println!(
+        "Library entries: {} created, {} already present",
+        report.library_entries_created, report.library_entries_skipped
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn library_entries_find_books_by_isbn_however_it_was_written() {
+        let (database_name, connection) = initialisation::open_test_database("seed_isbn_forms");
+        let seed = Seed {
+            users: vec![SeedUser {
+                email: "reader@example.org".to_string(),
+                firstname: "Ada".to_string(),
+                lastname: "Reader".to_string(),
+                password_hash: "$2b$12$abcdefghijklmnopqrstuuvwxyzABCDEFGHIJKLMNOPQRSTUVWXY".to_string(),
+                ..SeedUser::default()
+            }],
+            books: vec![SeedBook {
+                isbn: "978-0-261-10221-7".to_string(),
+                title: "The Hobbit".to_string(),
+                author: "J. R. R. Tolkien".to_string(),
+            }],
+            libraries: vec![
+                SeedLibraryEntry { email: "reader@example.org".to_string(), isbn: "0-261-10221-4".to_string() },
+                SeedLibraryEntry { email: "reader@example.org".to_string(), isbn: "9780261102217".to_string() },
+            ],
+            ..Seed::default()
+        };
+
+        let report = apply_seed(&database_name, &seed).unwrap();
+        assert_eq!((report.library_entries_created, report.library_entries_skipped), (1, 1));
+        let copies: i64 = connection.query_row("SELECT COUNT(*) FROM holdings", [], |row| row.get(0)).unwrap();
+        assert_eq!(copies, 1);
+    }
+}
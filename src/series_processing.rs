@@ -0,0 +1,347 @@
+use std::collections::HashSet;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::json;
+use crate::audit_management;
+use crate::book_object::SeriesEntry;
+use crate::book_processing::get_audit_target;
+use crate::catalogue_processing::SOURCE_MANUAL;
+use crate::provenance_management;
+use crate::user_object::User;
+use crate::utilities::{self, clear_screen, get_optional_input};
+
+// Words that come between a series name and the number, e.g. "Discworld ; vol. 3".
+const NUMBER_WORDS: [&str; 9] = ["no.", "no", "vol.", "vol", "volume", "book", "bk.", "pt.", "part"];
+
+// A book in a series, with the state of the user's reading of it.
+struct SeriesBook {
+    title: String,
+    position: Option<f64>,
+    in_collection: bool,
+    reading_status: Option<String>,
+    read: bool,
+}
+
+/*
+ *  Splits Open Library's free-text series, e.g. "The Lord of the Rings ; 2",
+ *  "Discworld (3)" or "Penguin classics", into a name and a position. The
+ *  number must be set apart by a space, "#" or "(", so that names such as
+ *  "Catch-22" are left whole.
+ */
+pub fn parse_series(text: &str) -> Option<SeriesEntry> {
+    let text = text.trim().trim_end_matches([')', ']', '.']).trim_end();
+    let number_start = text.rfind(|c: char| !(c.is_ascii_digit() || c == '.')).map_or(0, |index| index + 1);
+    let (mut name, number) = text.split_at(number_start);
+    let mut position = None;
+    if !number.is_empty() && (name.is_empty() || name.ends_with([' ', '#', '('])) {
+        position = number.parse::<f64>().ok().filter(|position| position.is_finite());
+    }
+    if position.is_none() {
+        name = text;
+    }
+
+    // Strip the separators and words left between the name and the number.
+    let mut name = name.trim_end();
+    loop {
+        let trimmed = name.trim_end_matches([' ', ';', ',', '#', '(', '[', '-', ':']);
+        let trimmed = match trimmed.rfind(char::is_whitespace) {
+            Some(index) if NUMBER_WORDS.iter().any(|word| word.eq_ignore_ascii_case(trimmed[index..].trim_start())) => &trimmed[..index],
+            _ => trimmed,
+        };
+        if trimmed == name || position.is_none() {
+            break;
+        }
+        name = trimmed;
+    }
+    let name = name.trim();
+    (!name.is_empty()).then(|| SeriesEntry { name: name.to_string(), position })
+}
+
+/// Positions are shown without a fraction when they are whole numbers, e.g. 2 and 2.5.
+pub fn format_position(position: f64) -> String {
+    if position.fract() == 0.0 {
+        format!("{}", position as i64)
+    } else {
+        format!("{}", position)
+    }
+}
+
+pub fn describe_series(series: &[SeriesEntry]) -> Option<String> {
+    let described: Vec<String> = series.iter()
+        .map(|entry| match entry.position {
+            Some(position) => format!("{} #{}", entry.name, format_position(position)),
+            None => entry.name.clone(),
+        })
+        .collect();
+    Some(described.join("; ")).filter(|described| !described.is_empty())
+}
+
+pub fn find_series(connection: &Connection, name: &str) -> Result<Option<i64>, rusqlite::Error> {
+    connection.query_row(
+        "SELECT series_id FROM series WHERE name = ?1 COLLATE NOCASE",
+        params![name.trim()],
+        |row| row.get(0),
+    ).optional()
+}
+
+pub fn get_or_create_series(connection: &Connection, name: &str) -> Result<i64, rusqlite::Error> {
+    if let Some(series_id) = find_series(connection, name)? {
+        return Ok(series_id);
+    }
+    connection.execute(
+        "INSERT INTO series (name, created_at) VALUES (?1, ?2)",
+        params![name.trim(), utilities::current_timestamp()],
+    )?;
+    Ok(connection.last_insert_rowid())
+}
+
+/// Adds the book to each series it is not already in. Existing positions are kept.
+pub fn save_book_series(connection: &Connection, book_id: i32, series: &[SeriesEntry]) -> Result<(), rusqlite::Error> {
+    for entry in series {
+        let series_id = get_or_create_series(connection, &entry.name)?;
+        connection.execute(
+            "INSERT OR IGNORE INTO book_series (book_id, series_id, position) VALUES (?1, ?2, ?3)",
+            params![book_id, series_id, entry.position],
+        )?;
+    }
+    Ok(())
+}
+
+pub fn get_series_for_book(connection: &Connection, book_id: i32) -> Result<Vec<SeriesEntry>, rusqlite::Error> {
+    let mut stmt = connection.prepare(
+        "SELECT series.name, book_series.position FROM book_series
+         JOIN series ON series.series_id = book_series.series_id
+         WHERE book_series.book_id = ?1
+         ORDER BY series.name COLLATE NOCASE",
+    )?;
+    let series = stmt.query_map(params![book_id], |row| Ok(SeriesEntry { name: row.get(0)?, position: row.get(1)? }))?;
+    series.collect()
+}
+
+/*
+ *  Puts the book in a series at the given position, or moves it there
+ *  if it is already in it; None as the position removes it instead.
+ *  The change is kept in the record's edit history as set by hand.
+ */
+pub fn set_book_series(connection: &mut Connection, user_id: i32, book_id: i32, name: &str, position: Option<Option<f64>>) -> Result<bool, rusqlite::Error> {
+    let transaction = connection.transaction()?;
+    let old = describe_series(&get_series_for_book(&transaction, book_id)?);
+    match position {
+        Some(position) => {
+            let series_id = get_or_create_series(&transaction, name)?;
+            transaction.execute(
+                "INSERT INTO book_series (book_id, series_id, position) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (book_id, series_id) DO UPDATE SET position = excluded.position",
+                params![book_id, series_id, position],
+            )?;
+        }
+        None => {
+            if let Some(series_id) = find_series(&transaction, name)? {
+                transaction.execute(
+                    "DELETE FROM book_series WHERE book_id = ?1 AND series_id = ?2",
+                    params![book_id, series_id],
+                )?;
+            }
+        }
+    }
+    let new = describe_series(&get_series_for_book(&transaction, book_id)?);
+    if old == new {
+        return Ok(false);
+    }
+
+    provenance_management::record_edit(&transaction, book_id, "series", old.as_deref(), new.as_deref(), SOURCE_MANUAL, Some(user_id))?;
+    let isbn: Option<String> = transaction.query_row("SELECT isbn FROM books WHERE book_id = ?1", params![book_id], |row| row.get(0))?;
+    audit_management::record_event(
+        &transaction,
+        Some(user_id),
+        audit_management::BOOK_RECORD_EDITED,
+        &get_audit_target(isbn.as_deref(), book_id),
+        json!({ "book_id": book_id, "changes": { "series": { "old": old, "new": new } } }),
+    )?;
+    transaction.commit()?;
+    Ok(true)
+}
+
+pub(crate) fn set_series_from_user(connection: &mut Connection, user: &User, book_id: i32) -> bool {
+    match get_series_for_book(connection, book_id) {
+        Ok(series) => match describe_series(&series) {
+            Some(described) => println!("Series: {}", described),
+            None => println!("This book is not in any series."),
+        },
+        Err(e) => println!("Error retrieving the book's series: {}", e),
+    }
+    let Some(name) = get_optional_input("Series name (leave blank to cancel):") else { return false; };
+    let position = loop {
+        let prompt = "Position in the series, e.g. 2 or 2.5 (leave blank if it is not numbered, or enter - to remove the book from the series):";
+        match get_optional_input(prompt).as_deref() {
+            None => break Some(None),
+            Some("-") => break None,
+            Some(position) => match position.parse::<f64>() {
+                Ok(position) if position.is_finite() && position >= 0.0 => break Some(Some(position)),
+                _ => println!("Invalid position. Please enter a number such as 2 or 2.5."),
+            },
+        }
+    };
+
+    match set_book_series(connection, user.get_user_id(), book_id, &name, position) {
+        Ok(true) => true,
+        Ok(false) => {
+            println!("No changes were made.");
+            false
+        }
+        Err(e) => {
+            println!("Failed to update the book's series: {}", e);
+            false
+        }
+    }
+}
+
+fn get_series_books(connection: &Connection, series_id: i64, user_id: i32) -> Result<Vec<SeriesBook>, rusqlite::Error> {
+    let mut stmt = connection.prepare(
+        "SELECT books.title, book_series.position, libraries.user_id IS NOT NULL, libraries.reading_status,
+                COALESCE(libraries.reading_status = 'read', 0) OR EXISTS (
+                    SELECT 1 FROM reading_sessions AS sessions
+                    WHERE sessions.user_id = ?2 AND sessions.book_id = books.book_id AND sessions.outcome = 'read')
+         FROM book_series
+         JOIN books ON books.book_id = book_series.book_id
+         LEFT JOIN libraries ON libraries.book_id = books.book_id AND libraries.user_id = ?2
+         WHERE book_series.series_id = ?1
+         ORDER BY book_series.position IS NULL, book_series.position, libraries.user_id IS NULL, books.title",
+    )?;
+    let books = stmt.query_map(params![series_id, user_id], |row| {
+        Ok(SeriesBook {
+            title: row.get(0)?,
+            position: row.get(1)?,
+            in_collection: row.get(2)?,
+            reading_status: row.get(3)?,
+            read: row.get(4)?,
+        })
+    })?;
+    books.collect()
+}
+
+/*
+ *  The next book to read is the first in series order that the user has
+ *  not read. Several editions can share a position, and reading any of
+ *  them counts for that position; an edition in the user's collection
+ *  is suggested before one that is not.
+ */
+fn get_next_unread(books: &[SeriesBook]) -> Option<&SeriesBook> {
+    let read_positions: HashSet<String> = books.iter()
+        .filter(|book| book.read)
+        .filter_map(|book| book.position.map(format_position))
+        .collect();
+    books.iter().find(|book| {
+        !book.read && !book.position.map(format_position).is_some_and(|position| read_positions.contains(&position))
+    })
+}
+
+/*
+ *  Lists every series with a book in the user's collection, in series
+ *  order with the user's reading status, and the next one to read.
+ */
+pub(crate) fn view_series(database_name: &str, user: &User, max_rows: usize) -> bool {
+    clear_screen();
+    print_series_header();
+    let connection = match Connection::open(database_name) {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("Failed to connect to the database: {}", e);
+            return false;
+        }
+    };
+
+    let series: Vec<(i64, String)> = match connection.prepare(
+        "SELECT DISTINCT series.series_id, series.name FROM series
+         JOIN book_series ON book_series.series_id = series.series_id
+         JOIN libraries ON libraries.book_id = book_series.book_id AND libraries.user_id = ?1
+         ORDER BY series.name COLLATE NOCASE",
+    ).and_then(|mut stmt| {
+        stmt.query_map(params![user.get_user_id()], |row| Ok((row.get(0)?, row.get(1)?)))?.collect()
+    }) {
+        Ok(series) => series,
+        Err(e) => {
+            println!("Error retrieving your series: {}", e);
+            return false;
+        }
+    };
+    if series.is_empty() {
+        println!("None of the books in your collection are in a series.");
+        return true;
+    }
+
+    for (series_id, name) in series.iter().take(max_rows) {
+        println!("Series: {}", name);
+        let books = match get_series_books(&connection, *series_id, user.get_user_id()) {
+            Ok(books) => books,
+            Err(e) => {
+                println!("  Error retrieving the books in this series: {}", e);
+                continue;
+            }
+        };
+        for book in &books {
+            let state = match (book.in_collection, book.reading_status.as_deref()) {
+                (false, _) => "not in your collection",
+                (true, Some(status)) => status,
+                (true, None) => "no status",
+            };
+            match book.position {
+                Some(position) => println!("  #{} {} ({})", format_position(position), book.title, state),
+                None => println!("  {} ({})", book.title, state),
+            }
+        }
+        match get_next_unread(&books) {
+            Some(next) => println!("  Next to read: {}", next.title),
+            None => println!("  You have read every book in this series that is in the catalogue."),
+        }
+    }
+    if series.len() > max_rows {
+        println!("Only the first {} of {} series are shown.", max_rows, series.len());
+    }
+    true
+}
+
+fn print_series_header() {
+    println!("################");
+    println!("## Your Series ##");
+    println!("################");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(text: &str) -> Option<(String, Option<f64>)> {
+        parse_series(text).map(|entry| (entry.name, entry.position))
+    }
+
+    #[test]
+    fn parse_series_splits_name_and_position() {
+        assert_eq!(parsed("The Lord of the Rings ; 2"), Some(("The Lord of the Rings".to_string(), Some(2.0))));
+        assert_eq!(parsed("Discworld (3)"), Some(("Discworld".to_string(), Some(3.0))));
+        assert_eq!(parsed("Expanse #4.5"), Some(("Expanse".to_string(), Some(4.5))));
+        assert_eq!(parsed("Discworld ; vol. 3"), Some(("Discworld".to_string(), Some(3.0))));
+        assert_eq!(parsed("Narnia, Book 7"), Some(("Narnia".to_string(), Some(7.0))));
+        assert_eq!(parsed("Dune  PART 2"), Some(("Dune".to_string(), Some(2.0))));
+    }
+
+    #[test]
+    fn parse_series_keeps_names_without_a_position() {
+        assert_eq!(parsed("Penguin classics"), Some(("Penguin classics".to_string(), None)));
+        assert_eq!(parsed("Catch-22"), Some(("Catch-22".to_string(), None)));
+        assert_eq!(parsed("Oxford World's Classics; no"), Some(("Oxford World's Classics; no".to_string(), None)));
+        assert_eq!(parsed("  "), None);
+    }
+
+    #[test]
+    fn parse_series_handles_non_ascii_text() {
+        assert_eq!(parsed("İstanbul Üçlemesi vol. 2"), Some(("İstanbul Üçlemesi".to_string(), Some(2.0))));
+        // The Kelvin sign lowercases to "k", but it is not the word "bk." and is three bytes long.
+        assert_eq!(parsed("Saga B\u{212A}. 3"), Some(("Saga B\u{212A}.".to_string(), Some(3.0))));
+    }
+
+    #[test]
+    fn format_position_drops_whole_number_fractions() {
+        assert_eq!(format_position(2.0), "2");
+        assert_eq!(format_position(2.5), "2.5");
+    }
+}
@@ -0,0 +1,245 @@
+use rusqlite::types::Value;
+use crate::oai_object::MetadataFormat;
+
+/*
+ *  A parsed CQL query. Booleans group to the left, as CQL has no
+ *  precedence: "a or b and c" is "(a or b) and c".
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum CqlQuery {
+    Clause(SearchClause),
+    Boolean {
+        operator: BooleanOperator,
+        // Modifiers such as "/distance=1"; none are supported.
+        modifiers: Vec<String>,
+        left: Box<CqlQuery>,
+        right: Box<CqlQuery>,
+    },
+}
+
+// A search term, and the index and relation it is searched with if the query named them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchClause {
+    pub index: Option<String>,
+    pub relation: Option<String>,
+    pub modifiers: Vec<String>,
+    // As written, with any backslash escapes, masks (* and ?) and anchors (^) still in it.
+    pub term: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BooleanOperator {
+    And,
+    Or,
+    Not,
+    Prox,
+}
+
+impl BooleanOperator {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "and" => Some(BooleanOperator::And),
+            "or" => Some(BooleanOperator::Or),
+            "not" => Some(BooleanOperator::Not),
+            "prox" => Some(BooleanOperator::Prox),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchIndex {
+    // Title, author, subject or ISBN: what a search with no index looks in.
+    ServerChoice,
+    AllRecords,
+    Title,
+    Creator,
+    Subject,
+    Publisher,
+    // The year of publication.
+    Date,
+    // The ISBN or any other identifier.
+    Identifier,
+    Isbn,
+}
+
+/*
+ *  The indexes that can be searched: the context set, the index name, what
+ *  it is and what it stands for. An index named without a context set is
+ *  looked up by name alone.
+ */
+pub const INDEXES: [(&str, &str, &str, SearchIndex); 9] = [
+    ("cql", "serverChoice", "Title, author, subject or ISBN", SearchIndex::ServerChoice),
+    ("cql", "allRecords", "Every record", SearchIndex::AllRecords),
+    ("dc", "title", "Title", SearchIndex::Title),
+    ("dc", "creator", "Author", SearchIndex::Creator),
+    ("dc", "subject", "Subject", SearchIndex::Subject),
+    ("dc", "publisher", "Publisher", SearchIndex::Publisher),
+    ("dc", "date", "Year of publication", SearchIndex::Date),
+    ("dc", "identifier", "ISBN, LCCN or OCLC number", SearchIndex::Identifier),
+    ("bath", "isbn", "ISBN", SearchIndex::Isbn),
+];
+
+// The context sets the indexes come from, with their identifiers.
+pub const CONTEXT_SETS: [(&str, &str); 3] = [
+    ("cql", "info:srw/cql-context-set/1/cql-v1.2"),
+    ("dc", "info:srw/cql-context-set/1/dc-v1.1"),
+    ("bath", "http://zing.z3950.org/cql/bath/2.0/"),
+];
+
+impl SearchIndex {
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.to_lowercase();
+        let (set, index) = match name.split_once('.') {
+            Some((set, index)) => (Some(set), index),
+            None => (None, name.as_str()),
+        };
+        INDEXES.iter()
+            .find(|(index_set, index_name, _, _)| {
+                set.is_none_or(|set| set == *index_set) && index_name.to_lowercase() == index
+            })
+            .map(|(_, _, _, search_index)| *search_index)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Relation {
+    // = and adj: the words in this order, anywhere in the field.
+    Phrase,
+    // == and exact: the whole field.
+    Exact,
+    NotEqual,
+    Any,
+    All,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Relation {
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.to_lowercase();
+        match name.strip_prefix("cql.").unwrap_or(&name) {
+            "=" | "adj" => Some(Relation::Phrase),
+            "==" | "exact" => Some(Relation::Exact),
+            "<>" => Some(Relation::NotEqual),
+            "any" => Some(Relation::Any),
+            "all" => Some(Relation::All),
+            "<" => Some(Relation::Less),
+            "<=" => Some(Relation::LessOrEqual),
+            ">" => Some(Relation::Greater),
+            ">=" => Some(Relation::GreaterOrEqual),
+            _ => None,
+        }
+    }
+
+    pub fn is_comparison(&self) -> bool {
+        matches!(self, Relation::Less | Relation::LessOrEqual | Relation::Greater | Relation::GreaterOrEqual)
+    }
+}
+
+/*
+ *  An SRU diagnostic, numbered as in the standard list at
+ *  info:srw/diagnostic/1/. Details say what it was about, e.g. the name
+ *  of an unsupported index.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub number: u32,
+    pub details: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(number: u32, details: &str) -> Self {
+        Diagnostic { number, details: Some(details.to_string()).filter(|details| !details.is_empty()) }
+    }
+
+    pub fn get_uri(&self) -> String {
+        format!("info:srw/diagnostic/1/{}", self.number)
+    }
+
+    pub fn get_message(&self) -> &'static str {
+        match self.number {
+            1 => "General system error",
+            4 => "Unsupported operation",
+            5 => "Unsupported version",
+            6 => "Unsupported parameter value",
+            7 => "Mandatory parameter not supplied",
+            8 => "Unsupported parameter",
+            10 => "Query syntax error",
+            16 => "Unsupported index",
+            19 => "Unsupported relation",
+            20 => "Unsupported relation modifier",
+            36 => "Term in invalid format for index or relation",
+            37 => "Unsupported boolean operator",
+            38 => "Too many boolean operators in query",
+            46 => "Unsupported boolean modifier",
+            61 => "First record position out of range",
+            66 => "Unknown schema for retrieval",
+            80 => "Sort not supported",
+            _ => "Unknown diagnostic",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SruVersion {
+    // Also answers 1.1 requests, which differ only in what 1.2 added.
+    V1_2,
+    V2_0,
+}
+
+impl SruVersion {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "1.1" | "1.2" => Some(SruVersion::V1_2),
+            "2.0" => Some(SruVersion::V2_0),
+            _ => None,
+        }
+    }
+
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            SruVersion::V1_2 => "1.2",
+            SruVersion::V2_0 => "2.0",
+        }
+    }
+
+    // The prefix and namespace of the response elements.
+    pub fn get_response_namespace(&self) -> (&'static str, &'static str) {
+        match self {
+            SruVersion::V1_2 => ("srw", "http://www.loc.gov/zing/srw/"),
+            SruVersion::V2_0 => ("sruResponse", "http://docs.oasis-open.org/ns/search-ws/sruResponse"),
+        }
+    }
+
+    pub fn get_diagnostic_namespace(&self) -> &'static str {
+        match self {
+            SruVersion::V1_2 => "http://www.loc.gov/zing/srw/diagnostic/",
+            SruVersion::V2_0 => "http://docs.oasis-open.org/ns/search-ws/diagnostic",
+        }
+    }
+
+    // What says whether records are sent as XML or as escaped text.
+    pub fn get_packing_parameter(&self) -> &'static str {
+        match self {
+            SruVersion::V1_2 => "recordPacking",
+            SruVersion::V2_0 => "recordXMLEscaping",
+        }
+    }
+}
+
+// A searchRetrieve request once its parameters have been checked and its query translated.
+#[derive(Debug, Clone)]
+pub struct SearchRequest {
+    // Counted from 1.
+    pub start: usize,
+    pub maximum: usize,
+    // The identifier of the record schema, and the format it is.
+    pub schema: &'static str,
+    pub format: MetadataFormat,
+    // The query as a condition on books, with its parameters.
+    pub condition: String,
+    pub values: Vec<Value>,
+}
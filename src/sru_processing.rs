@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection};
+use crate::book_object::Book;
+use crate::configuration::Config;
+use crate::oai_object::MetadataFormat;
+use crate::sru_object::{Diagnostic, SearchRequest, SruVersion, CONTEXT_SETS, INDEXES};
+use crate::utilities::escape_xml;
+use crate::{catalogue_processing, cql_processing, oai_processing};
+
+/*
+ *  An SRU server, so other libraries' systems can search the catalogue
+ *  with CQL. It answers SRU 1.2 (and 1.1) and 2.0: the version parameter
+ *  says which, and requests without one are taken as 2.0, which dropped
+ *  it. Records come as Dublin Core or MARCXML, the same as over OAI-PMH.
+ *  Problems are reported as diagnostics in the response, never with an
+ *  HTTP error.
+ */
+const EXPLAIN_NAMESPACE: &str = "http://explain.z3950.org/dtd/2.0/";
+
+// The record schemas by short name and by identifier, either of which a request may use.
+const RECORD_SCHEMAS: [(&str, &str, &str, MetadataFormat); 2] = [
+    ("dc", "info:srw/schema/1/dc-v1.1", "Dublin Core", MetadataFormat::DublinCore),
+    ("marcxml", "info:srw/schema/1/marcxml-v1.1", "MARC 21 in XML", MetadataFormat::MarcXml),
+];
+
+const RELATIONS: [&str; 11] = ["=", "==", "<>", "<", "<=", ">", ">=", "adj", "all", "any", "exact"];
+
+// Parameters that are understood, apart from extensions (x-...), which are ignored.
+const PARAMETERS: [&str; 13] = [
+    "version", "operation", "stylesheet", "query", "queryType", "startRecord", "maximumRecords",
+    "recordSchema", "recordPacking", "recordXMLEscaping", "sortKeys", "resultSetTTL", "httpAccept",
+];
+
+// The identifier and format of a record schema named either way.
+fn get_record_schema(name: &str) -> Option<(&'static str, MetadataFormat)> {
+    RECORD_SCHEMAS.into_iter()
+        .find(|(short_name, identifier, _, _)| name.eq_ignore_ascii_case(short_name) || name == *identifier)
+        .map(|(_, identifier, _, format)| (identifier, format))
+}
+
+/// Answers a request with the given arguments, from the query string or a form.
+pub fn handle_request(connection: &Connection, config: &Config, base_url: &str, arguments: &[(String, String)]) -> Result<String, rusqlite::Error> {
+    let mut parameters: HashMap<&str, &str> = HashMap::new();
+    let mut diagnostics = Vec::new();
+    for (key, value) in arguments {
+        if parameters.insert(key.as_str(), value.as_str()).is_some() {
+            diagnostics.push(Diagnostic::new(6, key));
+        }
+    }
+    if let Some((key, _)) = arguments.iter().find(|(key, _)| !PARAMETERS.contains(&key.as_str()) && !key.starts_with("x-")) {
+        diagnostics.push(Diagnostic::new(8, key));
+    }
+
+    let version = match parameters.get("version") {
+        Some(name) => SruVersion::from_name(name).unwrap_or_else(|| {
+            diagnostics.push(Diagnostic::new(5, "2.0"));
+            SruVersion::V1_2
+        }),
+        None => SruVersion::V2_0,
+    };
+    // Records are sent as XML unless asked for as escaped text.
+    let escaped = match parameters.get(version.get_packing_parameter()).copied() {
+        None | Some("xml") => false,
+        Some("string") => true,
+        Some(_) => {
+            diagnostics.push(Diagnostic::new(6, version.get_packing_parameter()));
+            false
+        }
+    };
+    if version == SruVersion::V2_0 && parameters.get("recordPacking").is_some_and(|packing| *packing != "packed") {
+        diagnostics.push(Diagnostic::new(6, "recordPacking"));
+    }
+
+    let search = match parameters.get("operation").copied() {
+        Some("searchRetrieve") => true,
+        Some("explain") => false,
+        Some(operation) => {
+            diagnostics.push(Diagnostic::new(4, operation));
+            false
+        }
+        None => parameters.contains_key("query"),
+    };
+    if search {
+        search_retrieve(connection, config, version, &parameters, escaped, diagnostics)
+    } else {
+        Ok(explain(config, version, base_url, escaped, &diagnostics))
+    }
+}
+
+fn render_response(version: SruVersion, operation: &str, body: &str, diagnostics: &[Diagnostic]) -> String {
+    let (prefix, namespace) = version.get_response_namespace();
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<{p}:{} xmlns:{p}=\"{}\">\n  <{p}:version>{}</{p}:version>\n",
+        operation,
+        namespace,
+        version.get_name(),
+        p = prefix
+    );
+    xml.push_str(body);
+    if !diagnostics.is_empty() {
+        xml.push_str(&format!("  <{}:diagnostics>\n", prefix));
+        for diagnostic in diagnostics {
+            xml.push_str(&format!("    <diag:diagnostic xmlns:diag=\"{}\">\n", version.get_diagnostic_namespace()));
+            xml.push_str(&format!("      <diag:uri>{}</diag:uri>\n", diagnostic.get_uri()));
+            if let Some(details) = &diagnostic.details {
+                xml.push_str(&format!("      <diag:details>{}</diag:details>\n", escape_xml(details)));
+            }
+            xml.push_str(&format!("      <diag:message>{}</diag:message>\n", diagnostic.get_message()));
+            xml.push_str("    </diag:diagnostic>\n");
+        }
+        xml.push_str(&format!("  </{}:diagnostics>\n", prefix));
+    }
+    xml.push_str(&format!("</{}:{}>\n", prefix, operation));
+    xml
+}
+
+// A record in the response: its data as XML, or as text if the request asked for it escaped.
+fn render_record(version: SruVersion, schema: &str, data: &str, escaped: bool, position: Option<usize>, indent: &str) -> String {
+    let prefix = version.get_response_namespace().0;
+    let packing = version.get_packing_parameter();
+    let mut xml = format!("{}<{}:record>\n", indent, prefix);
+    xml.push_str(&format!("{}  <{p}:recordSchema>{}</{p}:recordSchema>\n", indent, schema, p = prefix));
+    xml.push_str(&format!("{}  <{p}:{k}>{}</{p}:{k}>\n", indent, if escaped { "string" } else { "xml" }, p = prefix, k = packing));
+    if escaped {
+        xml.push_str(&format!("{}  <{p}:recordData>{}</{p}:recordData>\n", indent, escape_xml(data.trim()), p = prefix));
+    } else {
+        xml.push_str(&format!("{i}  <{p}:recordData>\n{}{i}  </{p}:recordData>\n", data, i = indent, p = prefix));
+    }
+    if let Some(position) = position {
+        xml.push_str(&format!("{}  <{p}:recordPosition>{}</{p}:recordPosition>\n", indent, position, p = prefix));
+    }
+    xml.push_str(&format!("{}</{}:record>\n", indent, prefix));
+    xml
+}
+
+fn render_dublin_core(book: &Book, indent: &str) -> String {
+    let mut xml = format!(
+        "{}<srw_dc:dc xmlns:srw_dc=\"info:srw/schema/1/dc-schema\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n",
+        indent
+    );
+    for (element, value) in oai_processing::get_dublin_core_elements(book) {
+        xml.push_str(&format!("{}  <dc:{1}>{2}</dc:{1}>\n", indent, element, escape_xml(&value)));
+    }
+    xml.push_str(&format!("{}</srw_dc:dc>\n", indent));
+    xml
+}
+
+fn parse_position(parameters: &HashMap<&str, &str>, name: &str, default: usize, minimum: usize) -> Result<usize, Diagnostic> {
+    match parameters.get(name) {
+        Some(value) => value.trim().parse::<usize>()
+            .ok()
+            .filter(|value| *value >= minimum)
+            .ok_or_else(|| Diagnostic::new(6, name)),
+        None => Ok(default),
+    }
+}
+
+fn get_search_request(config: &Config, parameters: &HashMap<&str, &str>) -> Result<SearchRequest, Diagnostic> {
+    let query = parameters.get("query").ok_or_else(|| Diagnostic::new(7, "query"))?;
+    if parameters.get("queryType").is_some_and(|query_type| *query_type != "cql") {
+        return Err(Diagnostic::new(6, "queryType"));
+    }
+    if parameters.contains_key("sortKeys") {
+        return Err(Diagnostic::new(80, ""));
+    }
+    let start = parse_position(parameters, "startRecord", 1, 1)?;
+    // Asking for more than the most allowed is not an error; fewer are sent.
+    let maximum = parse_position(parameters, "maximumRecords", config.sru.default_records, 0)?.min(config.sru.max_records);
+    let (schema, format) = match parameters.get("recordSchema") {
+        Some(name) => get_record_schema(name).ok_or_else(|| Diagnostic::new(66, name))?,
+        None => (RECORD_SCHEMAS[0].1, RECORD_SCHEMAS[0].3),
+    };
+    let (condition, values) = cql_processing::get_sql_condition(&cql_processing::parse_query(query)?)?;
+    Ok(SearchRequest { start, maximum, schema, format, condition, values })
+}
+
+fn search_retrieve(
+    connection: &Connection,
+    config: &Config,
+    version: SruVersion,
+    parameters: &HashMap<&str, &str>,
+    escaped: bool,
+    mut diagnostics: Vec<Diagnostic>,
+) -> Result<String, rusqlite::Error> {
+    let prefix = version.get_response_namespace().0;
+    let fail = |diagnostics: &[Diagnostic]| {
+        render_response(version, "searchRetrieveResponse", &format!("  <{p}:numberOfRecords>0</{p}:numberOfRecords>\n", p = prefix), diagnostics)
+    };
+    if !diagnostics.is_empty() {
+        return Ok(fail(&diagnostics));
+    }
+
+    let SearchRequest { start, maximum, schema, format, condition, mut values } = match get_search_request(config, parameters) {
+        Ok(request) => request,
+        Err(diagnostic) => return Ok(fail(&[diagnostic])),
+    };
+
+    let total: i64 = connection.query_row(
+        &format!("SELECT COUNT(*) FROM books WHERE {}", condition),
+        params_from_iter(values.iter()),
+        |row| row.get(0),
+    )?;
+    let total = total as usize;
+    if total > 0 && start > total {
+        diagnostics.push(Diagnostic::new(61, &total.to_string()));
+    }
+    values.push(Value::Integer(maximum as i64));
+    values.push(Value::Integer(start as i64 - 1));
+    let mut stmt = connection.prepare(&format!(
+        "SELECT book_id, modified_at FROM books WHERE {} ORDER BY book_id LIMIT ? OFFSET ?",
+        condition
+    ))?;
+    let found = stmt
+        .query_map(params_from_iter(values.iter()), |row| Ok((row.get::<_, i32>(0)?, row.get::<_, Option<i64>>(1)?)))?
+        .collect::<Result<Vec<(i32, Option<i64>)>, rusqlite::Error>>()?;
+
+    let mut body = format!("  <{p}:numberOfRecords>{}</{p}:numberOfRecords>\n", total, p = prefix);
+    if !found.is_empty() {
+        body.push_str(&format!("  <{}:records>\n", prefix));
+        for (offset, (book_id, modified_at)) in found.iter().enumerate() {
+            let book = catalogue_processing::load_catalogue_record(connection, *book_id)?;
+            let data = match format {
+                MetadataFormat::DublinCore => render_dublin_core(&book, "        "),
+                MetadataFormat::MarcXml => oai_processing::render_marc(&book, *book_id, modified_at.unwrap_or_default(), "        "),
+            };
+            body.push_str(&render_record(version, schema, &data, escaped, Some(start + offset), "    "));
+        }
+        body.push_str(&format!("  </{}:records>\n", prefix));
+    }
+    if start + found.len() <= total && !found.is_empty() {
+        body.push_str(&format!("  <{p}:nextRecordPosition>{}</{p}:nextRecordPosition>\n", start + found.len(), p = prefix));
+    }
+    Ok(render_response(version, "searchRetrieveResponse", &body, &diagnostics))
+}
+
+// The host and port the server was reached at, for the explain record.
+fn get_host_and_port(base_url: &str) -> (String, String) {
+    let (scheme, host) = base_url.split_once("://").unwrap_or(("http", base_url));
+    let default_port = if scheme == "https" { "443" } else { "80" };
+    match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => (name.to_string(), port.to_string()),
+        _ => (host.to_string(), default_port.to_string()),
+    }
+}
+
+fn explain(config: &Config, version: SruVersion, base_url: &str, escaped: bool, diagnostics: &[Diagnostic]) -> String {
+    let (host, port) = get_host_and_port(base_url);
+    let mut data = format!("      <zr:explain xmlns:zr=\"{}\">\n", EXPLAIN_NAMESPACE);
+    data.push_str(&format!(
+        "        <zr:serverInfo protocol=\"SRU\" version=\"{}\" transport=\"{}\">\n",
+        version.get_name(),
+        if base_url.starts_with("https://") { "https" } else { "http" }
+    ));
+    data.push_str(&format!("          <zr:host>{}</zr:host>\n", escape_xml(&host)));
+    data.push_str(&format!("          <zr:port>{}</zr:port>\n", escape_xml(&port)));
+    data.push_str("          <zr:database>sru</zr:database>\n");
+    data.push_str("        </zr:serverInfo>\n");
+    data.push_str("        <zr:databaseInfo>\n");
+    data.push_str(&format!("          <zr:title lang=\"en\" primary=\"true\">{}</zr:title>\n", escape_xml(&config.sru.title)));
+    data.push_str("        </zr:databaseInfo>\n");
+
+    data.push_str("        <zr:indexInfo>\n");
+    for (name, identifier) in CONTEXT_SETS {
+        data.push_str(&format!("          <zr:set name=\"{}\" identifier=\"{}\"/>\n", name, identifier));
+    }
+    for (set, name, title, _) in INDEXES {
+        data.push_str(&format!(
+            "          <zr:index>\n            <zr:title>{}</zr:title>\n            <zr:map><zr:name set=\"{}\">{}</zr:name></zr:map>\n          </zr:index>\n",
+            title, set, name
+        ));
+    }
+    data.push_str("        </zr:indexInfo>\n");
+
+    data.push_str("        <zr:schemaInfo>\n");
+    for (name, identifier, title, _) in RECORD_SCHEMAS {
+        data.push_str(&format!(
+            "          <zr:schema identifier=\"{}\" name=\"{}\">\n            <zr:title>{}</zr:title>\n          </zr:schema>\n",
+            identifier, name, title
+        ));
+    }
+    data.push_str("        </zr:schemaInfo>\n");
+
+    data.push_str("        <zr:configInfo>\n");
+    data.push_str(&format!("          <zr:default type=\"numberOfRecords\">{}</zr:default>\n", config.sru.default_records));
+    data.push_str(&format!("          <zr:setting type=\"maximumRecords\">{}</zr:setting>\n", config.sru.max_records));
+    for relation in RELATIONS {
+        data.push_str(&format!("          <zr:supports type=\"relation\">{}</zr:supports>\n", escape_xml(relation)));
+    }
+    data.push_str("        </zr:configInfo>\n");
+    data.push_str("      </zr:explain>\n");
+
+    let body = render_record(version, EXPLAIN_NAMESPACE, &data, escaped, None, "  ");
+    render_response(version, "explainResponse", &body, diagnostics)
+}
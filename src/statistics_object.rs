@@ -0,0 +1,98 @@
+use serde::Serialize;
+
+/*
+ *  Figures over the whole library, for the statistics report. Lists are
+ *  kept in the order they are shown, so the terminal summary, the CSV
+ *  and JSON exports and the web charts all present them alike. Nothing
+ *  here names a user, so the report can be shown to anyone.
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct Count {
+    pub label: String,
+    pub count: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MonthlyCount {
+    // YYYY-MM
+    pub month: String,
+    pub titles_added: i64,
+    pub copies_added: i64,
+    pub total_titles: i64,
+    pub total_copies: i64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Statistics {
+    pub generated_at: String,
+    pub titles: i64,
+    pub copies: i64,
+    pub library_copies: i64,
+    pub total_pages: i64,
+    pub users: i64,
+    // Users with at least one book in their collection.
+    pub collections: i64,
+    pub by_month: Vec<MonthlyCount>,
+    pub top_authors: Vec<Count>,
+    pub top_subjects: Vec<Count>,
+    pub top_publishers: Vec<Count>,
+    pub by_decade: Vec<Count>,
+    pub reading_statuses: Vec<Count>,
+    pub users_by_role: Vec<Count>,
+    // Circulation: copies on loan, lost and on the shelves.
+    pub copies_by_status: Vec<Count>,
+}
+
+// The width of the longest bar in the terminal summary.
+const BAR_WIDTH: i64 = 30;
+
+impl Statistics {
+    // Each list with the name it is exported under and its heading.
+    pub fn get_sections(&self) -> [(&'static str, &'static str, &[Count]); 7] {
+        [
+            ("top_authors", "Top authors", &self.top_authors),
+            ("top_subjects", "Top subjects", &self.top_subjects),
+            ("top_publishers", "Top publishers", &self.top_publishers),
+            ("publish_decade", "Books by publish decade", &self.by_decade),
+            ("reading_status", "Reading statuses", &self.reading_statuses),
+            ("users_by_role", "Users by role", &self.users_by_role),
+            ("copies_by_status", "Circulation", &self.copies_by_status),
+        ]
+    }
+
+    pub fn print_statistics(&self, max_rows: usize) {
+        println!("Generated: {}", self.generated_at);
+        println!(
+            "Titles: {}, copies: {} ({} owned by the library), total pages: {}",
+            self.titles, self.copies, self.library_copies, self.total_pages
+        );
+        println!("Users: {}, with a collection: {}", self.users, self.collections);
+
+        println!("\nAdded by month:");
+        println!("  {:<8} {:>7} {:>7} {:>13} {:>13}", "Month", "Titles", "Copies", "Total titles", "Total copies");
+        for month in self.by_month.iter().skip(self.by_month.len().saturating_sub(max_rows)) {
+            println!(
+                "  {:<8} {:>7} {:>7} {:>13} {:>13}",
+                month.month, month.titles_added, month.copies_added, month.total_titles, month.total_copies
+            );
+        }
+
+        for (_, heading, counts) in self.get_sections() {
+            println!("\n{}:", heading);
+            if counts.is_empty() {
+                println!("  None yet.");
+            }
+            let largest = counts.iter().map(|count| count.count).max().unwrap_or(1).max(1);
+            let label_width = counts.iter().take(max_rows).map(|count| count.label.chars().count()).max().unwrap_or(0).min(40);
+            for count in counts.iter().take(max_rows) {
+                println!(
+                    "  {:<width$} {:>5} {}",
+                    count.label.chars().take(40).collect::<String>(),
+                    count.count,
+                    "#".repeat((count.count * BAR_WIDTH / largest) as usize),
+                    width = label_width
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs;
+use rusqlite::{params, Connection, Params};
+use crate::holding_object::HOLDING_STATUSES;
+use crate::role_object::Role;
+use crate::statistics_object::{Count, MonthlyCount, Statistics};
+use crate::utilities::{self, clear_screen, escape_xml, get_optional_input, get_yes_or_no};
+
+// How many authors, subjects and publishers the top lists name.
+const TOP_COUNT: usize = 10;
+
+fn get_counts<P: Params>(connection: &Connection, sql: &str, params: P) -> Result<Vec<Count>, rusqlite::Error> {
+    let mut stmt = connection.prepare(sql)?;
+    let counts = stmt.query_map(params, |row| Ok(Count { label: row.get(0)?, count: row.get(1)? }))?;
+    counts.collect()
+}
+
+// The first four digits in a publish date that look like a year, e.g. 1991 in "October 1, 1991".
+pub(crate) fn get_publish_year(publish_date: &str) -> Option<i32> {
+    let bytes = publish_date.as_bytes();
+    (0..bytes.len().saturating_sub(3))
+        .filter(|&start| bytes[start..start + 4].iter().all(u8::is_ascii_digit))
+        .filter(|&start| start == 0 || !bytes[start - 1].is_ascii_digit())
+        .filter(|&start| bytes.get(start + 4).is_none_or(|next| !next.is_ascii_digit()))
+        .filter_map(|start| publish_date[start..start + 4].parse::<i32>().ok())
+        .find(|year| (1000..=2100).contains(year))
+}
+
+fn get_decades(connection: &Connection) -> Result<Vec<Count>, rusqlite::Error> {
+    let mut decades: BTreeMap<i32, i64> = BTreeMap::new();
+    let mut unknown = 0;
+    let mut stmt = connection.prepare("SELECT publish_date FROM books")?;
+    for publish_date in stmt.query_map([], |row| row.get::<_, Option<String>>(0))? {
+        match publish_date?.as_deref().and_then(get_publish_year) {
+            Some(year) => *decades.entry(year - year % 10).or_default() += 1,
+            None => unknown += 1,
+        }
+    }
+    let mut counts: Vec<Count> = decades.into_iter()
+        .map(|(decade, count)| Count { label: format!("{}s", decade), count })
+        .collect();
+    if unknown > 0 {
+        counts.push(Count { label: "Unknown".to_string(), count: unknown });
+    }
+    Ok(counts)
+}
+
+/*
+ *  Copies and titles added each month, from when copies were added to
+ *  collections, since catalogue records keep no date of their own. A
+ *  title counts from the month its first copy was added. Months with
+ *  nothing added are included, so the totals chart has no gaps.
+ */
+fn get_months(connection: &Connection) -> Result<Vec<MonthlyCount>, rusqlite::Error> {
+    let mut added: BTreeMap<String, (i64, i64)> = BTreeMap::new();
+    let copies = get_counts(
+        connection,
+        "SELECT strftime('%Y-%m', created_at, 'unixepoch'), COUNT(*) FROM holdings GROUP BY 1",
+        [],
+    )?;
+    for count in copies {
+        added.entry(count.label).or_default().1 = count.count;
+    }
+    let titles = get_counts(
+        connection,
+        "SELECT strftime('%Y-%m', first_added, 'unixepoch'), COUNT(*)
+         FROM (SELECT MIN(created_at) AS first_added FROM holdings GROUP BY book_id)
+         GROUP BY 1",
+        [],
+    )?;
+    for count in titles {
+        added.entry(count.label).or_default().0 = count.count;
+    }
+
+    let parse_month = |month: &str| -> Option<(i32, u32)> {
+        let (year, month) = month.split_once('-')?;
+        Some((year.parse().ok()?, month.parse().ok()?))
+    };
+    let (Some((mut year, mut month)), Some(last)) = (
+        added.keys().next().and_then(|month| parse_month(month)),
+        added.keys().last().and_then(|month| parse_month(month)),
+    ) else {
+        return Ok(Vec::new());
+    };
+    let mut months = Vec::new();
+    let (mut total_titles, mut total_copies) = (0, 0);
+    while (year, month) <= last {
+        let label = format!("{:04}-{:02}", year, month);
+        let (titles_added, copies_added) = added.get(&label).copied().unwrap_or_default();
+        total_titles += titles_added;
+        total_copies += copies_added;
+        months.push(MonthlyCount { month: label, titles_added, copies_added, total_titles, total_copies });
+        (year, month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+    }
+    Ok(months)
+}
+
+pub fn get_statistics(connection: &Connection) -> Result<Statistics, rusqlite::Error> {
+    let mut statistics = Statistics {
+        generated_at: utilities::format_timestamp(utilities::current_timestamp()),
+        ..Statistics::default()
+    };
+    (statistics.titles, statistics.total_pages) = connection.query_row(
+        "SELECT COUNT(*), COALESCE(SUM(number_of_pages), 0) FROM books",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    (statistics.copies, statistics.library_copies) = connection.query_row(
+        "SELECT COUNT(*), COALESCE(SUM(owner_user_id IS NULL), 0) FROM holdings",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    (statistics.users, statistics.collections) = connection.query_row(
+        "SELECT (SELECT COUNT(*) FROM users), (SELECT COUNT(DISTINCT user_id) FROM libraries)",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    statistics.by_month = get_months(connection)?;
+
+    // Records from before authors were kept separately only have the author column.
+    statistics.top_authors = get_counts(
+        connection,
+        "SELECT name, COUNT(*) FROM (
+            SELECT book_id, name FROM book_authors
+            UNION
+            SELECT book_id, author FROM books
+            WHERE author != '' AND book_id NOT IN (SELECT book_id FROM book_authors)
+         )
+         GROUP BY name COLLATE NOCASE ORDER BY COUNT(*) DESC, name COLLATE NOCASE LIMIT ?1",
+        params![TOP_COUNT],
+    )?;
+    statistics.top_subjects = get_counts(
+        connection,
+        "SELECT subject, COUNT(*) FROM book_subjects
+         GROUP BY subject COLLATE NOCASE ORDER BY COUNT(*) DESC, subject COLLATE NOCASE LIMIT ?1",
+        params![TOP_COUNT],
+    )?;
+    statistics.top_publishers = get_counts(
+        connection,
+        "SELECT publisher, COUNT(*) FROM books WHERE publisher != ''
+         GROUP BY publisher COLLATE NOCASE ORDER BY COUNT(*) DESC, publisher COLLATE NOCASE LIMIT ?1",
+        params![TOP_COUNT],
+    )?;
+    statistics.by_decade = get_decades(connection)?;
+    statistics.reading_statuses = get_counts(
+        connection,
+        "SELECT COALESCE(reading_status, 'not set'), COUNT(*) FROM libraries GROUP BY 1 ORDER BY COUNT(*) DESC, 1",
+        [],
+    )?;
+    statistics.users_by_role = get_counts(
+        connection,
+        "SELECT COALESCE(user_roles.role, 'patron'), COUNT(*) FROM users
+         LEFT JOIN user_roles ON user_roles.user_id = users.user_id
+         GROUP BY 1 ORDER BY COUNT(*) DESC, 1",
+        [],
+    )?;
+    for count in statistics.users_by_role.iter_mut() {
+        if let Some(role) = Role::from_name(&count.label) {
+            count.label = role.get_display_name().to_string();
+        }
+    }
+    // Every status is listed, so a library with nothing on loan still shows it.
+    let statuses = get_counts(connection, "SELECT status, COUNT(*) FROM holdings GROUP BY status", [])?;
+    statistics.copies_by_status = HOLDING_STATUSES.iter()
+        .map(|status| Count {
+            label: status.to_string(),
+            count: statuses.iter().find(|count| count.label == *status).map_or(0, |count| count.count),
+        })
+        .collect();
+    Ok(statistics)
+}
+
+// One row per figure: which list it is in, its label and its value.
+pub fn get_statistics_csv(statistics: &Statistics) -> Result<String, Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(["report", "label", "value"])?;
+    for (label, value) in [
+        ("titles", statistics.titles),
+        ("copies", statistics.copies),
+        ("library_copies", statistics.library_copies),
+        ("total_pages", statistics.total_pages),
+        ("users", statistics.users),
+        ("collections", statistics.collections),
+    ] {
+        writer.write_record(["summary", label, &value.to_string()])?;
+    }
+    for month in &statistics.by_month {
+        for (report, value) in [
+            ("titles_added", month.titles_added),
+            ("copies_added", month.copies_added),
+            ("total_titles", month.total_titles),
+            ("total_copies", month.total_copies),
+        ] {
+            writer.write_record([report, &month.month, &value.to_string()])?;
+        }
+    }
+    for (report, _, counts) in statistics.get_sections() {
+        for count in counts {
+            writer.write_record([report, &count.label, &count.count.to_string()])?;
+        }
+    }
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+fn export_statistics(statistics: &Statistics, format: &str) -> Result<String, Box<dyn Error>> {
+    let file_name = format!("statistics_{}.{}", utilities::current_timestamp(), format);
+    match format {
+        "csv" => fs::write(&file_name, get_statistics_csv(statistics)?)?,
+        "json" => fs::write(&file_name, serde_json::to_string_pretty(statistics)?)?,
+        other => return Err(format!("Unknown export format: {}", other).into()),
+    }
+    Ok(file_name)
+}
+
+// A horizontal bar for each count, with its label on the left and its value on the right.
+fn render_bar_chart(counts: &[Count]) -> String {
+    let (label_width, bar_space, row_height) = (220.0, 440.0, 22.0);
+    let largest = counts.iter().map(|count| count.count).max().unwrap_or(1).max(1) as f64;
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"720\" height=\"{}\" role=\"img\">\n",
+        counts.len() as f64 * row_height + 4.0
+    );
+    for (index, count) in counts.iter().enumerate() {
+        let y = index as f64 * row_height;
+        let width = count.count as f64 / largest * bar_space;
+        let label: String = count.label.chars().take(32).collect();
+        let _ = writeln!(
+            svg,
+            "<text x=\"{:.0}\" y=\"{:.0}\" text-anchor=\"end\">{}</text>\
+             <rect x=\"{:.0}\" y=\"{:.0}\" width=\"{:.1}\" height=\"16\" fill=\"#4a7ab5\"/>\
+             <text x=\"{:.1}\" y=\"{:.0}\">{}</text>",
+            label_width - 8.0, y + 14.0, escape_xml(&label),
+            label_width, y + 2.0, width,
+            label_width + width + 6.0, y + 14.0, count.count
+        );
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+// A column for each month, labelled often enough to read without crowding.
+fn render_column_chart(points: &[(String, i64)]) -> String {
+    let (width, height, left, bottom) = (720.0, 240.0, 40.0, 30.0);
+    let largest = points.iter().map(|point| point.1).max().unwrap_or(1).max(1) as f64;
+    let column = (width - left) / points.len().max(1) as f64;
+    let label_every = points.len().div_ceil(12).max(1);
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" role=\"img\">\n\
+         <text x=\"{2}\" y=\"12\" text-anchor=\"end\">{3}</text>\n\
+         <line x1=\"{4}\" y1=\"{5}\" x2=\"{0}\" y2=\"{5}\" stroke=\"#888\"/>\n",
+        width, height, left - 6.0, largest, left, height - bottom
+    );
+    for (index, (label, value)) in points.iter().enumerate() {
+        let x = left + index as f64 * column;
+        let bar_height = *value as f64 / largest * (height - bottom - 16.0);
+        let _ = writeln!(
+            svg,
+            "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"#4a7ab5\"><title>{}: {}</title></rect>",
+            x + column * 0.1, height - bottom - bar_height, column * 0.8, bar_height, escape_xml(label), value
+        );
+        if index % label_every == 0 {
+            let _ = writeln!(
+                svg,
+                "<text x=\"{:.1}\" y=\"{:.0}\" text-anchor=\"middle\">{}</text>",
+                x + column / 2.0, height - bottom + 18.0, escape_xml(label)
+            );
+        }
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// The statistics as a web page of charts, for the /reports page.
+pub fn render_statistics_page(statistics: &Statistics) -> String {
+    let mut page = String::from(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<title>Library statistics</title>\n\
+         <style>body { font-family: sans-serif; max-width: 760px; margin: 2em auto; } svg { font-size: 12px; }</style>\n\
+         </head>\n<body>\n<h1>Library statistics</h1>\n",
+    );
+    let _ = writeln!(
+        page,
+        "<p>Generated {}. Download as <a href=\"/reports/statistics.csv\">CSV</a> or <a href=\"/reports/statistics.json\">JSON</a>.</p>\n\
+         <ul><li>Titles: {}</li><li>Copies: {} ({} owned by the library)</li><li>Total pages: {}</li>\
+         <li>Users: {}, with a collection: {}</li></ul>",
+        escape_xml(&statistics.generated_at), statistics.titles, statistics.copies, statistics.library_copies,
+        statistics.total_pages, statistics.users, statistics.collections
+    );
+    let monthly = |value: fn(&MonthlyCount) -> i64| -> Vec<(String, i64)> {
+        statistics.by_month.iter().map(|month| (month.month.clone(), value(month))).collect()
+    };
+    page.push_str("<h2>Collection size</h2>\n");
+    page.push_str(&render_column_chart(&monthly(|month| month.total_copies)));
+    page.push_str("<h2>Titles added by month</h2>\n");
+    page.push_str(&render_column_chart(&monthly(|month| month.titles_added)));
+    for (_, heading, counts) in statistics.get_sections() {
+        let _ = writeln!(page, "<h2>{}</h2>", heading);
+        if counts.is_empty() {
+            page.push_str("<p>None yet.</p>\n");
+        } else {
+            page.push_str(&render_bar_chart(counts));
+        }
+    }
+    page.push_str("</body>\n</html>\n");
+    page
+}
+
+pub(crate) fn view_statistics(database_name: &str, max_rows: usize) -> bool {
+    clear_screen();
+    print_statistics_header();
+    let connection = match Connection::open(database_name) {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("Failed to connect to the database: {}", e);
+            return false;
+        }
+    };
+    let statistics = match get_statistics(&connection) {
+        Ok(statistics) => statistics,
+        Err(e) => {
+            println!("Failed to gather the statistics: {}", e);
+            return false;
+        }
+    };
+    statistics.print_statistics(max_rows);
+
+    println!("\nWould you like to export these statistics? (y/n)");
+    if !get_yes_or_no() { return true; }
+    let format = loop {
+        match get_optional_input("Export format (csv/json):").as_deref() {
+            Some("csv") => break "csv",
+            Some("json") => break "json",
+            _ => println!("Invalid format. Please enter csv or json."),
+        }
+    };
+    match export_statistics(&statistics, format) {
+        Ok(file_name) => {
+            println!("The statistics were exported to {}", file_name);
+            true
+        }
+        Err(e) => {
+            println!("Failed to export the statistics: {}", e);
+            false
+        }
+    }
+}
+
+fn print_statistics_header() {
+    println!("################");
+    println!("## Statistics ##");
+    println!("################");
+}
@@ -0,0 +1,97 @@
+use crate::utilities;
+
+/*
+ *  A stocktake of one location and every location inside it. Copies
+ *  recorded there and available are expected on the shelves; copies on
+ *  loan or already lost are not, but are reported if they turn up. A
+ *  stocktake stays open, so the shelves can be walked over several
+ *  days, until it is finished.
+ */
+#[derive(Debug, Clone)]
+pub struct Stocktake {
+    pub stocktake_id: i64,
+    pub location_id: i64,
+    // None if the location has since been deleted.
+    pub location_path: Option<String>,
+    pub started_at: i64,
+    pub finished_at: Option<i64>,
+    pub scans: u32,
+}
+
+impl Stocktake {
+    pub fn is_open(&self) -> bool { self.finished_at.is_none() }
+
+    pub fn print_stocktake_info(&self) {
+        println!(
+            "Stocktake {}: {}, started {}, {} barcode(s) scanned, {}",
+            self.stocktake_id,
+            self.location_path.as_deref().unwrap_or("Deleted location"),
+            utilities::format_timestamp(self.started_at),
+            self.scans,
+            match self.finished_at {
+                Some(finished_at) => format!("finished {}", utilities::format_timestamp(finished_at)),
+                None => "open".to_string(),
+            }
+        );
+    }
+}
+
+// A copy named in a stocktake report.
+#[derive(Debug, Clone)]
+pub struct StocktakeItem {
+    pub holding_id: i64,
+    pub title: String,
+    pub call_number: Option<String>,
+    pub barcode: Option<String>,
+    // Where the copy is recorded to be.
+    pub location_path: Option<String>,
+}
+
+impl StocktakeItem {
+    fn print_item_info(&self) {
+        println!(
+            "  {:<20} {} (Copy ID {}, {}), recorded at {}",
+            self.call_number.as_deref().unwrap_or("[no call number]"),
+            self.title,
+            self.holding_id,
+            self.barcode.as_deref().map_or("no barcode".to_string(), |barcode| format!("barcode {}", barcode)),
+            self.location_path.as_deref().unwrap_or("no location")
+        );
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct StocktakeReport {
+    pub expected: usize,
+    pub found: usize,
+    // Expected but not scanned.
+    pub missing: Vec<StocktakeItem>,
+    // Scanned here but recorded somewhere else, or nowhere.
+    pub unexpected: Vec<StocktakeItem>,
+    pub on_loan_found: Vec<StocktakeItem>,
+    pub lost_found: Vec<StocktakeItem>,
+    // Scanned barcodes that belong to no copy.
+    pub unknown_barcodes: Vec<String>,
+}
+
+impl StocktakeReport {
+    pub fn print_report(&self, max_rows: usize) {
+        println!("Expected on the shelves: {}, found: {}", self.expected, self.found);
+        let sections = [
+            ("Missing", &self.missing),
+            ("Shelved in the wrong place", &self.unexpected),
+            ("Marked on loan but found on the shelf", &self.on_loan_found),
+            ("Marked lost but found on the shelf", &self.lost_found),
+        ];
+        for (heading, items) in sections {
+            println!("{} ({}):", heading, items.len());
+            items.iter().take(max_rows).for_each(StocktakeItem::print_item_info);
+            if items.len() > max_rows {
+                println!("  Only the first {} are shown.", max_rows);
+            }
+        }
+        if !self.unknown_barcodes.is_empty() {
+            println!("Barcodes that match no copy ({}): {}", self.unknown_barcodes.len(), self.unknown_barcodes.join(", "));
+        }
+    }
+}
@@ -0,0 +1,418 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::io;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::json;
+use crate::audit_management;
+use crate::barcode_management::{self, BarcodeOwner};
+use crate::configuration::Config;
+use crate::holding_processing;
+use crate::location_object::Location;
+use crate::location_processing;
+use crate::stocktake_object::{Stocktake, StocktakeItem, StocktakeReport};
+use crate::user_object::User;
+use crate::utilities::{self, clear_screen, get_optional_input, get_yes_or_no};
+
+// What became of one scanned barcode.
+enum ScanOutcome {
+    Copy(i64),
+    Unknown,
+    Patron,
+    AlreadyScanned,
+}
+
+const STOCKTAKE_COLUMNS: &str =
+    "stocktakes.stocktake_id, stocktakes.location_id, stocktakes.started_at, stocktakes.finished_at,
+     (SELECT COUNT(*) FROM stocktake_scans WHERE stocktake_scans.stocktake_id = stocktakes.stocktake_id)";
+
+pub fn start_stocktake(connection: &Connection, user_id: i32, location_id: i64) -> Result<i64, rusqlite::Error> {
+    connection.execute(
+        "INSERT INTO stocktakes (location_id, started_by, started_at) VALUES (?1, ?2, ?3)",
+        params![location_id, user_id, utilities::current_timestamp()],
+    )?;
+    Ok(connection.last_insert_rowid())
+}
+
+pub fn finish_stocktake(connection: &Connection, stocktake_id: i64) -> Result<(), rusqlite::Error> {
+    connection.execute(
+        "UPDATE stocktakes SET finished_at = ?2 WHERE stocktake_id = ?1 AND finished_at IS NULL",
+        params![stocktake_id, utilities::current_timestamp()],
+    )?;
+    Ok(())
+}
+
+/// Every stocktake, the latest first.
+pub fn get_stocktakes(connection: &Connection) -> Result<Vec<Stocktake>, rusqlite::Error> {
+    let paths: HashMap<i64, String> = location_processing::get_locations(connection)?
+        .into_iter()
+        .map(|location| (location.location_id, location.get_path()))
+        .collect();
+    let mut stmt = connection.prepare(&format!(
+        "SELECT {} FROM stocktakes ORDER BY stocktakes.stocktake_id DESC",
+        STOCKTAKE_COLUMNS
+    ))?;
+    let stocktakes = stmt.query_map([], |row| {
+        let location_id: i64 = row.get(1)?;
+        Ok(Stocktake {
+            stocktake_id: row.get(0)?,
+            location_id,
+            location_path: paths.get(&location_id).cloned(),
+            started_at: row.get(2)?,
+            finished_at: row.get(3)?,
+            scans: row.get(4)?,
+        })
+    })?;
+    stocktakes.collect()
+}
+
+/// Records a scanned barcode once, with the copy it belongs to if there is one.
+fn record_scan(connection: &Connection, stocktake_id: i64, barcode: &str) -> Result<ScanOutcome, rusqlite::Error> {
+    let holding_id = match barcode_management::find_barcode(connection, barcode)? {
+        Some(BarcodeOwner::Patron(_)) => return Ok(ScanOutcome::Patron),
+        Some(BarcodeOwner::Item(holding_id)) => Some(holding_id),
+        None => None,
+    };
+    let inserted = connection.execute(
+        "INSERT OR IGNORE INTO stocktake_scans (stocktake_id, barcode, holding_id, scanned_at) VALUES (?1, ?2, ?3, ?4)",
+        params![stocktake_id, barcode.trim(), holding_id, utilities::current_timestamp()],
+    )?;
+    if inserted == 0 {
+        return Ok(ScanOutcome::AlreadyScanned);
+    }
+    Ok(holding_id.map_or(ScanOutcome::Unknown, ScanOutcome::Copy))
+}
+
+// The IDs of a location and of every location inside it.
+fn get_locations_inside(locations: &[Location], location_id: i64) -> HashSet<i64> {
+    let Some(root) = locations.iter().find(|location| location.location_id == location_id) else {
+        return HashSet::new();
+    };
+    locations.iter()
+        .filter(|location| location.path.starts_with(&root.path))
+        .map(|location| location.location_id)
+        .collect()
+}
+
+/*
+ *  Compares the copies scanned with the copies recorded in the
+ *  stocktake's location. A copy's status decides which part of the
+ *  report it belongs in before its location does, so a copy marked on
+ *  loan is reported as such wherever it was found.
+ */
+pub fn get_report(connection: &Connection, stocktake: &Stocktake) -> Result<StocktakeReport, rusqlite::Error> {
+    let locations = location_processing::get_locations(connection)?;
+    let inside = get_locations_inside(&locations, stocktake.location_id);
+    let paths: HashMap<i64, String> = locations.iter().map(|location| (location.location_id, location.get_path())).collect();
+
+    let mut stmt = connection.prepare(
+        "SELECT holdings.holding_id, books.title, holdings.call_number, holdings.barcode, holdings.location_id,
+                holdings.status, stocktake_scans.holding_id IS NOT NULL
+         FROM holdings
+         JOIN books ON books.book_id = holdings.book_id
+         LEFT JOIN stocktake_scans ON stocktake_scans.holding_id = holdings.holding_id
+                                  AND stocktake_scans.stocktake_id = ?1
+         WHERE holdings.location_id IS NOT NULL OR stocktake_scans.holding_id IS NOT NULL
+         ORDER BY holdings.call_number_sort IS NULL, holdings.call_number_sort, books.title COLLATE NOCASE, holdings.holding_id",
+    )?;
+    let rows = stmt.query_map(params![stocktake.stocktake_id], |row| {
+        let location_id: Option<i64> = row.get(4)?;
+        Ok((
+            StocktakeItem {
+                holding_id: row.get(0)?,
+                title: row.get(1)?,
+                call_number: row.get(2)?,
+                barcode: row.get(3)?,
+                location_path: location_id.and_then(|location_id| paths.get(&location_id).cloned()),
+            },
+            location_id.is_some_and(|location_id| inside.contains(&location_id)),
+            row.get::<_, String>(5)?,
+            row.get::<_, bool>(6)?,
+        ))
+    })?;
+
+    let mut report = StocktakeReport::default();
+    for row in rows {
+        let (item, belongs, status, scanned) = row?;
+        match (status.as_str(), scanned) {
+            ("on-loan", true) => report.on_loan_found.push(item),
+            ("lost", true) => report.lost_found.push(item),
+            ("available", _) if belongs => {
+                report.expected += 1;
+                if scanned {
+                    report.found += 1;
+                } else {
+                    report.missing.push(item);
+                }
+            }
+            (_, true) if !belongs => report.unexpected.push(item),
+            _ => {}
+        }
+    }
+    report.unknown_barcodes = connection
+        .prepare("SELECT barcode FROM stocktake_scans WHERE stocktake_id = ?1 AND holding_id IS NULL ORDER BY scanned_at")?
+        .query_map(params![stocktake.stocktake_id], |row| row.get(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+    Ok(report)
+}
+
+/// Marks the missing copies as lost in one go; copies whose status has changed since are left alone.
+pub fn mark_missing_as_lost(connection: &mut Connection, user_id: i32, stocktake: &Stocktake, missing: &[StocktakeItem]) -> Result<usize, rusqlite::Error> {
+    let transaction = connection.transaction()?;
+    let mut marked = Vec::new();
+    for item in missing {
+        let updated = transaction.execute(
+            "UPDATE holdings SET status = 'lost' WHERE holding_id = ?1 AND status = 'available'",
+            params![item.holding_id],
+        )?;
+        if updated > 0 {
+            marked.push(item.holding_id);
+        }
+    }
+    audit_management::record_event(
+        &transaction,
+        Some(user_id),
+        audit_management::HOLDINGS_MARKED_LOST,
+        &format!("stocktake:{}", stocktake.stocktake_id),
+        json!({
+            "stocktake_id": stocktake.stocktake_id,
+            "location": stocktake.location_path,
+            "holding_ids": marked,
+        }),
+    )?;
+    transaction.commit()?;
+    Ok(marked.len())
+}
+
+// Says straight away what a scanned barcode is, so a misplaced copy can be pulled from the shelf there and then.
+fn print_scan_outcome(connection: &Connection, inside: &HashSet<i64>, barcode: &str, outcome: &ScanOutcome) {
+    let holding_id = match outcome {
+        ScanOutcome::Copy(holding_id) => *holding_id,
+        ScanOutcome::Unknown => return println!("  {}: matches no copy.", barcode),
+        ScanOutcome::Patron => return println!("  {}: is a patron card, not a copy.", barcode),
+        ScanOutcome::AlreadyScanned => return println!("  {}: already scanned.", barcode),
+    };
+    let holding = match holding_processing::get_holding(connection, holding_id) {
+        Ok(Some(holding)) => holding,
+        _ => return println!("  {}: copy {} could not be read.", barcode, holding_id),
+    };
+    let title: String = connection
+        .query_row("SELECT title FROM books WHERE book_id = ?1", params![holding.book_id], |row| row.get(0))
+        .unwrap_or_default();
+    let status = holding.status.as_deref().unwrap_or_default();
+    if status == "on-loan" || status == "lost" {
+        println!("  {}: {} is marked {}.", barcode, title, status.replace('-', " "));
+    } else if holding.location_id.is_some_and(|location_id| inside.contains(&location_id)) {
+        println!("  {}: {}", barcode, title);
+    } else {
+        println!(
+            "  {}: {} belongs at {}.",
+            barcode,
+            title,
+            holding.location_path.as_deref().unwrap_or("no recorded location")
+        );
+    }
+}
+
+// Barcodes may be scanned one to a line, or pasted several at once.
+fn split_barcodes(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| c.is_whitespace() || c == ',' || c == ';').filter(|barcode| !barcode.is_empty())
+}
+
+fn scan_barcodes(connection: &Connection, stocktake: &Stocktake) {
+    let locations = location_processing::get_locations(connection).unwrap_or_default();
+    let inside = get_locations_inside(&locations, stocktake.location_id);
+    println!("Scan the barcodes of the copies on the shelves. Enter a blank line to stop.");
+    let mut recorded = 0;
+    while let Some(line) = get_optional_input("Barcode:") {
+        for barcode in split_barcodes(&line) {
+            match record_scan(connection, stocktake.stocktake_id, barcode) {
+                Ok(outcome) => {
+                    if matches!(outcome, ScanOutcome::Copy(_) | ScanOutcome::Unknown) {
+                        recorded += 1;
+                    }
+                    print_scan_outcome(connection, &inside, barcode, &outcome);
+                }
+                Err(e) => println!("  {}: could not be recorded: {}", barcode, e),
+            }
+        }
+    }
+    println!("{} barcode(s) recorded.", recorded);
+}
+
+// Loads the barcodes saved by a scanner that was used away from the computer.
+fn import_scans(connection: &Connection, stocktake: &Stocktake, path: &str) -> Result<(usize, usize, usize), Box<dyn Error>> {
+    let text = fs::read_to_string(path)?;
+    let (mut recorded, mut unknown, mut skipped) = (0, 0, 0);
+    for barcode in split_barcodes(&text) {
+        match record_scan(connection, stocktake.stocktake_id, barcode)? {
+            ScanOutcome::Copy(_) => recorded += 1,
+            ScanOutcome::Unknown => unknown += 1,
+            ScanOutcome::Patron | ScanOutcome::AlreadyScanned => skipped += 1,
+        }
+    }
+    Ok((recorded, unknown, skipped))
+}
+
+/*
+ *  Asks for a stocktake by ID. A blank answer picks the latest open one,
+ *  or the latest of all if only_open is false and none are open.
+ */
+fn get_stocktake_from_user(connection: &Connection, only_open: bool) -> Option<Stocktake> {
+    let stocktakes = match get_stocktakes(connection) {
+        Ok(stocktakes) => stocktakes,
+        Err(e) => {
+            println!("Error retrieving the stocktakes: {}", e);
+            return None;
+        }
+    };
+    let stocktake = match get_optional_input("Stocktake ID (leave blank for the latest open one):") {
+        None => stocktakes.iter()
+            .find(|stocktake| stocktake.is_open())
+            .or_else(|| if only_open { None } else { stocktakes.first() }),
+        Some(input) => match input.parse::<i64>() {
+            Ok(stocktake_id) => stocktakes.iter().find(|stocktake| stocktake.stocktake_id == stocktake_id),
+            Err(_) => None,
+        },
+    };
+    match stocktake {
+        Some(stocktake) if only_open && !stocktake.is_open() => {
+            println!("Stocktake {} has been finished, so no more barcodes can be added to it.", stocktake.stocktake_id);
+            None
+        }
+        Some(stocktake) => Some(stocktake.clone()),
+        None => {
+            println!("No such stocktake. Start one first, or use List stocktakes to see them.");
+            None
+        }
+    }
+}
+
+fn reconcile(connection: &mut Connection, user: &User, stocktake: &Stocktake, max_rows: usize) {
+    let report = match get_report(connection, stocktake) {
+        Ok(report) => report,
+        Err(e) => {
+            println!("Failed to compare the scans: {}", e);
+            return;
+        }
+    };
+    stocktake.print_stocktake_info();
+    report.print_report(max_rows);
+    if !stocktake.is_open() {
+        return;
+    }
+
+    if !report.missing.is_empty() {
+        println!("Mark the {} missing copies as lost? (y/n)", report.missing.len());
+        if get_yes_or_no() {
+            match mark_missing_as_lost(connection, user.get_user_id(), stocktake, &report.missing) {
+                Ok(marked) => println!("{} copies marked as lost.", marked),
+                Err(e) => println!("Failed to mark the copies as lost: {}", e),
+            }
+        }
+    }
+    println!("Finish this stocktake? No more barcodes can be scanned into it afterwards. (y/n)");
+    if get_yes_or_no() {
+        match finish_stocktake(connection, stocktake.stocktake_id) {
+            Ok(()) => println!("Stocktake {} finished.", stocktake.stocktake_id),
+            Err(e) => println!("Failed to finish the stocktake: {}", e),
+        }
+    }
+}
+
+pub(crate) fn manage_stocktakes(database_name: &str, user: &User, config: &Config) {
+    let mut connection = match Connection::open(database_name) {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("Failed to connect to the database: {}", e);
+            return;
+        }
+    };
+
+    loop {
+        clear_screen();
+        print_stocktake_header();
+        println!(
+            "Choose from the options below:\n\
+            \t1. Start a stocktake\n\
+            \t2. Scan barcodes\n\
+            \t3. Import scanned barcodes from a file\n\
+            \t4. Report and reconcile\n\
+            \t5. List stocktakes\n\
+            \t0. Back"
+        );
+        let mut choice = String::new();
+        if io::stdin().read_line(&mut choice).is_err() {
+            println!("Failed to read input. Please try again.");
+            continue;
+        }
+
+        match choice.trim() {
+            "1" => {
+                let Some(location) = location_processing::get_location_from_user(&connection, "Location to take stock of (path or ID):") else {
+                    utilities::pause(2);
+                    continue;
+                };
+                let open = connection.query_row(
+                    "SELECT stocktake_id FROM stocktakes WHERE location_id = ?1 AND finished_at IS NULL",
+                    params![location.location_id],
+                    |row| row.get::<_, i64>(0),
+                ).optional();
+                match open {
+                    Ok(Some(stocktake_id)) => println!("Stocktake {} of {} is still open. Scan into that one instead.", stocktake_id, location.get_path()),
+                    Ok(None) => match start_stocktake(&connection, user.get_user_id(), location.location_id) {
+                        Ok(stocktake_id) => {
+                            println!("Started stocktake {} of {}.", stocktake_id, location.get_path());
+                            let stocktake = get_stocktakes(&connection)
+                                .ok()
+                                .and_then(|stocktakes| stocktakes.into_iter().find(|stocktake| stocktake.stocktake_id == stocktake_id));
+                            if let Some(stocktake) = stocktake {
+                                scan_barcodes(&connection, &stocktake);
+                            }
+                        }
+                        Err(e) => println!("Failed to start the stocktake: {}", e),
+                    },
+                    Err(e) => println!("Failed to search the stocktakes: {}", e),
+                }
+            }
+            "2" => {
+                if let Some(stocktake) = get_stocktake_from_user(&connection, true) {
+                    scan_barcodes(&connection, &stocktake);
+                }
+            }
+            "3" => {
+                let Some(stocktake) = get_stocktake_from_user(&connection, true) else {
+                    utilities::pause(2);
+                    continue;
+                };
+                let Some(path) = get_optional_input("File of barcodes, one per line:") else { continue; };
+                match import_scans(&connection, &stocktake, &path) {
+                    Ok((recorded, unknown, skipped)) => println!(
+                        "{} copies recorded, {} barcode(s) matched no copy, {} skipped as repeats or patron cards.",
+                        recorded, unknown, skipped
+                    ),
+                    Err(e) => println!("Failed to import the barcodes: {}", e),
+                }
+            }
+            "4" => {
+                if let Some(stocktake) = get_stocktake_from_user(&connection, false) {
+                    reconcile(&mut connection, user, &stocktake, config.interface.max_list_rows);
+                }
+            }
+            "5" => match get_stocktakes(&connection) {
+                Ok(stocktakes) if stocktakes.is_empty() => println!("No stocktakes have been started yet."),
+                Ok(stocktakes) => stocktakes.iter().take(config.interface.max_list_rows).for_each(Stocktake::print_stocktake_info),
+                Err(e) => println!("Error retrieving the stocktakes: {}", e),
+            },
+            "0" => return,
+            _ => println!("Invalid choice. Please try again."),
+        }
+        utilities::pause(2);
+    }
+}
+
+fn print_stocktake_header() {
+    println!("###############");
+    println!("## Stocktake ##");
+    println!("###############");
+}
@@ -0,0 +1,115 @@
+use rusqlite::{params, Connection};
+use sha2::{Digest, Sha256};
+use crate::utilities;
+
+pub const EMAIL_VERIFICATION: &str = "email_verification";
+pub const PASSWORD_RESET: &str = "password_reset";
+
+const TOKEN_LENGTH: usize = 32;
+
+/*
+ *  Tokens are mailed to the user in plain text but only their SHA-256
+ *  hash is stored, so someone reading the database cannot use them.
+ *  A bcrypt hash is not needed here because the tokens are long and
+ *  random rather than chosen by a person.
+ */
+pub(crate) fn hash_token(token: &str) -> String {
+    Sha256::digest(token.trim().as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Creates a new single-use token and returns the plain text to send to the user.
+/// Any earlier unused tokens for the same user and purpose stop working.
+pub fn create_token(database_name: &str, user_id: i32, purpose: &str, lifetime_seconds: i64) -> Result<String, rusqlite::Error> {
+    let connection = Connection::open(database_name)?;
+    let now = utilities::current_timestamp();
+    let token = utilities::generate_token(TOKEN_LENGTH);
+
+    connection.execute(
+        "UPDATE user_tokens SET used_at = ?3
+         WHERE user_id = ?1 AND purpose = ?2 AND used_at IS NULL",
+        params![user_id, purpose, now],
+    )?;
+    connection.execute(
+        "INSERT INTO user_tokens (user_id, purpose, token_hash, created_at, expires_at)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![user_id, purpose, hash_token(&token), now, now + lifetime_seconds],
+    )?;
+    Ok(token)
+}
+
+/// Marks the token as used and returns the user it belongs to, or None if
+/// the token is unknown, already used, expired or meant for something else.
+/// If owner_user_id is given, a token belonging to anyone else is left unused.
+pub fn consume_token(database_name: &str, purpose: &str, token: &str, owner_user_id: Option<i32>) -> Result<Option<i32>, rusqlite::Error> {
+    let connection = Connection::open(database_name)?;
+    let now = utilities::current_timestamp();
+    let token_hash = hash_token(token);
+
+    let user_id = match connection.query_row(
+        "SELECT user_id FROM user_tokens
+         WHERE token_hash = ?1 AND purpose = ?2 AND used_at IS NULL AND expires_at > ?3
+           AND (?4 IS NULL OR user_id = ?4)",
+        params![token_hash, purpose, now, owner_user_id],
+        |row| row.get::<_, i32>(0),
+    ) {
+        Ok(user_id) => user_id,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    // The used_at check makes sure that two simultaneous uses cannot both succeed.
+    let updated = connection.execute(
+        "UPDATE user_tokens SET used_at = ?3 WHERE token_hash = ?1 AND user_id = ?2 AND used_at IS NULL",
+        params![token_hash, user_id, now],
+    )?;
+    if updated == 1 { Ok(Some(user_id)) } else { Ok(None) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialisation;
+
+    #[test]
+    fn hash_token_is_the_sha256_of_the_trimmed_token() {
+        assert_eq!(hash_token("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hash_token(" abc\n"), hash_token("abc"));
+    }
+
+    #[test]
+    fn tokens_work_once_for_their_purpose() {
+        let (database_name, connection) = initialisation::open_test_database("tokens_once");
+        let token = create_token(&database_name, 7, EMAIL_VERIFICATION, 3600).unwrap();
+        assert_eq!(token.len(), TOKEN_LENGTH);
+        let stored: String = connection.query_row("SELECT token_hash FROM user_tokens", [], |row| row.get(0)).unwrap();
+        assert_eq!(stored, hash_token(&token));
+
+        assert_eq!(consume_token(&database_name, PASSWORD_RESET, &token, None).unwrap(), None);
+        assert_eq!(consume_token(&database_name, EMAIL_VERIFICATION, &format!(" {} ", token), None).unwrap(), Some(7));
+        assert_eq!(consume_token(&database_name, EMAIL_VERIFICATION, &token, None).unwrap(), None);
+    }
+
+    #[test]
+    fn new_and_expired_tokens_replace_earlier_ones() {
+        let (database_name, _connection) = initialisation::open_test_database("tokens_replaced");
+        let first = create_token(&database_name, 7, PASSWORD_RESET, 3600).unwrap();
+        let second = create_token(&database_name, 7, PASSWORD_RESET, 3600).unwrap();
+        assert_eq!(consume_token(&database_name, PASSWORD_RESET, &first, None).unwrap(), None);
+        assert_eq!(consume_token(&database_name, PASSWORD_RESET, &second, None).unwrap(), Some(7));
+
+        let expired = create_token(&database_name, 7, PASSWORD_RESET, 0).unwrap();
+        assert_eq!(consume_token(&database_name, PASSWORD_RESET, &expired, None).unwrap(), None);
+        assert_eq!(consume_token(&database_name, PASSWORD_RESET, "not a token", None).unwrap(), None);
+    }
+
+    #[test]
+    fn a_token_checked_against_its_owner_is_only_used_by_them() {
+        let (database_name, _connection) = initialisation::open_test_database("tokens_owner");
+        let token = create_token(&database_name, 7, EMAIL_VERIFICATION, 3600).unwrap();
+        assert_eq!(consume_token(&database_name, EMAIL_VERIFICATION, &token, Some(8)).unwrap(), None);
+        assert_eq!(consume_token(&database_name, EMAIL_VERIFICATION, &token, Some(7)).unwrap(), Some(7));
+    }
+}
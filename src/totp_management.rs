@@ -0,0 +1,456 @@
+use std::io;
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use qrcode::render::unicode;
+use qrcode::QrCode;
+use rand::RngCore;
+use rusqlite::{params, Connection};
+use sha1::Sha1;
+use serde_json::json;
+use crate::audit_management;
+use crate::configuration::Config;
+use crate::token_management::hash_token;
+use crate::role_object::{Permission, Role};
+use crate::user_object::User;
+use crate::utilities::{self, clear_screen, get_yes_or_no};
+
+/*
+ *  Time-based one-time passwords as described in RFC 6238, using the
+ *  defaults that every authenticator app understands: HMAC-SHA1,
+ *  6 digits and a 30 second step.
+ */
+const TOTP_STEP_SECONDS: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+const TOTP_ISSUER: &str = "rLMS";
+const SECRET_LENGTH: usize = 20;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+fn generate_secret() -> String {
+    let mut secret = [0u8; SECRET_LENGTH];
+    rand::thread_rng().fill_bytes(&mut secret);
+    BASE32_NOPAD.encode(&secret)
+}
+
+fn totp_code(secret: &[u8], step: i64) -> Option<u32> {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).ok()?;
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // Dynamic truncation, RFC 4226 section 5.3
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([hash[offset], hash[offset + 1], hash[offset + 2], hash[offset + 3]])
+        & 0x7fff_ffff;
+    Some(binary % 10u32.pow(TOTP_DIGITS))
+}
+
+/// Returns the time step the code belongs to, allowing one step either side
+/// for clock drift, or None if the code does not match.
+fn find_matching_step(secret: &str, code: &str, now: i64) -> Option<i64> {
+    let secret = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    // Exactly the digits of a code, so that "+123456" or "0123456" are not read as 123456.
+    let code = code.trim().replace(' ', "");
+    if code.len() != TOTP_DIGITS as usize || !code.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let current_step = now / TOTP_STEP_SECONDS;
+    (current_step - 1..=current_step + 1).find(|step| totp_code(&secret, *step) == Some(code))
+}
+
+pub fn provisioning_uri(email: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = TOTP_ISSUER,
+        account = utilities::percent_encode(email),
+        secret = secret,
+        digits = TOTP_DIGITS,
+        period = TOTP_STEP_SECONDS
+    )
+}
+
+fn print_qr_code(data: &str) {
+    match QrCode::new(data.as_bytes()) {
+        Ok(code) => {
+            let image = code
+                .render::<unicode::Dense1x2>()
+                .dark_color(unicode::Dense1x2::Light)
+                .light_color(unicode::Dense1x2::Dark)
+                .build();
+            println!("{}", image);
+        }
+        Err(e) => println!("Failed to draw QR code: {}", e),
+    }
+}
+
+fn get_two_factor_secret(connection: &Connection, user_id: i32, enabled_only: bool) -> Result<Option<(String, i64)>, rusqlite::Error> {
+    let query = "SELECT secret, last_used_step FROM two_factor WHERE user_id = ?1 AND (enabled = 1 OR ?2 = 0)";
+    match connection.query_row(query, params![user_id, enabled_only], |row| Ok((row.get(0)?, row.get(1)?))) {
+        Ok(secret) => Ok(Some(secret)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/*
+ *  Whether the configuration makes a role use 2FA. This goes by the role
+ *  alone, not whether the email address is verified, so that an account
+ *  cannot avoid 2FA at login or turn it off while its address is unverified.
+ */
+pub fn is_two_factor_required(config: &Config, role: Role) -> bool {
+    config.auth.require_admin_two_factor && role.has_permission(Permission::ManageUsers)
+}
+
+pub fn is_two_factor_enabled(database_name: &str, user_id: i32) -> Result<bool, rusqlite::Error> {
+    let connection = Connection::open(database_name)?;
+    Ok(get_two_factor_secret(&connection, user_id, true)?.is_some())
+}
+
+/*
+ *  Checks a code against the user's secret. A code can only be used once:
+ *  the step of the last accepted code is stored and anything at or before
+ *  it is refused, so a code seen over someone's shoulder is worthless.
+ */
+fn verify_totp(connection: &Connection, user_id: i32, code: &str, enabled_only: bool) -> Result<bool, rusqlite::Error> {
+    let (secret, last_used_step) = match get_two_factor_secret(connection, user_id, enabled_only)? {
+        Some(secret) => secret,
+        None => return Ok(false),
+    };
+    match find_matching_step(&secret, code, utilities::current_timestamp()) {
+        Some(step) if step > last_used_step => {
+            connection.execute(
+                "UPDATE two_factor SET last_used_step = ?2 WHERE user_id = ?1",
+                params![user_id, step],
+            )?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+fn use_recovery_code(connection: &Connection, user_id: i32, code: &str) -> Result<bool, rusqlite::Error> {
+    let updated = connection.execute(
+        "UPDATE recovery_codes SET used_at = ?3
+         WHERE user_id = ?1 AND code_hash = ?2 AND used_at IS NULL",
+        params![user_id, hash_token(&code.to_lowercase()), utilities::current_timestamp()],
+    )?;
+    Ok(updated == 1)
+}
+
+fn create_recovery_codes(connection: &Connection, user_id: i32) -> Result<Vec<String>, rusqlite::Error> {
+    connection.execute("DELETE FROM recovery_codes WHERE user_id = ?1", params![user_id])?;
+    let mut codes = Vec::new();
+    for _ in 0..RECOVERY_CODE_COUNT {
+        let raw = utilities::generate_token(10).to_lowercase();
+        let code = format!("{}-{}", &raw[..5], &raw[5..]);
+        connection.execute(
+            "INSERT INTO recovery_codes (user_id, code_hash) VALUES (?1, ?2)",
+            params![user_id, hash_token(&code)],
+        )?;
+        codes.push(code);
+    }
+    Ok(codes)
+}
+
+/*
+ *  Called by login_user once the password has been accepted. Returns true
+ *  if the user may continue logging in. Administrators without 2FA are
+ *  made to set it up here when the configuration requires it.
+ */
+pub fn challenge_at_login(database_name: &str, config: &Config, user_id: i32, email: &str, role: Role) -> bool {
+    let connection = match Connection::open(database_name) {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("Failed to connect to the database: {}", e);
+            return false;
+        }
+    };
+
+    match get_two_factor_secret(&connection, user_id, true) {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            if is_two_factor_required(config, role) {
+                println!("Two-factor authentication is required for administrator accounts.");
+                return enrol(&connection, user_id, email);
+            }
+            return true;
+        }
+        Err(e) => {
+            eprintln!("Failed to check two-factor authentication: {}", e);
+            return false;
+        }
+    }
+
+    println!("Enter the 6-digit code from your authenticator app (or a recovery code): ");
+    let mut input = String::new();
+    if io::stdin().read_line(&mut input).is_err() {
+        println!("Failed to read input.");
+        return false;
+    }
+    let code = input.trim();
+
+    let accepted = if code.contains('-') {
+        let accepted = use_recovery_code(&connection, user_id, code).unwrap_or(false);
+        if accepted {
+            let remaining: i32 = connection
+                .query_row(
+                    "SELECT COUNT(*) FROM recovery_codes WHERE user_id = ?1 AND used_at IS NULL",
+                    params![user_id],
+                    |row| row.get(0),
+                )
+                .unwrap_or(0);
+            println!("Recovery code accepted. You have {} recovery code(s) left.", remaining);
+        }
+        accepted
+    } else {
+        verify_totp(&connection, user_id, code, true).unwrap_or(false)
+    };
+
+    if !accepted {
+        println!("Invalid two-factor code.");
+    }
+    accepted
+}
+
+fn enrol(connection: &Connection, user_id: i32, email: &str) -> bool {
+    let secret = generate_secret();
+    if let Err(e) = connection.execute(
+        "INSERT INTO two_factor (user_id, secret, enabled, last_used_step, created_at)
+         VALUES (?1, ?2, 0, 0, ?3)
+         ON CONFLICT(user_id) DO UPDATE SET secret = excluded.secret, enabled = 0,
+            last_used_step = 0, created_at = excluded.created_at",
+        params![user_id, secret, utilities::current_timestamp()],
+    ) {
+        println!("Failed to start two-factor enrolment: {}", e);
+        return false;
+    }
+
+    let uri = provisioning_uri(email, &secret);
+    println!("Scan this QR code with your authenticator app:");
+    print_qr_code(&uri);
+    println!("Or add this provisioning URI manually:\n\t{}", uri);
+    println!("Secret key: {}", secret);
+
+    for _ in 0..3 {
+        println!("Enter the 6-digit code shown by your app to confirm: ");
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input).is_err() {
+            println!("Failed to read input. Please try again.");
+            continue;
+        }
+        match verify_totp(connection, user_id, input.trim(), false) {
+            Ok(true) => {
+                if let Err(e) = connection.execute(
+                    "UPDATE two_factor SET enabled = 1 WHERE user_id = ?1",
+                    params![user_id],
+                ) {
+                    println!("Failed to enable two-factor authentication: {}", e);
+                    return false;
+                }
+                match create_recovery_codes(connection, user_id) {
+                    Ok(codes) => print_recovery_codes(&codes),
+                    Err(e) => println!("Failed to create recovery codes: {}", e),
+                }
+                if let Err(e) = audit_management::record_event(
+                    connection, Some(user_id), audit_management::TWO_FACTOR_ENABLED, email, json!({})
+                ) {
+                    eprintln!("Failed to write to the audit log: {}", e);
+                }
+                println!("Two-factor authentication is now enabled.");
+                return true;
+            }
+            Ok(false) => println!("That code is not correct. Please try again."),
+            Err(e) => {
+                println!("Failed to check code: {}", e);
+                return false;
+            }
+        }
+    }
+    println!("Two-factor enrolment was not completed.");
+    false
+}
+
+fn print_recovery_codes(codes: &[String]) {
+    println!("Your recovery codes are shown below. Each can be used once instead of");
+    println!("a code from your app. Store them somewhere safe; they will not be shown again.");
+    for code in codes {
+        println!("\t{}", code);
+    }
+    println!("Press Enter once you have saved them.");
+    let mut input = String::new();
+    let _ = io::stdin().read_line(&mut input);
+}
+
+pub(crate) fn manage_two_factor(database_name: &str, config: &Config, user: &User) -> bool {
+    clear_screen();
+    print_two_factor_header();
+    let connection = match Connection::open(database_name) {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("Failed to connect to the database: {}", e);
+            return false;
+        }
+    };
+
+    let enabled = match get_two_factor_secret(&connection, user.get_user_id(), true) {
+        Ok(secret) => secret.is_some(),
+        Err(e) => {
+            println!("Failed to check two-factor authentication: {}", e);
+            return false;
+        }
+    };
+
+    if !enabled {
+        println!("Two-factor authentication is not enabled. Enable it now? (y/n)");
+        if !get_yes_or_no() { return false; }
+        return enrol(&connection, user.get_user_id(), user.get_email());
+    }
+
+    println!("Two-factor authentication is enabled.");
+    println!("\t1. Generate new recovery codes");
+    println!("\t2. Disable two-factor authentication");
+    println!("\t0. Back");
+    let mut input = String::new();
+    if io::stdin().read_line(&mut input).is_err() {
+        println!("Failed to read input.");
+        return false;
+    }
+    let choice = input.trim().to_string();
+    if choice != "1" && choice != "2" { return false; }
+
+    if choice == "2" && is_two_factor_required(config, user.get_role()) {
+        println!("Two-factor authentication is required for administrators and cannot be disabled.");
+        return false;
+    }
+
+    println!("Enter a current code from your authenticator app to confirm: ");
+    input.clear();
+    if io::stdin().read_line(&mut input).is_err() {
+        println!("Failed to read input.");
+        return false;
+    }
+    if !verify_totp(&connection, user.get_user_id(), input.trim(), true).unwrap_or(false) {
+        println!("Invalid two-factor code.");
+        return false;
+    }
+
+    if choice == "1" {
+        match create_recovery_codes(&connection, user.get_user_id()) {
+            Ok(codes) => {
+                print_recovery_codes(&codes);
+                audit_management::log_event(
+                    database_name, Some(user.get_user_id()), audit_management::RECOVERY_CODES_REGENERATED,
+                    user.get_email(), json!({})
+                );
+                true
+            }
+            Err(e) => {
+                println!("Failed to create recovery codes: {}", e);
+                false
+            }
+        }
+    } else {
+        let result = connection
+            .execute("DELETE FROM two_factor WHERE user_id = ?1", params![user.get_user_id()])
+            .and_then(|_| connection.execute("DELETE FROM recovery_codes WHERE user_id = ?1", params![user.get_user_id()]));
+        match result {
+            Ok(_) => {
+                println!("Two-factor authentication has been disabled.");
+                audit_management::log_event(
+                    database_name, Some(user.get_user_id()), audit_management::TWO_FACTOR_DISABLED,
+                    user.get_email(), json!({})
+                );
+                true
+            }
+            Err(e) => {
+                println!("Failed to disable two-factor authentication: {}", e);
+                false
+            }
+        }
+    }
+}
+
+fn print_two_factor_header() {
+    println!("###############################");
+    println!("## Two-Factor Authentication ##");
+    println!("###############################");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialisation;
+
+    // The SHA-1 secret of RFC 6238 appendix B.
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    fn get_code(secret: &str, step: i64) -> String {
+        let secret = BASE32_NOPAD.decode(secret.as_bytes()).unwrap();
+        format!("{:06}", totp_code(&secret, step).unwrap())
+    }
+
+    #[test]
+    fn codes_match_the_rfc_6238_test_vectors() {
+        // The appendix gives 8 digits; a 6 digit code is the last 6 of them.
+        let vectors = [
+            (59, 94287082),
+            (1111111109, 7081804),
+            (1111111111, 14050471),
+            (1234567890, 89005924),
+            (2000000000, 69279037),
+            (20000000000, 65353130),
+        ];
+        for (time, code) in vectors {
+            assert_eq!(totp_code(RFC_SECRET, time / TOTP_STEP_SECONDS), Some(code % 1_000_000), "at {}", time);
+        }
+    }
+
+    #[test]
+    fn codes_are_accepted_one_step_either_side() {
+        let secret = BASE32_NOPAD.encode(RFC_SECRET);
+        let now = 1_234_567_890;
+        let step = now / TOTP_STEP_SECONDS;
+        for offset in [-1, 0, 1] {
+            assert_eq!(find_matching_step(&secret, &get_code(&secret, step + offset), now), Some(step + offset));
+        }
+        for offset in [-2, 2] {
+            assert_eq!(find_matching_step(&secret, &get_code(&secret, step + offset), now), None);
+        }
+    }
+
+    #[test]
+    fn codes_must_be_exactly_six_digits() {
+        let secret = BASE32_NOPAD.encode(RFC_SECRET);
+        let now = 1_234_567_890;
+        let code = get_code(&secret, now / TOTP_STEP_SECONDS);
+        assert!(find_matching_step(&secret, &format!(" {} {} ", &code[..3], &code[3..]), now).is_some());
+        for wrong in [format!("0{}", code), format!("+{}", code), code[1..].to_string(), format!("{}0", code)] {
+            assert_eq!(find_matching_step(&secret, &wrong, now), None, "{}", wrong);
+        }
+    }
+
+    #[test]
+    fn a_code_cannot_be_used_twice() {
+        let (_, connection) = initialisation::open_test_database("totp_replay");
+        let secret = BASE32_NOPAD.encode(RFC_SECRET);
+        connection.execute(
+            "INSERT INTO two_factor (user_id, secret, enabled, created_at) VALUES (1, ?1, 1, 0)",
+            params![secret],
+        ).unwrap();
+        let step = utilities::current_timestamp() / TOTP_STEP_SECONDS;
+        assert!(verify_totp(&connection, 1, &get_code(&secret, step), true).unwrap());
+        assert!(!verify_totp(&connection, 1, &get_code(&secret, step), true).unwrap());
+        // Nor can a code from before the one used, though it is still within the window.
+        assert!(!verify_totp(&connection, 1, &get_code(&secret, step - 1), true).unwrap());
+    }
+
+    #[test]
+    fn two_factor_is_required_by_role_alone() {
+        let mut config = Config::default();
+        config.auth.require_admin_two_factor = true;
+        assert!(is_two_factor_required(&config, Role::Librarian));
+        assert!(is_two_factor_required(&config, Role::Sysadmin));
+        assert!(!is_two_factor_required(&config, Role::Cataloguer));
+        config.auth.require_admin_two_factor = false;
+        assert!(!is_two_factor_required(&config, Role::Sysadmin));
+    }
+}
@@ -6,8 +6,13 @@ use rusqlite::params;
 use crate::utilities;
 use crate::user_object;
 use std::error::Error;
+use std::sync::OnceLock;
+use crate::configuration::Config;
+use crate::role_object::Role;
+use crate::{audit_management, mailer, token_management, totp_management};
+use serde_json::json;
 
-pub fn login_user(database_name: &str) -> (user_object::User, bool){
+pub fn login_user(database_name: &str, config: &Config) -> (user_object::User, bool){
     utilities::clear_screen();
     println!("==============================");
     println!("===       User Login       ===");
@@ -18,90 +23,319 @@ pub fn login_user(database_name: &str) -> (user_object::User, bool){
     let email: String = get_user_email();
     let password: String = get_user_password();
 
-    let mut user_id: i32;
-    match get_user_id_by_email(database_name, &email) {
-        Ok(id) => user_id = id,
-        Err(_e) => {
-            println!("Invalid credentials. Please try again");
+    let connection = match Connection::open(database_name) {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("Failed to connect to the database: {}", e);
+            return (user, false);
+        }
+    };
+
+    let now = utilities::current_timestamp();
+    let throttle = match get_login_throttle(&connection, config, &email, now) {
+        Ok(throttle) => throttle,
+        Err(e) => {
+            eprintln!("Failed to check previous login attempts: {}", e);
             return (user, false);
         }
+    };
+
+    // Exactly one bcrypt verification happens on every path below, so the
+    // time taken to answer does not reveal whether the email is registered.
+    let credentials = get_login_credentials(&connection, &email).ok();
+    let password_matches = match &credentials {
+        Some((_, salt, hashed)) => {
+            let salted_password = format!("{}{}", password, salt.trim());
+            utilities::verify_hash(&salted_password, hashed).unwrap_or(false)
+        }
+        None => {
+            verify_dummy_password(&password);
+            false
+        }
+    };
+
+    if throttle.is_locked(config, now) {
+        record_login_attempt(&connection, &email, None, now, "locked");
+        println!(
+            "Too many failed login attempts. Please try again in {} minute(s).",
+            throttle.minutes_remaining(config, now)
+        );
+        return (user, false);
     }
 
-    let mut user_salt:String;
-    match get_user_salt_by_id(database_name, &user_id) {
-        Ok(s) => user_salt = s.trim().to_string(),
+    let user_id = match credentials {
+        Some((user_id, _, _)) if password_matches => user_id,
+        _ => {
+            let failures = throttle.consecutive_failures + 1;
+            record_login_attempt(&connection, &email, None, now, "failure");
+            // Not scaled by interface.pause_scale, which only affects the menus.
+            std::thread::sleep(std::time::Duration::from_secs(login_backoff_seconds(failures, config)));
+            if failures >= config.auth.max_failed_logins {
+                println!(
+                    "Too many failed login attempts. Login is locked for {} minute(s).",
+                    config.get_lockout_seconds() / 60
+                );
+            } else {
+                println!("Invalid credentials. Please try again.");
+            }
+            return (user, false);
+        }
+    };
+    let role = match get_user_role_by_id(database_name, &user_id) {
+        Ok(role) => role,
+        Err(_) => {
+            println!("Failed to determine user role.");
+            Role::default()
+        }
+    };
+
+    if !totp_management::challenge_at_login(
+        database_name, config, user_id, &email, role
+    ) {
+        record_login_attempt(&connection, &email, None, now, "failure");
+        return (user, false);
+    }
+    record_login_attempt(&connection, &email, Some(user_id), now, "success");
+
+    let firstname: String = match get_user_firstname_by_id(database_name, &user_id) {
+        Ok(s) => s,
         Err(_e) => {
             println!("Invalid credentials. Please try again");
             return (user, false);
         }
-    }
+    };
 
-    let mut user_password: String;
-    match get_user_password_by_id(database_name, &user_id) {
-        Ok(p) => user_password = p,
+    let lastname: String = match get_user_lastname_by_id(database_name, &user_id) {
+        Ok(s) => s,
         Err(_e) => {
             println!("Invalid credentials. Please try again");
             return (user, false);
         }
-    }
+    };
+    user.set_user_id(user_id);
+    user.set_email(&email);
+    user.set_firstname(&firstname);
+    user.set_lastname(&lastname);
+    user.set_role(role);
+    user.set_email_verified(get_user_email_verified_by_id(database_name, &user_id).unwrap_or(false));
+    (user, true)
+}
 
+#[derive(Debug, PartialEq)]
+pub enum CredentialCheck {
+    Valid(i32),
+    Invalid,
+    // The password was right, but the account needs a second factor that HTTP cannot ask for.
+    TwoFactorRequired,
+}
 
-    let salted_password = format!("{}{}", password, user_salt);
-    if bcrypt::verify(&salted_password, &user_password).unwrap_or(false) {
-        let mut firstname: String;
-        match get_user_firstname_by_id(database_name, &user_id) {
-            Ok(s) => firstname = s,
-            Err(_e) => {
-                println!("Invalid credentials. Please try again");
-                return (user, false);
-            }
+/*
+ *  Checks an email and password sent over HTTP, e.g. by an e-reader app
+ *  reading the OPDS feeds. Failures count towards, and a lockout applies
+ *  to, the terminal login as well. There is no way to ask for a 2FA code,
+ *  so accounts that would be asked for one at login are refused.
+ */
+pub fn verify_credentials(database_name: &str, config: &Config, email: &str, password: &str) -> Result<CredentialCheck, rusqlite::Error> {
+    let connection = Connection::open(database_name)?;
+    let email = email.trim().to_lowercase();
+    let now = utilities::current_timestamp();
+    let throttle = get_login_throttle(&connection, config, &email, now)?;
+
+    let credentials = get_login_credentials(&connection, &email).ok();
+    let password_matches = match &credentials {
+        Some((_, salt, hashed)) => {
+            let salted_password = format!("{}{}", password, salt.trim());
+            utilities::verify_hash(&salted_password, hashed).unwrap_or(false)
+        }
+        None => {
+            verify_dummy_password(password);
+            false
         }
+    };
 
-        let mut lastname: String;
-        match get_user_lastname_by_id(database_name, &user_id) {
-            Ok(s) => lastname = s,
-            Err(_e) => {
-                println!("Invalid credentials. Please try again");
-                return (user, false);
-            }
+    if throttle.is_locked(config, now) {
+        record_login_attempt(&connection, &email, None, now, "locked");
+        return Ok(CredentialCheck::Invalid);
+    }
+    let user_id = match credentials {
+        Some((user_id, _, _)) if password_matches => user_id,
+        _ => {
+            record_login_attempt(&connection, &email, None, now, "failure");
+            return Ok(CredentialCheck::Invalid);
         }
-        let is_admin = match get_user_is_admin_by_id(database_name, &user_id){
-            Ok(true) => true,
-            Ok(false) => false,
-            Err(_) => {
-                println!("Failed to determine admin status.");
-                false
+    };
+
+    if requires_two_factor(database_name, config, user_id)? {
+        return Ok(CredentialCheck::TwoFactorRequired);
+    }
+    record_login_attempt(&connection, &email, Some(user_id), now, "success");
+    Ok(CredentialCheck::Valid(user_id))
+}
+
+pub fn requires_two_factor(database_name: &str, config: &Config, user_id: i32) -> Result<bool, rusqlite::Error> {
+    let role = get_user_role_by_id(database_name, &user_id)?;
+    Ok(totp_management::is_two_factor_enabled(database_name, user_id)?
+        || totp_management::is_two_factor_required(config, role))
+}
+
+/*
+ *  The user an email belongs to and their stored password hash. Both
+ *  change when the account's email or password is changed or the account
+ *  is removed, so a login checked earlier can be trusted while they match.
+ */
+pub fn get_login_fingerprint(database_name: &str, email: &str) -> Result<Option<(i32, String)>, rusqlite::Error> {
+    let connection = Connection::open(database_name)?;
+    match get_login_credentials(&connection, &email.trim().to_lowercase()) {
+        Ok((user_id, _, hashed)) => Ok(Some((user_id, hashed))),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/*
+ *  Failed attempts are counted from the last successful login or admin
+ *  unlock for that email, whichever happened most recently, and start
+ *  again from nothing once a lockout has run its course.
+ */
+#[derive(Debug, PartialEq)]
+struct LoginThrottle {
+    consecutive_failures: u32,
+    last_failure_at: Option<i64>,
+}
+
+impl LoginThrottle {
+    // The failures are the times of each, in the order they happened.
+    fn from_failures(failures: &[i64], config: &Config, now: i64) -> LoginThrottle {
+        let mut throttle = LoginThrottle { consecutive_failures: 0, last_failure_at: None };
+        for &failed_at in failures {
+            if throttle.has_expired(config, failed_at) {
+                throttle.consecutive_failures = 0;
             }
-        };
+            throttle.consecutive_failures += 1;
+            throttle.last_failure_at = Some(failed_at);
+        }
+        if throttle.has_expired(config, now) {
+            return LoginThrottle { consecutive_failures: 0, last_failure_at: None };
+        }
+        throttle
+    }
 
-        user.set_user_id(user_id);
-        user.set_email(&email);
-        user.set_firstname(&firstname);
-        user.set_lastname(&lastname);
-        user.set_is_admin(is_admin);
-        (user, true)
-    } else {
-        println!("Invalid credentials. Please try again.");
-        (user, false)
+    fn is_locked(&self, config: &Config, now: i64) -> bool {
+        self.consecutive_failures >= config.auth.max_failed_logins
+            && self.locked_until(config) > now
+    }
+
+    // A lockout that has ended, after which failures are counted afresh.
+    fn has_expired(&self, config: &Config, now: i64) -> bool {
+        self.consecutive_failures >= config.auth.max_failed_logins
+            && self.locked_until(config) <= now
+    }
+
+    fn locked_until(&self, config: &Config) -> i64 {
+        self.last_failure_at.unwrap_or(0) + config.get_lockout_seconds()
+    }
+
+    fn minutes_remaining(&self, config: &Config, now: i64) -> i64 {
+        ((self.locked_until(config) - now) + 59) / 60
     }
 }
 
-pub fn get_user_id_by_email(database_name: &str, email: &str) -> Result<i32, rusqlite::Error> {
+fn get_login_throttle(connection: &Connection, config: &Config, email: &str, now: i64) -> Result<LoginThrottle, rusqlite::Error> {
+    let query = "SELECT attempted_at FROM login_attempts
+                 WHERE email = ?1 AND outcome = 'failure'
+                 AND attempt_id > COALESCE(
+                    (SELECT MAX(attempt_id) FROM login_attempts
+                     WHERE email = ?1 AND outcome IN ('success', 'unlock')), 0)
+                 ORDER BY attempt_id";
+    let mut stmt = connection.prepare(query)?;
+    let failures = stmt
+        .query_map(params![email], |row| row.get(0))?
+        .collect::<Result<Vec<i64>, rusqlite::Error>>()?;
+    Ok(LoginThrottle::from_failures(&failures, config, now))
+}
+
+fn record_login_attempt(connection: &Connection, email: &str, user_id: Option<i32>, attempted_at: i64, outcome: &str) {
+    if let Err(e) = connection.execute(
+        "INSERT INTO login_attempts (email, attempted_at, outcome) VALUES (?1, ?2, ?3)",
+        params![email, attempted_at, outcome],
+    ) {
+        eprintln!("Failed to record login attempt: {}", e);
+    }
+
+    let action = match outcome {
+        "success" => audit_management::LOGIN_SUCCEEDED,
+        "locked" => audit_management::LOGIN_LOCKED,
+        _ => audit_management::LOGIN_FAILED,
+    };
+    if let Err(e) = audit_management::record_event(connection, user_id, action, email, json!({})) {
+        eprintln!("Failed to write to the audit log: {}", e);
+    }
+}
+
+/// Clears any lockout on the given email by starting a fresh failure count.
+pub fn unlock_account(database_name: &str, email: &str) -> Result<(), rusqlite::Error> {
     let connection = Connection::open(database_name)?;
-    let query = "SELECT user_id FROM users WHERE email = ?1";
-    connection.query_row(query, params![email], |row| row.get(0))
+    connection.execute(
+        "INSERT INTO login_attempts (email, attempted_at, outcome) VALUES (?1, ?2, 'unlock')",
+        params![email, utilities::current_timestamp()],
+    )?;
+    Ok(())
 }
 
-pub fn get_user_salt_by_id(database_name: &str, user_id: &i32) -> Result<String, rusqlite::Error> {
+/// Returns true if the email currently cannot log in because of failed attempts.
+pub fn is_account_locked(database_name: &str, email: &str, config: &Config) -> Result<bool, rusqlite::Error> {
     let connection = Connection::open(database_name)?;
-    let query = "SELECT salt FROM salts WHERE user_id = ?1";
-    connection.query_row(query, params![user_id], |row| row.get(0))
+    let now = utilities::current_timestamp();
+    let throttle = get_login_throttle(&connection, config, email, now)?;
+    Ok(throttle.is_locked(config, now))
+}
+
+fn login_backoff_seconds(failures: u32, config: &Config) -> u64 {
+    2u64.saturating_pow(failures.saturating_sub(1))
+        .min(config.auth.max_login_backoff_seconds)
 }
 
-pub fn get_user_password_by_id(database_name: &str, user_id: &i32) -> Result<String, rusqlite::Error> {
+// Checked against when the email is not registered, to take as long as a real verification.
+static DUMMY_PASSWORD_HASH: OnceLock<String> = OnceLock::new();
+
+/*
+ *  Made at start-up, before anyone can log in, so that the first login
+ *  with an unregistered email takes no longer than any other.
+ */
+pub fn init_dummy_password_hash(config: &Config) -> Result<(), bcrypt::BcryptError> {
+    if DUMMY_PASSWORD_HASH.get().is_none() {
+        let hash = utilities::hash_password(
+            "rLMS-dummy-password",
+            &utilities::generate_salt(config.auth.salt_length),
+            config.auth.bcrypt_cost,
+        )?;
+        let _ = DUMMY_PASSWORD_HASH.set(hash);
+    }
+    Ok(())
+}
+
+fn verify_dummy_password(password: &str) {
+    match DUMMY_PASSWORD_HASH.get() {
+        Some(hash) => {
+            let _ = utilities::verify_hash(password, hash);
+        }
+        None => log::error!("The dummy password hash was not made at start-up, so unregistered emails are answered sooner"),
+    }
+}
+
+fn get_login_credentials(connection: &Connection, email: &str) -> Result<(i32, String, String), rusqlite::Error> {
+    let query = "SELECT users.user_id, salts.salt, passwords.password
+                 FROM users
+                 JOIN salts ON salts.user_id = users.user_id
+                 JOIN passwords ON passwords.user_id = users.user_id
+                 WHERE users.email = ?1";
+    connection.query_row(query, params![email], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+}
+
+pub fn get_user_id_by_email(database_name: &str, email: &str) -> Result<i32, rusqlite::Error> {
     let connection = Connection::open(database_name)?;
-    let query = "SELECT password FROM passwords WHERE user_id = ?1";
-    connection.query_row(query, params![user_id], |row| row.get(0))
+    let query = "SELECT user_id FROM users WHERE email = ?1";
+    connection.query_row(query, params![email], |row| row.get(0))
 }
 
 pub fn get_user_firstname_by_id(database_name: &str, user_id: &i32) -> Result<String, rusqlite::Error> {
@@ -116,13 +350,58 @@ pub fn get_user_lastname_by_id(database_name: &str, user_id: &i32) -> Result<Str
     connection.query_row(query, params![user_id], |row| row.get(0))
 }
 
-pub fn get_user_is_admin_by_id(database_name: &str, user_id: &i32) -> Result<bool, rusqlite::Error> {
+/*
+ *  Users without a row in user_roles (e.g. created before roles existed)
+ *  are treated as patrons.
+ */
+pub fn get_user_role_by_id(database_name: &str, user_id: &i32) -> Result<Role, rusqlite::Error> {
     let connection = Connection::open(database_name)?;
-    let query = "SELECT COUNT(*) FROM admins WHERE user_id = ?1";
-    let count: i32 = connection.query_row(query, params![user_id], |row| row.get(0))?;
-    Ok(count > 0)
+    let query = "SELECT role FROM user_roles WHERE user_id = ?1";
+    match connection.query_row(query, params![user_id], |row| row.get::<_, String>(0)) {
+        Ok(name) => Ok(Role::from_name(&name).unwrap_or_default()),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(Role::default()),
+        Err(e) => Err(e),
+    }
 }
-pub fn register_user(database_name: &str){
+
+pub fn get_user_email_verified_by_id(database_name: &str, user_id: &i32) -> Result<bool, rusqlite::Error> {
+    let connection = Connection::open(database_name)?;
+    let query = "SELECT email_verified FROM users WHERE user_id = ?1";
+    connection.query_row(query, params![user_id], |row| row.get(0))
+}
+
+// The user as they are once logged in, for checks made away from the terminal.
+pub fn get_user_by_id(database_name: &str, user_id: &i32) -> Result<user_object::User, rusqlite::Error> {
+    let connection = Connection::open(database_name)?;
+    let query = "SELECT email, firstname, lastname, email_verified FROM users WHERE user_id = ?1";
+    let mut user = connection.query_row(query, params![user_id], |row| {
+        let mut user = user_object::User::default();
+        user.set_email(&row.get::<_, String>(0)?);
+        user.set_firstname(&row.get::<_, String>(1)?);
+        user.set_lastname(&row.get::<_, String>(2)?);
+        user.set_email_verified(row.get(3)?);
+        Ok(user)
+    })?;
+    user.set_user_id(*user_id);
+    user.set_role(get_user_role_by_id(database_name, user_id)?);
+    Ok(user)
+}
+
+pub fn set_user_role(database_name: &str, user_id: i32, role: Role) -> Result<(), rusqlite::Error> {
+    let connection = Connection::open(database_name)?;
+    connection.execute(
+        "INSERT INTO user_roles (user_id, role) VALUES (?1, ?2)
+         ON CONFLICT(user_id) DO UPDATE SET role = excluded.role",
+        params![user_id, role.get_name()],
+    )?;
+    Ok(())
+}
+
+/*
+ *  actor is the administrator adding the account, or None when someone
+ *  registers themselves from the login menu.
+ */
+pub fn register_user(database_name: &str, config: &Config, actor: Option<&user_object::User>){
     utilities::clear_screen();
     println!("=============================");
     println!("=== New User Registration ===");
@@ -139,8 +418,8 @@ pub fn register_user(database_name: &str){
         firstname = utilities::get_name_from_user("firstname");
         lastname = utilities::get_name_from_user("lastname");
         password = utilities::get_password_from_user();
-        salt = utilities::generate_salt(25).trim().to_string();
-        hashed = utilities::hash_password(&password, &salt).unwrap();
+        salt = utilities::generate_salt(config.auth.salt_length).trim().to_string();
+        hashed = utilities::hash_password(&password, &salt, config.auth.bcrypt_cost).unwrap();
 
         if !confirm_user_information(&email, &firstname, &lastname) { continue; }
         else {break; }
@@ -149,6 +428,19 @@ pub fn register_user(database_name: &str){
     match create_new_user(database_name, &email, &firstname, &lastname, &salt, &hashed) {
         Ok(_) => {
             println!("User {} account created successfully!", email);
+            match get_user_id_by_email(database_name, &email) {
+                Ok(user_id) => {
+                    audit_management::log_event(
+                        database_name,
+                        Some(actor.map_or(user_id, |actor| actor.get_user_id())),
+                        audit_management::USER_REGISTERED,
+                        &email,
+                        json!({ "firstname": firstname, "lastname": lastname, "role": Role::Patron.get_name() }),
+                    );
+                    send_verification_email(database_name, config, user_id, &email)
+                },
+                Err(e) => println!("Failed to send verification email: {}", e),
+            }
             utilities::pause(1);
         },
         Err(e) => {
@@ -178,8 +470,198 @@ fn create_new_user(database_name: &str, email: &str, firstname: &str, lastname:
         params![email, hashed],
     )?;
 
+    connection.execute(
+        "INSERT INTO user_roles (user_id, role) VALUES (
+                (SELECT user_id FROM users WHERE email = ?1), ?2)",
+        params![email, Role::Patron.get_name()],
+    )?;
+
+    Ok(())
+}
+pub fn send_verification_email(database_name: &str, config: &Config, user_id: i32, email: &str) {
+    let token = match token_management::create_token(
+        database_name, user_id, token_management::EMAIL_VERIFICATION, config.get_email_verification_seconds()
+    ) {
+        Ok(token) => token,
+        Err(e) => {
+            println!("Failed to create verification code: {}", e);
+            return;
+        }
+    };
+    let body = format!(
+        "Welcome to rLMS!\n\n\
+        To verify your email address, log in and choose \"Verify Email\" from the menu,\n\
+        then enter the following code:\n\n\t{}\n\n\
+        This code expires in {} hour(s). Until your email is verified some features are unavailable.\n",
+        token,
+        config.get_email_verification_seconds() / 3600
+    );
+    match mailer::send_email(config, email, "Verify your rLMS email address", &body) {
+        Ok(_) => println!("A verification code has been sent to {}.", email),
+        Err(e) => println!("Failed to send verification email: {}", e),
+    }
+}
+
+pub fn verify_email(database_name: &str, config: &Config, user: &mut user_object::User) -> bool {
+    utilities::clear_screen();
+    println!("==============================");
+    println!("===      Verify Email      ===");
+    println!("==============================");
+    if user.get_email_verified() {
+        println!("Your email address is already verified.");
+        return true;
+    }
+
+    println!("Do you have a verification code? (y/n)");
+    if !utilities::get_yes_or_no() {
+        send_verification_email(database_name, config, user.get_user_id(), user.get_email());
+        println!("Do you want to enter the code now? (y/n)");
+        if !utilities::get_yes_or_no() { return false; }
+    }
+
+    let token = get_code_from_user("verification");
+    match token_management::consume_token(
+        database_name, token_management::EMAIL_VERIFICATION, &token, Some(user.get_user_id())
+    ) {
+        Ok(Some(user_id)) => {
+            match mark_email_verified(database_name, user_id) {
+                Ok(_) => {
+                    user.set_email_verified(true);
+                    audit_management::log_event(
+                        database_name, Some(user_id), audit_management::EMAIL_VERIFIED, user.get_email(), json!({})
+                    );
+                    true
+                }
+                Err(e) => {
+                    println!("Failed to verify email: {}", e);
+                    false
+                }
+            }
+        }
+        Ok(_) => {
+            println!("That code is invalid or has expired.");
+            false
+        }
+        Err(e) => {
+            println!("Failed to check verification code: {}", e);
+            false
+        }
+    }
+}
+
+fn mark_email_verified(database_name: &str, user_id: i32) -> Result<(), rusqlite::Error> {
+    let connection = Connection::open(database_name)?;
+    connection.execute("UPDATE users SET email_verified = 1 WHERE user_id = ?1", params![user_id])?;
+    Ok(())
+}
+
+pub fn reset_forgotten_password(database_name: &str, config: &Config) -> bool {
+    utilities::clear_screen();
+    println!("==============================");
+    println!("===     Reset Password     ===");
+    println!("==============================");
+    println!("Do you already have a password reset code? (y/n)");
+    if !utilities::get_yes_or_no() {
+        let email = get_user_email();
+        // The same message is printed whether or not the email is registered.
+        if let Ok(user_id) = get_user_id_by_email(database_name, &email) {
+            send_password_reset_email(database_name, config, user_id, &email);
+        }
+        println!("If {} is registered, a password reset code has been sent to it.", email);
+        println!("Do you want to enter the code now? (y/n)");
+        if !utilities::get_yes_or_no() { return false; }
+    }
+
+    let token = get_code_from_user("password reset");
+    let user_id = match token_management::consume_token(database_name, token_management::PASSWORD_RESET, &token, None) {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => {
+            println!("That code is invalid or has expired.");
+            return false;
+        }
+        Err(e) => {
+            println!("Failed to check password reset code: {}", e);
+            return false;
+        }
+    };
+
+    let password = utilities::get_password_from_user();
+    if let Err(e) = update_user_password(database_name, config, user_id, &password) {
+        println!("Failed to reset password: {}", e);
+        return false;
+    }
+    let email = get_user_email_by_id(database_name, &user_id).unwrap_or_default();
+    audit_management::log_event(database_name, Some(user_id), audit_management::PASSWORD_RESET, &email, json!({}));
+
+    // Receiving the code proves the user owns the address, and a reset
+    // should also lift any lockout caused by the forgotten password.
+    if let Err(e) = mark_email_verified(database_name, user_id) {
+        println!("Failed to verify email: {}", e);
+    }
+    if let Err(e) = unlock_account(database_name, &email) {
+        println!("Failed to unlock account: {}", e);
+    }
+    true
+}
+
+fn send_password_reset_email(database_name: &str, config: &Config, user_id: i32, email: &str) {
+    let token = match token_management::create_token(
+        database_name, user_id, token_management::PASSWORD_RESET, config.get_password_reset_seconds()
+    ) {
+        Ok(token) => token,
+        Err(e) => {
+            eprintln!("Failed to create password reset code: {}", e);
+            return;
+        }
+    };
+    let body = format!(
+        "A password reset was requested for your rLMS account.\n\n\
+        To choose a new password, choose \"Forgot Password\" from the login menu\n\
+        and enter the following code:\n\n\t{}\n\n\
+        This code expires in {} minute(s) and can only be used once.\n\
+        If you did not request a reset you can ignore this email.\n",
+        token,
+        config.get_password_reset_seconds() / 60
+    );
+    if let Err(e) = mailer::send_email(config, email, "Reset your rLMS password", &body) {
+        eprintln!("Failed to send password reset email: {}", e);
+    }
+}
+
+pub fn update_user_password(database_name: &str, config: &Config, user_id: i32, password: &str) -> Result<(), Box<dyn Error>> {
+    let mut connection = Connection::open(database_name)?;
+    let salt = utilities::generate_salt(config.auth.salt_length).trim().to_string();
+    let hashed = utilities::hash_password(password, &salt, config.auth.bcrypt_cost)?;
+    let transaction = connection.transaction()?;
+    transaction.execute("UPDATE salts SET salt = ?2 WHERE user_id = ?1", params![user_id, salt])?;
+    transaction.execute("UPDATE passwords SET password = ?2 WHERE user_id = ?1", params![user_id, hashed])?;
+    transaction.commit()?;
     Ok(())
 }
+
+pub fn get_user_email_by_id(database_name: &str, user_id: &i32) -> Result<String, rusqlite::Error> {
+    let connection = Connection::open(database_name)?;
+    let query = "SELECT email FROM users WHERE user_id = ?1";
+    connection.query_row(query, params![user_id], |row| row.get(0))
+}
+
+fn get_code_from_user(code_type: &str) -> String {
+    loop {
+        println!("Enter your {} code: ", code_type);
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input).is_err() {
+            println!("Failed to read input. Please try again.");
+            continue;
+        }
+        let code = input.trim();
+        if code.is_empty() {
+            println!("No code entered. Please try again.");
+            continue;
+        }
+        return code.to_string();
+    }
+}
+
 fn confirm_user_information(email: &str, firstname: &str, lastname: &str) -> bool {
     loop {
         println!("Please confirm the following information: ");
@@ -239,3 +721,75 @@ fn get_user_email() -> String {
         return email;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialisation;
+
+    fn get_config() -> Config {
+        let mut config = Config::default();
+        config.auth.bcrypt_cost = 4;
+        config.auth.max_failed_logins = 3;
+        config.auth.lockout_minutes = 15;
+        config.auth.max_login_backoff_seconds = 30;
+        config
+    }
+
+    #[test]
+    fn failures_lock_the_email_until_the_lockout_ends() {
+        let config = get_config();
+        let lockout = config.get_lockout_seconds();
+        assert!(!LoginThrottle::from_failures(&[0, 10], &config, 20).is_locked(&config, 20));
+
+        let throttle = LoginThrottle::from_failures(&[0, 10, 20], &config, 30);
+        assert!(throttle.is_locked(&config, 30));
+        assert_eq!(throttle.minutes_remaining(&config, 30), 15);
+        assert!(!throttle.is_locked(&config, 20 + lockout));
+    }
+
+    #[test]
+    fn the_count_starts_again_once_a_lockout_has_ended() {
+        let config = get_config();
+        let lockout = config.get_lockout_seconds();
+        assert_eq!(
+            LoginThrottle::from_failures(&[0, 10, 20], &config, 20 + lockout),
+            LoginThrottle { consecutive_failures: 0, last_failure_at: None }
+        );
+        // One failure after the lockout is one failure, not a fourth that locks again at once.
+        let throttle = LoginThrottle::from_failures(&[0, 10, 20, 20 + lockout], &config, 21 + lockout);
+        assert_eq!(throttle.consecutive_failures, 1);
+        assert!(!throttle.is_locked(&config, 21 + lockout));
+    }
+
+    #[test]
+    fn the_wait_after_each_failure_doubles_up_to_the_limit() {
+        let config = get_config();
+        let waits: Vec<u64> = (1..=7).map(|failures| login_backoff_seconds(failures, &config)).collect();
+        assert_eq!(waits, vec![1, 2, 4, 8, 16, 30, 30]);
+        assert_eq!(login_backoff_seconds(u32::MAX, &config), 30);
+    }
+
+    #[test]
+    fn unregistered_emails_are_throttled_like_accounts() {
+        let config = get_config();
+        init_dummy_password_hash(&config).unwrap();
+        let (database_name, connection) = initialisation::open_test_database("login_throttle");
+        let email = "nobody@example.org";
+        for _ in 0..config.auth.max_failed_logins {
+            assert_eq!(verify_credentials(&database_name, &config, email, "guess").unwrap(), CredentialCheck::Invalid);
+        }
+        assert!(is_account_locked(&database_name, email, &config).unwrap());
+        assert_eq!(verify_credentials(&database_name, &config, email, "guess").unwrap(), CredentialCheck::Invalid);
+        let outcome: String = connection
+            .query_row("SELECT outcome FROM login_attempts ORDER BY attempt_id DESC LIMIT 1", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(outcome, "locked");
+
+        // Once the lockout has passed, a single further failure does not lock the email again.
+        connection.execute("UPDATE login_attempts SET attempted_at = attempted_at - ?1", params![config.get_lockout_seconds()]).unwrap();
+        assert!(!is_account_locked(&database_name, email, &config).unwrap());
+        verify_credentials(&database_name, &config, email, "guess").unwrap();
+        assert!(!is_account_locked(&database_name, email, &config).unwrap());
+    }
+}
@@ -1,25 +1,24 @@
+use crate::role_object::{Permission, Role};
+
 #[derive(Default)]
 pub(crate) struct User {
     user_id: i32,
     email: String,
     firstname: String,
     lastname: String,
-    is_admin: bool,
+    role: Role,
+    email_verified: bool,
 }
 
 impl User {
-    pub fn new(user_id: i32, email: String, firstname: String, lastname: String, is_admin_int: i32) -> Self {
-        let is_admin = match is_admin_int {
-            1 => true,
-            2 => false,
-            _ => panic!("Invalid value for is admin: {}. Exiting program.", is_admin_int),
-        };
+    pub fn new(user_id: i32, email: String, firstname: String, lastname: String, role: Role) -> Self {
         User {
             user_id,
             email,
             firstname,
             lastname,
-            is_admin
+            role,
+            email_verified: false,
         }
     }
 
@@ -27,22 +26,32 @@ impl User {
     pub fn set_email(&mut self, email: &str) { self.email = email.to_string();}
     pub fn set_firstname(&mut self, firstname: &str) { self.firstname = firstname.to_string();}
     pub fn set_lastname(&mut self, lastname: &str) { self.lastname = lastname.to_string();}
-    pub fn set_is_admin(&mut self, is_admin: bool) {
-        self.is_admin = is_admin;
-    }
+    pub fn set_role(&mut self, role: Role) { self.role = role; }
+    pub fn set_email_verified(&mut self, email_verified: bool) { self.email_verified = email_verified; }
 
     pub fn get_user_id(&self) -> i32 { self.user_id }
     pub fn get_email(&self) -> &String { &self.email }
     pub fn get_firstname(&self) -> &String { &self.firstname }
     pub fn get_lastname(&self) -> &String { &self.lastname }
-    pub fn get_is_admin(&self) -> bool { self.is_admin }
+    pub fn get_role(&self) -> Role { self.role }
+    pub fn get_email_verified(&self) -> bool { self.email_verified }
+
+    // Until the email address is verified a user can only look after their
+    // own collection, whatever their role.
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        if !self.email_verified && permission != Permission::ManageOwnCollection {
+            return false;
+        }
+        self.role.has_permission(permission)
+    }
 
     pub fn pretty_print(&self) {
         println!("User ID: {}", self.user_id);
         println!("Email: {}", self.email);
         println!("First Name: {}", self.firstname);
         println!("Last Name: {}", self.lastname);
-        println!("IsAdmin: {}", self.is_admin);
+        println!("Role: {}", self.role.get_display_name());
+        println!("Email Verified: {}", self.email_verified);
     }
 
-}
\ No newline at end of file
+}
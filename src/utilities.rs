@@ -1,21 +1,21 @@
 use crate::{user_processing, User};
-use crate::book_processing;
+use crate::{admin_processing, audit_management, backup_management, book_processing, catalogue_processing, duplicate_processing, holding_processing, label_processing, label_sheet_processing, location_processing, notification_processing, provenance_management, reading_processing, series_processing, statistics_processing, stocktake_processing, totp_management, user_management};
+use crate::configuration::{Config, LoggingConfig};
+use crate::role_object::Permission;
 use crossterm::terminal::ClearType;
 use crossterm::terminal::Clear;
 use std::{io, thread};
-use std::time::Duration;
-use bcrypt::{hash, verify, BcryptError, DEFAULT_COST};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use bcrypt::{hash, verify, BcryptError};
 use crossterm::execute;
 use rand::Rng;
 use rpassword::read_password;
 use validator::ValidateEmail;
 use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::path::PathBuf;
-use crate::book_object::Book;
 use rusqlite::Connection;
-use rusqlite::params;
-use std::error::Error;
-use anyhow::{Context};
+use chrono::{DateTime, NaiveDate};
 
 pub fn get_email_from_user(db_name: &str) -> anyhow::Result<String, String> {
     loop {
@@ -82,6 +82,40 @@ pub fn generate_salt(length: usize) -> String {
         .map(|_| allowed_chars[rng.gen_range(0..allowed_chars.len())])
         .collect()
 }
+// Unlike generate_salt this only uses letters and digits, so the result
+// is safe to put in emails, file names and URLs.
+pub fn generate_token(length: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+// Encodes everything except unreserved characters (RFC 3986 section 2.3).
+pub fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/*
+ *  Escapes text for use in XML and HTML, including inside quoted attributes.
+ *  Control characters other than tab and line breaks, and U+FFFE and U+FFFF,
+ *  cannot appear in XML 1.0 even escaped, so they are left out.
+ */
+pub fn escape_xml(text: &str) -> String {
+    text.replace(|c: char| (c < ' ' && !matches!(c, '\t' | '\n' | '\r')) || c == '\u{FFFE}' || c == '\u{FFFF}', "")
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
 pub fn get_password_from_user() -> String {
     loop {
         println!("Enter a strong password: ");
@@ -142,12 +176,14 @@ pub fn print_login_menu(){
     println!("Please choose from the following options:");
     println!("\t1. Login");
     println!("\t2. Register");
-    println!("\t3. Exit");
+    println!("\t3. Forgot Password");
+    println!("\t4. Exit");
 }
 
 pub fn get_yes_or_no() -> bool {
     let mut choice = String::new();
     loop {
+        choice.clear();
         if io::stdin().read_line(&mut choice).is_err() {
             println!("Failed to read input. Please try again.");
             continue;
@@ -173,15 +209,30 @@ pub fn get_yes_or_no() -> bool {
     }
 }
 
-pub fn get_menu_choice(menu_name: &str) -> usize {
+// Returns None when the user just presses Enter.
+pub fn get_optional_input(prompt: &str) -> Option<String> {
+    loop {
+        println!("{}", prompt);
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input).is_err() {
+            println!("Failed to read input. Please try again.");
+            continue;
+        }
+        let trimmed = input.trim();
+        return if trimmed.is_empty() { None } else { Some(trimmed.to_string()) };
+    }
+}
+
+// The items are those of the user menu, as given by get_menu_items; other menus have none.
+pub fn get_menu_choice(menu_name: &str, items: &[MenuItem]) -> usize {
     let mut loop_count: u32 = 0;
     loop {
         loop_count += 1;
         match menu_name {
             "login" => print_login_menu(),
             "user"  => {
-                if loop_count > 1 { print_user_menu(false); }
-                else { print_user_menu(true) }
+                if loop_count > 1 { print_user_menu(items, false); }
+                else { print_user_menu(items, true) }
             },
             &_ => println!("Invalid menu provided."),
         }
@@ -196,7 +247,7 @@ pub fn get_menu_choice(menu_name: &str) -> usize {
         // Attempt to parse the input
         match input.trim().parse::<usize>() {
             Ok(output) => {
-                if is_valid_menu_choice(output, menu_name) {
+                if is_valid_menu_choice(output, menu_name, items) {
                     return output
                 } else {
                     println!("Invalid menu option. Please try again.");
@@ -216,13 +267,13 @@ pub fn get_menu_choice(menu_name: &str) -> usize {
     }
 }
 
-fn is_valid_menu_choice(choice: usize, menu_name: &str) -> bool {
+fn is_valid_menu_choice(choice: usize, menu_name: &str, items: &[MenuItem]) -> bool {
     match menu_name {
         "login" => {
-            if choice >= 1 && choice <= 3 { return true; }
+            if (1..=4).contains(&choice) { return true; }
         },
         "user" => {
-            if choice >= 0 && choice <= 4 { return true;}
+            if choice <= items.len() { return true;}
         },
         &_ => {
             println!("Invalid menu option.");
@@ -241,25 +292,71 @@ pub fn is_safe_password(password: &str) -> bool {
     let has_min_length = password.len() >= 8;
     let has_uppercase = password.chars().any(|c| c.is_uppercase());
     let has_lowercase = password.chars().any(|c| c.is_lowercase());
-    let has_digit = password.chars().any(|c| c.is_digit(10));
+    let has_digit = password.chars().any(|c| c.is_ascii_digit());
     let has_special = password.chars().any(|c| !c.is_alphanumeric());
     has_min_length && has_uppercase && has_lowercase && has_digit && has_special
 }
 
-pub fn hash_password(password: &str, salt: &str) -> anyhow::Result<String, BcryptError> {
+pub fn hash_password(password: &str, salt: &str, cost: u32) -> anyhow::Result<String, BcryptError> {
     let salted_password = format!("{}{}", password, salt);
-    hash(&salted_password, DEFAULT_COST)
+    hash(&salted_password, cost)
 }
 
 pub fn verify_hash(password: &str, hashed: &str) -> anyhow::Result<bool, BcryptError> {
     verify(password, hashed)
 }
 
+pub fn current_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+pub fn format_timestamp(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .map(|date_time| date_time.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+// Parses a YYYY-MM-DD date as UTC, either at the start or the very end of the day.
+pub fn parse_date_to_timestamp(date: &str, end_of_day: bool) -> Option<i64> {
+    let date = NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d").ok()?;
+    let time = if end_of_day { date.and_hms_opt(23, 59, 59)? } else { date.and_hms_opt(0, 0, 0)? };
+    Some(time.and_utc().timestamp())
+}
+
+// interface.pause_scale in thousandths, set at start-up.
+static PAUSE_SCALE_MILLIS: AtomicU64 = AtomicU64::new(1000);
+
+pub fn set_pause_scale(scale: f64) {
+    PAUSE_SCALE_MILLIS.store((scale * 1000.0).round() as u64, Ordering::Relaxed);
+}
+
 pub fn pause(seconds: u64) {
-    thread::sleep(Duration::from_secs(seconds));
+    thread::sleep(Duration::from_millis(seconds * PAUSE_SCALE_MILLIS.load(Ordering::Relaxed)));
+}
+
+/*
+ *  Log messages go to stderr unless logging.file is set, in which case
+ *  they are appended to that file. RUST_LOG still takes precedence over
+ *  logging.level so that one run can be made more verbose.
+ */
+pub fn init_logging(logging: &LoggingConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let mut builder = env_logger::Builder::new();
+    builder.parse_filters(&logging.level);
+    if let Ok(filters) = env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    if let Some(file) = logging.file.as_deref() {
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(file)?;
+        builder.target(env_logger::Target::Pipe(Box::new(file)));
+    }
+    builder.try_init()?;
+    Ok(())
 }
 
-pub fn clear_screen() { execute!(io::stdout(), Clear(ClearType::All)); }
+pub fn clear_screen() { let _ = execute!(io::stdout(), Clear(ClearType::All)); }
 
 pub fn default_config_path() -> PathBuf {
     PathBuf::from("config.json")
@@ -268,101 +365,404 @@ pub fn get_config_path() -> PathBuf {
     env::var("CONFIG_PATH").map(PathBuf::from).unwrap_or_else(|_| default_config_path())
 }
 
-pub fn print_admin_menu_header(){
-    println!("======================");
-    println!("== rLMS Admin Panel ==");
-    println!("======================");
-}
-
 pub fn print_user_menu_header() {
-    let welcome_string: &str = &("== Welcome back ==");
+    let welcome_string: &str = "== Welcome back ==";
     let header_footer_length = welcome_string.len();
     let header_footer = "=".repeat(header_footer_length);
     let menu = format!("{}\n{}\n{}", header_footer, welcome_string, header_footer);
     println!("{}", menu);
 }
 
-pub fn print_admin_menu(header: bool){
+pub fn print_user_menu(items: &[MenuItem], header: bool) {
     if header {
-        print_admin_menu_header();
+        print_user_menu_header();
     }
+    println!("Choose from the options below:");
+    for (index, item) in items.iter().enumerate() {
+        println!("\t{}. {}", index + 1, item.get_name());
+    }
+    println!("\t0. Logout\n");
+}
 
-    println!(
-        "Choose from the options below:\n\
-        \t1. List Users\n\
-        \t2. Add User\n\
-        \t3. Remove User\n
-        \t0. Logout\n"
-    );
+/*
+ *  There is one menu for everyone, and each user sees only the items
+ *  their permissions allow, numbered in the order they are shown.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MenuItem {
+    SearchBooks,
+    AddBook,
+    DeleteBook,
+    PersonalInformation,
+    VerifyEmail,
+    TwoFactor,
+    BookDetails,
+    EditBookRecord,
+    ReadingLog,
+    ShelvesAndTags,
+    Series,
+    Locations,
+    NotificationPreferences,
+    Stocktake,
+    Duplicates,
+    BarcodesAndLabels,
+    ShelfList,
+    Statistics,
+    EditHistory,
+    AuditLog,
+    ListUsers,
+    AddUser,
+    RemoveUser,
+    UnlockUser,
+    ChangeUserRole,
+    BackupDatabase,
+    CheckDatabase,
 }
-pub fn print_user_menu(header: bool) {
-    if header {
-        print_user_menu_header();
+
+impl MenuItem {
+    pub const ALL: [MenuItem; 27] = [
+        MenuItem::SearchBooks,
+        MenuItem::AddBook,
+        MenuItem::DeleteBook,
+        MenuItem::PersonalInformation,
+        MenuItem::VerifyEmail,
+        MenuItem::TwoFactor,
+        MenuItem::BookDetails,
+        MenuItem::EditBookRecord,
+        MenuItem::ReadingLog,
+        MenuItem::ShelvesAndTags,
+        MenuItem::Series,
+        MenuItem::Locations,
+        MenuItem::NotificationPreferences,
+        MenuItem::Stocktake,
+        MenuItem::Duplicates,
+        MenuItem::BarcodesAndLabels,
+        MenuItem::ShelfList,
+        MenuItem::Statistics,
+        MenuItem::EditHistory,
+        MenuItem::AuditLog,
+        MenuItem::ListUsers,
+        MenuItem::AddUser,
+        MenuItem::RemoveUser,
+        MenuItem::UnlockUser,
+        MenuItem::ChangeUserRole,
+        MenuItem::BackupDatabase,
+        MenuItem::CheckDatabase,
+    ];
+
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            MenuItem::SearchBooks => "Search Your Books",
+            MenuItem::AddBook => "Add Book",
+            MenuItem::DeleteBook => "Delete Book",
+            MenuItem::PersonalInformation => "Modify Personal Information",
+            MenuItem::VerifyEmail => "Verify Email",
+            MenuItem::TwoFactor => "Two-Factor Authentication",
+            MenuItem::BookDetails => "View Book Details",
+            MenuItem::EditBookRecord => "Edit Book Record",
+            MenuItem::ReadingLog => "Reading Log",
+            MenuItem::ShelvesAndTags => "Shelves and Tags",
+            MenuItem::Series => "Series",
+            MenuItem::Locations => "Locations and Call Numbers",
+            MenuItem::NotificationPreferences => "Notification Preferences",
+            MenuItem::Stocktake => "Stocktake",
+            MenuItem::Duplicates => "Duplicates",
+            MenuItem::BarcodesAndLabels => "Barcodes and Labels",
+            MenuItem::ShelfList => "Shelf List",
+            MenuItem::Statistics => "Statistics",
+            MenuItem::EditHistory => "Book Edit History",
+            MenuItem::AuditLog => "Audit Log",
+            MenuItem::ListUsers => "List Users",
+            MenuItem::AddUser => "Add User",
+            MenuItem::RemoveUser => "Remove User",
+            MenuItem::UnlockUser => "Unlock User",
+            MenuItem::ChangeUserRole => "Change User Role",
+            MenuItem::BackupDatabase => "Backup Database",
+            MenuItem::CheckDatabase => "Check Database",
+        }
+    }
+
+    // Any one of these lets a user choose the item; items with none are for everyone.
+    pub fn get_permissions(&self) -> &'static [Permission] {
+        use Permission::*;
+        match self {
+            MenuItem::PersonalInformation
+            | MenuItem::VerifyEmail
+            | MenuItem::TwoFactor
+            | MenuItem::NotificationPreferences => &[],
+            MenuItem::SearchBooks
+            | MenuItem::AddBook
+            | MenuItem::DeleteBook
+            | MenuItem::BookDetails
+            | MenuItem::ReadingLog
+            | MenuItem::ShelvesAndTags
+            | MenuItem::Series => &[ManageOwnCollection],
+            MenuItem::EditBookRecord
+            | MenuItem::Locations
+            | MenuItem::Duplicates
+            | MenuItem::BarcodesAndLabels => &[EditCatalogue],
+            // Circulation staff walk the shelves and mark missing copies lost as well.
+            MenuItem::Stocktake => &[EditCatalogue, CheckOutToOthers],
+            MenuItem::ShelfList
+            | MenuItem::Statistics
+            | MenuItem::EditHistory
+            | MenuItem::AuditLog => &[ViewReports],
+            MenuItem::ListUsers
+            | MenuItem::AddUser
+            | MenuItem::RemoveUser
+            | MenuItem::UnlockUser
+            | MenuItem::ChangeUserRole => &[ManageUsers],
+            MenuItem::BackupDatabase | MenuItem::CheckDatabase => &[ManageSystem],
+        }
+    }
+
+    pub fn is_allowed(&self, user: &User) -> bool {
+        let permissions = self.get_permissions();
+        permissions.is_empty() || permissions.iter().any(|permission| user.has_permission(*permission))
+    }
+}
+
+pub fn get_menu_items(user: &User) -> Vec<MenuItem> {
+    MenuItem::ALL.iter().copied().filter(|item| item.is_allowed(user)).collect()
+}
+
+/*
+ *  Every menu action (and any future API endpoint) should call this
+ *  before doing anything, so that what a user can do is decided by
+ *  their role in one place.
+ */
+pub fn require_permission(user: &User, permission: Permission) -> bool {
+    if user.has_permission(permission) {
+        return true;
+    }
+    println!("You do not have permission to {}.", permission.description());
+    if !user.get_email_verified() {
+        println!("Some features are unavailable until you verify your email address.");
+    }
+    pause(2);
+    false
+}
+
+pub async fn process_menu_choice(choice: usize, items: &[MenuItem], user: &mut User, database_name: &str, config: &Config) -> bool {
+    let Some(&item) = choice.checked_sub(1).and_then(|index| items.get(index)) else {
+        println!("Logging out...");
+        pause(1);
+        return false; // Exit the loop
+    };
+    // The menu only shows what the user may do, but their permissions may have changed since.
+    if !item.is_allowed(user) {
+        if let Some(&permission) = item.get_permissions().first() {
+            require_permission(user, permission);
+        }
+        return true;
     }
-    println!(
-        "Choose from the options below:\n\
-        \t1. Search Your Books\n\
-        \t2. Add Book\n\
-        \t3. Delete Book\n\
-        \t4. Modify Personal Information\n\
-        \t0. Logout\n"
-    );
-}
-
-pub async fn process_user_menu_choice(choice: usize, user: &User, database_name: &str) -> bool {
-    match choice {
-        1 => {
-            println!("You chose to Search Your Books.");
-            // Implement search functionality here
-            // For example:
-            // search_books().await;
+
+    match item {
+        MenuItem::SearchBooks => {
+            holding_processing::search_collection(database_name, user, config.interface.max_list_rows);
             pause(2);
-            true // Continue the loop
         },
-        2 => {
-            if book_processing::add_new_book_to_collection(database_name, &user).await {
+        MenuItem::AddBook => {
+            if book_processing::add_new_book_to_collection(database_name, config, user).await {
                 println!("Book added successfully.");
             } else {
                 println!("Failed to add the book.");
             }
             pause(2);
-            true // Continue the loop
         },
-        3 => {
-            println!("You chose to Delete a Book.");
-            // Implement delete functionality here
-            // For example:
-            // delete_book().await;
-            if book_processing::delete_book_from_collection(database_name, &user) {
+        MenuItem::DeleteBook => {
+            if book_processing::delete_book_from_collection(database_name, user) {
                 println!("Book deleted successfully.");
             } else {
                 println!("Failed to delete the book.");
             }
             pause(2);
-            true // Continue the loop
         },
-        4 => {
-            println!("You chose to Modify Personal Information.");
-            // Implement modification functionality here
-            // For example:
-            // modify_user_info().await;
-            if user_processing::change_personal_information(database_name, &user) {
+        MenuItem::PersonalInformation => {
+            if user_processing::change_personal_information(database_name, user) {
                 println!("Personal information changed successfully.");
             } else {
                 println!("Failed to modify personal information.");
             }
             pause(2);
-            true // Continue the loop
         },
-        0 => {
-            println!("Logging out...");
-            pause(1);
-            false // Exit the loop
+        MenuItem::VerifyEmail => {
+            if user_management::verify_email(database_name, config, user) {
+                println!("Email verified successfully.");
+            } else {
+                println!("Failed to verify your email.");
+            }
+            pause(2);
         },
-        _ => {
-            println!("Invalid choice. Please try again.");
+        MenuItem::TwoFactor => {
+            if totp_management::manage_two_factor(database_name, config, user) {
+                println!("Two-factor settings updated.");
+            } else {
+                println!("Two-factor settings were not changed.");
+            }
+            pause(2);
+        },
+        MenuItem::BookDetails => {
+            book_processing::view_book_details(database_name, user);
+            pause(2);
+        },
+        MenuItem::EditBookRecord => {
+            if catalogue_processing::edit_book_record(database_name, config, user).await {
+                println!("Book record updated successfully.");
+            } else {
+                println!("The book record was not changed.");
+            }
+            pause(2);
+        },
+        MenuItem::ReadingLog => {
+            if reading_processing::update_reading(database_name, user) {
+                println!("Reading log saved.");
+            }
+            pause(2);
+        },
+        MenuItem::ShelvesAndTags => {
+            if label_processing::manage_labels(database_name, user) {
+                println!("Shelves and tags saved.");
+            }
+            pause(2);
+        },
+        MenuItem::Series => {
+            series_processing::view_series(database_name, user, config.interface.max_list_rows);
+            pause(2);
+        },
+        MenuItem::Locations => {
+            if location_processing::manage_locations(database_name, user, config.interface.max_list_rows) {
+                println!("Locations saved.");
+            }
+            pause(2);
+        },
+        MenuItem::NotificationPreferences => {
+            if notification_processing::manage_notification_preferences(database_name, user, &config.notifications) {
+                println!("Notification preferences saved.");
+            }
+            pause(2);
+        },
+        MenuItem::Stocktake => {
+            stocktake_processing::manage_stocktakes(database_name, user, config);
+        },
+        MenuItem::Duplicates => {
+            duplicate_processing::manage_duplicates(database_name, user, config);
+        },
+        MenuItem::BarcodesAndLabels => {
+            label_sheet_processing::manage_barcodes(database_name, config);
+        },
+        MenuItem::ShelfList => {
+            location_processing::view_shelf_list(database_name, config.interface.max_list_rows);
+            pause(2);
+        },
+        MenuItem::Statistics => {
+            statistics_processing::view_statistics(database_name, config.interface.max_list_rows);
+            pause(2);
+        },
+        MenuItem::EditHistory => {
+            provenance_management::view_edit_history(database_name, config);
+            pause(2);
+        },
+        MenuItem::AuditLog => {
+            audit_management::view_audit_log(database_name, config);
+            pause(2);
+        },
+        MenuItem::ListUsers => {
+            admin_processing::list_users(database_name, config);
             pause(2);
-            true // Continue the loop
         },
+        MenuItem::AddUser => {
+            user_management::register_user(database_name, config, Some(user));
+        },
+        MenuItem::RemoveUser => {
+            println!("You chose to Remove a User.");
+            // Implement user removal here
+            pause(2);
+        },
+        MenuItem::UnlockUser => {
+            if admin_processing::unlock_user(database_name, user) {
+                println!("User unlocked successfully.");
+            } else {
+                println!("Failed to unlock the user.");
+            }
+            pause(2);
+        },
+        MenuItem::ChangeUserRole => {
+            if admin_processing::change_user_role(database_name, user) {
+                println!("User role changed successfully.");
+            } else {
+                println!("Failed to change the user role.");
+            }
+            pause(2);
+        },
+        MenuItem::BackupDatabase => {
+            backup_management::backup_database(database_name, &config.backup);
+            pause(2);
+        },
+        MenuItem::CheckDatabase => {
+            backup_management::check_database(database_name);
+            pause(2);
+        },
+    }
+    true // Continue the loop
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::role_object::Role;
+
+    fn get_user(role: Role, email_verified: bool) -> User {
+        let mut user = User::new(1, "reader@example.org".to_string(), "Ann".to_string(), "Reader".to_string(), role);
+        user.set_email_verified(email_verified);
+        user
+    }
+
+    #[test]
+    fn staff_keep_the_collection_items_alongside_their_own() {
+        let items = get_menu_items(&get_user(Role::Librarian, true));
+        for item in [MenuItem::SearchBooks, MenuItem::EditBookRecord, MenuItem::ListUsers, MenuItem::Statistics] {
+            assert!(items.contains(&item), "{:?}", item);
+        }
+        assert!(!items.contains(&MenuItem::BackupDatabase));
+        assert_eq!(get_menu_items(&get_user(Role::Sysadmin, true)), MenuItem::ALL.to_vec());
+    }
+
+    #[test]
+    fn patrons_see_only_their_own_collection_and_account() {
+        let items = get_menu_items(&get_user(Role::Patron, true));
+        assert!(items.contains(&MenuItem::AddBook));
+        assert!(items.iter().all(|item| item.get_permissions().iter().all(|permission| *permission == Permission::ManageOwnCollection)));
+    }
+
+    #[test]
+    fn an_unverified_email_hides_everything_but_the_own_collection() {
+        let verified = get_menu_items(&get_user(Role::Librarian, true));
+        let unverified = get_menu_items(&get_user(Role::Librarian, false));
+        assert!(unverified.len() < verified.len());
+        assert!(unverified.contains(&MenuItem::VerifyEmail));
+        assert!(unverified.contains(&MenuItem::SearchBooks));
+        assert!(!unverified.contains(&MenuItem::ListUsers));
+    }
+
+    #[test]
+    fn cataloguers_and_the_circulation_desk_can_take_stock() {
+        assert!(get_menu_items(&get_user(Role::Cataloguer, true)).contains(&MenuItem::Stocktake));
+        assert!(get_menu_items(&get_user(Role::CirculationDesk, true)).contains(&MenuItem::Stocktake));
+        assert!(!get_menu_items(&get_user(Role::Patron, true)).contains(&MenuItem::Stocktake));
+    }
+
+    #[test]
+    fn anyone_who_edits_the_catalogue_can_review_duplicates() {
+        assert!(get_menu_items(&get_user(Role::Cataloguer, true)).contains(&MenuItem::Duplicates));
+        assert!(get_menu_items(&get_user(Role::Librarian, true)).contains(&MenuItem::Duplicates));
+        assert!(!get_menu_items(&get_user(Role::CirculationDesk, true)).contains(&MenuItem::Duplicates));
+        assert!(!get_menu_items(&get_user(Role::Cataloguer, false)).contains(&MenuItem::Duplicates));
+    }
+
+    #[test]
+    fn escaped_text_leaves_out_characters_xml_cannot_hold() {
+        assert_eq!(escape_xml("a\u{1}b\u{1F}c\u{FFFF}"), "abc");
+        assert_eq!(escape_xml("tab\there\r\nnext \"<&>\""), "tab\there\r\nnext &quot;&lt;&amp;&gt;&quot;");
     }
 }
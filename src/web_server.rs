@@ -0,0 +1,442 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use actix_web::http::header;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use data_encoding::BASE64;
+use rusqlite::Connection;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use crate::{backup_management, cover_management, notification_processing, oai_processing, opds_processing, sru_processing, statistics_processing, user_management, utilities};
+use crate::configuration::Config;
+use crate::opds_object::{FeedFormat, FeedQuery};
+use crate::role_object::Permission;
+use crate::statistics_object::Statistics;
+use crate::user_management::CredentialCheck;
+
+pub struct AppState {
+    pub database_name: String,
+    pub config: Config,
+    // Accepted basic authentication, by hash of email and password.
+    pub credentials: Mutex<HashMap<String, CachedLogin>>,
+}
+
+// The user and password hash a login was checked against, and when the check expires.
+pub struct CachedLogin {
+    user_id: i32,
+    password_hash: String,
+    expires_at: i64,
+}
+
+// E-reader apps send the password with every request; checking it each time would take a bcrypt verification per page.
+const CREDENTIAL_CACHE_SECONDS: i64 = 300;
+
+/*
+ *  Server mode, started with `rlms serve`. Runs until it is stopped and
+ *  also looks after the jobs that need no one at the keyboard, such as
+ *  scheduled backups and notifications. The address comes from the [web] section.
+ */
+pub async fn run_server(config: Config) -> std::io::Result<()> {
+    let database_name = config.database.file.clone().unwrap_or_default();
+    let address = (config.web.bind_address.clone(), config.web.port);
+
+    if config.backup.interval_minutes > 0 {
+        log::info!("Backing up {} every {} minute(s)", database_name, config.backup.interval_minutes);
+        tokio::spawn(backup_management::run_scheduled_backups(database_name.clone(), config.backup.clone()));
+    }
+    if config.notifications.interval_minutes > 0 {
+        log::info!("Sending notifications every {} minute(s)", config.notifications.interval_minutes);
+        tokio::spawn(notification_processing::run_scheduled_notifications(database_name.clone(), config.clone()));
+    }
+
+    let state = web::Data::new(AppState { database_name, config, credentials: Mutex::new(HashMap::new()) });
+    println!("Listening on http://{}:{}", address.0, address.1);
+    HttpServer::new(move || {
+        App::new()
+            .app_data(state.clone())
+            .route("/health", web::get().to(health))
+            .route("/covers/{book_id}", web::get().to(cover))
+            .route("/covers/{book_id}/thumbnail", web::get().to(cover_thumbnail))
+            .route("/reports", web::get().to(statistics_page))
+            .route("/reports/statistics.json", web::get().to(statistics_json))
+            .route("/reports/statistics.csv", web::get().to(statistics_csv))
+            .route("/opds/opensearch.xml", web::get().to(opds_opensearch))
+            .route("/opds", web::get().to(opds_start))
+            .route("/opds/{version}", web::get().to(opds_root))
+            .route("/opds/{version}/books/{book_id}", web::get().to(opds_publication))
+            .route("/opds/{version}/{feed}", web::get().to(opds_feed))
+            .route("/oai", web::get().to(oai_get))
+            .route("/oai", web::post().to(oai_post))
+            .route("/sru", web::get().to(sru_get))
+            .route("/sru", web::post().to(sru_post))
+    })
+    .bind(address)?
+    .run()
+    .await
+}
+
+async fn health(state: web::Data<AppState>) -> impl Responder {
+    let database_ok = Connection::open(&state.database_name)
+        .and_then(|connection| connection.query_row("SELECT 1", [], |row| row.get::<_, i32>(0)))
+        .is_ok();
+    if database_ok {
+        HttpResponse::Ok().json(json!({ "status": "ok" }))
+    } else {
+        HttpResponse::ServiceUnavailable().json(json!({ "status": "database unavailable" }))
+    }
+}
+
+async fn cover(state: web::Data<AppState>, request: HttpRequest, book_id: web::Path<i32>) -> HttpResponse {
+    serve_cover(&state, &request, book_id.into_inner(), false).await
+}
+
+async fn cover_thumbnail(state: web::Data<AppState>, request: HttpRequest, book_id: web::Path<i32>) -> HttpResponse {
+    serve_cover(&state, &request, book_id.into_inner(), true).await
+}
+
+/*
+ *  Covers are served from the database, never from Open Library, so they
+ *  show when offline. A stored cover only changes if it is downloaded
+ *  again, so its hash is a strong ETag.
+ */
+async fn serve_cover(state: &AppState, request: &HttpRequest, book_id: i32, thumbnail: bool) -> HttpResponse {
+    let database_name = state.database_name.clone();
+    let cover = web::block(move || {
+        Connection::open(&database_name).and_then(|connection| cover_management::get_cover(&connection, book_id, thumbnail))
+    }).await;
+    match cover {
+        Ok(Ok(Some((content_type, image, sha256)))) => {
+            let etag = format!("\"{}{}\"", sha256, if thumbnail { "-thumbnail" } else { "" });
+            let not_modified = request.headers()
+                .get(header::IF_NONE_MATCH)
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag));
+            if not_modified {
+                return HttpResponse::NotModified().insert_header((header::ETAG, etag)).finish();
+            }
+            HttpResponse::Ok()
+                .content_type(content_type)
+                .insert_header((header::ETAG, etag))
+                .insert_header((header::CACHE_CONTROL, "public, max-age=86400"))
+                .body(image)
+        }
+        Ok(Ok(None)) => HttpResponse::NotFound().json(json!({ "error": "no cover stored for this book" })),
+        Ok(Err(e)) => {
+            log::error!("Could not read the cover of book {}: {}", book_id, e);
+            HttpResponse::InternalServerError().json(json!({ "error": "database unavailable" }))
+        }
+        Err(e) => {
+            log::error!("Could not read the cover of book {}: {}", book_id, e);
+            HttpResponse::InternalServerError().json(json!({ "error": "could not answer the request" }))
+        }
+    }
+}
+
+fn load_statistics(state: &AppState) -> Result<Statistics, rusqlite::Error> {
+    Connection::open(&state.database_name).and_then(|connection| statistics_processing::get_statistics(&connection))
+}
+
+fn statistics_error(e: rusqlite::Error) -> HttpResponse {
+    log::error!("Could not gather the statistics: {}", e);
+    HttpResponse::InternalServerError().json(json!({ "error": "database unavailable" }))
+}
+
+async fn statistics_page(state: web::Data<AppState>, request: HttpRequest) -> HttpResponse {
+    if let Err(response) = authorise_reports(&state, &request).await {
+        return response;
+    }
+    match load_statistics(&state) {
+        Ok(statistics) => HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(statistics_processing::render_statistics_page(&statistics)),
+        Err(e) => statistics_error(e),
+    }
+}
+
+async fn statistics_json(state: web::Data<AppState>, request: HttpRequest) -> HttpResponse {
+    if let Err(response) = authorise_reports(&state, &request).await {
+        return response;
+    }
+    match load_statistics(&state) {
+        Ok(statistics) => HttpResponse::Ok().json(statistics),
+        Err(e) => statistics_error(e),
+    }
+}
+
+async fn statistics_csv(state: web::Data<AppState>, request: HttpRequest) -> HttpResponse {
+    if let Err(response) = authorise_reports(&state, &request).await {
+        return response;
+    }
+    let statistics = match load_statistics(&state) {
+        Ok(statistics) => statistics,
+        Err(e) => return statistics_error(e),
+    };
+    match statistics_processing::get_statistics_csv(&statistics) {
+        Ok(csv) => HttpResponse::Ok()
+            .content_type("text/csv; charset=utf-8")
+            .insert_header((header::CONTENT_DISPOSITION, "attachment; filename=\"statistics.csv\""))
+            .body(csv),
+        Err(e) => {
+            log::error!("Could not write the statistics as CSV: {}", e);
+            HttpResponse::InternalServerError().json(json!({ "error": "could not write the statistics" }))
+        }
+    }
+}
+
+// The reports are for the same users as the Reports menu: a valid login with permission to view reports.
+async fn authorise_reports(state: &web::Data<AppState>, request: &HttpRequest) -> Result<(), HttpResponse> {
+    let user_id = authenticate(state, request).await?;
+    let database_name = state.database_name.clone();
+    // The same check as the terminal makes, so an unverified email address withholds reports here too.
+    match web::block(move || user_management::get_user_by_id(&database_name, &user_id)).await {
+        Ok(Ok(user)) if user.has_permission(Permission::ViewReports) => Ok(()),
+        Ok(Ok(_)) => Err(HttpResponse::Forbidden().json(json!({ "error": "you do not have permission to view reports" }))),
+        Ok(Err(e)) => {
+            log::error!("Could not look up the user's permissions: {}", e);
+            Err(HttpResponse::InternalServerError().json(json!({ "error": "database unavailable" })))
+        }
+        Err(e) => {
+            log::error!("Could not look up the user's permissions: {}", e);
+            Err(HttpResponse::InternalServerError().json(json!({ "error": "could not check the user's permissions" })))
+        }
+    }
+}
+
+fn get_base_url(request: &HttpRequest) -> String {
+    let connection_info = request.connection_info();
+    format!("{}://{}", connection_info.scheme(), connection_info.host())
+}
+
+fn unauthorised() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header((header::WWW_AUTHENTICATE, "Basic realm=\"rLMS\", charset=\"UTF-8\""))
+        .json(json!({ "error": "an email and password are required" }))
+}
+
+/*
+ *  The OPDS feeds and reports use HTTP basic authentication with the
+ *  same email and password as the terminal. Returns the user's ID, or the
+ *  response to send instead. Only use this over HTTPS (e.g. behind a
+ *  proxy), as the password is sent with every request.
+ */
+async fn authenticate(state: &web::Data<AppState>, request: &HttpRequest) -> Result<i32, HttpResponse> {
+    let credentials = request.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|encoded| BASE64.decode(encoded.trim().as_bytes()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok());
+    let Some((email, password)) = credentials.as_deref().and_then(|credentials| credentials.split_once(':')) else {
+        return Err(unauthorised());
+    };
+    let (email, password) = (email.trim().to_lowercase(), password.to_string());
+
+    let key = Sha256::digest(format!("{}\0{}", email, password).as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    let now = utilities::current_timestamp();
+    let cached = state.credentials.lock().ok().and_then(|mut cache| {
+        cache.retain(|_, login| login.expires_at > now);
+        cache.get(&key).map(|login| (login.user_id, login.password_hash.clone()))
+    });
+    // A cached login only stands while the account's email, password and need for 2FA are as they were.
+    if let Some((user_id, hashed)) = cached {
+        let (database_name, config, email) = (state.database_name.clone(), state.config.clone(), email.clone());
+        let current = web::block(move || -> Result<bool, rusqlite::Error> {
+            Ok(user_management::get_login_fingerprint(&database_name, &email)? == Some((user_id, hashed))
+                && !user_management::requires_two_factor(&database_name, &config, user_id)?)
+        }).await;
+        match current {
+            Ok(Ok(true)) => return Ok(user_id),
+            _ => {
+                if let Ok(mut cache) = state.credentials.lock() {
+                    cache.remove(&key);
+                }
+            }
+        }
+    }
+
+    // Taken before the password is checked, so a change made meanwhile leaves the cached login stale.
+    let (database_name, config) = (state.database_name.clone(), state.config.clone());
+    let check = web::block(move || -> Result<(CredentialCheck, Option<(i32, String)>), rusqlite::Error> {
+        let login = user_management::get_login_fingerprint(&database_name, &email)?;
+        Ok((user_management::verify_credentials(&database_name, &config, &email, &password)?, login))
+    }).await;
+    match check {
+        Ok(Ok((CredentialCheck::Valid(user_id), login))) => {
+            if let (Some((_, password_hash)), Ok(mut cache)) = (login.filter(|login| login.0 == user_id), state.credentials.lock()) {
+                cache.insert(key, CachedLogin { user_id, password_hash, expires_at: now + CREDENTIAL_CACHE_SECONDS });
+            }
+            Ok(user_id)
+        }
+        Ok(Ok((CredentialCheck::Invalid, _))) => Err(unauthorised()),
+        Ok(Ok((CredentialCheck::TwoFactorRequired, _))) => Err(HttpResponse::Forbidden().json(json!({
+            "error": "this account uses two-factor authentication, which basic authentication cannot ask for"
+        }))),
+        Ok(Err(e)) => {
+            log::error!("Could not check the credentials: {}", e);
+            Err(HttpResponse::InternalServerError().json(json!({ "error": "database unavailable" })))
+        }
+        Err(e) => {
+            log::error!("Could not check the credentials: {}", e);
+            Err(HttpResponse::InternalServerError().json(json!({ "error": "could not check the credentials" })))
+        }
+    }
+}
+
+fn opds_response(format: FeedFormat, acquisition: bool, body: String) -> HttpResponse {
+    HttpResponse::Ok().content_type(format.get_feed_type(acquisition)).body(body)
+}
+
+fn opds_error(e: rusqlite::Error) -> HttpResponse {
+    log::error!("Could not build the OPDS feed: {}", e);
+    HttpResponse::InternalServerError().json(json!({ "error": "database unavailable" }))
+}
+
+// Feeds are read from the database on the blocking pool, so the async workers are never held up.
+async fn load_opds<T: Send + 'static>(
+    state: &AppState,
+    load: impl FnOnce(&Connection) -> Result<T, rusqlite::Error> + Send + 'static,
+) -> Result<T, HttpResponse> {
+    let database_name = state.database_name.clone();
+    match web::block(move || Connection::open(&database_name).and_then(|connection| load(&connection))).await {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) => Err(opds_error(e)),
+        Err(e) => {
+            log::error!("Could not build the OPDS feed: {}", e);
+            Err(HttpResponse::InternalServerError().json(json!({ "error": "could not answer the request" })))
+        }
+    }
+}
+
+fn opds_not_found() -> HttpResponse {
+    HttpResponse::NotFound().json(json!({ "error": "no such feed" }))
+}
+
+async fn opds_opensearch(state: web::Data<AppState>, request: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(opds_processing::OPENSEARCH_TYPE)
+        .body(opds_processing::render_opensearch_description(&get_base_url(&request), &state.config.web.opds_title))
+}
+
+// Apps that are given the bare address get OPDS 1.2, which every app reads.
+async fn opds_start() -> HttpResponse {
+    HttpResponse::Found().insert_header((header::LOCATION, FeedFormat::Atom.get_prefix())).finish()
+}
+
+async fn opds_root(state: web::Data<AppState>, request: HttpRequest, version: web::Path<String>) -> HttpResponse {
+    let Some(format) = FeedFormat::from_version(&version) else { return opds_not_found() };
+    let user_id = match authenticate(&state, &request).await {
+        Ok(user_id) => user_id,
+        Err(response) => return response,
+    };
+    let web_config = &state.config.web;
+    let title = web_config.opds_title.clone();
+    match load_opds(&state, move |connection| opds_processing::get_root_feed(connection, user_id, &title)).await {
+        Ok(feed) => opds_response(format, false, opds_processing::render_feed(&feed, format, &get_base_url(&request), &web_config.opds_title)),
+        Err(response) => response,
+    }
+}
+
+async fn opds_feed(state: web::Data<AppState>, request: HttpRequest, path: web::Path<(String, String)>, query: web::Query<FeedQuery>) -> HttpResponse {
+    let (version, name) = path.into_inner();
+    let Some(format) = FeedFormat::from_version(&version) else { return opds_not_found() };
+    let user_id = match authenticate(&state, &request).await {
+        Ok(user_id) => user_id,
+        Err(response) => return response,
+    };
+    let web_config = &state.config.web;
+    let (query, page_size) = (query.into_inner(), web_config.opds_page_size);
+    let page = query.page.unwrap_or(1).max(1);
+    if opds_processing::get_page_start(page, page_size).is_none() {
+        return HttpResponse::BadRequest().json(json!({ "error": "there is no such page" }));
+    }
+    let feed = load_opds(&state, move |connection| match name.as_str() {
+        "books" => opds_processing::get_books_feed(connection, user_id, &query, false, page_size).map(Some),
+        "recent" => opds_processing::get_books_feed(connection, user_id, &query, true, page_size).map(Some),
+        list => opds_processing::get_list_feed(connection, user_id, list, page, page_size),
+    }).await;
+    match feed {
+        Ok(Some(feed)) => opds_response(
+            format,
+            feed.acquisition,
+            opds_processing::render_feed(&feed, format, &get_base_url(&request), &web_config.opds_title),
+        ),
+        Ok(None) => opds_not_found(),
+        Err(response) => response,
+    }
+}
+
+async fn opds_publication(state: web::Data<AppState>, request: HttpRequest, path: web::Path<(String, i32)>) -> HttpResponse {
+    let (version, book_id) = path.into_inner();
+    let Some(format) = FeedFormat::from_version(&version) else { return opds_not_found() };
+    let user_id = match authenticate(&state, &request).await {
+        Ok(user_id) => user_id,
+        Err(response) => return response,
+    };
+    match load_opds(&state, move |connection| opds_processing::get_publication(connection, user_id, book_id)).await {
+        Ok(Some(publication)) => HttpResponse::Ok()
+            .content_type(format.get_entry_type())
+            .body(opds_processing::render_publication(&publication, format, &get_base_url(&request))),
+        Ok(None) => HttpResponse::NotFound().json(json!({ "error": "this book is not in your collection" })),
+        Err(response) => response,
+    }
+}
+
+async fn oai_get(state: web::Data<AppState>, request: HttpRequest, arguments: web::Query<Vec<(String, String)>>) -> HttpResponse {
+    oai_respond(&state, &request, arguments.into_inner()).await
+}
+
+// Harvesters may send the arguments as a form instead, when they are too long for a URL.
+async fn oai_post(state: web::Data<AppState>, request: HttpRequest, arguments: web::Form<Vec<(String, String)>>) -> HttpResponse {
+    oai_respond(&state, &request, arguments.into_inner()).await
+}
+
+async fn oai_respond(state: &AppState, request: &HttpRequest, arguments: Vec<(String, String)>) -> HttpResponse {
+    let (database_name, config, base_url) = (state.database_name.clone(), state.config.clone(), get_base_url(request));
+    let response = web::block(move || {
+        Connection::open(&database_name)
+            .and_then(|connection| oai_processing::handle_request(&connection, &config, &base_url, &arguments))
+    }).await;
+    match response {
+        Ok(Ok(xml)) => HttpResponse::Ok().content_type("text/xml; charset=utf-8").body(xml),
+        Ok(Err(e)) => {
+            log::error!("Could not answer the OAI-PMH request: {}", e);
+            HttpResponse::ServiceUnavailable()
+                .insert_header((header::RETRY_AFTER, "60"))
+                .json(json!({ "error": "database unavailable" }))
+        }
+        Err(e) => {
+            log::error!("Could not answer the OAI-PMH request: {}", e);
+            HttpResponse::InternalServerError().json(json!({ "error": "could not answer the request" }))
+        }
+    }
+}
+
+async fn sru_get(state: web::Data<AppState>, request: HttpRequest, arguments: web::Query<Vec<(String, String)>>) -> HttpResponse {
+    sru_respond(&state, &request, arguments.into_inner()).await
+}
+
+// SRU 2.0 allows the parameters to be posted as a form.
+async fn sru_post(state: web::Data<AppState>, request: HttpRequest, arguments: web::Form<Vec<(String, String)>>) -> HttpResponse {
+    sru_respond(&state, &request, arguments.into_inner()).await
+}
+
+async fn sru_respond(state: &AppState, request: &HttpRequest, arguments: Vec<(String, String)>) -> HttpResponse {
+    let (database_name, config, base_url) = (state.database_name.clone(), state.config.clone(), get_base_url(request));
+    let response = web::block(move || {
+        Connection::open(&database_name)
+            .and_then(|connection| sru_processing::handle_request(&connection, &config, &base_url, &arguments))
+    }).await;
+    match response {
+        Ok(Ok(xml)) => HttpResponse::Ok().content_type("application/xml; charset=utf-8").body(xml),
+        Ok(Err(e)) => {
+            log::error!("Could not answer the SRU request: {}", e);
+            HttpResponse::InternalServerError().json(json!({ "error": "database unavailable" }))
+        }
+        Err(e) => {
+            log::error!("Could not answer the SRU request: {}", e);
+            HttpResponse::InternalServerError().json(json!({ "error": "could not answer the request" }))
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use crate::book_object::Book;
+use crate::book_processing::get_book_info;
+use crate::configuration::MetadataConfig;
+use crate::utilities;
+
+/*
+ *  A work is the book in the abstract: every edition, translation and
+ *  format of it is a separate record in `books`, linked to the same work.
+ *  Works are identified by their Open Library work key, e.g. /works/OL61W,
+ *  so records without a key are simply not grouped with anything.
+ */
+pub fn get_work_key(book: &Book) -> Option<String> {
+    book.works
+        .as_ref()?
+        .first()
+        .map(|work| work.key.trim().to_string())
+        .filter(|key| !key.is_empty())
+}
+
+pub fn get_or_create_work(connection: &Connection, work_key: &str, title: &str, author: &str) -> Result<i64, rusqlite::Error> {
+    connection.execute(
+        "INSERT INTO works (work_key, title, author, created_at) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT (work_key) DO NOTHING",
+        params![work_key, title, author, utilities::current_timestamp()],
+    )?;
+    connection.query_row("SELECT work_id FROM works WHERE work_key = ?1", params![work_key], |row| row.get(0))
+}
+
+/// Links the book to the work unless it is already linked to one.
+pub fn link_book_to_work(connection: &Connection, book_id: i32, work_id: i64) -> Result<bool, rusqlite::Error> {
+    let updated = connection.execute(
+        "UPDATE books SET work_id = ?2 WHERE book_id = ?1 AND work_id IS NULL",
+        params![book_id, work_id],
+    )?;
+    Ok(updated == 1)
+}
+
+/// Returns (work key, work title) for the work the book belongs to, if any.
+pub fn get_work_for_book(connection: &Connection, book_id: i32) -> Result<Option<(String, String)>, rusqlite::Error> {
+    connection.query_row(
+        "SELECT works.work_key, works.title FROM works
+         JOIN books ON books.work_id = works.work_id
+         WHERE books.book_id = ?1",
+        params![book_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).optional()
+}
+
+/// Returns (book_id, title, isbn) for every other edition of the same work.
+pub fn get_other_editions(connection: &Connection, book_id: i32) -> Result<Vec<(i32, String, Option<String>)>, rusqlite::Error> {
+    let mut stmt = connection.prepare(
+        "SELECT other.book_id, other.title, other.isbn
+         FROM books AS this
+         JOIN books AS other ON other.work_id = this.work_id AND other.book_id != this.book_id
+         WHERE this.book_id = ?1
+         ORDER BY other.title, other.isbn",
+    )?;
+    let editions = stmt.query_map(params![book_id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    editions.collect()
+}
+
+/*
+ *  Catalogue records saved before works existed have no work. This looks
+ *  up each of them that has an ISBN again and links it to its work,
+ *  returning how many were linked and how many could not be (not found,
+ *  or no work key).
+ */
+pub async fn link_unlinked_books(database_name: &str, metadata: &MetadataConfig) -> Result<(usize, usize), Box<dyn std::error::Error>> {
+    let connection = Connection::open(database_name)?;
+    let books: Vec<(i32, String, String, String)> = {
+        let mut stmt = connection.prepare("SELECT book_id, isbn, title, author FROM books WHERE work_id IS NULL AND isbn IS NOT NULL")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?;
+        rows.collect::<Result<_, _>>()?
+    };
+
+    let (mut linked, mut skipped) = (0, 0);
+    for (book_id, isbn, title, author) in books {
+        let work_key = match get_book_info(&isbn, metadata).await {
+            Ok(book) => get_work_key(&book),
+            Err(e) => {
+                log::warn!("Could not look up ISBN {}: {}", isbn, e);
+                None
+            }
+        };
+        match work_key {
+            Some(work_key) => {
+                let work_id = get_or_create_work(&connection, &work_key, &title, &author)?;
+                link_book_to_work(&connection, book_id, work_id)?;
+                linked += 1;
+            }
+            None => skipped += 1,
+        }
+    }
+    Ok((linked, skipped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book_object::{Author, WorkLink};
+    use crate::catalogue_processing;
+    use crate::initialisation;
+
+    fn save_edition(connection: &Connection, title: &str, isbn: &str, work_key: Option<&str>) -> i32 {
+        let book = Book {
+            title: title.to_string(),
+            isbn: isbn.to_string(),
+            authors: vec![Author { name: "J. R. R. Tolkien".to_string() }],
+            works: work_key.map(|key| vec![WorkLink { key: key.to_string() }]),
+            ..Book::default()
+        };
+        catalogue_processing::save_catalogue_record(connection, &book, catalogue_processing::SOURCE_MANUAL).unwrap()
+    }
+
+    #[test]
+    fn editions_with_the_same_work_key_are_grouped() {
+        let (_, connection) = initialisation::open_test_database("works_grouped");
+        let hardback = save_edition(&connection, "The Hobbit", "9780261102217", Some("/works/OL27482W"));
+        let paperback = save_edition(&connection, "The Hobbit", "9780547928227", Some(" /works/OL27482W "));
+        let unrelated = save_edition(&connection, "Farmer Giles of Ham", "9780261102163", None);
+
+        let works: i64 = connection.query_row("SELECT COUNT(*) FROM works", [], |row| row.get(0)).unwrap();
+        assert_eq!(works, 1);
+        assert_eq!(
+            get_work_for_book(&connection, paperback).unwrap(),
+            Some(("/works/OL27482W".to_string(), "The Hobbit".to_string()))
+        );
+        assert_eq!(
+            get_other_editions(&connection, hardback).unwrap(),
+            vec![(paperback, "The Hobbit".to_string(), Some("9780547928227".to_string()))]
+        );
+        assert_eq!(get_work_for_book(&connection, unrelated).unwrap(), None);
+        assert!(get_other_editions(&connection, unrelated).unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_book_keeps_the_work_it_was_first_linked_to() {
+        let (_, connection) = initialisation::open_test_database("works_kept");
+        let book_id = save_edition(&connection, "The Hobbit", "9780261102217", Some("/works/OL27482W"));
+        let other_work = get_or_create_work(&connection, "/works/OL1W", "Other", "Someone").unwrap();
+        assert!(!link_book_to_work(&connection, book_id, other_work).unwrap());
+        assert_eq!(get_work_for_book(&connection, book_id).unwrap().unwrap().0, "/works/OL27482W");
+        assert_eq!(get_or_create_work(&connection, "/works/OL1W", "Renamed", "Someone").unwrap(), other_work);
+    }
+
+    #[test]
+    fn blank_work_keys_are_ignored() {
+        let book = Book { works: Some(vec![WorkLink { key: "  ".to_string() }]), ..Book::default() };
+        assert_eq!(get_work_key(&book), None);
+        assert_eq!(get_work_key(&Book::default()), None);
+    }
+}